@@ -0,0 +1,136 @@
+use std::{fs, io, ops::Range, path::Path};
+
+use crate::devices::{bus::Bus, Device, memory::{MEMORY_START, MEMORY_END, PAGE_SIZE}, tree::DTB_ADDRESS, framebuffer};
+
+// A Linux Image and initrd loaded next to the firmware, as qemu does with -kernel. The firmware finds the kernel
+// through the fw_dynamic info in a2, the kernel its initrd through the device tree.
+
+const IMAGE_HEADER_SIZE: usize = 64;
+const IMAGE_TEXT_OFFSET: usize = 8;
+const IMAGE_SIZE: usize = 16;
+// "RISCV\0\0\0", deprecated but still written
+const IMAGE_MAGIC: usize = 48;
+// "RSC\x05"
+const IMAGE_MAGIC2: usize = 56;
+
+// the kernel maps itself with 2 MiB pages
+const KERNEL_ALIGN: u64 = 0x200000;
+
+// the last page of memory, above the device tree
+pub(crate) const FW_DYNAMIC_ADDRESS: u64 = MEMORY_END + 1 - PAGE_SIZE;
+const FW_DYNAMIC_MAGIC: u64 = 0x4942534f;
+const FW_DYNAMIC_VERSION: u64 = 2;
+const NEXT_MODE_SUPERVISOR: u64 = 1;
+
+#[derive(Debug)]
+pub(crate) struct Kernel {
+  image: Vec<u8>,
+  text_offset: u64,
+  // in memory, with the bss
+  size: u64,
+  initrd: Option<Vec<u8>>,
+}
+
+impl Kernel {
+  pub(crate) fn open(image: &Path, initrd: Option<&Path>) -> io::Result<Kernel> {
+    let image = fs::read(image)?;
+    if image.len() < IMAGE_HEADER_SIZE
+      || (&image[IMAGE_MAGIC..IMAGE_MAGIC + 8] != b"RISCV\0\0\0" && &image[IMAGE_MAGIC2..IMAGE_MAGIC2 + 4] != b"RSC\x05") {
+      return Err(io::Error::new(io::ErrorKind::InvalidData, "not a RISC-V Image"));
+    }
+    let field = |offset: usize| u64::from_le_bytes(image[offset..offset + 8].try_into().unwrap());
+    let (text_offset, size) = (field(IMAGE_TEXT_OFFSET), field(IMAGE_SIZE));
+    // old headers leave the size out
+    let size = size.max(image.len() as u64);
+    let initrd = initrd.map(fs::read).transpose()?;
+    Ok(Kernel { image, text_offset, size, initrd })
+  }
+
+  // the kernel at its text offset or past the firmware, the initrd at the top of memory under the device tree.
+  // Returns the entry point and where the initrd went
+  pub(crate) fn load(&self, bus: &mut Bus, firmware_end: u64) -> Result<(u64, Option<Range<u64>>), String> {
+    // both come from the header, which may be corrupt
+    let start = MEMORY_START.checked_add(self.text_offset)
+      .ok_or_else(|| format!("the kernel does not fit in memory at text offset {:#x}", self.text_offset))?
+      .max(firmware_end.next_multiple_of(KERNEL_ALIGN));
+    let end = start.checked_add(self.size).filter(|&end| end <= DTB_ADDRESS)
+      .ok_or_else(|| format!("the kernel does not fit in memory at {:#x}", start))?;
+    if framebuffer::overlaps(start, end) {
+      return Err(format!("the kernel at {:#x}..{:#x} overlaps the framebuffer", start, end));
+    }
+    bus.write_bytes(start, &self.image).map_err(|_| format!("failed to load the kernel at {:#x}", start))?;
+    let initrd = match &self.initrd {
+      Some(initrd) => {
+        let initrd_start = DTB_ADDRESS.checked_sub(initrd.len() as u64).map(|start| start / PAGE_SIZE * PAGE_SIZE)
+          .filter(|&initrd_start| initrd_start >= end)
+          .ok_or("the initrd does not fit in memory above the kernel")?;
+        let initrd_end = initrd_start + initrd.len() as u64;
+        if framebuffer::overlaps(initrd_start, initrd_end) {
+          return Err(format!("the initrd at {:#x}..{:#x} overlaps the framebuffer", initrd_start, initrd_end));
+        }
+        bus.write_bytes(initrd_start, initrd).map_err(|_| "failed to load the initrd".to_string())?;
+        Some(initrd_start..initrd_end)
+      },
+      None => None,
+    };
+    Ok((start, initrd))
+  }
+}
+
+// where fw_dynamic firmware goes next, in supervisor mode
+pub(crate) fn write_fw_dynamic(bus: &mut Bus, next: u64, hartid: u64) -> u64 {
+  // magic, version, next address, next mode, options, boot hart
+  let info = [FW_DYNAMIC_MAGIC, FW_DYNAMIC_VERSION, next, NEXT_MODE_SUPERVISOR, 0, hartid];
+  for (index, field) in info.into_iter().enumerate() {
+    bus.write64(FW_DYNAMIC_ADDRESS + 8 * index as u64, field).unwrap();
+  }
+  FW_DYNAMIC_ADDRESS
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::devices::{bus::Bus, Device};
+
+  use super::*;
+
+  #[test]
+  fn image_and_initrd() {
+    let directory = std::env::temp_dir();
+    let (image, initrd) = (directory.join(format!("yuri-image-{}", std::process::id())), directory.join(format!("yuri-initrd-{}", std::process::id())));
+    let mut header = vec![0; IMAGE_HEADER_SIZE];
+    header[IMAGE_TEXT_OFFSET..IMAGE_TEXT_OFFSET + 8].copy_from_slice(&0x200000u64.to_le_bytes());
+    header[IMAGE_SIZE..IMAGE_SIZE + 8].copy_from_slice(&0x1000000u64.to_le_bytes());
+    header[IMAGE_MAGIC2..IMAGE_MAGIC2 + 4].copy_from_slice(b"RSC\x05");
+    fs::write(&image, &header).unwrap();
+    fs::write(&initrd, [7; 5000]).unwrap();
+    let kernel = Kernel::open(&image, Some(&initrd)).unwrap();
+    let (mut bus, _controller) = Bus::new();
+    // at the text offset, or past firmware which reaches beyond it
+    assert_eq!(kernel.load(&mut bus, MEMORY_START + 0x40000).unwrap().0, MEMORY_START + 0x200000);
+    let (entry, initrd_range) = kernel.load(&mut bus, MEMORY_START + 0x280000).unwrap();
+    assert_eq!(entry, MEMORY_START + 0x400000);
+    let initrd_range = initrd_range.unwrap();
+    assert_eq!(initrd_range, DTB_ADDRESS - 0x2000..DTB_ADDRESS - 0x2000 + 5000);
+    assert_eq!(bus.read8(initrd_range.start).unwrap(), 7);
+    assert_eq!(write_fw_dynamic(&mut bus, entry, 0), FW_DYNAMIC_ADDRESS);
+    assert_eq!(bus.read64(FW_DYNAMIC_ADDRESS).unwrap(), FW_DYNAMIC_MAGIC);
+    assert_eq!(bus.read64(FW_DYNAMIC_ADDRESS + 16).unwrap(), entry);
+    // offsets and sizes which wrap around
+    for (text_offset, size) in [(u64::MAX - 0x1000, 0x1000000), (0x200000, u64::MAX - 0x1000)] {
+      header[IMAGE_TEXT_OFFSET..IMAGE_TEXT_OFFSET + 8].copy_from_slice(&text_offset.to_le_bytes());
+      header[IMAGE_SIZE..IMAGE_SIZE + 8].copy_from_slice(&size.to_le_bytes());
+      fs::write(&image, &header).unwrap();
+      assert!(Kernel::open(&image, None).unwrap().load(&mut bus, MEMORY_START).is_err());
+    }
+    // a bss reaching into the framebuffer
+    header[IMAGE_TEXT_OFFSET..IMAGE_TEXT_OFFSET + 8].copy_from_slice(&0x200000u64.to_le_bytes());
+    header[IMAGE_SIZE..IMAGE_SIZE + 8].copy_from_slice(&0x8000000u64.to_le_bytes());
+    fs::write(&image, &header).unwrap();
+    let err = Kernel::open(&image, None).unwrap().load(&mut bus, MEMORY_START).unwrap_err();
+    assert!(err.contains("overlaps the framebuffer"), "{}", err);
+    fs::write(&image, [0; 100]).unwrap();
+    assert!(Kernel::open(&image, None).is_err());
+    fs::remove_file(image).unwrap();
+    fs::remove_file(initrd).unwrap();
+  }
+}
@@ -1,13 +1,29 @@
-use std::{fs, path::PathBuf};
+use std::{fs, path::PathBuf, time::{Duration, Instant}};
 
 use elf::{ElfBytes, endian::LittleEndian};
 
-use crate::{hart::Hart, devices::{bus::{Bus, DeviceController}, Device}, mmu::MMU, utils::channel::{Receiver, Sender}};
+use crate::{boot::{Kernel, write_fw_dynamic}, loader::Image, hart::{Hart, Mode}, sbi, devices::{bus::{Bus, DeviceController}, Device, memory::MEMORY_START, aclint::TIMEBASE_FREQUENCY, framebuffer::Screenshots, finisher::Finish, virtio::input::script::InputScript, tree::{Chosen, DTB_ADDRESS}}, mmu::MMU, monitor::Monitor, utils::channel::{Receiver, Sender}};
 
 pub(crate) struct Cpu {
   pub(crate) bus: Bus,
   pub(crate) mmu: MMU,
   pub(crate) hart: Hart,
+  // guest time only depends on the instructions executed
+  pub(crate) deterministic: bool,
+  pub(crate) screenshots: Option<Screenshots>,
+  pub(crate) input_script: Option<InputScript>,
+  pub(crate) monitor: Option<Monitor>,
+  pub(crate) chosen: Chosen,
+  // loaded after the firmware
+  pub(crate) kernel: Option<Kernel>,
+  // the built-in SBI stands in for firmware
+  pub(crate) sbi: bool,
+  // the firmware first, then further images, loaded again on reset
+  pub(crate) images: Vec<Image>,
+  // retired since the start
+  pub(crate) instructions: u64,
+  #[cfg(feature = "jit")]
+  pub(crate) jit: Option<crate::jit::Jit>,
   bus_step: u32,
 }
 
@@ -19,34 +35,133 @@ impl Cpu {
       mmu,
       bus: bus.clone(),
       hart: Hart::new(),
+      deterministic: false,
+      screenshots: None,
+      input_script: None,
+      monitor: None,
+      chosen: Chosen::default(),
+      kernel: None,
+      sbi: false,
+      images: Vec::new(),
+      instructions: 0,
+      #[cfg(feature = "jit")]
+      jit: None,
       bus_step: 0,
     }, controller)
   }
 
-  pub(crate) fn run_elf(&mut self, file: PathBuf) {
-    let file = fs::read(file).unwrap();
-    let elf = ElfBytes::<LittleEndian>::minimal_parse(&file).unwrap();
-    for segment in elf.segments().unwrap() {
-      for i in 0..segment.p_filesz {
-        if segment.p_type != elf::abi::PT_LOAD { continue; }
-        self.bus.write8(segment.p_paddr + i, file[(segment.p_offset + i) as usize]).unwrap();
+  // the images, then the kernel past them with its fw_dynamic info, then the device tree. The boot ROM passes them on
+  // and enters the firmware in machine mode, or with the built-in SBI the kernel or else the firmware in supervisor mode.
+  // Returns the device tree
+  pub(crate) fn boot(&mut self) -> Vec<u8> {
+    let mut end = MEMORY_START;
+    for image in &self.images {
+      end = end.max(image.load(&mut self.bus).unwrap_or_else(|err| panic!("failed to load an image: {}", err)));
+    }
+    let mut next = self.images.first().map_or(MEMORY_START, |firmware| firmware.entry);
+    let mut info = 0;
+    if let Some(kernel) = &self.kernel {
+      let (entry, initrd) = kernel.load(&mut self.bus, end).unwrap_or_else(|err| panic!("{}", err));
+      self.chosen.initrd = initrd;
+      if self.sbi {
+        next = entry;
+      } else {
+        info = write_fw_dynamic(&mut self.bus, entry, self.hart.csr.read_mhartid());
       }
     }
-    self.hart.pc = elf.ehdr.e_entry;
+    let mode = if self.sbi {
+      sbi::enter(&mut self.hart, &mut self.bus);
+      Mode::Supervisor
+    } else {
+      Mode::Machine
+    };
+    let dtb = self.bus.device_tree(&self.hart, &self.chosen);
+    self.bus.write_bytes(DTB_ADDRESS, &dtb).unwrap();
+    let mut rom = self.bus.rom.lock().unwrap();
+    rom.set_next(next, mode, DTB_ADDRESS, info);
+    self.hart.pc = rom.start();
+    dtb
+  }
+
+  // returns the exit code the guest gave to the finisher
+  pub(crate) fn run(&mut self) -> i32 {
+    self.boot();
 
     let mut bus = self.bus.clone();
     loop {
-      self.hart.step(&mut self.mmu);
+      let retired = self.step();
+      self.bus_step += retired;
+      self.instructions += retired as u64;
       if self.bus_step > 1000 || self.hart.wfi {
         self.bus_step = 0;
         self.bus.step(&mut bus, &mut self.hart);
-      } else {
-        self.bus_step += 1;
+        if let Some(screenshots) = &mut self.screenshots {
+          screenshots.step(&self.bus.memory, self.instructions);
+        }
+        if let Some(script) = &mut self.input_script {
+          script.step(self.instructions, self.bus.aclint.lock().unwrap().mtime());
+        }
+        let mut finish = self.bus.finisher.lock().unwrap().finish.take();
+        if let Some(monitor) = &mut self.monitor {
+          finish = finish.or_else(|| monitor.poll(&self.hart, &mut self.bus));
+        }
+        match finish {
+          Some(Finish::Exit(code)) => return code,
+          Some(Finish::Reset) => {
+            self.reset();
+            continue;
+          },
+          None => {},
+        }
+        if self.hart.wfi && self.hart.csr.read_mie_mip() == 0 {
+          self.idle();
+        }
+      }
+    }
+  }
+
+  // the machine back to power-on state without restarting yuri: the hart with its CSRs and the devices are reset,
+  // memory keeps its contents but the images are loaded again, and the hart starts at the reset vector
+  pub(crate) fn reset(&mut self) {
+    self.hart = Hart::new();
+    self.mmu = MMU::new(self.bus.clone());
+    self.bus.reset();
+    self.bus_step = 0;
+    self.boot();
+  }
+
+  // run a block, returns retired instructions
+  fn step(&mut self) -> u32 {
+    #[cfg(feature = "jit")]
+    if let Some(jit) = &mut self.jit {
+      return jit.step_block(&mut self.hart, &mut self.mmu);
+    }
+    self.hart.step_block(&mut self.mmu)
+  }
+
+  // sleep until the next device deadline or script line, or until something notifies the bus
+  fn idle(&mut self) {
+    let mtime = self.bus.aclint.lock().unwrap().mtime();
+    let script = self.input_script.as_ref().and_then(InputScript::next_time).map(|time| time.saturating_sub(mtime));
+    let deadline = [self.bus.deadline(), script].into_iter().flatten().min();
+    if self.deterministic {
+      // skip the ticks which would have been spent spinning
+      match deadline {
+        Some(ticks) => self.bus.aclint.lock().unwrap().advance(ticks),
+        None => self.bus.notifier.wait(None),
       }
+    } else {
+      let start = Instant::now();
+      self.bus.notifier.wait(deadline.map(|ticks|
+        Duration::from_nanos((ticks as u128 * 1_000_000_000 / TIMEBASE_FREQUENCY as u128) as u64)));
+      let elapsed = (start.elapsed().as_nanos() * TIMEBASE_FREQUENCY as u128 / 1_000_000_000) as u64;
+      let ticks = deadline.map_or(elapsed, |deadline| elapsed.min(deadline));
+      self.bus.aclint.lock().unwrap().advance(ticks);
     }
   }
 
-  pub(crate) fn run_htif(&mut self, file: PathBuf, stdin: Receiver<i32>, stdout: Sender<i32>) {
+  // blocks -> the hart runs a block at a time as in run, else an instruction at a time
+  pub(crate) fn run_htif(&mut self, file: PathBuf, stdin: Receiver<i32>, stdout: Sender<i32>, blocks: bool) {
     let file = fs::read(file).unwrap();
     let elf = ElfBytes::<LittleEndian>::minimal_parse(&file).unwrap();
     for segment in elf.segments().unwrap() {
@@ -66,7 +181,11 @@ impl Cpu {
 
     let mut bus = self.bus.clone();
     loop {
-      self.hart.step(&mut self.mmu);
+      if blocks {
+        self.hart.step_block(&mut self.mmu);
+      } else {
+        self.hart.step(&mut self.mmu);
+      }
       self.bus.step(&mut bus, &mut self.hart);
       let tovm = self.bus.read64(fromhost).unwrap();
       if tovm != 0 { continue; }
@@ -90,19 +209,140 @@ impl Cpu {
 #[cfg(test)]
 mod tests {
   use std::fs;
-  use crate::utils::channel::channel;
+  use crate::{csrs::CsrRegistry, devices::{Device, memory::MEMORY_START, aclint::MTIMECMP0_START, plic::{PLIC_START, PLIC_SOURCE_ENABLE_START}, uart::{UART_START, UART_INTERRUPT_ID}, virtio::input::script::{InputScript, Inputs}}, loader::{Format, Image}, utils::channel::channel};
   use super::Cpu;
 
   #[test]
   fn riscv_tests() {
     let dir = fs::read_dir("tests").unwrap();
     for file in dir {
-      let (_, htif_receiver) = channel::<i32>();
-      let (htif_sender, _) = channel::<i32>();
       let file = file.unwrap().path();
-      let (mut cpu, _) = Cpu::new();
-      cpu.run_htif(file.clone(), htif_receiver, htif_sender);
-      println!("'{}' passed", file.to_str().unwrap());
+      // through the interpreter, then through translated and chained blocks
+      for blocks in [false, true] {
+        let (_, htif_receiver) = channel::<i32>();
+        let (htif_sender, _) = channel::<i32>();
+        let (mut cpu, _) = Cpu::new();
+        cpu.run_htif(file.clone(), htif_receiver, htif_sender, blocks);
+        println!("'{}' passed{}", file.to_str().unwrap(), if blocks { " in blocks" } else { "" });
+      }
     }
   }
+
+  #[test]
+  fn wfi_wakes_on_timer() {
+    for deterministic in [true, false] {
+      let (mut cpu, _controller) = Cpu::new();
+      cpu.deterministic = deterministic;
+      let mut bus = cpu.bus.clone();
+      // wfi with only mie.MTIE set, a millisecond before the timer fires
+      cpu.bus.write32(MEMORY_START, 0x10500073).unwrap();
+      CsrRegistry::write(&mut cpu.hart, 0x304, 1 << 7).unwrap();
+      cpu.bus.write64(MTIMECMP0_START, 10000).unwrap();
+      cpu.hart.pc = MEMORY_START;
+      cpu.step();
+      assert!(cpu.hart.wfi);
+      for _ in 0..100 {
+        if !cpu.hart.wfi { break; }
+        cpu.bus.step(&mut bus, &mut cpu.hart);
+        if cpu.hart.csr.read_mie_mip() == 0 {
+          cpu.idle();
+        }
+        cpu.step();
+      }
+      assert!(!cpu.hart.wfi);
+      assert!(cpu.bus.aclint.lock().unwrap().mtime() >= 10000);
+    }
+  }
+
+  #[test]
+  fn wfi_wakes_on_character_timeout() {
+    for deterministic in [true, false] {
+      let (mut cpu, controller) = Cpu::new();
+      cpu.deterministic = deterministic;
+      let mut bus = cpu.bus.clone();
+      // wfi with mie.MEIE, the UART interrupt routed to machine mode and no timer
+      cpu.bus.write32(MEMORY_START, 0x10500073).unwrap();
+      CsrRegistry::write(&mut cpu.hart, 0x304, 1 << 11).unwrap();
+      cpu.bus.write64(MTIMECMP0_START, u64::MAX).unwrap();
+      cpu.bus.write32(PLIC_START + UART_INTERRUPT_ID as u64 * 4, 1).unwrap();
+      cpu.bus.write32(PLIC_SOURCE_ENABLE_START, 1 << UART_INTERRUPT_ID).unwrap();
+      // FIFO with a trigger level of 8 and one byte in it
+      cpu.bus.write8(UART_START + 2, 0x81).unwrap();
+      cpu.bus.write8(UART_START + 1, 0x01).unwrap();
+      controller.uart_sender.send(vec![b'x']);
+      cpu.hart.pc = MEMORY_START;
+      cpu.step();
+      assert!(cpu.hart.wfi);
+      for _ in 0..100 {
+        if !cpu.hart.wfi { break; }
+        cpu.bus.step(&mut bus, &mut cpu.hart);
+        if cpu.hart.csr.read_mie_mip() == 0 {
+          cpu.idle();
+        }
+        cpu.step();
+      }
+      assert!(!cpu.hart.wfi);
+      // the character timeout, IIR is CTI
+      assert_eq!(cpu.bus.read8(UART_START + 2).unwrap() & 0x0f, 0x0c);
+      assert!(cpu.bus.aclint.lock().unwrap().mtime() < 1_000_000);
+    }
+  }
+
+  #[test]
+  fn wfi_waits_for_script_time() {
+    let path = std::env::temp_dir().join(format!("yuri-script-wfi-{}", std::process::id()));
+    fs::write(&path, "5ms key a\n").unwrap();
+    for deterministic in [true, false] {
+      let (mut cpu, _controller) = Cpu::new();
+      cpu.deterministic = deterministic;
+      let mut bus = cpu.bus.clone();
+      let (keyboard, keys) = channel();
+      let (tablet, _) = channel();
+      cpu.input_script = Some(InputScript::load(&path, Inputs { keyboard, tablet }).unwrap());
+      // wfi with only mie.MTIE set and no timer
+      cpu.bus.write32(MEMORY_START, 0x10500073).unwrap();
+      CsrRegistry::write(&mut cpu.hart, 0x304, 1 << 7).unwrap();
+      cpu.bus.write64(MTIMECMP0_START, u64::MAX).unwrap();
+      cpu.hart.pc = MEMORY_START;
+      cpu.step();
+      assert!(cpu.hart.wfi);
+      for _ in 0..100 {
+        cpu.bus.step(&mut bus, &mut cpu.hart);
+        let mtime = cpu.bus.aclint.lock().unwrap().mtime();
+        cpu.input_script.as_mut().unwrap().step(0, mtime);
+        if keys.try_recv().is_some() { break; }
+        cpu.idle();
+      }
+      let mtime = cpu.bus.aclint.lock().unwrap().mtime();
+      assert!((50000..50100).contains(&mtime), "{}", mtime);
+      assert!(cpu.hart.wfi);
+    }
+    fs::remove_file(path).unwrap();
+  }
+
+  #[test]
+  fn finisher_reset() {
+    // resets through the finisher on the first run, and fails with 42 on the second
+    let program: [u32; 13] = [
+      0x00001397, // auipc t2, 1
+      0x00100337, // lui t1, 0x100
+      0x0003a283, // lw t0, 0(t2)
+      0x00029c63, // bnez t0, fail
+      0x00100293, // li t0, 1
+      0x0053a023, // sw t0, 0(t2)
+      0x000072b7, // lui t0, 7
+      0x77728293, // addi t0, t0, 0x777
+      0x00c0006f, // j finish
+      0x002a32b7, // fail: lui t0, 0x2a3
+      0x33328293, // addi t0, t0, 0x333
+      0x00532023, // finish: sw t0, 0(t1)
+      0x0000006f, // j .
+    ];
+    let data: Vec<u8> = program.iter().flat_map(|word| word.to_le_bytes()).collect();
+    let (mut cpu, _controller) = Cpu::new();
+    cpu.images.push(Image::parse(&data, Format::Bin, Some(MEMORY_START), None).unwrap());
+    assert_eq!(cpu.run(), 42);
+    // memory outlived the reset, the program was loaded again
+    assert_eq!(cpu.bus.read32(MEMORY_START + 0x1000).unwrap(), 1);
+  }
 }
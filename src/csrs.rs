@@ -4,6 +4,8 @@ const FFLAGS: u16 = 0x001;
 const FRM: u16 = 0x002;
 const FCSR: u16 = 0x003;
 
+const TIME: u16 = 0xC01;
+
 const MVENDORID: u16 = 0xF11;
 const MARCHID: u16 = 0xF12;
 const MIMPID: u16 = 0xF13;
@@ -145,6 +147,7 @@ impl CsrRegistry {
           }
         },
         FCSR => self.csr[FCSR as usize] = data & 0b11111111,
+        TIME => {},
         MISA => {},
         MVENDORID => {},
         MARCHID => {},
@@ -283,6 +286,11 @@ impl CsrRegistry {
     }
   }
 
+  // interrupts which are both pending and enabled
+  pub(crate) fn read_mie_mip(&self) -> u64 {
+    self.csr[MIE as usize] & self.csr[MIP as usize]
+  }
+
   pub(crate) fn read_medeleg(&self) -> u64 {
     self.csr[MEDELEG as usize]
   }
@@ -308,19 +316,35 @@ impl CsrRegistry {
   }
 
   pub(crate) fn write_mip_meip(&mut self, bit: u64) {
-    self.csr[MIP as usize] &= !(1 << 11) | ((bit & 0b1) << 3);
+    self.csr[MIP as usize] = (self.csr[MIP as usize] & !(1 << 11)) | ((bit & 0b1) << 11);
   }
 
   pub(crate) fn write_mip_seip(&mut self, bit: u64) {
-    self.csr[MIP as usize] &= !(1 << 9) | ((bit & 0b1) << 3);
+    self.csr[MIP as usize] = (self.csr[MIP as usize] & !(1 << 9)) | ((bit & 0b1) << 9);
   }
 
   pub(crate) fn write_mip_mtip(&mut self, bit: u64) {
-    self.csr[MIP as usize] &= !(1 << 7) | ((bit & 0b1) << 7);
+    self.csr[MIP as usize] = (self.csr[MIP as usize] & !(1 << 7)) | ((bit & 0b1) << 7);
   }
 
   pub(crate) fn write_mip_msip(&mut self, bit: u64) {
-    self.csr[MIP as usize] &= !(1 << 3) | ((bit & 0b1) << 3);
+    self.csr[MIP as usize] = (self.csr[MIP as usize] & !(1 << 3)) | ((bit & 0b1) << 3);
+  }
+
+  pub(crate) fn write_mip_stip(&mut self, bit: u64) {
+    self.csr[MIP as usize] = (self.csr[MIP as usize] & !(1 << 5)) | ((bit & 0b1) << 5);
+  }
+
+  pub(crate) fn write_mip_ssip(&mut self, bit: u64) {
+    self.csr[MIP as usize] = (self.csr[MIP as usize] & !(1 << 1)) | ((bit & 0b1) << 1);
+  }
+
+  pub(crate) fn write_medeleg(&mut self, data: u64) {
+    self.csr[MEDELEG as usize] = data;
+  }
+
+  pub(crate) fn write_mideleg(&mut self, data: u64) {
+    self.csr[MIDELEG as usize] = data;
   }
 
   pub(crate) fn write_sepc(&mut self, data: u64) {
@@ -342,4 +366,17 @@ impl CsrRegistry {
   pub(crate) fn read_satp(&self) -> u64 {
     self.csr[SATP as usize]
   }
+
+  pub(crate) fn read_misa(&self) -> u64 {
+    self.csr[MISA as usize]
+  }
+
+  pub(crate) fn read_mhartid(&self) -> u64 {
+    self.csr[MHARTID as usize]
+  }
+
+  // the time CSR shadows mtime
+  pub(crate) fn write_time(&mut self, data: u64) {
+    self.csr[TIME as usize] = data;
+  }
 }
@@ -1,4 +1,4 @@
-use crate::{device_atomic, device_rw, hart::Hart, trap::Exception};
+use crate::{device_atomic, device_rw, hart::Hart, trap::Exception, utils::channel::Notifier};
 
 use super::{Device, bus::Bus};
 
@@ -8,12 +8,15 @@ pub(crate) const ACLINT_END: u64 = ACLINT_START + 0x0000bfff;
 const MTIME_START: u64 = ACLINT_START + 0x0000bff8;
 const MTIME_END: u64 = MTIME_START + 8 - 1;
 
-const MTIMECMP0_START: u64 = ACLINT_START + 0x00004000;
+pub(crate) const MTIMECMP0_START: u64 = ACLINT_START + 0x00004000;
 const MTIMECMP0_END: u64 = MTIMECMP0_START + 8 - 1;
 
 const MSIP0_START: u64 = ACLINT_START;
 const MSIP0_END: u64 = MSIP0_START + 4 - 1;
 
+// ticks of mtime per second, timebase-frequency in the device tree
+pub(crate) const TIMEBASE_FREQUENCY: u64 = 10000000;
+
 #[derive(Debug)]
 pub(crate) struct Aclint {
   mtime: u64,
@@ -21,18 +24,37 @@ pub(crate) struct Aclint {
   msip0: u32,
   msip0_wrote: bool,
   // setssip0: u32,
+  notifier: Notifier,
 }
 
 impl Aclint {
-  pub(crate) fn new() -> Aclint {
+  pub(crate) fn new(notifier: Notifier) -> Aclint {
     Aclint {
       mtime: 0,
       mtimecmp0: 0,
       msip0: 0,
       msip0_wrote: false,
       // setssip0: 0,
+      notifier,
     }
   }
+
+  // ticks left until the timer interrupt, None if it has already fired
+  pub(crate) fn deadline(&self) -> Option<u64> {
+    if self.mtime < self.mtimecmp0 {
+      Some(self.mtimecmp0 - self.mtime)
+    } else {
+      None
+    }
+  }
+
+  pub(crate) fn mtime(&self) -> u64 {
+    self.mtime
+  }
+
+  pub(crate) fn advance(&mut self, ticks: u64) {
+    self.mtime = self.mtime.wrapping_add(ticks);
+  }
 }
 
 impl Device for Aclint {
@@ -41,7 +63,13 @@ impl Device for Aclint {
 
   fn step(&mut self, _bus: &mut Bus, hart: &mut Hart) {
     self.mtime = self.mtime.wrapping_add(1);
-    hart.csr.write_mip_mtip(if self.mtime >= self.mtimecmp0 { 0 } else { 1 });
+    hart.csr.write_time(self.mtime);
+    let pending = if self.mtime >= self.mtimecmp0 { 1 } else { 0 };
+    hart.csr.write_mip_mtip(pending);
+    // without firmware to take the machine timer interrupt, the built-in SBI hands it on directly
+    if hart.sbi {
+      hart.csr.write_mip_stip(pending);
+    }
 
     if self.msip0_wrote {
       self.msip0_wrote = false;
@@ -70,6 +98,7 @@ impl Device for Aclint {
         msip0[(address - MSIP0_START) as usize] = data;
         self.msip0 = u32::from_le_bytes(msip0);
         self.msip0_wrote = true;
+        self.notifier.notify();
       },
       MTIMECMP0_START..=MTIMECMP0_END => {
         let mut mtimecmp0 = self.mtimecmp0.to_le_bytes();
@@ -1,36 +1,164 @@
 use std::sync::{atomic::Ordering, Arc, Mutex};
 
-use crate::{trap::Exception, hart::Hart, utils::channel::{Sender, Receiver}};
+use crate::{trap::Exception, hart::Hart, utils::channel::{Sender, Receiver, Notifier}};
 
-use super::{Device, memory::{Memory, MEMORY_START, MEMORY_END}, aclint::{Aclint, ACLINT_START, ACLINT_END}, plic::{Plic, PLIC_START, PLIC_END}, uart::{UART_START, UART_END, Uart}};
+use super::{Device, memory::{Memory, MEMORY_START, MEMORY_END}, aclint::{Aclint, ACLINT_START, ACLINT_END}, plic::{Plic, PLIC_START, PLIC_END, INTERRUPT_COUNT}, uart::{UART_START, UART_SIZE, UART_INTERRUPT_ID, Uart}, virtio::{VirtioMmio, VirtioDevice, VIRTIO_START, VIRTIO_END, VIRTIO_SIZE, VIRTIO_COUNT, VIRTIO_INTERRUPT_ID}, finisher::{Finisher, FINISHER_START, FINISHER_END}, rtc::{GoldfishRtc, Clock, RTC_START, RTC_END, RTC_INTERRUPT_ID}, pci::{PciHost, PciFunction, PCIE_ECAM_START, PCIE_ECAM_END, PCIE_MMIO_START, PCIE_MMIO_END, PCIE_INTERRUPT_ID, PCIE_INTERRUPT_COUNT}, flash::{CfiFlash, FLASH_START, FLASH_END, FLASH_SIZE, FLASH_COUNT}, rom::{Rom, RESET_ADDRESS, ROM_SIZE}};
 
 #[derive(Debug, Clone)]
 pub(crate) struct Bus {
   pub(crate) memory: Memory,
   pub(crate) aclint: Arc<Mutex<Aclint>>,
   pub(crate) plic: Arc<Mutex<Plic>>,
-  pub(crate) uart: Arc<Mutex<Uart>>,
+  // the first one is at UART_START
+  pub(crate) uarts: Vec<Arc<Mutex<Uart>>>,
+  pub(crate) virtio: Vec<Arc<Mutex<VirtioMmio>>>,
+  pub(crate) finisher: Arc<Mutex<Finisher>>,
+  pub(crate) rtc: Arc<Mutex<GoldfishRtc>>,
+  pub(crate) pci: Arc<Mutex<PciHost>>,
+  // the nth one is at FLASH_START + n * FLASH_SIZE
+  pub(crate) flash: Vec<Arc<Mutex<CfiFlash>>>,
+  // at the reset vector
+  pub(crate) rom: Arc<Mutex<Rom>>,
+  pub(crate) notifier: Notifier,
 }
 
+// input for a UART and its output
+pub(crate) type UartChannels = (Sender<Vec<u8>>, Receiver<Vec<u8>>);
+
 #[derive(Debug)]
 pub(crate) struct DeviceController {
-  pub(crate) uart_sender: Sender<u8>,
-  pub(crate) uart_receiver: Receiver<u8>,
+  // input for the first UART and its output
+  pub(crate) uart_sender: Sender<Vec<u8>>,
+  pub(crate) uart_receiver: Receiver<Vec<u8>>,
 }
 
 impl Bus {
   pub(crate) fn new() -> (Bus, DeviceController) {
-    let (uart, sender, receiver) = Uart::new();
+    let notifier = Notifier::new();
+    let (uart, sender, receiver) = Uart::new(UART_START, UART_INTERRUPT_ID, notifier.clone());
     (Bus {
       memory: Memory::new(),
-      aclint: Arc::new(Mutex::new(Aclint::new())),
+      aclint: Arc::new(Mutex::new(Aclint::new(notifier.clone()))),
       plic: Arc::new(Mutex::new(Plic::new())),
-      uart: Arc::new(Mutex::new(uart)),
+      uarts: vec![Arc::new(Mutex::new(uart))],
+      virtio: (0..VIRTIO_COUNT)
+        .map(|index| Arc::new(Mutex::new(VirtioMmio::new(VIRTIO_INTERRUPT_ID + index as u32))))
+        .collect(),
+      finisher: Arc::new(Mutex::new(Finisher::new())),
+      rtc: Arc::new(Mutex::new(GoldfishRtc::new(Clock::Host))),
+      pci: Arc::new(Mutex::new(PciHost::new())),
+      flash: Vec::new(),
+      rom: Arc::new(Mutex::new(Rom::new(RESET_ADDRESS))),
+      notifier,
     }, DeviceController {
       uart_sender: sender,
       uart_receiver: receiver,
     })
   }
+  // put the device in the first empty virtio slot, None if all slots are used
+  pub(crate) fn attach_virtio(&mut self, device: Box<dyn VirtioDevice>) -> Option<usize> {
+    let index = self.virtio.iter().position(|slot| slot.lock().unwrap().is_empty())?;
+    self.virtio[index].lock().unwrap().attach(device);
+    Some(index)
+  }
+
+  // put the function in the first empty PCI slot, None if all slots are used
+  pub(crate) fn attach_pci(&mut self, function: Box<dyn PciFunction>) -> Option<usize> {
+    self.pci.lock().unwrap().attach(function)
+  }
+
+  // put the flash in the next bank, None if all banks are used
+  pub(crate) fn attach_flash(&mut self, flash: CfiFlash) -> Option<usize> {
+    if self.flash.len() == FLASH_COUNT { return None; }
+    self.flash.push(Arc::new(Mutex::new(flash)));
+    Some(self.flash.len() - 1)
+  }
+
+  // the boot ROM and with it the reset vector somewhere else, where it must not overlap a device
+  pub(crate) fn move_rom(&mut self, start: u64) -> Result<(), String> {
+    if start & 0b111 != 0 {
+      return Err(format!("{:#x} is not aligned to 8 bytes", start));
+    }
+    let rom = self.rom.clone();
+    if start.checked_add(ROM_SIZE).is_none()
+      || (start..start + ROM_SIZE).any(|address| self.occupied(address) && !rom.lock().unwrap().contains(address)) {
+      return Err(format!("{:#x} is already taken", start));
+    }
+    *rom.lock().unwrap() = Rom::new(start);
+    Ok(())
+  }
+
+  // another UART, which must not overlap a device or share an interrupt
+  pub(crate) fn attach_uart(&mut self, start: u64, interrupt_id: u32) -> Result<UartChannels, String> {
+    if start.checked_add(UART_SIZE).is_none() || (start..start + UART_SIZE).any(|address| self.occupied(address)) {
+      return Err(format!("{:#x} is already taken", start));
+    }
+    let virtio = VIRTIO_INTERRUPT_ID..VIRTIO_INTERRUPT_ID + VIRTIO_COUNT as u32;
+    let pci = PCIE_INTERRUPT_ID..PCIE_INTERRUPT_ID + PCIE_INTERRUPT_COUNT;
+    if interrupt_id == 0 || interrupt_id as usize >= INTERRUPT_COUNT || virtio.contains(&interrupt_id) || pci.contains(&interrupt_id)
+      || interrupt_id == RTC_INTERRUPT_ID
+      || self.uarts.iter().any(|uart| uart.lock().unwrap().irq() == interrupt_id) {
+      return Err(format!("interrupt {} is invalid or already taken", interrupt_id));
+    }
+    let (uart, sender, receiver) = Uart::new(start, interrupt_id, self.notifier.clone());
+    self.uarts.push(Arc::new(Mutex::new(uart)));
+    Ok((sender, receiver))
+  }
+
+  fn occupied(&self, address: u64) -> bool {
+    matches!(address, MEMORY_START..=MEMORY_END | ACLINT_START..=ACLINT_END | PLIC_START..=PLIC_END
+      | VIRTIO_START..=VIRTIO_END | FINISHER_START..=FINISHER_END | RTC_START..=RTC_END
+      | PCIE_ECAM_START..=PCIE_ECAM_END | PCIE_MMIO_START..=PCIE_MMIO_END | FLASH_START..=FLASH_END)
+      || self.uart(address).is_some() || self.rom.lock().unwrap().contains(address)
+  }
+
+  fn uart(&self, address: u64) -> Option<&Arc<Mutex<Uart>>> {
+    self.uarts.iter().find(|uart| uart.lock().unwrap().contains(address))
+  }
+
+  // devices back to power-on state, memory keeps its contents and the host side of devices stays connected
+  pub(crate) fn reset(&mut self) {
+    *self.aclint.lock().unwrap() = Aclint::new(self.notifier.clone());
+    *self.plic.lock().unwrap() = Plic::new();
+    for uart in &self.uarts {
+      uart.lock().unwrap().reset();
+    }
+    for virtio in &self.virtio {
+      virtio.lock().unwrap().reset();
+    }
+    *self.finisher.lock().unwrap() = Finisher::new();
+    self.rtc.lock().unwrap().reset();
+    self.pci.lock().unwrap().reset();
+    for flash in &self.flash {
+      flash.lock().unwrap().reset();
+    }
+  }
+
+  // mtime ticks until the next timer interrupt, character timeout or RTC alarm, which an idle hart waits for
+  pub(crate) fn deadline(&self) -> Option<u64> {
+    let aclint = self.aclint.lock().unwrap();
+    let now = aclint.mtime();
+    self.uarts.iter().filter_map(|uart| uart.lock().unwrap().deadline(now))
+      .chain(aclint.deadline()).chain(self.rtc.lock().unwrap().deadline()).min()
+  }
+
+  // copy from guest physical memory, memory is copied directly
+  pub(crate) fn read_bytes(&mut self, address: u64, buf: &mut [u8]) -> Result<(), Exception> {
+    if self.memory.read_bytes(address, buf) { return Ok(()); }
+    for (index, byte) in buf.iter_mut().enumerate() {
+      *byte = self.read8(address.wrapping_add(index as u64))?;
+    }
+    Ok(())
+  }
+
+  pub(crate) fn write_bytes(&mut self, address: u64, data: &[u8]) -> Result<(), Exception> {
+    if self.memory.write_bytes(address, data) { return Ok(()); }
+    for (index, byte) in data.iter().enumerate() {
+      self.write8(address.wrapping_add(index as u64), *byte)?;
+    }
+    Ok(())
+  }
+
   #[inline]
   fn device_read<T, F>(&mut self, address: u64, run: F) -> Result<T, Exception>
   where
@@ -40,8 +168,20 @@ impl Bus {
       MEMORY_START..=MEMORY_END => Ok(run(&mut self.memory)?),
       ACLINT_START..=ACLINT_END => Ok(run(&mut *self.aclint.lock().unwrap())?),
       PLIC_START..=PLIC_END => Ok(run(&mut *self.plic.lock().unwrap())?),
-      UART_START..=UART_END => Ok(run(&mut *self.uart.lock().unwrap())?),
-      _ => Err(Exception::LoadAccessFault(address))
+      VIRTIO_START..=VIRTIO_END =>
+        Ok(run(&mut *self.virtio[((address - VIRTIO_START) / VIRTIO_SIZE) as usize].lock().unwrap())?),
+      FINISHER_START..=FINISHER_END => Ok(run(&mut *self.finisher.lock().unwrap())?),
+      RTC_START..=RTC_END => Ok(run(&mut *self.rtc.lock().unwrap())?),
+      PCIE_ECAM_START..=PCIE_ECAM_END | PCIE_MMIO_START..=PCIE_MMIO_END => Ok(run(&mut *self.pci.lock().unwrap())?),
+      FLASH_START..=FLASH_END => match self.flash.get(((address - FLASH_START) / FLASH_SIZE) as usize) {
+        Some(flash) => Ok(run(&mut *flash.lock().unwrap())?),
+        None => Err(Exception::LoadAccessFault(address)),
+      },
+      _ if self.rom.lock().unwrap().contains(address) => Ok(run(&mut *self.rom.lock().unwrap())?),
+      _ => match self.uart(address) {
+        Some(uart) => Ok(run(&mut *uart.lock().unwrap())?),
+        None => Err(Exception::LoadAccessFault(address)),
+      },
     }
   }
   #[inline]
@@ -53,8 +193,20 @@ impl Bus {
       MEMORY_START..=MEMORY_END => Ok(run(&mut self.memory)?),
       ACLINT_START..=ACLINT_END => Ok(run(&mut *self.aclint.lock().unwrap())?),
       PLIC_START..=PLIC_END => Ok(run(&mut *self.plic.lock().unwrap())?),
-      UART_START..=UART_END => Ok(run(&mut *self.uart.lock().unwrap())?),
-      _ => Err(Exception::StoreAMOAccessFault(address))
+      VIRTIO_START..=VIRTIO_END =>
+        Ok(run(&mut *self.virtio[((address - VIRTIO_START) / VIRTIO_SIZE) as usize].lock().unwrap())?),
+      FINISHER_START..=FINISHER_END => Ok(run(&mut *self.finisher.lock().unwrap())?),
+      RTC_START..=RTC_END => Ok(run(&mut *self.rtc.lock().unwrap())?),
+      PCIE_ECAM_START..=PCIE_ECAM_END | PCIE_MMIO_START..=PCIE_MMIO_END => Ok(run(&mut *self.pci.lock().unwrap())?),
+      FLASH_START..=FLASH_END => match self.flash.get(((address - FLASH_START) / FLASH_SIZE) as usize) {
+        Some(flash) => Ok(run(&mut *flash.lock().unwrap())?),
+        None => Err(Exception::StoreAMOAccessFault(address)),
+      },
+      _ if self.rom.lock().unwrap().contains(address) => Ok(run(&mut *self.rom.lock().unwrap())?),
+      _ => match self.uart(address) {
+        Some(uart) => Ok(run(&mut *uart.lock().unwrap())?),
+        None => Err(Exception::StoreAMOAccessFault(address)),
+      },
     }
   }
 }
@@ -63,8 +215,15 @@ impl Device for Bus {
   fn step(&mut self, bus: &mut Bus, hart: &mut Hart) {
     // TODO: move devices (except plic) to other threads
     self.memory.step(bus, hart);
-    self.uart.lock().unwrap().step(bus, hart);
+    for uart in &self.uarts {
+      uart.lock().unwrap().step(bus, hart);
+    }
     self.aclint.lock().unwrap().step(bus, hart);
+    self.rtc.lock().unwrap().step(bus, hart);
+    self.pci.lock().unwrap().step(bus, hart);
+    for virtio in &self.virtio {
+      virtio.lock().unwrap().step(bus, hart);
+    }
     self.plic.lock().unwrap().step(bus, hart);
   }
 
@@ -0,0 +1,287 @@
+use std::{fs::{File, OpenOptions}, io::{self, Read, Write}, net::{Ipv4Addr, TcpListener, TcpStream}, os::{fd::FromRawFd, unix::net::UnixStream}, path::{Path, PathBuf}, str::FromStr, sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}}, thread::{self, JoinHandle}, time::Duration};
+
+use crate::utils::{bind_socket, channel::{Receiver, Sender}};
+
+// host side of a serial port or a console port
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Endpoint {
+  // output goes to stdout, main decides where stdin goes
+  Stdio,
+  // output only
+  File(PathBuf),
+  // unix socket, one client at a time, output is dropped while there is none
+  Socket(PathBuf),
+  // localhost port, like Socket
+  Tcp(u16),
+  // like Tcp, for telnet clients in character mode
+  Telnet(u16),
+  // the path of the pty is printed to stderr
+  Pty,
+}
+
+// "stdio", "file=path", "socket=path", "tcp=port", "telnet=port" or "pty"
+impl FromStr for Endpoint {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let port = |port: &str| port.parse().map_err(|_| format!("invalid port '{}'", port));
+    match s.split_once('=').unwrap_or((s, "")) {
+      ("stdio", "") => Ok(Endpoint::Stdio),
+      ("pty", "") => Ok(Endpoint::Pty),
+      ("file", path) if !path.is_empty() => Ok(Endpoint::File(path.into())),
+      ("socket", path) if !path.is_empty() => Ok(Endpoint::Socket(path.into())),
+      ("tcp", value) => Ok(Endpoint::Tcp(port(value)?)),
+      ("telnet", value) => Ok(Endpoint::Telnet(port(value)?)),
+      _ => Err(format!("unknown endpoint '{}', expected stdio, file=path, socket=path, tcp=port, telnet=port or pty", s)),
+    }
+  }
+}
+
+impl Endpoint {
+  // name is what the pty is for
+  pub(crate) fn spawn(&self, name: &str, output: Receiver<Vec<u8>>, input: Sender<Vec<u8>>) -> io::Result<()> {
+    match self {
+      Endpoint::Stdio => {
+        write_to(Stdout, output);
+      },
+      Endpoint::File(path) => {
+        write_to(File::create(path)?, output);
+      },
+      Endpoint::Socket(path) => {
+        let listener = bind_socket(path)?;
+        serve(move || listener.accept().map(|(stream, _)| stream), false, output, input);
+      },
+      Endpoint::Tcp(port) | Endpoint::Telnet(port) => {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, *port))?;
+        let telnet = matches!(self, Endpoint::Telnet(_));
+        serve(move || listener.accept().map(|(stream, _)| stream), telnet, output, input);
+      },
+      Endpoint::Pty => {
+        let (master, path) = open_pty()?;
+        eprintln!("{} on {}", name, path);
+        let mut reader = master.try_clone()?;
+        // reads fail while nothing has the slave open
+        thread::spawn(move || loop {
+          read_from(&mut reader, None, &input);
+          thread::sleep(Duration::from_millis(100));
+        });
+        write_to(master, output);
+      },
+    }
+    Ok(())
+  }
+}
+
+trait Stream: Read + Write + Send + Sized + 'static {
+  fn try_clone(&self) -> io::Result<Self>;
+}
+
+impl Stream for UnixStream {
+  fn try_clone(&self) -> io::Result<Self> { UnixStream::try_clone(self) }
+}
+
+impl Stream for TcpStream {
+  fn try_clone(&self) -> io::Result<Self> { TcpStream::try_clone(self) }
+}
+
+// one client at a time, the next one is accepted once the last one disconnects
+fn serve<S: Stream>(mut accept: impl FnMut() -> io::Result<S> + Send + 'static, telnet: bool, output: Receiver<Vec<u8>>, input: Sender<Vec<u8>>) {
+  let client = Arc::new(Mutex::new(None::<S>));
+  let writer = client.clone();
+  thread::spawn(move || loop {
+    let Ok(mut stream) = accept() else { continue };
+    // the client echoes nothing and sends every key at once
+    if telnet && stream.write_all(&[IAC, WILL, OPTION_ECHO, IAC, WILL, OPTION_SUPPRESS_GO_AHEAD]).is_err() { continue; }
+    let Ok(reader) = stream.try_clone() else { continue };
+    *client.lock().unwrap() = Some(stream);
+    read_from(reader, telnet.then(Telnet::default), &input);
+  });
+  thread::spawn(move || loop {
+    let data = output.recv();
+    let mut client = writer.lock().unwrap();
+    if let Some(stream) = &mut *client {
+      if stream.write_all(&data).is_err() { *client = None; }
+    }
+  });
+}
+
+// stdout, copied to the log while there is one
+struct Stdout;
+
+impl Write for Stdout {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    io::stdout().write_all(buf)?;
+    if let Some(log) = &mut *LOG.lock().unwrap() {
+      log.write_all(buf)?;
+    }
+    Ok(buf.len())
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    io::stdout().flush()
+  }
+}
+
+static LOG: Mutex<Option<File>> = Mutex::new(None);
+
+// start appending stdio output to path, or stop, returns whether it is logging now
+pub(crate) fn toggle_log(path: &Path) -> io::Result<bool> {
+  let mut log = LOG.lock().unwrap();
+  *log = match log.take() {
+    Some(_) => None,
+    None => Some(OpenOptions::new().create(true).append(true).open(path)?),
+  };
+  Ok(log.is_some())
+}
+
+// writers to stdout and files, finished by flush
+static WRITERS: Mutex<Vec<JoinHandle<()>>> = Mutex::new(Vec::new());
+static FLUSHING: AtomicBool = AtomicBool::new(false);
+
+fn write_to(mut writer: impl Write + Send + 'static, output: Receiver<Vec<u8>>) {
+  let handle = thread::spawn(move || loop {
+    if FLUSHING.load(Ordering::Acquire) {
+      while let Some(data) = output.try_recv() {
+        if writer.write_all(&data).is_err() { break; }
+      }
+      let _ = writer.flush();
+      return;
+    }
+    let Some(data) = output.recv_timeout(Duration::from_millis(10)) else { continue };
+    if writer.write_all(&data).and_then(|_| writer.flush()).is_err() { return; }
+  });
+  WRITERS.lock().unwrap().push(handle);
+}
+
+// write out what the guest sent before yuri exits
+pub(crate) fn flush() {
+  FLUSHING.store(true, Ordering::Release);
+  for handle in WRITERS.lock().unwrap().drain(..) {
+    let _ = handle.join();
+  }
+}
+
+// until the end of the input
+fn read_from(mut reader: impl Read, mut telnet: Option<Telnet>, input: &Sender<Vec<u8>>) {
+  let mut buf = [0; 4096];
+  loop {
+    match reader.read(&mut buf) {
+      Ok(0) | Err(_) => return,
+      Ok(len) => {
+        let data = match &mut telnet {
+          Some(telnet) => telnet.filter(&buf[..len]),
+          None => buf[..len].to_vec(),
+        };
+        if !data.is_empty() { input.send(data); }
+      },
+    }
+  }
+}
+
+const IAC: u8 = 255;
+const WILL: u8 = 251;
+const DONT: u8 = 254;
+const SB: u8 = 250;
+const SE: u8 = 240;
+const OPTION_ECHO: u8 = 1;
+const OPTION_SUPPRESS_GO_AHEAD: u8 = 3;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum Telnet {
+  #[default]
+  Data,
+  // after a carriage return, which a NUL can follow
+  Return,
+  Command,
+  // WILL, WONT, DO or DONT, before the option
+  Option,
+  Subnegotiation,
+  SubnegotiationCommand,
+}
+
+impl Telnet {
+  // the data without commands and negotiations, which are all accepted silently
+  fn filter(&mut self, data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    for &byte in data {
+      *self = match (*self, byte) {
+        (Telnet::Data | Telnet::Return, IAC) => Telnet::Command,
+        (Telnet::Return, 0) => Telnet::Data,
+        (Telnet::Data | Telnet::Return, byte) => {
+          out.push(byte);
+          if byte == b'\r' { Telnet::Return } else { Telnet::Data }
+        },
+        (Telnet::Command, IAC) => {
+          out.push(IAC);
+          Telnet::Data
+        },
+        (Telnet::Command, WILL..=DONT) => Telnet::Option,
+        (Telnet::Command, SB) => Telnet::Subnegotiation,
+        (Telnet::Command | Telnet::Option, _) => Telnet::Data,
+        (Telnet::Subnegotiation, IAC) => Telnet::SubnegotiationCommand,
+        (Telnet::Subnegotiation, _) => Telnet::Subnegotiation,
+        (Telnet::SubnegotiationCommand, SE) => Telnet::Data,
+        (Telnet::SubnegotiationCommand, _) => Telnet::Subnegotiation,
+      };
+    }
+    out
+  }
+}
+
+// master side of a new pty in raw mode, and the path of the slave
+fn open_pty() -> io::Result<(File, String)> {
+  unsafe {
+    let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+    if fd < 0 { return Err(io::Error::last_os_error()); }
+    let master = File::from_raw_fd(fd);
+    if libc::grantpt(fd) != 0 || libc::unlockpt(fd) != 0 { return Err(io::Error::last_os_error()); }
+    let mut name = [0 as libc::c_char; 64];
+    if libc::ptsname_r(fd, name.as_mut_ptr(), name.len()) != 0 { return Err(io::Error::last_os_error()); }
+    let path = std::ffi::CStr::from_ptr(name.as_ptr()).to_string_lossy().into_owned();
+    let mut termios = std::mem::zeroed::<libc::termios>();
+    if libc::tcgetattr(fd, &mut termios) == 0 {
+      libc::cfmakeraw(&mut termios);
+      libc::tcsetattr(fd, libc::TCSANOW, &termios);
+    }
+    Ok((master, path))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::fs;
+
+  use crate::utils::channel::channel;
+
+  use super::{Endpoint, Telnet, IAC, WILL, SB, SE};
+
+  #[test]
+  fn telnet() {
+    let mut telnet = Telnet::default();
+    // negotiation, an escaped IAC, enter as CR NUL and a subnegotiation split across reads
+    assert_eq!(telnet.filter(&[IAC, WILL, 1, b'a', IAC, IAC, b'\r', 0, b'b', IAC, SB, 24]), [b'a', IAC, b'\r', b'b']);
+    assert_eq!(telnet.filter(&[0, 1, IAC, SE, b'c', b'\r', b'\n']), [b'c', b'\r', b'\n']);
+    assert_eq!("telnet=4444".parse(), Ok(Endpoint::Telnet(4444)));
+    assert_eq!("file=out.log".parse(), Ok(Endpoint::File("out.log".into())));
+    assert!("tcp=x".parse::<Endpoint>().is_err());
+  }
+
+  #[test]
+  fn socket_path() {
+    let path = std::env::temp_dir().join(format!("yuri-endpoint-{}", std::process::id()));
+    let spawn = || {
+      let (_, output) = channel();
+      let (input, _) = channel();
+      Endpoint::Socket(path.clone()).spawn("serial", output, input)
+    };
+    // a regular file is kept
+    fs::write(&path, "notes").unwrap();
+    assert!(spawn().is_err());
+    assert_eq!(fs::read_to_string(&path).unwrap(), "notes");
+    // a socket from an earlier run is replaced
+    fs::remove_file(&path).unwrap();
+    spawn().unwrap();
+    spawn().unwrap();
+    fs::remove_file(&path).unwrap();
+  }
+}
@@ -0,0 +1,87 @@
+use crate::{device_atomic, hart::Hart, trap::Exception};
+
+use super::{Device, bus::Bus};
+
+// SiFive test finisher, as on qemu's virt board.
+// Linux reaches it through syscon-poweroff and syscon-reboot, OpenSBI through its sifive,test0 driver.
+
+pub(crate) const FINISHER_START: u64 = 0x100000;
+pub(crate) const FINISHER_END: u64 = FINISHER_START + 0xfff;
+
+// the exit code is in the upper 16 bits
+pub(crate) const FINISHER_FAIL: u32 = 0x3333;
+pub(crate) const FINISHER_PASS: u32 = 0x5555;
+pub(crate) const FINISHER_RESET: u32 = 0x7777;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Finish {
+  Exit(i32),
+  Reset,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Finisher {
+  // taken by the cpu after the bus step
+  pub(crate) finish: Option<Finish>,
+}
+
+impl Finisher {
+  pub(crate) fn new() -> Finisher {
+    Finisher::default()
+  }
+}
+
+impl Device for Finisher {
+  device_atomic!();
+
+  fn step(&mut self, _bus: &mut Bus, _hart: &mut Hart) {}
+
+  fn read8(&mut self, _address: u64) -> Result<u8, Exception> { Ok(0) }
+  fn read16(&mut self, _address: u64) -> Result<u16, Exception> { Ok(0) }
+  fn read32(&mut self, _address: u64) -> Result<u32, Exception> { Ok(0) }
+  fn read64(&mut self, _address: u64) -> Result<u64, Exception> { Ok(0) }
+
+  fn write8(&mut self, address: u64, _data: u8) -> Result<(), Exception> {
+    Err(Exception::StoreAMOAccessFault(address))
+  }
+
+  fn write16(&mut self, address: u64, _data: u16) -> Result<(), Exception> {
+    Err(Exception::StoreAMOAccessFault(address))
+  }
+
+  fn write32(&mut self, address: u64, data: u32) -> Result<(), Exception> {
+    if address != FINISHER_START { return Ok(()); }
+    match data & 0xffff {
+      FINISHER_PASS => self.finish = Some(Finish::Exit(0)),
+      FINISHER_FAIL => self.finish = Some(Finish::Exit((data >> 16) as i32)),
+      FINISHER_RESET => self.finish = Some(Finish::Reset),
+      _ => {},
+    }
+    Ok(())
+  }
+
+  fn write64(&mut self, address: u64, data: u64) -> Result<(), Exception> {
+    self.write32(address, data as u32)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn finishes() {
+    let mut finisher = Finisher::new();
+    finisher.write32(FINISHER_START, FINISHER_PASS).unwrap();
+    assert_eq!(finisher.finish.take(), Some(Finish::Exit(0)));
+    finisher.write64(FINISHER_START, (3 << 16) | FINISHER_FAIL as u64).unwrap();
+    assert_eq!(finisher.finish.take(), Some(Finish::Exit(3)));
+    finisher.write32(FINISHER_START, FINISHER_RESET).unwrap();
+    assert_eq!(finisher.finish.take(), Some(Finish::Reset));
+    // other values and addresses do nothing
+    finisher.write32(FINISHER_START, 0x1234).unwrap();
+    finisher.write32(FINISHER_START + 4, FINISHER_PASS).unwrap();
+    assert_eq!(finisher.finish, None);
+    assert!(finisher.write8(FINISHER_START, 0x55).is_err());
+  }
+}
@@ -0,0 +1,395 @@
+use std::{fs::{File, OpenOptions}, io, os::unix::fs::FileExt, path::{Path, PathBuf}, str::FromStr};
+
+use crate::{device_atomic, hart::Hart, trap::Exception};
+
+use super::{Device, bus::Bus};
+
+// CFI NOR flash, a single x16 chip, with the Intel or the AMD command set. Programs and erases finish
+// at once and go straight to the backing file.
+
+pub(crate) const FLASH_START: u64 = 0x20000000;
+pub(crate) const FLASH_SIZE: u64 = 32 << 20;
+pub(crate) const FLASH_COUNT: usize = 2;
+pub(crate) const FLASH_END: u64 = FLASH_START + FLASH_SIZE * FLASH_COUNT as u64 - 1;
+
+pub(crate) const BLOCK_SIZE: u64 = 256 << 10;
+// bytes per bus cycle, commands go to word addresses
+pub(crate) const WIDTH: u64 = 2;
+
+const ERASED: u8 = 0xff;
+
+// Intel status register
+const STATUS_READY: u8 = 0x80;
+const STATUS_ERASE_ERROR: u8 = 0x20;
+const STATUS_PROGRAM_ERROR: u8 = 0x10;
+
+// AMD unlock cycles
+const UNLOCK1: u64 = 0x555;
+const UNLOCK2: u64 = 0x2aa;
+// where the query command goes
+const QUERY: u64 = 0x55;
+// the Intel extended query table, right after the basic one like qemu's
+const EXTENDED_QUERY: u64 = 0x31;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum CommandSet {
+  // Intel/Sharp extended, what qemu's virt machine has
+  Intel,
+  // AMD/Fujitsu standard
+  Amd,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct FlashOptions {
+  pub(crate) path: PathBuf,
+  pub(crate) command_set: CommandSet,
+}
+
+// "path[,intel|amd]"
+impl FromStr for FlashOptions {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let (path, command_set) = match s.rsplit_once(',') {
+      Some((path, "intel")) => (path, CommandSet::Intel),
+      Some((path, "amd")) => (path, CommandSet::Amd),
+      _ => (s, CommandSet::Intel),
+    };
+    if path.is_empty() {
+      return Err("expected path[,intel|amd]".to_string());
+    }
+    Ok(FlashOptions { path: PathBuf::from(path), command_set })
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+  Array,
+  // Intel only
+  Status,
+  Query,
+  Id,
+}
+
+// the first cycles of a command, waiting for the next one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Pending {
+  None,
+  Program,
+  // Intel block erase, lock and unlock, confirmed by the next cycle
+  Erase,
+  Lock,
+  // AMD, the number of unlock cycles seen and whether they follow an erase setup
+  Unlock(u8, bool),
+}
+
+#[derive(Debug)]
+pub(crate) struct CfiFlash {
+  command_set: CommandSet,
+  file: File,
+  data: Vec<u8>,
+  mode: Mode,
+  pending: Pending,
+  status: u8,
+}
+
+impl CfiFlash {
+  // the file is created if it is missing and grown to the size of the flash with erased blocks
+  pub(crate) fn open(path: &Path, command_set: CommandSet) -> io::Result<CfiFlash> {
+    let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+    let len = file.metadata()?.len();
+    if len > FLASH_SIZE {
+      return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("larger than {} MiB", FLASH_SIZE >> 20)));
+    }
+    let mut data = vec![ERASED; FLASH_SIZE as usize];
+    file.read_exact_at(&mut data[..len as usize], 0)?;
+    file.write_all_at(&data[len as usize..], len)?;
+    Ok(CfiFlash { command_set, file, data, mode: Mode::Array, pending: Pending::None, status: STATUS_READY })
+  }
+
+  // the CFI query table, by word address
+  fn query(&self, word: u64) -> u16 {
+    let command_set = match self.command_set {
+      CommandSet::Intel => 0x01,
+      CommandSet::Amd => 0x02,
+    };
+    let intel = self.command_set == CommandSet::Intel;
+    let blocks = (FLASH_SIZE / BLOCK_SIZE - 1) as u16;
+    let block_size = (BLOCK_SIZE / 256) as u16;
+    match word {
+      0x10 => b'Q' as u16,
+      0x11 => b'R' as u16,
+      0x12 => b'Y' as u16,
+      // primary command set and no alternate set, Linux won't probe an Intel chip without
+      // the extended table
+      0x13 => command_set,
+      0x15 if intel => EXTENDED_QUERY as u16,
+      // 2.7 to 3.6 V
+      0x1b => 0x27,
+      0x1c => 0x36,
+      // typical times, 2^n us for a word and 2^n ms for a block, no buffered writes or chip erase
+      0x1f => 4,
+      0x21 => 9,
+      // maximum times, 2^n times the typical ones
+      0x23 => 4,
+      0x25 => 4,
+      0x27 => FLASH_SIZE.trailing_zeros() as u16,
+      // x16 interface
+      0x28 => 0x01,
+      // one region of equal blocks
+      0x2c => 1,
+      0x2d => blocks & 0xff,
+      0x2e => blocks >> 8,
+      0x2f => block_size & 0xff,
+      0x30 => block_size >> 8,
+      0x31 if intel => b'P' as u16,
+      0x32 if intel => b'R' as u16,
+      0x33 if intel => b'I' as u16,
+      // version 1.0, no optional features, suspend or block status
+      0x34 if intel => b'1' as u16,
+      0x35 if intel => b'0' as u16,
+      // one protection register field
+      0x3f if intel => 1,
+      _ => 0,
+    }
+  }
+
+  // manufacturer and device, and the lock status of every block, which is never locked
+  fn id(&self, word: u64) -> u16 {
+    match (self.command_set, word % (BLOCK_SIZE / WIDTH)) {
+      (CommandSet::Intel, 0) => 0x89,
+      (CommandSet::Intel, 1) => 0x18,
+      (CommandSet::Amd, 0) => 0x01,
+      (CommandSet::Amd, 1) => 0x2249,
+      _ => 0,
+    }
+  }
+
+  fn read(&self, offset: u64, len: usize) -> u64 {
+    (0..len as u64).fold(0, |data, index| {
+      let offset = offset + index;
+      let word = match self.mode {
+        Mode::Array => return data | (*self.data.get(offset as usize).unwrap_or(&ERASED) as u64) << (8 * index),
+        Mode::Status => self.status as u16,
+        Mode::Query => self.query(offset / WIDTH),
+        Mode::Id => self.id(offset / WIDTH),
+      };
+      data | (word.to_le_bytes()[(offset % WIDTH) as usize] as u64) << (8 * index)
+    })
+  }
+
+  // programming only clears bits
+  fn program(&mut self, offset: u64, len: usize, data: u64) -> io::Result<()> {
+    let range = offset as usize..offset as usize + len;
+    for (byte, new) in self.data[range.clone()].iter_mut().zip(data.to_le_bytes()) {
+      *byte &= new;
+    }
+    self.file.write_all_at(&self.data[range.clone()], range.start as u64)
+  }
+
+  fn erase(&mut self, start: u64, len: u64) -> io::Result<()> {
+    let range = start as usize..(start + len) as usize;
+    self.data[range.clone()].fill(ERASED);
+    self.file.write_all_at(&self.data[range.clone()], range.start as u64)
+  }
+
+  fn write(&mut self, offset: u64, len: usize, data: u64) {
+    // an unaligned access past the end of the flash
+    let len = len.min((FLASH_SIZE - offset) as usize);
+    match self.command_set {
+      CommandSet::Intel => self.write_intel(offset, len, data),
+      CommandSet::Amd => self.write_amd(offset, len, data),
+    }
+  }
+
+  fn write_intel(&mut self, offset: u64, len: usize, data: u64) {
+    let command = data as u8;
+    match std::mem::replace(&mut self.pending, Pending::None) {
+      Pending::Program => {
+        if self.program(offset, len, data).is_err() {
+          self.status |= STATUS_PROGRAM_ERROR;
+        }
+        self.mode = Mode::Status;
+        return;
+      },
+      Pending::Erase => {
+        if command != 0xd0 {
+          // a bad command sequence
+          self.status |= STATUS_ERASE_ERROR | STATUS_PROGRAM_ERROR;
+        } else if self.erase(offset / BLOCK_SIZE * BLOCK_SIZE, BLOCK_SIZE).is_err() {
+          self.status |= STATUS_ERASE_ERROR;
+        }
+        self.mode = Mode::Status;
+        return;
+      },
+      // blocks are never locked
+      Pending::Lock => {
+        self.mode = Mode::Status;
+        return;
+      },
+      _ => {},
+    }
+    match command {
+      0xff => self.mode = Mode::Array,
+      0x90 => self.mode = Mode::Id,
+      0x98 => self.mode = Mode::Query,
+      0x70 => self.mode = Mode::Status,
+      0x50 => self.status = STATUS_READY,
+      0x40 | 0x10 => {
+        self.pending = Pending::Program;
+        self.mode = Mode::Status;
+      },
+      0x20 => {
+        self.pending = Pending::Erase;
+        self.mode = Mode::Status;
+      },
+      0x60 => {
+        self.pending = Pending::Lock;
+        self.mode = Mode::Status;
+      },
+      // nothing is ever in progress to suspend or resume
+      0xb0 | 0xd0 => self.mode = Mode::Status,
+      _ => self.mode = Mode::Array,
+    }
+  }
+
+  fn write_amd(&mut self, offset: u64, len: usize, data: u64) {
+    let (command, word) = (data as u8, offset / WIDTH);
+    let pending = std::mem::replace(&mut self.pending, Pending::None);
+    if pending == Pending::Program {
+      // errors can't be reported, the data polled back is wrong instead
+      let _ = self.program(offset, len, data);
+      return;
+    }
+    match (pending, command) {
+      (_, 0xf0) => self.mode = Mode::Array,
+      (Pending::None, 0x98) if word & 0xff == QUERY => self.mode = Mode::Query,
+      (Pending::None, 0xaa) if word & 0x7ff == UNLOCK1 => self.pending = Pending::Unlock(1, false),
+      (Pending::Unlock(1, erase), 0x55) if word & 0x7ff == UNLOCK2 => self.pending = Pending::Unlock(2, erase),
+      (Pending::Unlock(2, true), 0x30) => {
+        let _ = self.erase(offset / BLOCK_SIZE * BLOCK_SIZE, BLOCK_SIZE);
+      },
+      (Pending::Unlock(2, true), 0x10) if word & 0x7ff == UNLOCK1 => {
+        let _ = self.erase(0, FLASH_SIZE);
+      },
+      (Pending::Unlock(2, false), 0xa0) if word & 0x7ff == UNLOCK1 => self.pending = Pending::Program,
+      // the erase command comes after another two unlock cycles
+      (Pending::Unlock(2, false), 0x80) if word & 0x7ff == UNLOCK1 => self.pending = Pending::Unlock(0, true),
+      (Pending::Unlock(0, true), 0xaa) if word & 0x7ff == UNLOCK1 => self.pending = Pending::Unlock(1, true),
+      (Pending::Unlock(2, false), 0x90) if word & 0x7ff == UNLOCK1 => self.mode = Mode::Id,
+      // anything else aborts the sequence
+      _ => {},
+    }
+  }
+
+  // back to reading the array, the contents stay
+  pub(crate) fn reset(&mut self) {
+    self.mode = Mode::Array;
+    self.pending = Pending::None;
+    self.status = STATUS_READY;
+  }
+}
+
+impl Device for CfiFlash {
+  device_atomic!();
+
+  fn step(&mut self, _bus: &mut Bus, _hart: &mut Hart) {}
+
+  fn read8(&mut self, address: u64) -> Result<u8, Exception> { Ok(self.read(address % FLASH_SIZE, 1) as u8) }
+  fn read16(&mut self, address: u64) -> Result<u16, Exception> { Ok(self.read(address % FLASH_SIZE, 2) as u16) }
+  fn read32(&mut self, address: u64) -> Result<u32, Exception> { Ok(self.read(address % FLASH_SIZE, 4) as u32) }
+  fn read64(&mut self, address: u64) -> Result<u64, Exception> { Ok(self.read(address % FLASH_SIZE, 8)) }
+  fn write8(&mut self, address: u64, data: u8) -> Result<(), Exception> { self.write(address % FLASH_SIZE, 1, data as u64); Ok(()) }
+  fn write16(&mut self, address: u64, data: u16) -> Result<(), Exception> { self.write(address % FLASH_SIZE, 2, data as u64); Ok(()) }
+  fn write32(&mut self, address: u64, data: u32) -> Result<(), Exception> { self.write(address % FLASH_SIZE, 4, data as u64); Ok(()) }
+  fn write64(&mut self, address: u64, data: u64) -> Result<(), Exception> { self.write(address % FLASH_SIZE, 8, data); Ok(()) }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::fs;
+
+  use crate::devices::Device;
+
+  use super::*;
+
+  fn flash(name: &str, command_set: CommandSet) -> (CfiFlash, PathBuf) {
+    let path = std::env::temp_dir().join(format!("yuri-flash-{}-{}", name, std::process::id()));
+    fs::write(&path, b"firmware").unwrap();
+    (CfiFlash::open(&path, command_set).unwrap(), path)
+  }
+
+  #[test]
+  fn intel() {
+    let (mut flash, path) = flash("intel", CommandSet::Intel);
+    assert_eq!(fs::metadata(&path).unwrap().len(), FLASH_SIZE);
+    assert_eq!(flash.read64(0).unwrap(), u64::from_le_bytes(*b"firmware"));
+    flash.write16(QUERY * WIDTH, 0x98).unwrap();
+    let query: Vec<_> = (0x10..0x13).map(|word| flash.read16(word * WIDTH).unwrap() as u8).collect();
+    assert_eq!(query, b"QRY");
+    assert_eq!(flash.read16(0x13 * WIDTH).unwrap(), 1);
+    let table = flash.read16(0x15 * WIDTH).unwrap() as u64 | (flash.read16(0x16 * WIDTH).unwrap() as u64) << 8;
+    assert_eq!(table, EXTENDED_QUERY);
+    let extended: Vec<_> = (table..table + 5).map(|word| flash.read16(word * WIDTH).unwrap() as u8).collect();
+    assert_eq!(extended, b"PRI10");
+    flash.write16(0, 0xff).unwrap();
+    // programming only clears bits
+    flash.write16(BLOCK_SIZE, 0x40).unwrap();
+    flash.write16(BLOCK_SIZE, 0x1234).unwrap();
+    assert_eq!(flash.read8(0).unwrap(), STATUS_READY);
+    flash.write16(0, 0x40).unwrap();
+    flash.write16(0, 0x0f0f).unwrap();
+    flash.write16(0, 0xff).unwrap();
+    assert_eq!(flash.read16(0).unwrap(), u16::from_le_bytes(*b"fi") & 0x0f0f);
+    // erasing the first block leaves the second one
+    flash.write16(2, 0x20).unwrap();
+    flash.write16(2, 0xd0).unwrap();
+    flash.write16(0, 0xff).unwrap();
+    assert_eq!(flash.read32(0).unwrap(), u32::MAX);
+    assert_eq!(flash.read16(BLOCK_SIZE).unwrap(), 0x1234);
+    // a bad confirmation
+    flash.write16(0, 0x20).unwrap();
+    flash.write16(0, 0xff).unwrap();
+    assert_eq!(flash.read8(0).unwrap(), STATUS_READY | STATUS_ERASE_ERROR | STATUS_PROGRAM_ERROR);
+    flash.write16(0, 0x50).unwrap();
+    assert_eq!(flash.read8(0).unwrap(), STATUS_READY);
+    // the file follows
+    let data = fs::read(&path).unwrap();
+    assert_eq!(&data[..4], &[0xff; 4]);
+    assert_eq!(&data[BLOCK_SIZE as usize..BLOCK_SIZE as usize + 2], &[0x34, 0x12]);
+    fs::remove_file(path).unwrap();
+  }
+
+  #[test]
+  fn amd() {
+    let (mut flash, path) = flash("amd", CommandSet::Amd);
+    let command = |flash: &mut CfiFlash, word: u64, command: u16| flash.write16(word * WIDTH, command).unwrap();
+    let unlock = |flash: &mut CfiFlash| {
+      command(flash, UNLOCK1, 0xaa);
+      command(flash, UNLOCK2, 0x55);
+    };
+    unlock(&mut flash);
+    command(&mut flash, UNLOCK1, 0x90);
+    assert_eq!(flash.read16(WIDTH).unwrap(), 0x2249);
+    command(&mut flash, 0, 0xf0);
+    unlock(&mut flash);
+    command(&mut flash, UNLOCK1, 0xa0);
+    flash.write16(BLOCK_SIZE, 0x1234).unwrap();
+    assert_eq!(flash.read16(BLOCK_SIZE).unwrap(), 0x1234);
+    // without the unlock cycles nothing is programmed
+    flash.write16(BLOCK_SIZE + 2, 0).unwrap();
+    assert_eq!(flash.read16(BLOCK_SIZE + 2).unwrap(), 0xffff);
+    unlock(&mut flash);
+    command(&mut flash, UNLOCK1, 0x80);
+    unlock(&mut flash);
+    flash.write16(0, 0x30).unwrap();
+    assert_eq!(flash.read64(0).unwrap(), u64::MAX);
+    assert_eq!(flash.read16(BLOCK_SIZE).unwrap(), 0x1234);
+    unlock(&mut flash);
+    command(&mut flash, UNLOCK1, 0x80);
+    unlock(&mut flash);
+    command(&mut flash, UNLOCK1, 0x10);
+    assert!(fs::read(&path).unwrap().iter().all(|&byte| byte == ERASED));
+    fs::remove_file(path).unwrap();
+  }
+}
@@ -0,0 +1,146 @@
+use std::{fs::File, io::{self, BufWriter, Write}, path::PathBuf, str::FromStr, sync::atomic::{AtomicBool, Ordering}};
+
+use crate::utils::image::{write_png, write_ppm};
+
+use super::memory::Memory;
+
+// A simple-framebuffer in RAM, which the device tree reserves.
+// The guest draws into it directly, the host only reads it to take screenshots.
+
+pub(crate) const FRAMEBUFFER_START: u64 = 0x88000000;
+pub(crate) const FRAMEBUFFER_WIDTH: usize = 640;
+pub(crate) const FRAMEBUFFER_HEIGHT: usize = 480;
+// x8r8g8b8, little endian
+const BYTES_PER_PIXEL: usize = 4;
+pub(crate) const FRAMEBUFFER_STRIDE: usize = FRAMEBUFFER_WIDTH * BYTES_PER_PIXEL;
+pub(crate) const FRAMEBUFFER_SIZE: u64 = (FRAMEBUFFER_STRIDE * FRAMEBUFFER_HEIGHT) as u64;
+
+// whether start..end of memory overlaps the framebuffer, which the guest doesn't expect anything loaded into
+pub(crate) fn overlaps(start: u64, end: u64) -> bool {
+  start < FRAMEBUFFER_START + FRAMEBUFFER_SIZE && FRAMEBUFFER_START < end
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+  Png,
+  Ppm,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Screenshot {
+  pub(crate) path: PathBuf,
+  format: Format,
+  // retired instructions, None -> on SIGUSR1
+  at: Option<u64>,
+}
+
+// "path.png" or "path.ppm", optionally followed by "@instructions"
+impl FromStr for Screenshot {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let (path, at) = match s.rsplit_once('@') {
+      Some((path, at)) => (path, Some(at.parse().map_err(|_| format!("invalid instruction count '{}'", at))?)),
+      None => (s, None),
+    };
+    let path = PathBuf::from(path);
+    let format = match path.extension().and_then(|extension| extension.to_str()) {
+      Some("png") => Format::Png,
+      Some("ppm") => Format::Ppm,
+      _ => return Err(format!("unknown image format of '{}', expected .png or .ppm", path.display())),
+    };
+    Ok(Screenshot { path, format, at })
+  }
+}
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request(_signal: libc::c_int) {
+  REQUESTED.store(true, Ordering::Relaxed);
+}
+
+#[derive(Debug)]
+pub(crate) struct Screenshots {
+  // latest first
+  scheduled: Vec<Screenshot>,
+  on_demand: Vec<Screenshot>,
+}
+
+impl Screenshots {
+  pub(crate) fn new(screenshots: Vec<Screenshot>) -> Screenshots {
+    let (mut scheduled, on_demand): (Vec<_>, Vec<_>) = screenshots.into_iter().partition(|screenshot| screenshot.at.is_some());
+    scheduled.sort_by_key(|screenshot| std::cmp::Reverse(screenshot.at));
+    if !on_demand.is_empty() {
+      unsafe { libc::signal(libc::SIGUSR1, request as *const () as libc::sighandler_t); }
+    }
+    Screenshots { scheduled, on_demand }
+  }
+
+  // called on every bus step, so scheduled screenshots are up to a block late
+  pub(crate) fn step(&mut self, memory: &Memory, instructions: u64) {
+    while self.scheduled.last().is_some_and(|screenshot| screenshot.at <= Some(instructions)) {
+      let screenshot = self.scheduled.pop().unwrap();
+      save(memory, &screenshot);
+    }
+    if REQUESTED.swap(false, Ordering::Relaxed) {
+      for screenshot in &self.on_demand {
+        save(memory, screenshot);
+      }
+    }
+  }
+}
+
+fn save(memory: &Memory, screenshot: &Screenshot) {
+  if let Err(err) = capture(memory, screenshot) {
+    eprintln!("failed to save {}: {}", screenshot.path.display(), err);
+  }
+}
+
+fn capture(memory: &Memory, screenshot: &Screenshot) -> io::Result<()> {
+  let mut pixels = vec![0; FRAMEBUFFER_STRIDE * FRAMEBUFFER_HEIGHT];
+  memory.read_bytes(FRAMEBUFFER_START, &mut pixels);
+  let rgb = frame(&pixels);
+  let mut out = BufWriter::new(File::create(&screenshot.path)?);
+  match screenshot.format {
+    Format::Png => write_png(&mut out, FRAMEBUFFER_WIDTH, FRAMEBUFFER_HEIGHT, &rgb)?,
+    Format::Ppm => write_ppm(&mut out, FRAMEBUFFER_WIDTH, FRAMEBUFFER_HEIGHT, &rgb)?,
+  }
+  out.flush()
+}
+
+// x8r8g8b8 to rgb
+fn frame(pixels: &[u8]) -> Vec<u8> {
+  pixels.chunks(BYTES_PER_PIXEL).flat_map(|pixel| [pixel[2], pixel[1], pixel[0]]).collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use std::fs;
+
+  use super::*;
+
+  #[test]
+  fn scheduled() {
+    let path = std::env::temp_dir().join(format!("yuri-screenshot-{}.ppm", std::process::id()));
+    let screenshot: Screenshot = format!("{}@1000", path.display()).parse().unwrap();
+    assert_eq!(screenshot, Screenshot { path: path.clone(), format: Format::Ppm, at: Some(1000) });
+    assert!("out.jpg".parse::<Screenshot>().is_err());
+    assert!("out.png@soon".parse::<Screenshot>().is_err());
+
+    let mut memory = Memory::new();
+    // x8r8g8b8, the first pixel is red
+    memory.write_bytes(FRAMEBUFFER_START, &[0, 0, 0xff, 0]);
+    let mut screenshots = Screenshots::new(vec![screenshot]);
+    screenshots.step(&memory, 999);
+    assert!(!path.exists());
+    screenshots.step(&memory, 1000);
+    let header = format!("P6\n{} {}\n255\n", FRAMEBUFFER_WIDTH, FRAMEBUFFER_HEIGHT);
+    let data = fs::read(&path).unwrap();
+    assert_eq!(data.len(), header.len() + FRAMEBUFFER_WIDTH * FRAMEBUFFER_HEIGHT * 3);
+    assert_eq!(&data[header.len()..header.len() + 6], &[0xff, 0, 0, 0, 0, 0]);
+    // taken once
+    fs::remove_file(&path).unwrap();
+    screenshots.step(&memory, 2000);
+    assert!(!path.exists());
+  }
+}
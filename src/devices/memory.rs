@@ -1,4 +1,4 @@
-use std::sync::{Mutex, atomic::{AtomicU32, AtomicU64, AtomicI32, AtomicI64, Ordering}, Arc};
+use std::{ptr::null_mut, sync::{Mutex, atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicI32, AtomicI64, Ordering}, Arc}};
 
 use crate::{trap::Exception, hart::Hart};
 
@@ -8,10 +8,66 @@ pub(crate) const MEMORY_SIZE: usize = 1024 * 1024 * 1024;
 pub(crate) const MEMORY_START: u64 = 0x80000000;
 pub(crate) const MEMORY_END: u64 = MEMORY_START + MEMORY_SIZE as u64 - 1;
 
+pub(crate) const PAGE_SIZE: u64 = 4096;
+pub(crate) const PAGE_COUNT: usize = MEMORY_SIZE / PAGE_SIZE as usize;
+
+// Pages which have instructions in a decode cache.
+// Stores to them bump the generation of the page, so the caches can drop stale instructions.
+#[derive(Debug)]
+pub(crate) struct CodePages {
+  watched: Box<[AtomicBool]>,
+  generations: Box<[AtomicU32]>,
+  generation: AtomicU64,
+}
+
+impl CodePages {
+  fn new() -> CodePages {
+    CodePages {
+      watched: (0..PAGE_COUNT).map(|_| AtomicBool::new(false)).collect(),
+      generations: (0..PAGE_COUNT).map(|_| AtomicU32::new(0)).collect(),
+      generation: AtomicU64::new(0),
+    }
+  }
+
+  // page is the index of the page in memory
+  pub(crate) fn watch(&self, page: usize) -> u32 {
+    self.watched[page].store(true, Ordering::Relaxed);
+    self.generations[page].load(Ordering::Acquire)
+  }
+
+  // by page, native stores leave watched pages to the interpreter
+  #[cfg(feature = "jit")]
+  pub(crate) fn watched(&self) -> *const AtomicBool {
+    self.watched.as_ptr()
+  }
+
+  pub(crate) fn page_generation(&self, page: usize) -> u32 {
+    self.generations[page].load(Ordering::Acquire)
+  }
+
+  pub(crate) fn generation(&self) -> u64 {
+    self.generation.load(Ordering::Acquire)
+  }
+
+  // address is the offset in memory
+  #[inline]
+  pub(crate) fn touch(&self, address: u64, len: u64) {
+    let first = (address / PAGE_SIZE) as usize;
+    let last = ((address + len - 1) / PAGE_SIZE) as usize;
+    for page in first..=last.min(PAGE_COUNT - 1) {
+      if self.watched[page].load(Ordering::Relaxed) {
+        self.generations[page].fetch_add(1, Ordering::Release);
+        self.generation.fetch_add(1, Ordering::Release);
+      }
+    }
+  }
+}
+
 #[derive(Debug, Clone)]
 pub(crate) struct Memory {
   mem: *mut u8,
   _boxed: Arc<Mutex<Box<[u8]>>>,
+  pub(crate) code: Arc<CodePages>,
 }
 
 impl Memory {
@@ -20,9 +76,37 @@ impl Memory {
     Memory {
       mem: &mut mem[0],
       _boxed: Arc::new(Mutex::new(mem)),
+      code: Arc::new(CodePages::new()),
     }
   }
 
+  // pointer to the byte in host memory, null if the address is not in memory
+  pub(crate) fn host(&self, address: u64) -> *mut u8 {
+    if !(MEMORY_START..=MEMORY_END).contains(&address) { return null_mut(); }
+    self.mem.wrapping_add((address - MEMORY_START) as usize)
+  }
+
+  // false if the range is not in memory
+  pub(crate) fn read_bytes(&self, address: u64, buf: &mut [u8]) -> bool {
+    if !self.contains(address, buf.len()) { return false; }
+    let src = self.mem.wrapping_add((address - MEMORY_START) as usize);
+    unsafe { src.copy_to_nonoverlapping(buf.as_mut_ptr(), buf.len()); }
+    true
+  }
+
+  pub(crate) fn write_bytes(&mut self, address: u64, data: &[u8]) -> bool {
+    if !self.contains(address, data.len()) { return false; }
+    if data.is_empty() { return true; }
+    self.code.touch(address - MEMORY_START, data.len() as u64);
+    let dst = self.mem.wrapping_add((address - MEMORY_START) as usize);
+    unsafe { dst.copy_from_nonoverlapping(data.as_ptr(), data.len()); }
+    true
+  }
+
+  fn contains(&self, address: u64, len: usize) -> bool {
+    address >= MEMORY_START && address.checked_add(len as u64).is_some_and(|end| end <= MEMORY_END + 1)
+  }
+
   fn atomic_u32(&mut self, address: u64) -> &AtomicU32 {
     let ptr = self.mem.wrapping_add(address as usize) as *mut u32;
     unsafe { AtomicU32::from_ptr(ptr) }
@@ -69,132 +153,154 @@ impl Device for Memory {
 
   fn write8(&mut self, address: u64, data: u8) -> Result<(), Exception> {
     let address = address - MEMORY_START;
+    self.code.touch(address, 1);
     unsafe { *(self.mem.wrapping_add(address as usize)) = data; };
     Ok(())
   }
 
   fn write16(&mut self, address: u64, data: u16) -> Result<(), Exception> {
     let address = address - MEMORY_START;
+    self.code.touch(address, 2);
     unsafe { *(self.mem.wrapping_add(address as usize) as *mut _) = data.to_le(); };
     Ok(())
   }
 
   fn write32(&mut self, address: u64, data: u32) -> Result<(), Exception> {
     let address = address - MEMORY_START;
+    self.code.touch(address, 4);
     unsafe { *(self.mem.wrapping_add(address as usize) as *mut _) = data.to_le(); };
     Ok(())
   }
 
   fn write64(&mut self, address: u64, data: u64) -> Result<(), Exception> {
     let address = address - MEMORY_START;
+    self.code.touch(address, 8);
     unsafe { *(self.mem.wrapping_add(address as usize) as *mut _) = data.to_le(); };
     Ok(())
   }
 
   fn atomic_swap32(&mut self, address: u64, val: u32, ordering: Ordering) -> Result<u32, Exception> {
     let address = address - MEMORY_START;
+    self.code.touch(address, 4);
     let atomic = self.atomic_u32(address);
     Ok(atomic.swap(val, ordering))
   }
 
   fn atomic_swap64(&mut self, address: u64, val: u64, ordering: Ordering) -> Result<u64, Exception> {
     let address = address - MEMORY_START;
+    self.code.touch(address, 8);
     let atomic = self.atomic_u64(address);
     Ok(atomic.swap(val, ordering))
   }
 
   fn atomic_add32(&mut self, address: u64, val: u32, ordering: Ordering) -> Result<u32, Exception> {
     let address = address - MEMORY_START;
+    self.code.touch(address, 4);
     let atomic = self.atomic_u32(address);
     Ok(atomic.fetch_add(val, ordering))
   }
 
   fn atomic_add64(&mut self, address: u64, val: u64, ordering: Ordering) -> Result<u64, Exception> {
     let address = address - MEMORY_START;
+    self.code.touch(address, 8);
     let atomic = self.atomic_u64(address);
     Ok(atomic.fetch_add(val, ordering))
   }
 
   fn atomic_xor32(&mut self, address: u64, val: u32, ordering: Ordering) -> Result<u32, Exception> {
     let address = address - MEMORY_START;
+    self.code.touch(address, 4);
     let atomic = self.atomic_u32(address);
     Ok(atomic.fetch_xor(val, ordering))
   }
 
   fn atomic_xor64(&mut self, address: u64, val: u64, ordering: Ordering) -> Result<u64, Exception> {
     let address = address - MEMORY_START;
+    self.code.touch(address, 8);
     let atomic = self.atomic_u64(address);
     Ok(atomic.fetch_xor(val, ordering))
   }
 
   fn atomic_and32(&mut self, address: u64, val: u32, ordering: Ordering) -> Result<u32, Exception> {
     let address = address - MEMORY_START;
+    self.code.touch(address, 4);
     let atomic = self.atomic_u32(address);
     Ok(atomic.fetch_and(val, ordering))
   }
 
   fn atomic_and64(&mut self, address: u64, val: u64, ordering: Ordering) -> Result<u64, Exception> {
     let address = address - MEMORY_START;
+    self.code.touch(address, 8);
     let atomic = self.atomic_u64(address);
     Ok(atomic.fetch_and(val, ordering))
   }
 
   fn atomic_or32(&mut self, address: u64, val: u32, ordering: Ordering) -> Result<u32, Exception> {
     let address = address - MEMORY_START;
+    self.code.touch(address, 4);
     let atomic = self.atomic_u32(address);
     Ok(atomic.fetch_or(val, ordering))
   }
 
   fn atomic_or64(&mut self, address: u64, val: u64, ordering: Ordering) -> Result<u64, Exception> {
     let address = address - MEMORY_START;
+    self.code.touch(address, 8);
     let atomic = self.atomic_u64(address);
     Ok(atomic.fetch_or(val, ordering))
   }
 
   fn atomic_min_i32(&mut self, address: u64, val: i32, ordering: Ordering) -> Result<i32, Exception> {
     let address = address - MEMORY_START;
+    self.code.touch(address, 4);
     let atomic = self.atomic_i32(address);
     Ok(atomic.fetch_min(val, ordering))
   }
 
   fn atomic_min_i64(&mut self, address: u64, val: i64, ordering: Ordering) -> Result<i64, Exception> {
     let address = address - MEMORY_START;
+    self.code.touch(address, 8);
     let atomic = self.atomic_i64(address);
     Ok(atomic.fetch_min(val, ordering))
   }
 
   fn atomic_max_i32(&mut self, address: u64, val: i32, ordering: Ordering) -> Result<i32, Exception> {
     let address = address - MEMORY_START;
+    self.code.touch(address, 4);
     let atomic = self.atomic_i32(address);
     Ok(atomic.fetch_max(val, ordering))
   }
 
   fn atomic_max_i64(&mut self, address: u64, val: i64, ordering: Ordering) -> Result<i64, Exception> {
     let address = address - MEMORY_START;
+    self.code.touch(address, 8);
     let atomic = self.atomic_i64(address);
     Ok(atomic.fetch_max(val, ordering))
   }
 
   fn atomic_min_u32(&mut self, address: u64, val: u32, ordering: Ordering) -> Result<u32, Exception> {
     let address = address - MEMORY_START;
+    self.code.touch(address, 4);
     let atomic = self.atomic_u32(address);
     Ok(atomic.fetch_min(val, ordering))
   }
 
   fn atomic_min_u64(&mut self, address: u64, val: u64, ordering: Ordering) -> Result<u64, Exception> {
     let address = address - MEMORY_START;
+    self.code.touch(address, 8);
     let atomic = self.atomic_u64(address);
     Ok(atomic.fetch_min(val, ordering))
   }
 
   fn atomic_max_u32(&mut self, address: u64, val: u32, ordering: Ordering) -> Result<u32, Exception> {
     let address = address - MEMORY_START;
+    self.code.touch(address, 4);
     let atomic = self.atomic_u32(address);
     Ok(atomic.fetch_max(val, ordering))
   }
 
   fn atomic_max_u64(&mut self, address: u64, val: u64, ordering: Ordering) -> Result<u64, Exception> {
     let address = address - MEMORY_START;
+    self.code.touch(address, 8);
     let atomic = self.atomic_u64(address);
     Ok(atomic.fetch_max(val, ordering))
   }
@@ -9,6 +9,15 @@ pub(crate) mod aclint;
 pub(crate) mod plic;
 pub(crate) mod uart;
 pub(crate) mod bus;
+pub(crate) mod virtio;
+pub(crate) mod framebuffer;
+pub(crate) mod finisher;
+pub(crate) mod rtc;
+pub(crate) mod endpoint;
+pub(crate) mod pci;
+pub(crate) mod flash;
+pub(crate) mod rom;
+pub(crate) mod tree;
 
 #[macro_export]
 macro_rules! device_atomic {
@@ -0,0 +1,189 @@
+use crate::devices::bus::Bus;
+
+use super::{Config, PciFunction, REVISION, COMMAND_MASTER, message, in_windows};
+
+// qemu's edu device, a test endpoint with a factorial unit, interrupts by INTx or MSI, and DMA
+
+const IDENT: u32 = 0x010000ed;
+const BAR_SIZE: u64 = 1 << 20;
+
+const REG_IDENT: u64 = 0x00;
+const REG_LIVENESS: u64 = 0x04;
+const REG_FACTORIAL: u64 = 0x08;
+const REG_STATUS: u64 = 0x20;
+const REG_INTERRUPT_STATUS: u64 = 0x24;
+const REG_INTERRUPT_RAISE: u64 = 0x60;
+const REG_INTERRUPT_ACK: u64 = 0x64;
+const REG_DMA_SOURCE: u64 = 0x80;
+const REG_DMA_DESTINATION: u64 = 0x88;
+const REG_DMA_COUNT: u64 = 0x90;
+const REG_DMA_COMMAND: u64 = 0x98;
+
+// raise an interrupt once the factorial is done
+const STATUS_IRQ: u32 = 0x80;
+
+const DMA_START: u64 = 1;
+// from the buffer to memory, else the other way
+const DMA_TO_MEMORY: u64 = 2;
+const DMA_IRQ: u64 = 4;
+
+const FACTORIAL_IRQ: u32 = 0x001;
+const DMA_DONE_IRQ: u32 = 0x100;
+
+// the buffer as seen by DMA
+const DMA_BUFFER: u64 = 0x40000;
+const DMA_SIZE: usize = 4096;
+
+// 64-bit MSI with a single message
+const MSI_ID: u8 = 0x05;
+const MSI_LEN: usize = 14;
+const MSI_CONTROL: usize = 2;
+const MSI_ADDRESS: usize = 4;
+const MSI_DATA: usize = 12;
+const MSI_ENABLE: u16 = 1;
+const MSI_64_BIT: u16 = 1 << 7;
+
+#[derive(Debug)]
+pub(crate) struct Edu {
+  config: Config,
+  msi: usize,
+  liveness: u32,
+  factorial: u32,
+  status: u32,
+  interrupt_status: u32,
+  // a message to send on the next step
+  message: bool,
+  dma_source: u64,
+  dma_destination: u64,
+  dma_count: u64,
+  dma_command: u64,
+  buffer: Vec<u8>,
+}
+
+impl Edu {
+  pub(crate) fn new() -> Edu {
+    let mut config = Config::new(0x1234, 0x11e8, 0xff0000);
+    config.set(REVISION, &[0x10]);
+    config.add_bar(0, BAR_SIZE);
+    let msi = config.add_capability(MSI_ID, MSI_LEN);
+    config.set16(msi + MSI_CONTROL, MSI_64_BIT);
+    config.set_wmask(msi + MSI_CONTROL, &MSI_ENABLE.to_le_bytes());
+    config.set_wmask(msi + MSI_ADDRESS, &[0xfc, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]);
+    config.set_wmask(msi + MSI_DATA, &[0xff, 0xff]);
+    Edu {
+      config,
+      msi,
+      liveness: 0,
+      factorial: 1,
+      status: 0,
+      interrupt_status: 0,
+      message: false,
+      dma_source: 0,
+      dma_destination: 0,
+      dma_count: 0,
+      dma_command: 0,
+      buffer: vec![0; DMA_SIZE],
+    }
+  }
+
+  fn msi_enabled(&self) -> bool {
+    self.config.get16(self.msi + MSI_CONTROL) & MSI_ENABLE != 0
+  }
+
+  fn raise(&mut self, value: u32) {
+    self.interrupt_status |= value;
+    self.message = true;
+  }
+
+  // the part of the buffer for a DMA address, None if it is outside
+  fn buffer_range(&self, address: u64) -> Option<std::ops::Range<usize>> {
+    let start = address.checked_sub(DMA_BUFFER)? as usize;
+    let end = start.checked_add(self.dma_count as usize)?;
+    (end <= DMA_SIZE).then_some(start..end)
+  }
+
+  fn dma(&mut self, bus: &mut Bus) {
+    let to_memory = self.dma_command & DMA_TO_MEMORY != 0;
+    let (device, memory) = if to_memory { (self.dma_source, self.dma_destination) } else { (self.dma_destination, self.dma_source) };
+    let end = memory.saturating_add(self.dma_count.saturating_sub(1));
+    if let (Some(range), false) = (self.buffer_range(device), in_windows(memory) || in_windows(end)) {
+      let _ = if to_memory {
+        bus.write_bytes(memory, &self.buffer[range])
+      } else {
+        bus.read_bytes(memory, &mut self.buffer[range])
+      };
+    }
+    self.dma_command &= !DMA_START;
+    if self.dma_command & DMA_IRQ != 0 {
+      self.raise(DMA_DONE_IRQ);
+    }
+  }
+}
+
+impl PciFunction for Edu {
+  fn config(&self) -> &Config { &self.config }
+  fn config_mut(&mut self) -> &mut Config { &mut self.config }
+
+  fn read_bar(&mut self, _bar: usize, offset: u64, len: usize) -> u64 {
+    // registers are read as dwords, the DMA ones also as qwords
+    if len != 4 && !(len == 8 && offset >= REG_DMA_SOURCE) {
+      return u64::MAX >> (64 - 8 * len);
+    }
+    let data = match offset {
+      REG_IDENT => IDENT as u64,
+      REG_LIVENESS => !self.liveness as u64,
+      REG_FACTORIAL => self.factorial as u64,
+      REG_STATUS => self.status as u64,
+      REG_INTERRUPT_STATUS => self.interrupt_status as u64,
+      REG_DMA_SOURCE => self.dma_source,
+      REG_DMA_DESTINATION => self.dma_destination,
+      REG_DMA_COUNT => self.dma_count,
+      REG_DMA_COMMAND => self.dma_command,
+      _ => u64::MAX,
+    };
+    data & (u64::MAX >> (64 - 8 * len))
+  }
+
+  fn write_bar(&mut self, _bar: usize, offset: u64, len: usize, data: u64) {
+    if len != 4 && !(len == 8 && offset >= REG_DMA_SOURCE) { return; }
+    match offset {
+      REG_LIVENESS => self.liveness = data as u32,
+      // computed at once, the status never shows it as busy
+      REG_FACTORIAL => {
+        // 34! is a multiple of 2^32, so every larger product wraps to 0 as well
+        self.factorial = (1..=(data as u32).min(34)).fold(1u32, |product, n| product.wrapping_mul(n));
+        if self.status & STATUS_IRQ != 0 {
+          self.raise(FACTORIAL_IRQ);
+        }
+      },
+      REG_STATUS => self.status = data as u32 & STATUS_IRQ,
+      REG_INTERRUPT_RAISE => self.raise(data as u32),
+      REG_INTERRUPT_ACK => self.interrupt_status &= !(data as u32),
+      REG_DMA_SOURCE => self.dma_source = data,
+      REG_DMA_DESTINATION => self.dma_destination = data,
+      REG_DMA_COUNT => self.dma_count = data,
+      // done on the next step, as the device has no bus until then
+      REG_DMA_COMMAND if self.dma_command & DMA_START == 0 => self.dma_command = data,
+      _ => {},
+    }
+  }
+
+  fn step(&mut self, bus: &mut Bus) -> bool {
+    if self.dma_command & DMA_START != 0 && self.config.command() & COMMAND_MASTER != 0 {
+      self.dma(bus);
+    }
+    if self.msi_enabled() {
+      if std::mem::take(&mut self.message) {
+        let address = self.config.read(self.msi + MSI_ADDRESS, 8);
+        message(bus, address, self.config.get16(self.msi + MSI_DATA) as u32);
+      }
+      return false;
+    }
+    self.message = false;
+    self.interrupt_status != 0
+  }
+
+  fn reset(&mut self) {
+    *self = Edu::new();
+  }
+}
@@ -0,0 +1,432 @@
+use std::fmt::Debug;
+
+use crate::{device_atomic, hart::Hart, trap::Exception};
+
+use super::{Device, bus::Bus};
+
+pub(crate) mod msix;
+pub(crate) mod edu;
+
+// PCIe host bridge with ECAM, as pci-host-ecam-generic, on the addresses of qemu's virt board.
+// Only bus 0 is populated, with one function per slot. There are no I/O BARs.
+
+pub(crate) const PCIE_ECAM_START: u64 = 0x30000000;
+pub(crate) const PCIE_ECAM_END: u64 = PCIE_ECAM_START + 0x10000000 - 1;
+// window for memory BARs, which yuri assigns at attach and reset
+pub(crate) const PCIE_MMIO_START: u64 = 0x40000000;
+pub(crate) const PCIE_MMIO_END: u64 = PCIE_MMIO_START + 0x40000000 - 1;
+// INTA to INTD, swizzled by slot
+pub(crate) const PCIE_INTERRUPT_ID: u32 = 32;
+pub(crate) const PCIE_INTERRUPT_COUNT: u32 = 4;
+pub(crate) const PCIE_SLOTS: usize = 32;
+
+pub(crate) const CONFIG_SIZE: usize = 4096;
+
+// type 0 header
+pub(crate) const VENDOR_ID: usize = 0x00;
+pub(crate) const DEVICE_ID: usize = 0x02;
+pub(crate) const COMMAND: usize = 0x04;
+pub(crate) const STATUS: usize = 0x06;
+pub(crate) const REVISION: usize = 0x08;
+pub(crate) const CLASS: usize = 0x09;
+pub(crate) const BAR0: usize = 0x10;
+pub(crate) const SUBSYSTEM_VENDOR_ID: usize = 0x2c;
+pub(crate) const SUBSYSTEM_ID: usize = 0x2e;
+pub(crate) const CAPABILITIES: usize = 0x34;
+pub(crate) const INTERRUPT_LINE: usize = 0x3c;
+pub(crate) const INTERRUPT_PIN: usize = 0x3d;
+
+pub(crate) const COMMAND_MEMORY: u16 = 1 << 1;
+pub(crate) const COMMAND_MASTER: u16 = 1 << 2;
+pub(crate) const COMMAND_INTX_DISABLE: u16 = 1 << 10;
+pub(crate) const STATUS_INTERRUPT: u16 = 1 << 3;
+pub(crate) const STATUS_CAPABILITIES: u16 = 1 << 4;
+
+pub(crate) const BAR_COUNT: usize = 6;
+// capabilities start after the header
+const CAPABILITIES_START: usize = 0x40;
+
+// Config space of a function, with the bits the guest can write.
+// The bits the guest can write are 0 at power-on, so reset clears them.
+#[derive(Debug, Clone)]
+pub(crate) struct Config {
+  bytes: Vec<u8>,
+  wmask: Vec<u8>,
+  bar_sizes: [u64; BAR_COUNT],
+  // end of the last capability
+  capabilities_end: usize,
+  last_capability: Option<usize>,
+}
+
+impl Config {
+  // class is base class, subclass and programming interface
+  pub(crate) fn new(vendor: u16, device: u16, class: u32) -> Config {
+    let mut config = Config {
+      bytes: vec![0; CONFIG_SIZE],
+      wmask: vec![0; CONFIG_SIZE],
+      bar_sizes: [0; BAR_COUNT],
+      capabilities_end: CAPABILITIES_START,
+      last_capability: None,
+    };
+    config.set16(VENDOR_ID, vendor);
+    config.set16(DEVICE_ID, device);
+    config.set(CLASS, &class.to_le_bytes()[..3]);
+    config.set_wmask(COMMAND, &(COMMAND_MEMORY | COMMAND_MASTER | COMMAND_INTX_DISABLE).to_le_bytes());
+    config.set_wmask(INTERRUPT_LINE, &[0xff]);
+    config.set(INTERRUPT_PIN, &[1]);
+    config
+  }
+
+  pub(crate) fn set(&mut self, offset: usize, data: &[u8]) {
+    self.bytes[offset..offset + data.len()].copy_from_slice(data);
+  }
+
+  pub(crate) fn set16(&mut self, offset: usize, data: u16) {
+    self.set(offset, &data.to_le_bytes());
+  }
+
+  pub(crate) fn set32(&mut self, offset: usize, data: u32) {
+    self.set(offset, &data.to_le_bytes());
+  }
+
+  pub(crate) fn set_wmask(&mut self, offset: usize, mask: &[u8]) {
+    self.wmask[offset..offset + mask.len()].copy_from_slice(mask);
+  }
+
+  pub(crate) fn get16(&self, offset: usize) -> u16 {
+    u16::from_le_bytes([self.bytes[offset], self.bytes[offset + 1]])
+  }
+
+  pub(crate) fn get32(&self, offset: usize) -> u32 {
+    u32::from_le_bytes(self.bytes[offset..offset + 4].try_into().unwrap())
+  }
+
+  // a 32-bit non-prefetchable memory BAR, size is a power of two
+  pub(crate) fn add_bar(&mut self, index: usize, size: u64) {
+    let size = size.max(16);
+    self.bar_sizes[index] = size;
+    self.set_wmask(BAR0 + 4 * index, &(!(size as u32 - 1)).to_le_bytes());
+  }
+
+  // len includes the id and the next pointer, returns the offset of the capability
+  pub(crate) fn add_capability(&mut self, id: u8, len: usize) -> usize {
+    let offset = self.capabilities_end;
+    match self.last_capability {
+      Some(last) => self.bytes[last + 1] = offset as u8,
+      None => {
+        self.bytes[CAPABILITIES] = offset as u8;
+        self.set16(STATUS, self.get16(STATUS) | STATUS_CAPABILITIES);
+      },
+    }
+    self.bytes[offset] = id;
+    self.last_capability = Some(offset);
+    // dword aligned
+    self.capabilities_end = (offset + len + 3) & !3;
+    offset
+  }
+
+  pub(crate) fn read(&self, offset: usize, len: usize) -> u64 {
+    let mut data = [0; 8];
+    data[..len].copy_from_slice(&self.bytes[offset..offset + len]);
+    u64::from_le_bytes(data)
+  }
+
+  pub(crate) fn write(&mut self, offset: usize, len: usize, data: u64) {
+    for (index, byte) in data.to_le_bytes()[..len].iter().enumerate() {
+      let (old, mask) = (self.bytes[offset + index], self.wmask[offset + index]);
+      self.bytes[offset + index] = (old & !mask) | (byte & mask);
+    }
+  }
+
+  pub(crate) fn command(&self) -> u16 {
+    self.get16(COMMAND)
+  }
+
+  pub(crate) fn bar_size(&self, index: usize) -> u64 {
+    self.bar_sizes[index]
+  }
+
+  pub(crate) fn bar_address(&self, index: usize) -> u64 {
+    (self.get32(BAR0 + 4 * index) & !0xf) as u64
+  }
+
+  // the address the host assigned, which the guest can change
+  fn assign_bar(&mut self, index: usize, address: u64) {
+    self.set32(BAR0 + 4 * index, address as u32);
+  }
+
+  fn set_interrupt_status(&mut self, asserted: bool) {
+    let status = self.get16(STATUS) & !STATUS_INTERRUPT;
+    self.set16(STATUS, if asserted { status | STATUS_INTERRUPT } else { status });
+  }
+
+  pub(crate) fn reset(&mut self) {
+    for (byte, mask) in self.bytes.iter_mut().zip(&self.wmask) {
+      *byte &= !mask;
+    }
+    self.set_interrupt_status(false);
+  }
+}
+
+// a function on the bus
+pub(crate) trait PciFunction: Debug + Send {
+  fn config(&self) -> &Config;
+  fn config_mut(&mut self) -> &mut Config;
+  // offset from the start of the BAR, len is 1, 2, 4 or 8
+  fn read_bar(&mut self, bar: usize, offset: u64, len: usize) -> u64;
+  fn write_bar(&mut self, bar: usize, offset: u64, len: usize, data: u64);
+  // true -> INTx is asserted
+  fn step(&mut self, bus: &mut Bus) -> bool;
+  // the config space is reset by the host
+  fn reset(&mut self) {}
+}
+
+// the host bridge is locked while functions step, so they cannot reach these addresses
+pub(crate) fn in_windows(address: u64) -> bool {
+  matches!(address, PCIE_ECAM_START..=PCIE_ECAM_END | PCIE_MMIO_START..=PCIE_MMIO_END)
+}
+
+// a message signalled interrupt is a write, which reaches an interrupt file if there is one
+pub(crate) fn message(bus: &mut Bus, address: u64, data: u32) {
+  if in_windows(address) { return; }
+  let _ = bus.write32(address, data);
+}
+
+// the emulated endpoint called name, for --pci-device and the monitor
+pub(crate) fn endpoint(name: &str) -> Option<Box<dyn PciFunction>> {
+  match name {
+    "edu" => Some(Box::new(edu::Edu::new())),
+    _ => None,
+  }
+}
+
+// same ids as qemu's generic host bridge
+#[derive(Debug)]
+struct HostBridge {
+  config: Config,
+}
+
+impl PciFunction for HostBridge {
+  fn config(&self) -> &Config { &self.config }
+  fn config_mut(&mut self) -> &mut Config { &mut self.config }
+  fn read_bar(&mut self, _bar: usize, _offset: u64, _len: usize) -> u64 { 0 }
+  fn write_bar(&mut self, _bar: usize, _offset: u64, _len: usize, _data: u64) {}
+  fn step(&mut self, _bus: &mut Bus) -> bool { false }
+}
+
+#[derive(Debug)]
+pub(crate) struct PciHost {
+  // by slot, the host bridge is in slot 0
+  functions: Vec<Option<Box<dyn PciFunction>>>,
+  // where the next BAR goes
+  next_bar: u64,
+}
+
+impl PciHost {
+  pub(crate) fn new() -> PciHost {
+    let mut bridge = Config::new(0x1b36, 0x0008, 0x060000);
+    bridge.set(INTERRUPT_PIN, &[0]);
+    let mut functions: Vec<Option<Box<dyn PciFunction>>> = (0..PCIE_SLOTS).map(|_| None).collect();
+    functions[0] = Some(Box::new(HostBridge { config: bridge }));
+    PciHost { functions, next_bar: PCIE_MMIO_START }
+  }
+
+  // put the function in the first empty slot, None if all slots are used or its BARs don't fit
+  pub(crate) fn attach(&mut self, mut function: Box<dyn PciFunction>) -> Option<usize> {
+    let slot = self.functions.iter().position(|function| function.is_none())?;
+    let (bars, next) = place_bars(self.next_bar, function.config())?;
+    assign_bars(&bars, &mut *function);
+    self.next_bar = next;
+    self.functions[slot] = Some(function);
+    Some(slot)
+  }
+
+  pub(crate) fn functions(&self) -> impl Iterator<Item = (usize, &dyn PciFunction)> {
+    self.functions.iter().enumerate().filter_map(|(slot, function)| Some((slot, function.as_deref()?)))
+  }
+
+  // functions back to power-on state, with their BARs assigned again
+  pub(crate) fn reset(&mut self) {
+    self.next_bar = PCIE_MMIO_START;
+    for function in self.functions.iter_mut().flatten() {
+      function.config_mut().reset();
+      function.reset();
+      // they fitted when attached in the same order
+      let (bars, next) = place_bars(self.next_bar, function.config()).unwrap();
+      assign_bars(&bars, &mut **function);
+      self.next_bar = next;
+    }
+  }
+
+  // the function and BAR at address
+  fn decode(&mut self, address: u64) -> Option<(&mut Box<dyn PciFunction>, usize, u64)> {
+    self.functions.iter_mut().flatten().find_map(|function| {
+      let config = function.config();
+      if config.command() & COMMAND_MEMORY == 0 { return None; }
+      let bar = (0..BAR_COUNT).find(|&bar| {
+        let (start, size) = (config.bar_address(bar), config.bar_size(bar));
+        size != 0 && (start..start + size).contains(&address)
+      })?;
+      let offset = address - config.bar_address(bar);
+      Some((function, bar, offset))
+    })
+  }
+
+  // function of a config space access, only function 0 on bus 0 exists
+  fn function(&mut self, address: u64, len: usize) -> Option<(&mut Box<dyn PciFunction>, usize)> {
+    let offset = address - PCIE_ECAM_START;
+    let (bus, slot, function) = (offset >> 20, (offset >> 15) & 0x1f, (offset >> 12) & 0x7);
+    let offset = (offset & 0xfff) as usize;
+    if bus != 0 || function != 0 || offset + len > CONFIG_SIZE { return None; }
+    Some((self.functions[slot as usize].as_mut()?, offset))
+  }
+
+  fn read(&mut self, address: u64, len: usize) -> u64 {
+    // nothing there reads as all ones
+    let none = u64::MAX >> (64 - 8 * len);
+    if address <= PCIE_ECAM_END {
+      match self.function(address, len) {
+        Some((function, offset)) => function.config().read(offset, len),
+        None => none,
+      }
+    } else {
+      match self.decode(address) {
+        Some((function, bar, offset)) => function.read_bar(bar, offset, len),
+        None => none,
+      }
+    }
+  }
+
+  fn write(&mut self, address: u64, len: usize, data: u64) {
+    if address <= PCIE_ECAM_END {
+      if let Some((function, offset)) = self.function(address, len) {
+        function.config_mut().write(offset, len, data);
+      }
+    } else if let Some((function, bar, offset)) = self.decode(address) {
+      function.write_bar(bar, offset, len, data);
+    }
+  }
+}
+
+// the BARs and their addresses from next, aligned to their size, with the next free address,
+// None if they don't all fit in the window
+fn place_bars(mut next: u64, config: &Config) -> Option<(Vec<(usize, u64)>, u64)> {
+  let mut bars = Vec::new();
+  for bar in 0..BAR_COUNT {
+    let size = config.bar_size(bar);
+    if size == 0 { continue; }
+    let address = next.checked_next_multiple_of(size)?;
+    let end = address.checked_add(size)?;
+    if end - 1 > PCIE_MMIO_END { return None; }
+    bars.push((bar, address));
+    next = end;
+  }
+  Some((bars, next))
+}
+
+fn assign_bars(bars: &[(usize, u64)], function: &mut dyn PciFunction) {
+  for &(bar, address) in bars {
+    function.config_mut().assign_bar(bar, address);
+  }
+}
+
+impl Device for PciHost {
+  device_atomic!();
+
+  fn step(&mut self, bus: &mut Bus, _hart: &mut Hart) {
+    let mut lines = [false; PCIE_INTERRUPT_COUNT as usize];
+    for (slot, function) in self.functions.iter_mut().enumerate() {
+      let Some(function) = function else { continue };
+      let asserted = function.step(bus);
+      let config = function.config_mut();
+      config.set_interrupt_status(asserted);
+      let pin = config.read(INTERRUPT_PIN, 1) as usize;
+      if asserted && pin != 0 && config.command() & COMMAND_INTX_DISABLE == 0 {
+        lines[(slot + pin - 1) % lines.len()] = true;
+      }
+    }
+    let mut plic = bus.plic.lock().unwrap();
+    for (index, asserted) in lines.into_iter().enumerate() {
+      plic.irq(PCIE_INTERRUPT_ID + index as u32, asserted);
+    }
+  }
+
+  fn read8(&mut self, address: u64) -> Result<u8, Exception> { Ok(self.read(address, 1) as u8) }
+  fn read16(&mut self, address: u64) -> Result<u16, Exception> { Ok(self.read(address, 2) as u16) }
+  fn read32(&mut self, address: u64) -> Result<u32, Exception> { Ok(self.read(address, 4) as u32) }
+
+  fn read64(&mut self, address: u64) -> Result<u64, Exception> {
+    // config space is accessed by dwords at most
+    if address <= PCIE_ECAM_END {
+      return Ok(self.read(address, 4) | self.read(address + 4, 4) << 32);
+    }
+    Ok(self.read(address, 8))
+  }
+
+  fn write8(&mut self, address: u64, data: u8) -> Result<(), Exception> { self.write(address, 1, data as u64); Ok(()) }
+  fn write16(&mut self, address: u64, data: u16) -> Result<(), Exception> { self.write(address, 2, data as u64); Ok(()) }
+  fn write32(&mut self, address: u64, data: u32) -> Result<(), Exception> { self.write(address, 4, data as u64); Ok(()) }
+
+  fn write64(&mut self, address: u64, data: u64) -> Result<(), Exception> {
+    if address <= PCIE_ECAM_END {
+      self.write(address, 4, data & 0xffffffff);
+      self.write(address + 4, 4, data >> 32);
+      return Ok(());
+    }
+    self.write(address, 8, data);
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::devices::{Device, bus::Bus};
+
+  use super::*;
+
+  #[test]
+  fn enumerate() {
+    let (mut bus, _controller) = Bus::new();
+    let slot = bus.attach_pci(endpoint("edu").unwrap()).unwrap();
+    assert_eq!(slot, 1);
+    let config = |slot: u64, offset: u64| PCIE_ECAM_START + (slot << 15) + offset;
+    assert_eq!(bus.read32(config(0, 0)).unwrap(), 0x00081b36);
+    assert_eq!(bus.read32(config(1, 0)).unwrap(), 0x11e81234);
+    // empty slots and other functions
+    assert_eq!(bus.read16(config(2, 0)).unwrap(), 0xffff);
+    assert_eq!(bus.read32(config(1, 0x100)).unwrap() & 0xffff, 0);
+    assert_eq!(bus.read16(PCIE_ECAM_START + (1 << 12)).unwrap(), 0xffff);
+    // sizing the BAR, which yuri assigned
+    let bar = config(1, BAR0 as u64);
+    assert_eq!(bus.read32(bar).unwrap(), PCIE_MMIO_START as u32);
+    bus.write32(bar, u32::MAX).unwrap();
+    assert_eq!(bus.read32(bar).unwrap(), 0xfff00000);
+    bus.write32(bar, 0x40100000).unwrap();
+    // decoded once memory is enabled
+    assert_eq!(bus.read32(0x40100000).unwrap(), u32::MAX);
+    bus.write16(config(1, COMMAND as u64), COMMAND_MEMORY).unwrap();
+    assert_eq!(bus.read32(0x40100000).unwrap(), 0x010000ed);
+    // the capability list
+    assert_ne!(bus.read16(config(1, STATUS as u64)).unwrap() & STATUS_CAPABILITIES, 0);
+    let capability = bus.read8(config(1, CAPABILITIES as u64)).unwrap() as u64;
+    assert_eq!(bus.read8(config(1, capability)).unwrap(), 0x05);
+    bus.reset();
+    assert_eq!(bus.read32(bar).unwrap(), PCIE_MMIO_START as u32);
+    assert_eq!(bus.read16(config(1, COMMAND as u64)).unwrap(), 0);
+  }
+
+  #[test]
+  fn attach_fails_whole() {
+    let mut host = PciHost::new();
+    // the first BAR fits, the second one only in an empty window
+    let mut config = Config::new(0x1b36, 0x0001, 0xff0000);
+    config.add_bar(0, 0x1000);
+    config.add_bar(1, PCIE_MMIO_END - PCIE_MMIO_START + 1);
+    assert_eq!(host.attach(endpoint("edu").unwrap()), Some(1));
+    let next = host.next_bar;
+    assert_eq!(host.attach(Box::new(HostBridge { config })), None);
+    assert_eq!(host.next_bar, next);
+    assert_eq!(host.functions().count(), 2);
+    assert_eq!(host.attach(endpoint("edu").unwrap()), Some(2));
+  }
+}
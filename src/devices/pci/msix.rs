@@ -0,0 +1,119 @@
+use crate::devices::bus::Bus;
+
+use super::{Config, message};
+
+// MSI-X with the table and the pending bits in a BAR of the function
+
+const CAPABILITY_ID: u8 = 0x11;
+const CAPABILITY_LEN: usize = 12;
+// in the capability
+const CONTROL: usize = 2;
+const TABLE: usize = 4;
+const PBA: usize = 8;
+
+const CONTROL_MASK: u16 = 1 << 14;
+const CONTROL_ENABLE: u16 = 1 << 15;
+
+const ENTRY_SIZE: u64 = 16;
+const ENTRY_MASKED: u32 = 1;
+
+pub(crate) const NO_VECTOR: u16 = 0xffff;
+
+#[derive(Debug)]
+pub(crate) struct Msix {
+  capability: usize,
+  // address low, address high, data and vector control of every vector
+  table: Vec<[u32; 4]>,
+  pending: Vec<bool>,
+}
+
+impl Msix {
+  // the table at table_offset and the pending bits at pba_offset of bar
+  pub(crate) fn new(config: &mut Config, vectors: u16, bar: u8, table_offset: u32, pba_offset: u32) -> Msix {
+    let capability = config.add_capability(CAPABILITY_ID, CAPABILITY_LEN);
+    config.set16(capability + CONTROL, vectors - 1);
+    config.set_wmask(capability + CONTROL, &(CONTROL_MASK | CONTROL_ENABLE).to_le_bytes());
+    config.set32(capability + TABLE, table_offset | bar as u32);
+    config.set32(capability + PBA, pba_offset | bar as u32);
+    let mut msix = Msix { capability, table: vec![[0; 4]; vectors as usize], pending: vec![false; vectors as usize] };
+    msix.reset();
+    msix
+  }
+
+  pub(crate) fn vectors(&self) -> u16 {
+    self.table.len() as u16
+  }
+
+  pub(crate) fn enabled(&self, config: &Config) -> bool {
+    config.get16(self.capability + CONTROL) & CONTROL_ENABLE != 0
+  }
+
+  fn masked(&self, config: &Config, vector: usize) -> bool {
+    config.get16(self.capability + CONTROL) & CONTROL_MASK != 0 || self.table[vector][3] & ENTRY_MASKED != 0
+  }
+
+  // dwords of the table, a qword covers two
+  pub(crate) fn read_table(&self, offset: u64, len: usize) -> u64 {
+    let dword = |offset: u64| {
+      let entry = (offset / ENTRY_SIZE) as usize;
+      self.table.get(entry).map_or(0, |entry| entry[(offset % ENTRY_SIZE / 4) as usize]) as u64
+    };
+    match len {
+      8 => dword(offset) | dword(offset + 4) << 32,
+      _ => dword(offset & !3) >> (8 * (offset & 3)),
+    }
+  }
+
+  pub(crate) fn write_table(&mut self, offset: u64, len: usize, data: u64) {
+    let mut dword = |offset: u64, data: u32| {
+      let entry = (offset / ENTRY_SIZE) as usize;
+      if let Some(entry) = self.table.get_mut(entry) {
+        entry[(offset % ENTRY_SIZE / 4) as usize] = data;
+      }
+    };
+    match len {
+      8 => {
+        dword(offset, data as u32);
+        dword(offset + 4, (data >> 32) as u32);
+      },
+      4 => dword(offset, data as u32),
+      _ => {},
+    }
+  }
+
+  pub(crate) fn read_pba(&self, offset: u64, len: usize) -> u64 {
+    let bits = self.pending.iter().enumerate().fold(0u64, |bits, (vector, &pending)| bits | (pending as u64) << vector);
+    // vectors beyond 64 are never used
+    if offset >= 8 { return 0; }
+    (bits >> (8 * offset)) & (u64::MAX >> (64 - 8 * len))
+  }
+
+  // send the message of vector, or hold it while the vector is masked
+  pub(crate) fn notify(&mut self, config: &Config, vector: u16, bus: &mut Bus) {
+    let vector = vector as usize;
+    if vector >= self.table.len() { return; }
+    if self.masked(config, vector) {
+      self.pending[vector] = true;
+      return;
+    }
+    let [low, high, data, _] = self.table[vector];
+    message(bus, (high as u64) << 32 | low as u64, data);
+  }
+
+  // messages held back by a mask which was cleared since
+  pub(crate) fn step(&mut self, config: &Config, bus: &mut Bus) {
+    if !self.enabled(config) { return; }
+    for vector in 0..self.table.len() {
+      if self.pending[vector] && !self.masked(config, vector) {
+        self.pending[vector] = false;
+        self.notify(config, vector as u16, bus);
+      }
+    }
+  }
+
+  // every vector masked
+  pub(crate) fn reset(&mut self) {
+    self.table.iter_mut().for_each(|entry| *entry = [0, 0, 0, ENTRY_MASKED]);
+    self.pending.iter_mut().for_each(|pending| *pending = false);
+  }
+}
@@ -19,7 +19,7 @@ pub(crate) const PLIC_THRESHOLD_CLIAM_COMPLETE_END: u64 = PLIC_START + 0x3FFFFFF
 
 // TODO: hart count
 const HART_COUNT: usize = 1;
-const INTERRUPT_COUNT: usize = 64;
+pub(crate) const INTERRUPT_COUNT: usize = 64;
 
 #[derive(Debug, Clone, Copy)]
 pub(crate) struct Pair<T: Clone + Copy> {
@@ -47,7 +47,7 @@ impl<T: Clone + Copy> Pair<T> {
 
 #[derive(Debug)]
 pub(crate) struct Plic {
-  priorities: [u32; 1023],
+  priorities: [u32; 1024],
   pending: [u32; 32],
   enable: [Pair<[u32; 32]>; HART_COUNT * 2],
   threshold: [Pair<u32>; HART_COUNT * 2],
@@ -58,7 +58,7 @@ pub(crate) struct Plic {
 impl Plic {
   pub(crate) fn new() -> Plic {
     Plic {
-      priorities: [0; 1023],
+      priorities: [0; 1024],
       pending: [0; 32],
       enable: [Pair { machine: [0; 32], supervisor: [0; 32] }; HART_COUNT * 2],
       threshold: [Pair { machine: 0, supervisor: 0 }; HART_COUNT * 2],
@@ -83,12 +83,13 @@ impl Plic {
 
   fn complete(&mut self, context: usize, irq: u32) {
     self.claimed[context / 2].at_mut(context % 2)[irq as usize] = false;
+    self.update = true;
   }
 
   fn claim(&mut self, context: usize) -> u32 {
     let irq = self.highest_irq(context);
-    let index = (irq / 8) as usize;
-    let offset = irq % 8;
+    let index = (irq / 32) as usize;
+    let offset = irq % 32;
     self.pending[index] &= !(1 << offset);
     self.claimed[context / 2].at_mut(context % 2)[irq as usize] = true;
     irq
@@ -104,7 +105,7 @@ impl Plic {
       let mode = context % 2;
       if self.enable[hart].at(mode)[index] & (1 << offset) != 0
         && self.pending[index] & (1 << offset) != 0
-        && !self.claimed[hart].at(mode)[index]
+        && !self.claimed[hart].at(mode)[i]
         && self.priorities[i] > *self.threshold[hart].at(mode)
         && self.priorities[i] > priority {
           irq = i as u32;
@@ -121,10 +122,10 @@ impl Device for Plic {
   fn step(&mut self, _bus: &mut Bus, hart: &mut Hart) {
     if self.update {
       self.update = false;
-      for context in 0..HART_COUNT {
-        let ip = if self.highest_irq(context) != 0 { 1 } else { 0 };
-        hart.csr.write_mip_meip(ip);
-        hart.csr.write_mip_seip(ip);
+      // context 2n is machine mode of hart n, 2n + 1 is supervisor mode
+      for index in 0..HART_COUNT {
+        hart.csr.write_mip_meip(if self.highest_irq(index * 2) != 0 { 1 } else { 0 });
+        hart.csr.write_mip_seip(if self.highest_irq(index * 2 + 1) != 0 { 1 } else { 0 });
       }
     }
   }
@@ -154,13 +155,13 @@ impl Device for Plic {
     if address % 4 != 0 { return Err(Exception::LoadAddressMisaligned(address)); }
     match address {
       PLIC_SOURCE_PRIORITY_START..=PLIC_SOURCE_PRIORITY_END =>
-        Ok(self.priorities[(address - PLIC_SOURCE_PRIORITY_START) as usize]),
+        Ok(self.priorities[((address - PLIC_START) / 4) as usize]),
       PLIC_PENDING_START..=PLIC_PENDING_END =>
-        Ok(self.pending[(address - PLIC_PENDING_START) as usize]),
+        Ok(self.pending[((address - PLIC_PENDING_START) / 4) as usize]),
       PLIC_SOURCE_ENABLE_START..=PLIC_SOURCE_ENABLE_END => {
         let offset = (address - PLIC_SOURCE_ENABLE_START) as usize;
         let context = offset / 0x80;
-        let item = offset % 0x80;
+        let item = offset % 0x80 / 4;
         Ok(self.enable[context / 2].at(context % 2)[item])
       },
       PLIC_THRESHOLD_CLIAM_COMPLETE_START..=PLIC_THRESHOLD_CLIAM_COMPLETE_END => {
@@ -171,7 +172,7 @@ impl Device for Plic {
           // threshold
           0 => Ok(*self.threshold[context / 2].at(context % 2)),
           // claim
-          1 => {
+          4 => {
             let irq = self.claim(context);
             self.update = true;
             Ok(irq)
@@ -186,13 +187,16 @@ impl Device for Plic {
   fn write32(&mut self, address: u64, data: u32) -> Result<(), Exception> {
     if address % 4 != 0 { return Err(Exception::StoreAMOAddressMisaligned(address)); }
     match address {
-      PLIC_SOURCE_PRIORITY_START..=PLIC_SOURCE_PRIORITY_END =>
-        self.priorities[(address - PLIC_SOURCE_PRIORITY_START) as usize] = data,
+      PLIC_SOURCE_PRIORITY_START..=PLIC_SOURCE_PRIORITY_END => {
+        self.priorities[((address - PLIC_START) / 4) as usize] = data;
+        self.update = true;
+      },
       PLIC_SOURCE_ENABLE_START..=PLIC_SOURCE_ENABLE_END => {
         let offset = (address - PLIC_SOURCE_ENABLE_START) as usize;
         let context = offset / 0x80;
-        let item = offset % 0x80;
+        let item = offset % 0x80 / 4;
         self.enable[context / 2].at_mut(context % 2)[item] = data;
+        self.update = true;
       },
       PLIC_THRESHOLD_CLIAM_COMPLETE_START..=PLIC_THRESHOLD_CLIAM_COMPLETE_END => {
         let offset = (address - PLIC_THRESHOLD_CLIAM_COMPLETE_START) as usize;
@@ -200,9 +204,12 @@ impl Device for Plic {
         let item = offset % 0x1000;
         match item {
           // threshold
-          0 => *self.threshold[context / 2].at_mut(context % 2) = data,
+          0 => {
+            *self.threshold[context / 2].at_mut(context % 2) = data;
+            self.update = true;
+          },
           // complete
-          1 => self.complete(context, data),
+          4 => self.complete(context, data),
           _ => return Err(Exception::LoadAccessFault(address)),
         };
       },
@@ -211,3 +218,38 @@ impl Device for Plic {
     Ok(())
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use crate::{devices::{Device, bus::Bus}, hart::Hart};
+
+  use super::*;
+
+  #[test]
+  fn claim_and_complete() {
+    let (mut bus, _controller) = Bus::new();
+    let mut hart = Hart::new();
+    let mut plic = Plic::new();
+    // source 33 with priority 2 for machine mode of hart 0
+    plic.write32(PLIC_START + 33 * 4, 2).unwrap();
+    plic.write32(PLIC_SOURCE_ENABLE_START + 4, 1 << 1).unwrap();
+    plic.irq(33, true);
+    assert_eq!(plic.read32(PLIC_PENDING_START + 4).unwrap(), 1 << 1);
+    plic.step(&mut bus, &mut hart);
+    assert!(hart.csr.read_mip().me);
+    assert!(!hart.csr.read_mip().se);
+    assert_eq!(plic.read32(PLIC_THRESHOLD_CLIAM_COMPLETE_START + 4).unwrap(), 33);
+    assert_eq!(plic.read32(PLIC_PENDING_START + 4).unwrap(), 0);
+    plic.step(&mut bus, &mut hart);
+    assert!(!hart.csr.read_mip().me);
+    plic.write32(PLIC_THRESHOLD_CLIAM_COMPLETE_START + 4, 33).unwrap();
+    // at the threshold, the source is masked
+    plic.irq(33, true);
+    plic.write32(PLIC_THRESHOLD_CLIAM_COMPLETE_START, 2).unwrap();
+    plic.step(&mut bus, &mut hart);
+    assert!(!hart.csr.read_mip().me);
+    plic.write32(PLIC_THRESHOLD_CLIAM_COMPLETE_START, 1).unwrap();
+    plic.step(&mut bus, &mut hart);
+    assert!(hart.csr.read_mip().me);
+  }
+}
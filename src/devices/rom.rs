@@ -0,0 +1,99 @@
+use crate::{device_atomic, device_rw, hart::{Hart, Mode}, trap::Exception};
+
+use super::{Device, bus::Bus};
+
+// Mask ROM at the reset vector, as on qemu's virt board. It passes the hart id in a0, the device tree in a1 and
+// the fw_dynamic info in a2 to the next stage, and mrets into it in the mode the next stage runs in.
+
+pub(crate) const RESET_ADDRESS: u64 = 0x1000;
+pub(crate) const ROM_SIZE: u64 = 0x100;
+
+const CODE: [u32; 9] = [
+  0x00000297, // auipc t0, 0
+  0xf1402573, // csrr  a0, mhartid
+  0x0302b583, // ld    a1, 48(t0)
+  0x0382b603, // ld    a2, 56(t0)
+  0x0402b303, // ld    t1, 64(t0)
+  0x30032073, // csrs  mstatus, t1
+  0x0282b283, // ld    t0, 40(t0)
+  0x34129073, // csrw  mepc, t0
+  0x30200073, // mret
+];
+const NEXT: usize = 40;
+const DTB: usize = 48;
+const INFO: usize = 56;
+// MPP of the next stage
+const STATUS: usize = 64;
+
+#[derive(Debug)]
+pub(crate) struct Rom {
+  start: u64,
+  data: [u8; ROM_SIZE as usize],
+}
+
+impl Rom {
+  pub(crate) fn new(start: u64) -> Rom {
+    let mut data = [0; ROM_SIZE as usize];
+    for (index, inst) in CODE.iter().enumerate() {
+      data[4 * index..4 * index + 4].copy_from_slice(&inst.to_le_bytes());
+    }
+    Rom { start, data }
+  }
+
+  pub(crate) fn contains(&self, address: u64) -> bool {
+    (self.start..self.start + ROM_SIZE).contains(&address)
+  }
+
+  pub(crate) fn start(&self) -> u64 {
+    self.start
+  }
+
+  // where the ROM jumps to, in which mode, and what it passes on. Info is zero without fw_dynamic
+  pub(crate) fn set_next(&mut self, next: u64, mode: Mode, dtb: u64, info: u64) {
+    let status = (mode.as_u8() as u64) << 11;
+    for (offset, value) in [(NEXT, next), (DTB, dtb), (INFO, info), (STATUS, status)] {
+      self.data[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+    }
+  }
+}
+
+impl Device for Rom {
+  device_atomic!();
+  device_rw!();
+
+  fn step(&mut self, _bus: &mut Bus, _hart: &mut Hart) {}
+
+  fn read8(&mut self, address: u64) -> Result<u8, Exception> {
+    Ok(self.data[(address - self.start) as usize])
+  }
+
+  fn write8(&mut self, address: u64, _data: u8) -> Result<(), Exception> {
+    Err(Exception::StoreAMOAccessFault(address))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::{devices::{bus::Bus, memory::MEMORY_START, tree::DTB_ADDRESS}, hart::{Hart, Mode}, mmu::MMU};
+
+  use super::*;
+
+  #[test]
+  fn reset_vector() {
+    let (mut bus, _controller) = Bus::new();
+    bus.rom.lock().unwrap().set_next(MEMORY_START + 0x200000, Mode::Supervisor, DTB_ADDRESS, 0x1234);
+    let mut mmu = MMU::new(bus.clone());
+    let mut hart = Hart::new();
+    hart.pc = RESET_ADDRESS;
+    for _ in 0..CODE.len() {
+      hart.step(&mut mmu);
+    }
+    assert_eq!((hart.pc, hart.mode), (MEMORY_START + 0x200000, Mode::Supervisor));
+    assert_eq!((hart.regs[10], hart.regs[11], hart.regs[12]), (0, DTB_ADDRESS, 0x1234));
+    assert!(bus.write8(RESET_ADDRESS, 0).is_err());
+    // not over other devices
+    assert!(bus.move_rom(0x10000000).is_err());
+    assert!(bus.move_rom(0x8000).is_ok());
+    assert_eq!(bus.read32(0x8000).unwrap(), CODE[0]);
+  }
+}
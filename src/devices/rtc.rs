@@ -0,0 +1,265 @@
+use std::{str::FromStr, time::{SystemTime, UNIX_EPOCH}};
+
+use crate::{device_atomic, hart::Hart, trap::Exception};
+
+use super::{Device, bus::Bus, aclint::TIMEBASE_FREQUENCY};
+
+// Goldfish RTC, nanoseconds since the epoch.
+
+pub(crate) const RTC_START: u64 = 0x101000;
+pub(crate) const RTC_END: u64 = RTC_START + 0xfff;
+pub(crate) const RTC_INTERRUPT_ID: u32 = 16;
+
+const TIME_LOW: u64 = 0x00;
+const TIME_HIGH: u64 = 0x04;
+const ALARM_LOW: u64 = 0x08;
+const ALARM_HIGH: u64 = 0x0c;
+const IRQ_ENABLED: u64 = 0x10;
+const CLEAR_ALARM: u64 = 0x14;
+const ALARM_STATUS: u64 = 0x18;
+const CLEAR_INTERRUPT: u64 = 0x1c;
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Clock {
+  // wall-clock time of the host
+  Host,
+  // nanoseconds since the epoch at boot, advanced by guest time
+  Fixed(u64),
+}
+
+// "now", seconds since the epoch, or a UTC date as YYYY-MM-DD or YYYY-MM-DDTHH:MM:SS
+impl FromStr for Clock {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let invalid = || format!("invalid time '{}', expected now, seconds since the epoch or YYYY-MM-DD[THH:MM:SS]", s);
+    if s == "now" { return Ok(Clock::Host); }
+    if let Ok(seconds) = s.parse::<u64>() {
+      return seconds.checked_mul(NANOS_PER_SECOND).map(Clock::Fixed).ok_or_else(invalid);
+    }
+    let (date, time) = s.split_once('T').unwrap_or((s, "00:00:00"));
+    let fields = |text: &str, separator: char| -> Option<Vec<u64>> {
+      text.split(separator).map(|field| field.parse().ok()).collect()
+    };
+    let (Some(date), Some(time)) = (fields(date, '-'), fields(time, ':')) else { return Err(invalid()) };
+    let (&[year, month, day], &[hour, minute, second]) = (&date[..], &time[..]) else { return Err(invalid()) };
+    if year < 1970 || !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 59 {
+      return Err(invalid());
+    }
+    // far enough in the future overflows the nanoseconds
+    days_from_civil(year, month, day)
+      .and_then(|days| days.checked_mul(86400))
+      .and_then(|seconds| seconds.checked_add(hour * 3600 + minute * 60 + second))
+      .and_then(|seconds| seconds.checked_mul(NANOS_PER_SECOND))
+      .map(Clock::Fixed).ok_or_else(invalid)
+  }
+}
+
+// days since 1970-01-01 of a date in the proleptic gregorian calendar
+fn days_from_civil(year: u64, month: u64, day: u64) -> Option<u64> {
+  // years start in march, so the leap day is the last one
+  let year = if month <= 2 { year - 1 } else { year };
+  let era = year / 400;
+  let year_of_era = year - era * 400;
+  let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+  let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+  (era.checked_mul(146097)? + day_of_era).checked_sub(719468)
+}
+
+#[derive(Debug)]
+pub(crate) struct GoldfishRtc {
+  clock: Clock,
+  // set by the guest, relative to the clock
+  offset: i64,
+  // guest time of the last step
+  mtime: u64,
+  // latched by reading TIME_LOW
+  time_high: u32,
+  alarm_high: u32,
+  alarm: Option<u64>,
+  irq_enabled: bool,
+  interrupt: bool,
+}
+
+impl GoldfishRtc {
+  pub(crate) fn new(clock: Clock) -> GoldfishRtc {
+    GoldfishRtc {
+      clock,
+      offset: 0,
+      mtime: 0,
+      time_high: 0,
+      alarm_high: 0,
+      alarm: None,
+      irq_enabled: false,
+      interrupt: false,
+    }
+  }
+
+  // the time keeps going, as with a battery, while guest time starts again
+  pub(crate) fn reset(&mut self) {
+    let now = self.now();
+    *self = GoldfishRtc::new(self.clock);
+    self.set_time(now);
+  }
+
+  fn now(&self) -> u64 {
+    let now = match self.clock {
+      Clock::Host => SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_nanos() as u64),
+      Clock::Fixed(boot) => boot.wrapping_add((self.mtime as u128 * NANOS_PER_SECOND as u128 / TIMEBASE_FREQUENCY as u128) as u64),
+    };
+    now.wrapping_add_signed(self.offset)
+  }
+
+  // mtime ticks until the alarm, while it can interrupt
+  pub(crate) fn deadline(&self) -> Option<u64> {
+    let alarm = self.alarm.filter(|_| self.irq_enabled)?;
+    let nanos = alarm.saturating_sub(self.now()) as u128;
+    Some((nanos * TIMEBASE_FREQUENCY as u128).div_ceil(NANOS_PER_SECOND as u128).min(u64::MAX as u128) as u64)
+  }
+
+  fn set_time(&mut self, time: u64) {
+    self.offset = self.offset.wrapping_add(time.wrapping_sub(self.now()) as i64);
+  }
+}
+
+impl Device for GoldfishRtc {
+  device_atomic!();
+
+  fn step(&mut self, bus: &mut Bus, _hart: &mut Hart) {
+    self.mtime = bus.aclint.lock().unwrap().mtime();
+    if self.alarm.is_some_and(|alarm| self.now() >= alarm) {
+      self.alarm = None;
+      self.interrupt = true;
+    }
+    bus.plic.lock().unwrap().irq(RTC_INTERRUPT_ID, self.interrupt && self.irq_enabled);
+  }
+
+  // registers are 32 bits wide
+
+  fn read8(&mut self, address: u64) -> Result<u8, Exception> { Err(Exception::LoadAccessFault(address)) }
+  fn read16(&mut self, address: u64) -> Result<u16, Exception> { Err(Exception::LoadAccessFault(address)) }
+
+  fn read32(&mut self, address: u64) -> Result<u32, Exception> {
+    Ok(match address - RTC_START {
+      TIME_LOW => {
+        let now = self.now();
+        self.time_high = (now >> 32) as u32;
+        now as u32
+      },
+      TIME_HIGH => self.time_high,
+      ALARM_HIGH => self.alarm_high,
+      IRQ_ENABLED => self.irq_enabled as u32,
+      ALARM_STATUS => self.alarm.is_some() as u32,
+      _ => 0,
+    })
+  }
+
+  fn read64(&mut self, address: u64) -> Result<u64, Exception> {
+    Ok(self.read32(address)? as u64 | (self.read32(address + 4)? as u64) << 32)
+  }
+
+  fn write8(&mut self, address: u64, _data: u8) -> Result<(), Exception> { Err(Exception::StoreAMOAccessFault(address)) }
+  fn write16(&mut self, address: u64, _data: u16) -> Result<(), Exception> { Err(Exception::StoreAMOAccessFault(address)) }
+
+  fn write32(&mut self, address: u64, data: u32) -> Result<(), Exception> {
+    match address - RTC_START {
+      // the high half is written first, setting the low half sets the time
+      TIME_LOW => self.set_time((self.time_high as u64) << 32 | data as u64),
+      TIME_HIGH => self.time_high = data,
+      ALARM_LOW => self.alarm = Some((self.alarm_high as u64) << 32 | data as u64),
+      ALARM_HIGH => self.alarm_high = data,
+      IRQ_ENABLED => self.irq_enabled = data & 1 != 0,
+      CLEAR_ALARM => self.alarm = None,
+      CLEAR_INTERRUPT => self.interrupt = false,
+      _ => {},
+    }
+    Ok(())
+  }
+
+  // high half first, as the low half takes effect
+  fn write64(&mut self, address: u64, data: u64) -> Result<(), Exception> {
+    self.write32(address + 4, (data >> 32) as u32)?;
+    self.write32(address, data as u32)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::devices::{plic::PLIC_PENDING_START, bus::Bus};
+
+  use super::*;
+
+  fn step(bus: &mut Bus) {
+    let mut other = bus.clone();
+    bus.rtc.lock().unwrap().step(&mut other, &mut Hart::new());
+  }
+
+  #[test]
+  fn time_and_alarm() {
+    let (mut bus, _controller) = Bus::new();
+    // 50ns before the high half rolls over
+    *bus.rtc.lock().unwrap() = GoldfishRtc::new(Clock::Fixed((2 << 32) - 50));
+    step(&mut bus);
+    assert_eq!(bus.read32(RTC_START + TIME_LOW).unwrap(), u32::MAX - 49);
+    assert_eq!(bus.read32(RTC_START + TIME_HIGH).unwrap(), 1);
+    // the high half stays latched until the low half is read again
+    bus.aclint.lock().unwrap().advance(1);
+    step(&mut bus);
+    assert_eq!(bus.read32(RTC_START + TIME_HIGH).unwrap(), 1);
+    assert_eq!(bus.read32(RTC_START + TIME_LOW).unwrap(), 50);
+    assert_eq!(bus.read32(RTC_START + TIME_HIGH).unwrap(), 2);
+
+    // setting the time, which keeps going from there
+    bus.write64(RTC_START + TIME_LOW, 7 << 32 | 1000).unwrap();
+    assert_eq!(bus.read64(RTC_START + TIME_LOW).unwrap(), 7 << 32 | 1000);
+    bus.aclint.lock().unwrap().advance(3);
+    step(&mut bus);
+    assert_eq!(bus.read64(RTC_START + TIME_LOW).unwrap(), 7 << 32 | 1300);
+
+    // an alarm 1us away is 10 ticks
+    bus.write32(RTC_START + IRQ_ENABLED, 1).unwrap();
+    bus.write64(RTC_START + ALARM_LOW, 7 << 32 | 2300).unwrap();
+    assert_eq!(bus.read32(RTC_START + ALARM_STATUS).unwrap(), 1);
+    assert_eq!(bus.rtc.lock().unwrap().deadline(), Some(10));
+    bus.aclint.lock().unwrap().advance(9);
+    step(&mut bus);
+    assert_eq!(bus.read32(PLIC_PENDING_START).unwrap() & 1 << RTC_INTERRUPT_ID, 0);
+    bus.aclint.lock().unwrap().advance(1);
+    step(&mut bus);
+    assert_eq!(bus.read32(PLIC_PENDING_START).unwrap() & 1 << RTC_INTERRUPT_ID, 1 << RTC_INTERRUPT_ID);
+    assert_eq!(bus.read32(RTC_START + ALARM_STATUS).unwrap(), 0);
+    assert_eq!(bus.rtc.lock().unwrap().deadline(), None);
+    bus.write32(RTC_START + CLEAR_INTERRUPT, 1).unwrap();
+    step(&mut bus);
+    assert_eq!(bus.read32(PLIC_PENDING_START).unwrap() & 1 << RTC_INTERRUPT_ID, 0);
+  }
+
+  #[test]
+  fn fixed_clock_wraps() {
+    let (mut bus, _controller) = Bus::new();
+    *bus.rtc.lock().unwrap() = GoldfishRtc::new(Clock::Fixed(u64::MAX));
+    bus.aclint.lock().unwrap().advance(1);
+    step(&mut bus);
+    assert_eq!(bus.read64(RTC_START + TIME_LOW).unwrap(), 99);
+  }
+
+  #[test]
+  fn dates() {
+    let seconds = |s: &str| match s.parse::<Clock>().unwrap() {
+      Clock::Fixed(time) => time / NANOS_PER_SECOND,
+      Clock::Host => panic!("{} is the host clock", s),
+    };
+    assert_eq!(seconds("1970-01-01"), 0);
+    assert_eq!(seconds("2000-03-01"), 951868800);
+    assert_eq!(seconds("2024-02-29T12:34:56"), 1709210096);
+    assert_eq!(seconds("1700000000"), 1700000000);
+    assert_eq!("now".parse::<Clock>(), Ok(Clock::Host));
+    assert!("2024-13-01".parse::<Clock>().is_err());
+    assert!("2024-01-01T12:00".parse::<Clock>().is_err());
+    assert!("18446744074".parse::<Clock>().is_err());
+    assert!("999999999999-01-01".parse::<Clock>().is_err());
+    assert!("18446744073709551615-01-01".parse::<Clock>().is_err());
+  }
+}
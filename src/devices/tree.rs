@@ -0,0 +1,231 @@
+use std::ops::Range;
+
+use crate::{hart::Hart, utils::fdt::Fdt};
+
+use super::{bus::Bus, memory::{MEMORY_START, MEMORY_SIZE}, aclint::{ACLINT_START, ACLINT_END, TIMEBASE_FREQUENCY},
+  plic::{PLIC_START, PLIC_END, INTERRUPT_COUNT}, uart::{UART_SIZE, UART_CLOCK}, virtio::{VIRTIO_START, VIRTIO_SIZE, VIRTIO_INTERRUPT_ID},
+  finisher::{FINISHER_START, FINISHER_END}, rtc::{RTC_START, RTC_END, RTC_INTERRUPT_ID},
+  pci::{PCIE_ECAM_START, PCIE_ECAM_END, PCIE_MMIO_START, PCIE_MMIO_END, PCIE_INTERRUPT_ID, PCIE_INTERRUPT_COUNT},
+  flash::{FLASH_START, FLASH_SIZE, WIDTH}, framebuffer::{FRAMEBUFFER_START, FRAMEBUFFER_WIDTH, FRAMEBUFFER_HEIGHT, FRAMEBUFFER_STRIDE, FRAMEBUFFER_SIZE}};
+
+// The device tree of the machine as the bus has it, for firmware and kernels to find their devices.
+
+// the top 2 MiB of memory
+pub(crate) const DTB_ADDRESS: u64 = MEMORY_START + MEMORY_SIZE as u64 - 0x200000;
+
+const INTC_PHANDLE: u32 = 1;
+const PLIC_PHANDLE: u32 = 2;
+const TEST_PHANDLE: u32 = 3;
+
+// machine and supervisor external interrupts, timer and software interrupts
+const IRQ_M_EXT: u32 = 11;
+const IRQ_S_EXT: u32 = 9;
+const IRQ_M_TIMER: u32 = 7;
+const IRQ_M_SOFT: u32 = 3;
+
+// what the kernel is told in /chosen
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Chosen {
+  pub(crate) bootargs: Option<String>,
+  pub(crate) initrd: Option<Range<u64>>,
+}
+
+// lowercase letters of misa in canonical order, then what is always there
+fn isa(hart: &Hart) -> (String, Vec<String>) {
+  let misa = hart.csr.read_misa();
+  let mut extensions: Vec<String> = "imafdc".chars()
+    .filter(|letter| misa & (1 << (*letter as u8 - b'a')) != 0)
+    .map(String::from)
+    .collect();
+  let isa = format!("rv64{}_zicsr_zifencei", extensions.concat());
+  extensions.extend(["zicsr".to_string(), "zifencei".to_string()]);
+  (isa, extensions)
+}
+
+impl Bus {
+  pub(crate) fn device_tree(&self, hart: &Hart, chosen: &Chosen) -> Vec<u8> {
+    let mut fdt = Fdt::new();
+    let uarts: Vec<_> = self.uarts.iter().map(|uart| {
+      let uart = uart.lock().unwrap();
+      (uart.start(), uart.irq())
+    }).collect();
+    fdt.begin_node("")
+      .u32("#address-cells", 2)
+      .u32("#size-cells", 2)
+      .string("compatible", "yuri,yuri")
+      .string("model", "yuri");
+
+    fdt.begin_node("chosen").string("stdout-path", &format!("/uart@{:x}", uarts[0].0));
+    if let Some(bootargs) = &chosen.bootargs {
+      fdt.string("bootargs", bootargs);
+    }
+    if let Some(initrd) = &chosen.initrd {
+      fdt.u64("linux,initrd-start", initrd.start).u64("linux,initrd-end", initrd.end);
+    }
+    fdt.end_node();
+
+    fdt.begin_node(&format!("memory@{:x}", MEMORY_START))
+      .string("device_type", "memory")
+      .reg("reg", &[(MEMORY_START, MEMORY_SIZE as u64)])
+      .end_node();
+    // the framebuffer lives in memory
+    let framebuffer = (FRAMEBUFFER_START, FRAMEBUFFER_SIZE);
+    fdt.begin_node("reserved-memory")
+      .u32("#address-cells", 2)
+      .u32("#size-cells", 2)
+      .empty("ranges")
+      .begin_node(&format!("framebuffer@{:x}", FRAMEBUFFER_START))
+      .reg("reg", &[framebuffer])
+      .empty("no-map")
+      .end_node()
+      .end_node();
+
+    let (isa, extensions) = isa(hart);
+    let extensions: Vec<&str> = extensions.iter().map(String::as_str).collect();
+    fdt.begin_node("cpus")
+      .u32("#address-cells", 1)
+      .u32("#size-cells", 0)
+      .u32("timebase-frequency", TIMEBASE_FREQUENCY as u32)
+      .begin_node(&format!("cpu@{}", hart.csr.read_mhartid()))
+      .string("device_type", "cpu")
+      .string("compatible", "riscv")
+      .u32("reg", hart.csr.read_mhartid() as u32)
+      .string("status", "okay")
+      .string("riscv,isa", &isa)
+      .string("riscv,isa-base", "rv64i")
+      .strings_list("riscv,isa-extensions", &extensions)
+      .string("mmu-type", "riscv,sv39")
+      .begin_node("interrupt-controller")
+      .u32("#interrupt-cells", 1)
+      .empty("interrupt-controller")
+      .string("compatible", "riscv,cpu-intc")
+      .u32("phandle", INTC_PHANDLE)
+      .end_node()
+      .end_node()
+      .end_node();
+
+    fdt.begin_node(&format!("interrupt-controller@{:x}", PLIC_START))
+      .u32("#interrupt-cells", 1)
+      .u32("#address-cells", 0)
+      .string("compatible", "riscv,plic0")
+      .empty("interrupt-controller")
+      .u32("riscv,ndev", INTERRUPT_COUNT as u32 - 1)
+      .reg("reg", &[(PLIC_START, PLIC_END - PLIC_START + 1)])
+      .cells("interrupts-extended", &[INTC_PHANDLE, IRQ_M_EXT, INTC_PHANDLE, IRQ_S_EXT])
+      .u32("phandle", PLIC_PHANDLE)
+      .end_node();
+    fdt.begin_node(&format!("clint@{:x}", ACLINT_START))
+      .string("compatible", "riscv,clint0")
+      .reg("reg", &[(ACLINT_START, ACLINT_END - ACLINT_START + 1)])
+      .cells("interrupts-extended", &[INTC_PHANDLE, IRQ_M_SOFT, INTC_PHANDLE, IRQ_M_TIMER])
+      .end_node();
+
+    fdt.begin_node(&format!("test@{:x}", FINISHER_START))
+      .strings_list("compatible", &["sifive,test1", "sifive,test0", "syscon"])
+      .reg("reg", &[(FINISHER_START, FINISHER_END - FINISHER_START + 1)])
+      .u32("phandle", TEST_PHANDLE)
+      .end_node();
+    for (name, value) in [("poweroff", 0x5555), ("reboot", 0x7777)] {
+      fdt.begin_node(name)
+        .string("compatible", &format!("syscon-{}", name))
+        .u32("regmap", TEST_PHANDLE)
+        .u32("offset", 0)
+        .u32("value", value)
+        .end_node();
+    }
+
+    fdt.begin_node(&format!("rtc@{:x}", RTC_START))
+      .string("compatible", "google,goldfish-rtc")
+      .reg("reg", &[(RTC_START, RTC_END - RTC_START + 1)])
+      .u32("interrupts", RTC_INTERRUPT_ID)
+      .u32("interrupt-parent", PLIC_PHANDLE)
+      .end_node();
+    for (start, irq) in uarts {
+      fdt.begin_node(&format!("uart@{:x}", start))
+        .string("compatible", "ns16550a")
+        .reg("reg", &[(start, UART_SIZE)])
+        .u32("interrupts", irq)
+        .u32("interrupt-parent", PLIC_PHANDLE)
+        .u32("clock-frequency", UART_CLOCK)
+        .end_node();
+    }
+    // every slot, the empty ones read as no device
+    for index in 0..self.virtio.len() as u64 {
+      let start = VIRTIO_START + index * VIRTIO_SIZE;
+      fdt.begin_node(&format!("virtio_mmio@{:x}", start))
+        .string("compatible", "virtio,mmio")
+        .reg("reg", &[(start, VIRTIO_SIZE)])
+        .u32("interrupts", VIRTIO_INTERRUPT_ID + index as u32)
+        .u32("interrupt-parent", PLIC_PHANDLE)
+        .end_node();
+    }
+    if !self.flash.is_empty() {
+      let banks: Vec<_> = (0..self.flash.len() as u64).map(|index| (FLASH_START + index * FLASH_SIZE, FLASH_SIZE)).collect();
+      fdt.begin_node(&format!("flash@{:x}", FLASH_START))
+        .string("compatible", "cfi-flash")
+        .reg("reg", &banks)
+        .u32("bank-width", WIDTH as u32)
+        .end_node();
+    }
+
+    // INTx of slot and pin, swizzled over the lines
+    let interrupt_map: Vec<u32> = (0..PCIE_INTERRUPT_COUNT).flat_map(|slot| (1..=PCIE_INTERRUPT_COUNT).flat_map(move |pin|
+      [slot << 11, 0, 0, pin, PLIC_PHANDLE, PCIE_INTERRUPT_ID + (slot + pin - 1) % PCIE_INTERRUPT_COUNT])).collect();
+    let mmio = PCIE_MMIO_END - PCIE_MMIO_START + 1;
+    fdt.begin_node(&format!("pci@{:x}", PCIE_ECAM_START))
+      .string("compatible", "pci-host-ecam-generic")
+      .string("device_type", "pci")
+      .u32("#address-cells", 3)
+      .u32("#size-cells", 2)
+      .u32("#interrupt-cells", 1)
+      .reg("reg", &[(PCIE_ECAM_START, PCIE_ECAM_END - PCIE_ECAM_START + 1)])
+      .cells("bus-range", &[0, 0xff])
+      .empty("dma-coherent")
+      // 32-bit memory space, mapped one to one
+      .cells("ranges", &[0x2000000, (PCIE_MMIO_START >> 32) as u32, PCIE_MMIO_START as u32,
+        (PCIE_MMIO_START >> 32) as u32, PCIE_MMIO_START as u32, (mmio >> 32) as u32, mmio as u32])
+      .cells("interrupt-map-mask", &[0x1800, 0, 0, 7])
+      .cells("interrupt-map", &interrupt_map)
+      .u32("interrupt-parent", PLIC_PHANDLE)
+      .end_node();
+
+    fdt.begin_node(&format!("framebuffer@{:x}", FRAMEBUFFER_START))
+      .string("compatible", "simple-framebuffer")
+      .reg("reg", &[framebuffer])
+      .u32("width", FRAMEBUFFER_WIDTH as u32)
+      .u32("height", FRAMEBUFFER_HEIGHT as u32)
+      .u32("stride", FRAMEBUFFER_STRIDE as u32)
+      .string("format", "x8r8g8b8")
+      .end_node();
+
+    fdt.end_node();
+    fdt.finish()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::{devices::bus::Bus, hart::Hart};
+
+  use super::*;
+
+  fn contains(blob: &[u8], needle: &[u8]) -> bool {
+    blob.windows(needle.len()).any(|window| window == needle)
+  }
+
+  #[test]
+  fn device_tree() {
+    let (bus, _controller) = Bus::new();
+    let chosen = Chosen { bootargs: Some("console=ttyS0".to_string()), initrd: Some(0x88200000..0x88300000) };
+    let blob = bus.device_tree(&Hart::new(), &chosen);
+    let field = |index: usize| u32::from_be_bytes(blob[4 * index..4 * index + 4].try_into().unwrap());
+    assert_eq!(field(0), 0xd00dfeed);
+    assert_eq!(field(1) as usize, blob.len());
+    assert!(contains(&blob, b"console=ttyS0\0"));
+    assert!(contains(&blob, b"rv64imafdc_zicsr_zifencei\0"));
+    assert!(contains(&blob, &[0, 0, 0, 0, 0x80, 0, 0, 0, 0, 0, 0, 0, 0x40, 0, 0, 0]));
+    assert!(contains(&blob, &0x88300000u64.to_be_bytes()));
+    // no flash was attached
+    assert!(!contains(&blob, b"cfi-flash"));
+  }
+}
@@ -1,183 +1,622 @@
-use crate::{device_atomic, device_rw, trap::Exception, hart::Hart, utils::channel::{Receiver, Sender, channel}};
+use std::{collections::VecDeque, str::FromStr};
 
-use super::{Device, bus::Bus};
+use crate::{device_atomic, device_rw, trap::Exception, hart::Hart, utils::{parse_number, channel::{Receiver, Sender, Notifier, channel, notified_channel}}};
+
+use super::{Device, bus::Bus, endpoint::Endpoint, aclint::TIMEBASE_FREQUENCY};
 
 // NS16550A
+// The host takes transmitted bytes at once, so the transmit FIFO is always drained when a write returns.
+// Received bytes wait in the channel while the receive FIFO is full, there are no overruns from the host.
 
+// the first UART, which is always there, further ones follow every UART_STRIDE bytes by default
 pub(crate) const UART_START: u64 = 0x10000000;
-pub(crate) const UART_END: u64 = UART_START + 8 - 1;
-pub(crate) const INTERRUPT_ID: u32 = 1;
+pub(crate) const UART_SIZE: u64 = 8;
+pub(crate) const UART_STRIDE: u64 = 0x100;
+// input clock, which the guest divides down to baud rates
+pub(crate) const UART_CLOCK: u32 = 3686400;
+pub(crate) const UART_INTERRUPT_ID: u32 = 1;
+
+// register offsets, DLL and DLM replace RBR/THR and IER while LCR.DLAB is set
+const UART_RBR: u64 = 0;
+const UART_THR: u64 = 0;
+const UART_DLL: u64 = 0;
+const UART_IER: u64 = 1;
+const UART_DLM: u64 = 1;
+const UART_IIR: u64 = 2;
+const UART_FCR: u64 = 2;
+const UART_LCR: u64 = 3;
+const UART_MCR: u64 = 4;
+const UART_LSR: u64 = 5;
+const UART_MSR: u64 = 6;
+const UART_SCR: u64 = 7;
 
-const UART_RBR_DLL: u64 = UART_START;
-const UART_THR: u64 = UART_START;
+const UART_IER_RDI: u8 = 0b0001;
+const UART_IER_THRI: u8 = 0b0010;
+const UART_IER_RLSI: u8 = 0b0100;
+const UART_IER_MSI: u8 = 0b1000;
 
-const UART_IER_ILM: u64 = UART_START + 1;
-const UART_IER_RDI: u8 = 0b00000001;
-const UART_IER_THRI: u8 = 0b00000010;
+// interrupt identification, highest priority first
+const UART_IIR_NO_INT: u8 = 0x01;
+const UART_IIR_RLSI: u8 = 0x06;
+const UART_IIR_RDI: u8 = 0x04;
+const UART_IIR_CTI: u8 = 0x0c;
+const UART_IIR_THRI: u8 = 0x02;
+const UART_IIR_MSI: u8 = 0x00;
+const UART_IIR_FIFO_ENABLED: u8 = 0xc0;
 
-const UART_IIR: u64 = UART_START + 2;
-const UART_FCR: u64 = UART_START + 2;
-const UART_IIR_NO_INT: u8 = 0b00000001;
-const UART_IIR_THRI: u8 = 0b00000010;
-const UART_IIR_RDI: u8 = 0b00000100;
 const UART_FCR_ENABLE_FIFO: u8 = 0b00000001;
 const UART_FCR_CLEAR_RCVR: u8 = 0b00000010;
 const UART_FCR_CLEAR_XMIT: u8 = 0b00000100;
+const UART_FCR_DMA_SELECT: u8 = 0b00001000;
+const UART_FCR_TRIGGER: u8 = 0b11000000;
 
-const UART_LCR: u64 = UART_START + 3;
+const UART_LCR_SBC: u8 = 0b01000000;
 const UART_LCR_DLAB: u8 = 0b10000000;
 
-const UART_MCR: u64 = UART_START + 4;
-const UART_MCR_LOOP: u8 = 0b00010000;
+const UART_MCR_DTR: u8 = 0b00001;
+const UART_MCR_RTS: u8 = 0b00010;
+const UART_MCR_OUT1: u8 = 0b00100;
+const UART_MCR_OUT2: u8 = 0b01000;
+const UART_MCR_LOOP: u8 = 0b10000;
 
-const UART_LSR: u64 = UART_START + 5;
 const UART_LSR_DR: u8 = 0b00000001;
 const UART_LSR_OE: u8 = 0b00000010;
 const UART_LSR_BI: u8 = 0b00010000;
 const UART_LSR_THRE: u8 = 0b00100000;
 const UART_LSR_TEMT: u8 = 0b01000000;
+const UART_LSR_ERRORS: u8 = 0b00011110;
 
-const _UART_MSR: u64 = UART_START + 6;
-const UART_MCR_OUT2: u8 = 0b00001000;
+const UART_MSR_DCTS: u8 = 0b00000001;
+const UART_MSR_DDSR: u8 = 0b00000010;
+const UART_MSR_TERI: u8 = 0b00000100;
+const UART_MSR_DDCD: u8 = 0b00001000;
+const UART_MSR_CTS: u8 = 0b00010000;
+const UART_MSR_DSR: u8 = 0b00100000;
+const UART_MSR_RI: u8 = 0b01000000;
+const UART_MSR_DCD: u8 = 0b10000000;
+const UART_MSR_DELTAS: u8 = 0b00001111;
 
-const UART_SCR: u64 = UART_START + 7;
+const FIFO_SIZE: usize = 16;
+// characters without a byte received or read before the character timeout
+const TIMEOUT_CHARACTERS: u64 = 4;
+
+// a further UART, as an endpoint optionally followed by ,address=... and ,irq=...
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct UartOptions {
+  pub(crate) endpoint: Endpoint,
+  pub(crate) address: Option<u64>,
+  pub(crate) irq: Option<u32>,
+}
+
+impl FromStr for UartOptions {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let mut parts = s.split(',');
+    let endpoint = parts.next().unwrap_or_default().parse()?;
+    let (mut address, mut irq) = (None, None);
+    for part in parts {
+      match part.split_once('=') {
+        Some(("address", value)) => {
+          address = Some(parse_number(value).ok_or_else(|| format!("invalid address '{}'", value))?);
+        },
+        Some(("irq", value)) => irq = Some(value.parse().map_err(|_| format!("invalid irq '{}'", value))?),
+        _ => return Err(format!("unknown uart option '{}'", part)),
+      }
+    }
+    Ok(UartOptions { endpoint, address, irq })
+  }
+}
 
 #[derive(Debug)]
 pub(crate) struct Uart {
-  receiver: Receiver<u8>,
-  loop_sender: Sender<u8>,
-  sender: Sender<u8>,
-  lcr: u8,
+  start: u64,
+  interrupt_id: u32,
+  receiver: Receiver<Vec<u8>>,
+  sender: Sender<Vec<u8>>,
+  // from the host, waiting for room in the FIFO
+  input: VecDeque<u8>,
+  rx: VecDeque<u8>,
   dll: u8,
   dlm: u8,
   ier: u8,
-  iir: u8,
+  fcr: u8,
+  lcr: u8,
   mcr: u8,
   lsr: u8,
+  // modem lines and their changes since the last read
+  msr: u8,
   scr: u8,
-  fcr: u8,
+  // the transmitter became empty since IIR reported it or THR was written
+  thr_interrupt: bool,
+  timeout_interrupt: bool,
+  // mtime of the character timeout, from the first step after a byte was received or read
+  timeout_at: Option<u64>,
 }
 
 impl Uart {
-  pub(crate) fn new() -> (Uart, Sender<u8>, Receiver<u8>) {
-    let (recv_send, recv) = channel();
+  pub(crate) fn new(start: u64, interrupt_id: u32, notifier: Notifier) -> (Uart, Sender<Vec<u8>>, Receiver<Vec<u8>>) {
+    let (recv_send, recv) = notified_channel(notifier);
     let (send, send_recv) = channel();
-    (Uart {
+    let mut uart = Uart {
+      start,
+      interrupt_id,
       receiver: recv,
-      loop_sender: recv_send.clone(),
       sender: send,
-      lcr: 0,
-      dll: 0x0c,
+      input: VecDeque::new(),
+      rx: VecDeque::with_capacity(FIFO_SIZE),
+      dll: 0,
       dlm: 0,
       ier: 0,
-      iir: UART_IIR_NO_INT,
-      mcr: UART_MCR_OUT2,
-      #[allow(clippy::eq_op)]
-      lsr: UART_LSR_TEMT | UART_LSR_TEMT,
-      scr: 0,
       fcr: 0,
-    }, recv_send, send_recv)
+      lcr: 0,
+      mcr: 0,
+      lsr: 0,
+      msr: 0,
+      scr: 0,
+      thr_interrupt: false,
+      timeout_interrupt: false,
+      timeout_at: None,
+    };
+    uart.reset();
+    (uart, recv_send, send_recv)
   }
-}
 
-impl Device for Uart {
-  device_atomic!();
-  device_rw!();
+  // registers back to power-on state, input from the host keeps waiting in the channel
+  pub(crate) fn reset(&mut self) {
+    self.rx.clear();
+    self.dll = 0x0c;
+    self.dlm = 0;
+    self.ier = 0;
+    self.fcr = 0;
+    self.lcr = 0;
+    self.mcr = 0;
+    self.lsr = UART_LSR_TEMT | UART_LSR_THRE;
+    // the host is always there
+    self.msr = UART_MSR_DCD | UART_MSR_DSR | UART_MSR_CTS;
+    self.scr = 0;
+    self.thr_interrupt = false;
+    self.timeout_interrupt = false;
+    self.timeout_at = None;
+  }
 
-  fn step(&mut self, bus: &mut Bus, _hart: &mut Hart) {
-    // TODO: backoff counter?
-    if self.receiver.avaliable() {
-      self.lsr |= UART_LSR_DR;
+  pub(crate) fn contains(&self, address: u64) -> bool {
+    (self.start..self.start + UART_SIZE).contains(&address)
+  }
+
+  pub(crate) fn start(&self) -> u64 {
+    self.start
+  }
+
+  pub(crate) fn irq(&self) -> u32 {
+    self.interrupt_id
+  }
+
+  fn fifo_enabled(&self) -> bool {
+    self.fcr & UART_FCR_ENABLE_FIFO != 0
+  }
+
+  // 1, 4, 8 or 14 bytes
+  fn trigger_level(&self) -> usize {
+    match self.fcr >> 6 {
+      0 => 1,
+      1 => 4,
+      2 => 8,
+      _ => 14,
     }
+  }
+
+  // start, data, parity and stop bits
+  fn character_bits(&self) -> u64 {
+    let data = 5 + (self.lcr & 0b11) as u64;
+    let parity = (self.lcr >> 3 & 1) as u64;
+    let stop = if self.lcr & 0b100 != 0 { 2 } else { 1 };
+    1 + data + parity + stop
+  }
+
+  // mtime ticks of the character timeout at the programmed baud rate, 16 clocks a bit
+  fn timeout_ticks(&self) -> u64 {
+    let divisor = (self.dlm as u64) << 8 | self.dll as u64;
+    let clocks = TIMEOUT_CHARACTERS * self.character_bits() * divisor.max(1) * 16;
+    clocks * TIMEBASE_FREQUENCY / UART_CLOCK as u64
+  }
+
+  // mtime ticks from now until the character timeout, while one is coming
+  pub(crate) fn deadline(&self, now: u64) -> Option<u64> {
+    if self.timeout_interrupt { return None; }
+    self.timeout_at.map(|timeout| timeout.saturating_sub(now))
+  }
+
+  fn capacity(&self) -> usize {
+    if self.fifo_enabled() { FIFO_SIZE } else { 1 }
+  }
 
-    if self.lcr & UART_FCR_CLEAR_RCVR != 0 {
-      self.receiver.clear();
-      self.lsr &= !UART_LSR_DR & !UART_FCR_CLEAR_RCVR;
+  fn interrupt_id(&self) -> u8 {
+    if self.ier & UART_IER_RLSI != 0 && self.lsr & UART_LSR_ERRORS != 0 {
+      UART_IIR_RLSI
+    } else if self.ier & UART_IER_RDI != 0 && self.timeout_interrupt {
+      UART_IIR_CTI
+    } else if self.ier & UART_IER_RDI != 0 && self.lsr & UART_LSR_DR != 0 && (!self.fifo_enabled() || self.rx.len() >= self.trigger_level()) {
+      UART_IIR_RDI
+    } else if self.ier & UART_IER_THRI != 0 && self.thr_interrupt {
+      UART_IIR_THRI
+    } else if self.ier & UART_IER_MSI != 0 && self.msr & UART_MSR_DELTAS != 0 {
+      UART_IIR_MSI
+    } else {
+      UART_IIR_NO_INT
     }
+  }
+
+  fn iir(&self) -> u8 {
+    self.interrupt_id() | if self.fifo_enabled() { UART_IIR_FIFO_ENABLED } else { 0 }
+  }
 
-    if self.lcr & UART_FCR_CLEAR_XMIT != 0 {
-      self.lcr &= !UART_FCR_CLEAR_XMIT;
-      self.lsr |= UART_LSR_TEMT | UART_LSR_THRE;
+  pub(crate) fn interrupting(&self) -> bool {
+    self.interrupt_id() != UART_IIR_NO_INT
+  }
+
+  // a byte from the line, which is the host or the transmitter in loopback
+  fn receive(&mut self, data: u8) {
+    if self.rx.len() >= self.capacity() {
+      self.lsr |= UART_LSR_OE;
+      // a full FIFO keeps its bytes, without one the new byte replaces the held one
+      if self.fifo_enabled() { return; }
+      self.rx.clear();
+      self.lsr &= !UART_LSR_BI;
     }
+    self.rx.push_back(data);
+    self.lsr |= UART_LSR_DR;
+    self.timeout_at = None;
+  }
 
-    let mut interrupts: u8 = 0;
-    if (self.ier & UART_IER_RDI) != 0 && (self.lsr & UART_LSR_DR) != 0 {
-      interrupts |= UART_IIR_RDI;
+  // a break from the host, which loopback disconnects like data
+  pub(crate) fn receive_break(&mut self) {
+    if self.mcr & UART_MCR_LOOP != 0 { return; }
+    self.break_received();
+  }
+
+  // a break is received as a NUL with BI set
+  fn break_received(&mut self) {
+    self.receive(0);
+    self.lsr |= UART_LSR_BI;
+  }
+
+  fn transmit(&mut self, data: u8) {
+    if self.mcr & UART_MCR_LOOP != 0 {
+      self.receive(data);
+    } else {
+      self.sender.send(vec![data]);
     }
+    self.lsr |= UART_LSR_THRE | UART_LSR_TEMT;
+    self.thr_interrupt = true;
+  }
 
-    if (self.ier & UART_IER_THRI) != 0 && (self.lsr & UART_LSR_TEMT) != 0 {
-      interrupts |= UART_IIR_THRI;
+  // the inputs are wired to the outputs in loopback
+  fn modem_lines(&self) -> u8 {
+    if self.mcr & UART_MCR_LOOP == 0 { return UART_MSR_DCD | UART_MSR_DSR | UART_MSR_CTS; }
+    let mut lines = 0;
+    if self.mcr & UART_MCR_DTR != 0 { lines |= UART_MSR_DSR; }
+    if self.mcr & UART_MCR_RTS != 0 { lines |= UART_MSR_CTS; }
+    if self.mcr & UART_MCR_OUT1 != 0 { lines |= UART_MSR_RI; }
+    if self.mcr & UART_MCR_OUT2 != 0 { lines |= UART_MSR_DCD; }
+    lines
+  }
+
+  fn update_modem_lines(&mut self) {
+    let (old, new) = (self.msr, self.modem_lines());
+    let changed = old ^ new;
+    let mut deltas = self.msr & UART_MSR_DELTAS;
+    if changed & UART_MSR_CTS != 0 { deltas |= UART_MSR_DCTS; }
+    if changed & UART_MSR_DSR != 0 { deltas |= UART_MSR_DDSR; }
+    if changed & UART_MSR_DCD != 0 { deltas |= UART_MSR_DDCD; }
+    // trailing edge of ring indicator
+    if old & UART_MSR_RI != 0 && new & UART_MSR_RI == 0 { deltas |= UART_MSR_TERI; }
+    self.msr = new | deltas;
+  }
+
+  fn pop_rx(&mut self) -> Option<u8> {
+    let data = self.rx.pop_front();
+    if self.rx.is_empty() {
+      self.lsr &= !(UART_LSR_DR | UART_LSR_BI);
     }
+    self.timeout_interrupt = false;
+    self.timeout_at = None;
+    data
+  }
 
-    if interrupts != 0 {
-      self.iir = UART_IIR_NO_INT;
-      bus.plic.lock().unwrap().irq(INTERRUPT_ID, false);
-    } else {
-      self.iir = interrupts;
-      bus.plic.lock().unwrap().irq(INTERRUPT_ID, true);
+  // the SBI console, which goes around the registers like firmware polling LSR would
+  pub(crate) fn console_write(&mut self, data: &[u8]) {
+    self.sender.send(data.to_vec());
+  }
+
+  pub(crate) fn console_read(&mut self) -> Option<u8> {
+    if !self.rx.is_empty() {
+      return self.pop_rx();
+    }
+    if self.input.is_empty() {
+      if let Some(data) = self.receiver.try_recv() {
+        self.input.extend(data);
+      }
     }
+    self.input.pop_front()
+  }
 
-    if self.ier & UART_IER_THRI == 0 {
-      self.lsr |= UART_LSR_TEMT | UART_LSR_THRE;
+  fn clear_rx(&mut self) {
+    self.rx.clear();
+    self.lsr &= !(UART_LSR_DR | UART_LSR_BI);
+    self.timeout_interrupt = false;
+    self.timeout_at = None;
+  }
+}
+
+impl Device for Uart {
+  device_atomic!();
+  device_rw!();
+
+  fn step(&mut self, bus: &mut Bus, _hart: &mut Hart) {
+    if self.mcr & UART_MCR_LOOP == 0 {
+      while self.rx.len() < self.capacity() {
+        if let Some(data) = self.input.pop_front() {
+          self.receive(data);
+          continue;
+        }
+        let Some(data) = self.receiver.try_recv() else { break };
+        self.input.extend(data);
+      }
+    }
+    if self.fifo_enabled() && !self.rx.is_empty() && !self.timeout_interrupt {
+      let now = bus.aclint.lock().unwrap().mtime();
+      match self.timeout_at {
+        Some(timeout) if now >= timeout => self.timeout_interrupt = true,
+        Some(_) => {},
+        None => self.timeout_at = Some(now.saturating_add(self.timeout_ticks())),
+      }
     }
+    bus.plic.lock().unwrap().irq(self.interrupt_id, self.interrupting());
   }
 
   fn read8(&mut self, address: u64) -> Result<u8, Exception> {
-    match address {
-      UART_RBR_DLL => {
-        let res = if self.lcr & UART_LCR_DLAB != 0 {
-          self.dll
-        } else if self.lsr & UART_LSR_BI != 0 {
-          0
-        } else if self.receiver.avaliable() {
-          self.lsr &= !UART_LSR_OE;
-          self.receiver.recv()
-        } else {
-          0
-        };
-        Ok(res)
+    let dlab = self.lcr & UART_LCR_DLAB != 0;
+    Ok(match address - self.start {
+      UART_DLL if dlab => self.dll,
+      UART_RBR => self.pop_rx().unwrap_or(0),
+      UART_DLM if dlab => self.dlm,
+      UART_IER => self.ier,
+      UART_IIR => {
+        let iir = self.iir();
+        // reading IIR acknowledges the transmitter interrupt
+        if iir & 0x0f == UART_IIR_THRI {
+          self.thr_interrupt = false;
+        }
+        iir
       },
-      UART_IER_ILM => if self.lcr & UART_LCR_DLAB != 0 {
-        Ok(self.dlm)
-      } else {
-        Ok(self.ier)
+      UART_LCR => self.lcr,
+      UART_MCR => self.mcr,
+      UART_LSR => {
+        let lsr = self.lsr;
+        self.lsr &= !(UART_LSR_OE | UART_LSR_BI);
+        lsr
       },
-      UART_IIR => Ok(self.iir),
-      UART_LCR => Ok(self.lcr),
-      UART_MCR => Ok(self.mcr),
-      UART_LSR => Ok(self.lsr),
-      UART_SCR => Ok(self.scr),
-      _ => Err(Exception::LoadAccessFault(address)),
-    }
+      UART_MSR => {
+        let msr = self.msr;
+        self.msr &= !UART_MSR_DELTAS;
+        msr
+      },
+      UART_SCR => self.scr,
+      _ => return Err(Exception::LoadAccessFault(address)),
+    })
   }
 
   fn write8(&mut self, address: u64, data: u8) -> Result<(), Exception> {
-    match address {
+    let dlab = self.lcr & UART_LCR_DLAB != 0;
+    match address - self.start {
+      UART_DLL if dlab => self.dll = data,
       UART_THR => {
-        if self.lcr & UART_LCR_DLAB != 0 {
-          self.dll = data;
-        } else if self.fcr & UART_FCR_ENABLE_FIFO == 0 && self.receiver.avaliable() {
-          self.lsr |= UART_LSR_OE;
-        } else {
-          self.lsr |= UART_LSR_TEMT | UART_LSR_THRE;
-          if self.mcr & UART_MCR_LOOP != 0 {
-            self.loop_sender.send(data);
-          } else {
-            self.sender.send(data);
-          }
-        }
+        self.thr_interrupt = false;
+        self.transmit(data);
       },
-      UART_IER_ILM => if self.lcr & UART_LCR_DLAB != 0 {
-        self.dlm = data;
-      } else {
+      UART_DLM if dlab => self.dlm = data,
+      UART_IER => {
+        let enabled = !self.ier & data;
         self.ier = data & 0b1111;
+        // enabling the interrupt with an empty transmitter raises it
+        if enabled & UART_IER_THRI != 0 && self.lsr & UART_LSR_THRE != 0 {
+          self.thr_interrupt = true;
+        }
+      },
+      UART_FCR => {
+        // switching the FIFOs on or off empties them
+        if (self.fcr ^ data) & UART_FCR_ENABLE_FIFO != 0 || data & UART_FCR_CLEAR_RCVR != 0 {
+          self.clear_rx();
+        }
+        if data & UART_FCR_CLEAR_XMIT != 0 {
+          self.lsr |= UART_LSR_THRE | UART_LSR_TEMT;
+          self.thr_interrupt = true;
+        }
+        self.fcr = data & (UART_FCR_ENABLE_FIFO | UART_FCR_DMA_SELECT | UART_FCR_TRIGGER);
+      },
+      UART_LCR => {
+        // a break sent in loopback comes back as a received break
+        if data & UART_LCR_SBC != 0 && self.lcr & UART_LCR_SBC == 0 && self.mcr & UART_MCR_LOOP != 0 {
+          self.break_received();
+        }
+        self.lcr = data;
+      },
+      UART_MCR => {
+        self.mcr = data & 0b11111;
+        self.update_modem_lines();
       },
-      UART_FCR => self.fcr = data,
-      UART_LCR => self.lcr = data,
-      UART_MCR => self.mcr = data & 0b11111,
+      // LSR and MSR are read only
+      UART_LSR | UART_MSR => {},
       UART_SCR => self.scr = data,
       _ => return Err(Exception::StoreAMOAccessFault(address)),
     };
     Ok(())
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use crate::{devices::{Device, bus::Bus}, hart::Hart};
+
+  use super::*;
+
+  fn read(bus: &mut Bus, register: u64) -> u8 {
+    bus.read8(UART_START + register).unwrap()
+  }
+
+  fn write(bus: &mut Bus, register: u64, data: u8) {
+    bus.write8(UART_START + register, data).unwrap()
+  }
+
+  fn step(bus: &mut Bus) {
+    let mut other = bus.clone();
+    bus.uarts[0].lock().unwrap().step(&mut other, &mut Hart::new());
+  }
+
+  #[test]
+  fn reset_state() {
+    let (mut bus, _controller) = Bus::new();
+    assert_eq!(read(&mut bus, UART_IER), 0);
+    assert_eq!(read(&mut bus, UART_IIR), UART_IIR_NO_INT);
+    assert_eq!(read(&mut bus, UART_LCR), 0);
+    assert_eq!(read(&mut bus, UART_MCR), 0);
+    assert_eq!(read(&mut bus, UART_LSR), UART_LSR_THRE | UART_LSR_TEMT);
+    assert_eq!(read(&mut bus, UART_MSR), UART_MSR_DCD | UART_MSR_DSR | UART_MSR_CTS);
+  }
+
+  #[test]
+  fn divisor_and_scratch() {
+    let (mut bus, _controller) = Bus::new();
+    write(&mut bus, UART_IER, 0b0101);
+    write(&mut bus, UART_LCR, UART_LCR_DLAB | 0x03);
+    write(&mut bus, UART_DLL, 0x01);
+    write(&mut bus, UART_DLM, 0x02);
+    assert_eq!((read(&mut bus, UART_DLL), read(&mut bus, UART_DLM)), (0x01, 0x02));
+    write(&mut bus, UART_LCR, 0x03);
+    // the divisor latch doesn't touch IER
+    assert_eq!(read(&mut bus, UART_IER), 0b0101);
+    write(&mut bus, UART_IER, 0xff);
+    assert_eq!(read(&mut bus, UART_IER), 0x0f);
+    write(&mut bus, UART_SCR, 0xa5);
+    assert_eq!(read(&mut bus, UART_SCR), 0xa5);
+  }
+
+  #[test]
+  fn transmit_interrupt() {
+    let (mut bus, controller) = Bus::new();
+    write(&mut bus, UART_THR, b'a');
+    assert_eq!(controller.uart_receiver.try_recv(), Some(vec![b'a']));
+    // nothing without IER
+    assert_eq!(read(&mut bus, UART_IIR), UART_IIR_NO_INT);
+    write(&mut bus, UART_IER, UART_IER_THRI);
+    assert_eq!(read(&mut bus, UART_IIR), UART_IIR_THRI);
+    // acknowledged by the read
+    assert_eq!(read(&mut bus, UART_IIR), UART_IIR_NO_INT);
+    write(&mut bus, UART_THR, b'b');
+    assert!(bus.uarts[0].lock().unwrap().interrupting());
+    assert_eq!(read(&mut bus, UART_LSR) & (UART_LSR_THRE | UART_LSR_TEMT), UART_LSR_THRE | UART_LSR_TEMT);
+  }
+
+  #[test]
+  fn receive_fifo() {
+    let (mut bus, controller) = Bus::new();
+    // FIFO with a trigger level of 4
+    write(&mut bus, UART_FCR, UART_FCR_ENABLE_FIFO | 0x40);
+    write(&mut bus, UART_IER, UART_IER_RDI);
+    assert_eq!(read(&mut bus, UART_IIR), UART_IIR_FIFO_ENABLED | UART_IIR_NO_INT);
+    controller.uart_sender.send(b"abc".to_vec());
+    step(&mut bus);
+    assert_eq!(read(&mut bus, UART_LSR) & UART_LSR_DR, UART_LSR_DR);
+    assert_eq!(read(&mut bus, UART_IIR), UART_IIR_FIFO_ENABLED | UART_IIR_NO_INT);
+    controller.uart_sender.send(vec![b'd']);
+    step(&mut bus);
+    assert_eq!(read(&mut bus, UART_IIR), UART_IIR_FIFO_ENABLED | UART_IIR_RDI);
+    // only 16 bytes fit, the rest waits on the host side
+    controller.uart_sender.send((0..20).collect());
+    step(&mut bus);
+    let received: Vec<u8> = (0..16).map(|_| read(&mut bus, UART_RBR)).collect();
+    assert_eq!(&received[..4], b"abcd");
+    assert_eq!(received[4..], (0..12).collect::<Vec<u8>>());
+    assert_eq!(read(&mut bus, UART_LSR) & (UART_LSR_DR | UART_LSR_OE), 0);
+    step(&mut bus);
+    assert_eq!(read(&mut bus, UART_RBR), 12);
+    // clearing the FIFO drops the rest
+    write(&mut bus, UART_FCR, UART_FCR_ENABLE_FIFO | UART_FCR_CLEAR_RCVR);
+    assert_eq!(read(&mut bus, UART_LSR) & UART_LSR_DR, 0);
+  }
+
+  #[test]
+  fn character_timeout() {
+    let (mut bus, controller) = Bus::new();
+    write(&mut bus, UART_FCR, UART_FCR_ENABLE_FIFO | 0xc0);
+    write(&mut bus, UART_IER, UART_IER_RDI);
+    write(&mut bus, UART_LCR, 0x03);
+    controller.uart_sender.send(vec![b'x']);
+    step(&mut bus);
+    // 4 characters of 8N1 at the reset divisor of 12, 192 clocks a bit
+    let ticks = 4 * 10 * 192 * TIMEBASE_FREQUENCY / UART_CLOCK as u64;
+    assert_eq!(bus.uarts[0].lock().unwrap().deadline(0), Some(ticks));
+    bus.aclint.lock().unwrap().advance(ticks - 1);
+    step(&mut bus);
+    assert_eq!(read(&mut bus, UART_IIR) & 0x0f, UART_IIR_NO_INT);
+    bus.aclint.lock().unwrap().advance(1);
+    step(&mut bus);
+    assert_eq!(read(&mut bus, UART_IIR) & 0x0f, UART_IIR_CTI);
+    assert_eq!(bus.uarts[0].lock().unwrap().deadline(ticks), None);
+    assert_eq!(read(&mut bus, UART_RBR), b'x');
+    assert_eq!(read(&mut bus, UART_IIR) & 0x0f, UART_IIR_NO_INT);
+  }
+
+  #[test]
+  fn loopback_and_modem_status() {
+    let (mut bus, controller) = Bus::new();
+    write(&mut bus, UART_MCR, UART_MCR_LOOP | UART_MCR_RTS | UART_MCR_OUT2);
+    // what the 8250 driver probes for
+    assert_eq!(read(&mut bus, UART_MSR) & 0xf0, UART_MSR_DCD | UART_MSR_CTS);
+    write(&mut bus, UART_THR, b'z');
+    assert_eq!(controller.uart_receiver.try_recv(), None);
+    assert_eq!(read(&mut bus, UART_RBR), b'z');
+    // a second byte without FIFO overruns and replaces the first one
+    write(&mut bus, UART_IER, UART_IER_RLSI | UART_IER_RDI | UART_IER_MSI);
+    write(&mut bus, UART_THR, 1);
+    write(&mut bus, UART_THR, 2);
+    assert_eq!(read(&mut bus, UART_IIR), UART_IIR_RLSI);
+    assert_eq!(read(&mut bus, UART_LSR) & UART_LSR_OE, UART_LSR_OE);
+    assert_eq!(read(&mut bus, UART_IIR), UART_IIR_RDI);
+    assert_eq!(read(&mut bus, UART_RBR), 2);
+    // DTR, then OUT1 raised and dropped
+    write(&mut bus, UART_MCR, UART_MCR_LOOP | UART_MCR_DTR | UART_MCR_OUT1);
+    write(&mut bus, UART_MCR, UART_MCR_LOOP | UART_MCR_DTR);
+    assert_eq!(read(&mut bus, UART_IIR), UART_IIR_MSI);
+    assert_eq!(read(&mut bus, UART_MSR), UART_MSR_DSR | UART_MSR_DDSR | UART_MSR_DCTS | UART_MSR_DDCD | UART_MSR_TERI);
+    assert_eq!(read(&mut bus, UART_MSR), UART_MSR_DSR);
+    assert_eq!(read(&mut bus, UART_IIR), UART_IIR_NO_INT);
+    // break
+    write(&mut bus, UART_LCR, UART_LCR_SBC);
+    assert_eq!(read(&mut bus, UART_LSR) & (UART_LSR_BI | UART_LSR_DR), UART_LSR_BI | UART_LSR_DR);
+    assert_eq!(read(&mut bus, UART_RBR), 0);
+  }
+
+  #[test]
+  fn second_uart() {
+    let (mut bus, controller) = Bus::new();
+    let start = UART_START + UART_STRIDE;
+    assert!(bus.attach_uart(UART_START + 4, 2).is_err());
+    assert!(bus.attach_uart(u64::MAX - 4, 2).is_err());
+    assert!(bus.attach_uart(start, UART_INTERRUPT_ID).is_err());
+    let (sender, receiver) = bus.attach_uart(start, 2).unwrap();
+    bus.write8(start + UART_THR, b'a').unwrap();
+    assert_eq!(receiver.try_recv(), Some(vec![b'a']));
+    assert_eq!(controller.uart_receiver.try_recv(), None);
+    bus.write8(start + UART_IER, UART_IER_RDI).unwrap();
+    sender.send(vec![b'b']);
+    let mut other = bus.clone();
+    bus.uarts[1].lock().unwrap().step(&mut other, &mut Hart::new());
+    assert_eq!(bus.read32(crate::devices::plic::PLIC_PENDING_START).unwrap(), 1 << 2);
+    assert_eq!(bus.read8(start + UART_RBR).unwrap(), b'b');
+    assert_eq!(read(&mut bus, UART_LSR) & UART_LSR_DR, 0);
+    assert_eq!("tcp=4445,address=0x10000200,irq=3".parse(),
+      Ok(UartOptions { endpoint: Endpoint::Tcp(4445), address: Some(0x10000200), irq: Some(3) }));
+  }
+}
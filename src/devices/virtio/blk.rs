@@ -0,0 +1,272 @@
+use std::{collections::HashMap, fs::{File, OpenOptions}, io, os::unix::fs::FileExt, path::Path, str::FromStr};
+
+use crate::devices::bus::Bus;
+
+use super::{VirtioDevice, queue::{Queue, Chain, QUEUE_SIZE_MAX}};
+
+const DEVICE_ID: u32 = 2;
+const SECTOR_SIZE: u64 = 512;
+// bytes in a descriptor and descriptors in a request, past the header and status. Data is copied in chunks of
+// SIZE_MAX, so a request never takes more host memory than that
+const SIZE_MAX: u32 = 1024 * 1024;
+const SEG_MAX: u32 = QUEUE_SIZE_MAX as u32 - 2;
+
+const VIRTIO_BLK_F_SIZE_MAX: u64 = 1 << 1;
+const VIRTIO_BLK_F_SEG_MAX: u64 = 1 << 2;
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+const ID: &[u8; 20] = b"yuri-virtio-blk\0\0\0\0\0";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ImageMode {
+  ReadWrite,
+  ReadOnly,
+  // writes are kept in memory and dropped on exit
+  CopyOnWrite,
+}
+
+impl FromStr for ImageMode {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "rw" => Ok(ImageMode::ReadWrite),
+      "ro" => Ok(ImageMode::ReadOnly),
+      "cow" => Ok(ImageMode::CopyOnWrite),
+      _ => Err(format!("unknown image mode '{}', expected rw, ro or cow", s)),
+    }
+  }
+}
+
+// raw disk image
+#[derive(Debug)]
+struct Image {
+  file: File,
+  mode: ImageMode,
+  sectors: u64,
+  // sector -> data written in copy on write mode
+  overlay: HashMap<u64, Box<[u8]>>,
+}
+
+impl Image {
+  fn open(path: &Path, mode: ImageMode) -> io::Result<Image> {
+    let file = OpenOptions::new()
+      .read(true)
+      .write(mode == ImageMode::ReadWrite)
+      .open(path)?;
+    let sectors = file.metadata()?.len() / SECTOR_SIZE;
+    Ok(Image { file, mode, sectors, overlay: HashMap::new() })
+  }
+
+  fn check(&self, sector: u64, len: usize) -> io::Result<()> {
+    let count = len as u64 / SECTOR_SIZE;
+    if !(len as u64).is_multiple_of(SECTOR_SIZE) || sector.checked_add(count).is_none_or(|end| end > self.sectors) {
+      return Err(io::Error::new(io::ErrorKind::InvalidInput, "out of range"));
+    }
+    Ok(())
+  }
+
+  fn read(&self, sector: u64, buf: &mut [u8]) -> io::Result<()> {
+    self.check(sector, buf.len())?;
+    if self.overlay.is_empty() {
+      return self.file.read_exact_at(buf, sector * SECTOR_SIZE);
+    }
+    for (index, chunk) in buf.chunks_mut(SECTOR_SIZE as usize).enumerate() {
+      let sector = sector + index as u64;
+      match self.overlay.get(&sector) {
+        Some(data) => chunk.copy_from_slice(data),
+        None => self.file.read_exact_at(chunk, sector * SECTOR_SIZE)?,
+      }
+    }
+    Ok(())
+  }
+
+  fn write(&mut self, sector: u64, data: &[u8]) -> io::Result<()> {
+    self.check(sector, data.len())?;
+    match self.mode {
+      ImageMode::ReadWrite => self.file.write_all_at(data, sector * SECTOR_SIZE),
+      ImageMode::ReadOnly => Err(io::Error::new(io::ErrorKind::PermissionDenied, "read only")),
+      ImageMode::CopyOnWrite => {
+        for (index, chunk) in data.chunks(SECTOR_SIZE as usize).enumerate() {
+          self.overlay.insert(sector + index as u64, chunk.into());
+        }
+        Ok(())
+      },
+    }
+  }
+
+  fn flush(&self) -> io::Result<()> {
+    match self.mode {
+      ImageMode::ReadWrite => self.file.sync_data(),
+      _ => Ok(()),
+    }
+  }
+}
+
+// run copy(offset, chunk) over len bytes in chunks of at most SIZE_MAX, false if it fails or len is more than a
+// request can have
+fn chunks(len: usize, mut copy: impl FnMut(usize, &mut [u8]) -> bool) -> bool {
+  if len > SEG_MAX as usize * SIZE_MAX as usize { return false; }
+  let mut buf = vec![0; len.min(SIZE_MAX as usize)];
+  (0..len).step_by(SIZE_MAX as usize).all(|offset| copy(offset, &mut buf[..(len - offset).min(SIZE_MAX as usize)]))
+}
+
+#[derive(Debug)]
+pub(crate) struct VirtioBlk {
+  image: Image,
+}
+
+impl VirtioBlk {
+  pub(crate) fn open(path: &Path, mode: ImageMode) -> io::Result<VirtioBlk> {
+    Ok(VirtioBlk { image: Image::open(path, mode)? })
+  }
+
+  // returns the number of bytes written to the chain
+  fn request(&mut self, chain: &Chain, bus: &mut Bus) -> u32 {
+    let writable = chain.writable();
+    let mut header = [0; 16];
+    if writable == 0 || chain.read_at(bus, 0, &mut header).is_err() { return 0; }
+    let kind = u32::from_le_bytes(header[0..4].try_into().unwrap());
+    let sector = u64::from_le_bytes(header[8..16].try_into().unwrap());
+    let image = &mut self.image;
+    // the last writable byte is the status
+    let (status, written) = match kind {
+      VIRTIO_BLK_T_IN => {
+        let len = writable - 1;
+        let done = image.check(sector, len).is_ok() && chunks(len, |offset, chunk|
+          image.read(sector + offset as u64 / SECTOR_SIZE, chunk).is_ok() && chain.write_at(bus, offset, chunk).is_ok());
+        if done { (VIRTIO_BLK_S_OK, len) } else { (VIRTIO_BLK_S_IOERR, 0) }
+      },
+      VIRTIO_BLK_T_OUT => {
+        let len = chain.readable() - header.len();
+        let done = image.check(sector, len).is_ok() && chunks(len, |offset, chunk|
+          chain.read_at(bus, header.len() + offset, chunk).is_ok() && image.write(sector + offset as u64 / SECTOR_SIZE, chunk).is_ok());
+        if done { (VIRTIO_BLK_S_OK, 0) } else { (VIRTIO_BLK_S_IOERR, 0) }
+      },
+      VIRTIO_BLK_T_FLUSH => match image.flush() {
+        Ok(()) => (VIRTIO_BLK_S_OK, 0),
+        Err(_) => (VIRTIO_BLK_S_IOERR, 0),
+      },
+      VIRTIO_BLK_T_GET_ID => {
+        let len = ID.len().min(writable - 1);
+        match chain.write_at(bus, 0, &ID[..len]) {
+          Ok(()) => (VIRTIO_BLK_S_OK, len),
+          Err(_) => (VIRTIO_BLK_S_IOERR, 0),
+        }
+      },
+      _ => (VIRTIO_BLK_S_UNSUPP, 0),
+    };
+    let _ = chain.write_at(bus, writable - 1, &[status]);
+    written as u32 + 1
+  }
+}
+
+impl VirtioDevice for VirtioBlk {
+  fn device_id(&self) -> u32 {
+    DEVICE_ID
+  }
+
+  fn features(&self) -> u64 {
+    let features = VIRTIO_BLK_F_SIZE_MAX | VIRTIO_BLK_F_SEG_MAX | VIRTIO_BLK_F_FLUSH;
+    match self.image.mode {
+      ImageMode::ReadOnly => features | VIRTIO_BLK_F_RO,
+      _ => features,
+    }
+  }
+
+  fn queue_count(&self) -> usize {
+    1
+  }
+
+  fn read_config(&self, offset: u64) -> u8 {
+    match offset {
+      // capacity in sectors
+      0..=7 => self.image.sectors.to_le_bytes()[offset as usize],
+      8..=11 => SIZE_MAX.to_le_bytes()[offset as usize - 8],
+      12..=15 => SEG_MAX.to_le_bytes()[offset as usize - 12],
+      _ => 0,
+    }
+  }
+
+  fn notify(&mut self, queue: usize, queues: &mut [Queue], bus: &mut Bus) {
+    while let Some(chain) = queues[queue].pop(bus) {
+      let len = self.request(&chain, bus);
+      queues[queue].push(bus, chain, len);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::fs;
+
+  use crate::devices::{Device, memory::MEMORY_START, rom::RESET_ADDRESS};
+
+  use super::*;
+
+  const DESC: u64 = MEMORY_START;
+  const DRIVER: u64 = MEMORY_START + 0x1000;
+  const DEVICE: u64 = MEMORY_START + 0x2000;
+  const HEADER: u64 = MEMORY_START + 0x3000;
+  const DATA: u64 = MEMORY_START + 0x4000;
+  const STATUS: u64 = MEMORY_START + 0x5000;
+
+  // header, data and status in the next available slot, returns the status
+  fn request(blk: &mut VirtioBlk, queues: &mut [Queue], bus: &mut Bus, kind: u32, sector: u64, data: u64, len: u32) -> u8 {
+    bus.write32(HEADER, kind).unwrap();
+    bus.write64(HEADER + 8, sector).unwrap();
+    let write = if kind == VIRTIO_BLK_T_IN { 0b10 } else { 0 };
+    for (index, (address, len, flags)) in [(HEADER, 16, 0b01), (data, len, 0b01 | write), (STATUS, 1, 0b10)].into_iter().enumerate() {
+      let desc = DESC + 16 * index as u64;
+      bus.write64(desc, address).unwrap();
+      bus.write32(desc + 8, len).unwrap();
+      bus.write16(desc + 12, flags).unwrap();
+      bus.write16(desc + 14, index as u16 + 1).unwrap();
+    }
+    let avail = bus.read16(DRIVER + 2).unwrap();
+    bus.write16(DRIVER + 4 + 2 * (avail % 8) as u64, 0).unwrap();
+    bus.write16(DRIVER + 2, avail + 1).unwrap();
+    blk.notify(0, queues, bus);
+    assert_eq!(bus.read16(DEVICE + 2).unwrap(), avail + 1);
+    bus.read8(STATUS).unwrap()
+  }
+
+  #[test]
+  fn read_write() {
+    let path = std::env::temp_dir().join(format!("yuri-blk-{}", std::process::id()));
+    // sparse, large enough for a request past the limit
+    fs::File::create(&path).unwrap().set_len(1 << 30).unwrap();
+    let mut blk = VirtioBlk::open(&path, ImageMode::ReadWrite).unwrap();
+    let (mut bus, _controller) = Bus::new();
+    let mut queues = [Queue::new()];
+    (queues[0].size, queues[0].ready, queues[0].desc, queues[0].driver, queues[0].device) = (8, true, DESC, DRIVER, DEVICE);
+    let data: Vec<u8> = (0..1024).map(|index| index as u8).collect();
+    bus.write_bytes(DATA, &data).unwrap();
+    assert_eq!(request(&mut blk, &mut queues, &mut bus, VIRTIO_BLK_T_OUT, 2, DATA, 1024), VIRTIO_BLK_S_OK);
+    let mut written = vec![0; 1024];
+    fs::File::open(&path).unwrap().read_exact_at(&mut written, 1024).unwrap();
+    assert_eq!(written, data);
+    bus.write_bytes(DATA, &[0; 1024]).unwrap();
+    assert_eq!(request(&mut blk, &mut queues, &mut bus, VIRTIO_BLK_T_IN, 2, DATA, 1024), VIRTIO_BLK_S_OK);
+    let mut read = vec![0; 1024];
+    bus.read_bytes(DATA, &mut read).unwrap();
+    assert_eq!(read, data);
+    // the data and the status were written
+    assert_eq!(bus.read32(DEVICE + 4 + 8 + 4).unwrap(), 1025);
+    // past the end of the disk, larger than a request can be, and outside memory
+    assert_eq!(request(&mut blk, &mut queues, &mut bus, VIRTIO_BLK_T_IN, (1 << 30) / SECTOR_SIZE - 1, DATA, 1024), VIRTIO_BLK_S_IOERR);
+    assert_eq!(request(&mut blk, &mut queues, &mut bus, VIRTIO_BLK_T_IN, 0, DATA, 1 << 29), VIRTIO_BLK_S_IOERR);
+    assert_eq!(request(&mut blk, &mut queues, &mut bus, VIRTIO_BLK_T_OUT, 0, RESET_ADDRESS, 512), VIRTIO_BLK_S_IOERR);
+    fs::remove_file(&path).unwrap();
+  }
+}
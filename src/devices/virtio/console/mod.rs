@@ -0,0 +1,249 @@
+use std::{collections::VecDeque, io, str::FromStr};
+
+use crate::{devices::{bus::Bus, endpoint::Endpoint}, utils::channel::{Receiver, Sender, Notifier, channel, notified_channel}};
+
+use super::{VirtioDevice, queue::{Queue, Chain}};
+
+const DEVICE_ID: u32 = 3;
+
+const VIRTIO_CONSOLE_F_MULTIPORT: u64 = 1 << 1;
+
+// struct virtio_console_control
+const CONTROL_SIZE: usize = 8;
+
+const VIRTIO_CONSOLE_DEVICE_READY: u16 = 0;
+const VIRTIO_CONSOLE_DEVICE_ADD: u16 = 1;
+const VIRTIO_CONSOLE_PORT_READY: u16 = 3;
+const VIRTIO_CONSOLE_CONSOLE_PORT: u16 = 4;
+const VIRTIO_CONSOLE_PORT_OPEN: u16 = 6;
+const VIRTIO_CONSOLE_PORT_NAME: u16 = 7;
+
+const CONTROL_RX_QUEUE: usize = 2;
+const CONTROL_TX_QUEUE: usize = 3;
+
+#[derive(Debug)]
+struct Port {
+  name: Option<String>,
+  sender: Sender<Vec<u8>>,
+  receiver: Receiver<Vec<u8>>,
+  // input which didn't fit the last receive buffer
+  pending: Vec<u8>,
+  // the guest has the port open
+  open: bool,
+}
+
+// Multiport console, port 0 is the console (hvc0) and the others are named serial ports.
+#[derive(Debug)]
+pub(crate) struct VirtioConsole {
+  notifier: Notifier,
+  ports: Vec<Port>,
+  // control messages for the driver
+  control: VecDeque<Vec<u8>>,
+}
+
+impl VirtioConsole {
+  pub(crate) fn new(notifier: Notifier) -> VirtioConsole {
+    VirtioConsole {
+      notifier,
+      ports: Vec::new(),
+      control: VecDeque::new(),
+    }
+  }
+
+  // returns the channels for the endpoint: output of the guest and input for the guest
+  pub(crate) fn add_port(&mut self, name: Option<String>) -> (Receiver<Vec<u8>>, Sender<Vec<u8>>) {
+    let (recv_send, recv) = notified_channel(self.notifier.clone());
+    let (send, send_recv) = channel();
+    self.ports.push(Port {
+      name,
+      sender: send,
+      receiver: recv,
+      pending: Vec::new(),
+      open: false,
+    });
+    (send_recv, recv_send)
+  }
+
+  // receive and transmit queues of a port
+  fn queues(port: usize) -> (usize, usize) {
+    match port {
+      0 => (0, 1),
+      _ => (2 + 2 * port, 3 + 2 * port),
+    }
+  }
+
+  fn send_control(&mut self, id: usize, event: u16, value: u16, data: &[u8]) {
+    let mut message = Vec::with_capacity(CONTROL_SIZE + data.len());
+    message.extend_from_slice(&(id as u32).to_le_bytes());
+    message.extend_from_slice(&event.to_le_bytes());
+    message.extend_from_slice(&value.to_le_bytes());
+    message.extend_from_slice(data);
+    self.control.push_back(message);
+  }
+
+  fn handle_control(&mut self, message: &[u8; CONTROL_SIZE]) {
+    let id = u32::from_le_bytes(message[0..4].try_into().unwrap()) as usize;
+    let event = u16::from_le_bytes([message[4], message[5]]);
+    let value = u16::from_le_bytes([message[6], message[7]]);
+    match event {
+      VIRTIO_CONSOLE_DEVICE_READY if value == 1 => for id in 0..self.ports.len() {
+        self.send_control(id, VIRTIO_CONSOLE_DEVICE_ADD, 0, &[]);
+      },
+      VIRTIO_CONSOLE_PORT_READY if value == 1 && id < self.ports.len() => {
+        if id == 0 {
+          self.send_control(id, VIRTIO_CONSOLE_CONSOLE_PORT, 1, &[]);
+        }
+        if let Some(name) = self.ports[id].name.clone() {
+          self.send_control(id, VIRTIO_CONSOLE_PORT_NAME, 1, name.as_bytes());
+        }
+        // the host side is always connected
+        self.send_control(id, VIRTIO_CONSOLE_PORT_OPEN, 1, &[]);
+      },
+      VIRTIO_CONSOLE_PORT_OPEN if id < self.ports.len() => self.ports[id].open = value == 1,
+      _ => {},
+    }
+  }
+
+  fn transmit(&mut self, port: usize, queue: &mut Queue, bus: &mut Bus) {
+    while let Some(chain) = queue.pop(bus) {
+      let mut data = vec![0; chain.readable()];
+      if chain.read_at(bus, 0, &mut data).is_ok() {
+        self.ports[port].sender.send(data);
+      }
+      queue.push(bus, chain, 0);
+    }
+  }
+
+  fn transmit_control(&mut self, queue: &mut Queue, bus: &mut Bus) {
+    while let Some(chain) = queue.pop(bus) {
+      let mut message = [0; CONTROL_SIZE];
+      if chain.read_at(bus, 0, &mut message).is_ok() {
+        self.handle_control(&message);
+      }
+      queue.push(bus, chain, 0);
+    }
+  }
+
+  fn receive(&mut self, port: usize, queue: &mut Queue, bus: &mut Bus) {
+    let port = &mut self.ports[port];
+    // input waits until the guest opens the port
+    if !port.open { return; }
+    loop {
+      if port.pending.is_empty() {
+        match port.receiver.try_recv() {
+          Some(data) => port.pending = data,
+          None => return,
+        }
+      }
+      let Some(chain) = queue.pop(bus) else { return };
+      let len = port.pending.len().min(chain.writable());
+      let written = match chain.write_at(bus, 0, &port.pending[..len]) {
+        Ok(()) => len,
+        Err(_) => 0,
+      };
+      port.pending.drain(..written);
+      queue.push(bus, chain, written as u32);
+    }
+  }
+
+  fn receive_control(&mut self, queue: &mut Queue, bus: &mut Bus) {
+    while !self.control.is_empty() {
+      let Some(chain) = queue.pop(bus) else { return };
+      let message = self.control.pop_front().unwrap();
+      let len = if write_all(&chain, bus, &message) { message.len() } else { 0 };
+      queue.push(bus, chain, len as u32);
+    }
+  }
+}
+
+fn write_all(chain: &Chain, bus: &mut Bus, data: &[u8]) -> bool {
+  chain.writable() >= data.len() && chain.write_at(bus, 0, data).is_ok()
+}
+
+impl VirtioDevice for VirtioConsole {
+  fn device_id(&self) -> u32 {
+    DEVICE_ID
+  }
+
+  fn features(&self) -> u64 {
+    VIRTIO_CONSOLE_F_MULTIPORT
+  }
+
+  fn queue_count(&self) -> usize {
+    2 * (self.ports.len() + 1)
+  }
+
+  fn read_config(&self, offset: u64) -> u8 {
+    match offset {
+      // max_nr_ports, after cols and rows
+      4..=7 => (self.ports.len() as u32).to_le_bytes()[offset as usize - 4],
+      _ => 0,
+    }
+  }
+
+  fn notify(&mut self, queue: usize, queues: &mut [Queue], bus: &mut Bus) {
+    match queue {
+      CONTROL_TX_QUEUE => self.transmit_control(&mut queues[queue], bus),
+      CONTROL_RX_QUEUE => self.receive_control(&mut queues[queue], bus),
+      _ => {
+        let port = match queue / 2 {
+          0 => 0,
+          pair => pair - 1,
+        };
+        if queue % 2 == 1 {
+          self.transmit(port, &mut queues[queue], bus);
+        } else {
+          self.receive(port, &mut queues[queue], bus);
+        }
+      },
+    }
+  }
+
+  fn poll(&mut self, queues: &mut [Queue], bus: &mut Bus) -> bool {
+    self.receive_control(&mut queues[CONTROL_RX_QUEUE], bus);
+    for port in 0..self.ports.len() {
+      let (receive, _) = VirtioConsole::queues(port);
+      self.receive(port, &mut queues[receive], bus);
+    }
+    false
+  }
+
+  fn reset(&mut self) {
+    self.control.clear();
+    for port in self.ports.iter_mut() {
+      port.pending.clear();
+      port.open = false;
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct PortOptions {
+  pub(crate) endpoint: Endpoint,
+  pub(crate) name: Option<String>,
+}
+
+// an endpoint, which can end with ",name=..."
+impl FromStr for PortOptions {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let mut parts = s.split(',');
+    let endpoint = parts.next().unwrap_or_default().parse()?;
+    let mut name = None;
+    for part in parts {
+      match part.split_once('=') {
+        Some(("name", value)) => name = Some(value.to_string()),
+        _ => return Err(format!("unknown console option '{}'", part)),
+      }
+    }
+    Ok(PortOptions { endpoint, name })
+  }
+}
+
+impl PortOptions {
+  // connect the port's channels to the endpoint, input of stdio comes from main
+  pub(crate) fn spawn(&self, output: Receiver<Vec<u8>>, input: Sender<Vec<u8>>) -> io::Result<()> {
+    self.endpoint.spawn("virtio-console port", output, input)
+  }
+}
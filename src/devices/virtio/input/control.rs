@@ -0,0 +1,35 @@
+use std::{io::{self, BufRead, BufReader, Write}, os::unix::net::UnixStream, path::Path, thread};
+
+use crate::utils::bind_socket;
+
+use super::script::{Input, Inputs};
+
+// A unix socket taking one action per line, as in an input script but without the time.
+// Every line is answered with "ok" or "error: ...", once its events are queued.
+
+pub(crate) fn spawn(path: &Path, inputs: Inputs) -> io::Result<()> {
+  let listener = bind_socket(path)?;
+  thread::spawn(move || for stream in listener.incoming() {
+    let Ok(stream) = stream else { continue };
+    let inputs = inputs.clone();
+    thread::spawn(move || serve(stream, &inputs));
+  });
+  Ok(())
+}
+
+// until the client goes away
+fn serve(stream: UnixStream, inputs: &Inputs) {
+  let Ok(mut writer) = stream.try_clone() else { return };
+  for line in BufReader::new(stream).lines() {
+    let Ok(line) = line else { return };
+    if line.trim().is_empty() { continue; }
+    let reply = match Input::parse(&line) {
+      Ok(input) => {
+        inputs.send(input);
+        "ok\n".to_string()
+      },
+      Err(err) => format!("error: {}\n", err),
+    };
+    if writer.write_all(reply.as_bytes()).is_err() { return; }
+  }
+}
@@ -0,0 +1,196 @@
+use std::collections::VecDeque;
+
+use crate::{devices::{bus::Bus, framebuffer::{FRAMEBUFFER_WIDTH, FRAMEBUFFER_HEIGHT}}, utils::channel::{Receiver, Sender, Notifier, notified_channel}};
+
+use super::{VirtioDevice, queue::Queue};
+
+pub(crate) mod script;
+pub(crate) mod control;
+
+const DEVICE_ID: u32 = 18;
+
+const EVENT_QUEUE: usize = 0;
+const STATUS_QUEUE: usize = 1;
+
+// struct virtio_input_config
+const CONFIG_SELECT: u64 = 0;
+const CONFIG_SUBSEL: u64 = 1;
+const CONFIG_SIZE: u64 = 2;
+const CONFIG_DATA: u64 = 8;
+const CONFIG_DATA_SIZE: usize = 128;
+
+const VIRTIO_INPUT_CFG_ID_NAME: u8 = 0x01;
+const VIRTIO_INPUT_CFG_ID_DEVIDS: u8 = 0x03;
+const VIRTIO_INPUT_CFG_EV_BITS: u8 = 0x11;
+const VIRTIO_INPUT_CFG_ABS_INFO: u8 = 0x12;
+
+const BUS_VIRTUAL: u16 = 0x06;
+
+// struct virtio_input_event
+const EVENT_SIZE: usize = 8;
+
+// linux event types and codes
+pub(crate) const EV_SYN: u16 = 0x00;
+pub(crate) const EV_KEY: u16 = 0x01;
+pub(crate) const EV_ABS: u16 = 0x03;
+const SYN_REPORT: u16 = 0;
+pub(crate) const ABS_X: u16 = 0x00;
+pub(crate) const ABS_Y: u16 = 0x01;
+pub(crate) const BTN_LEFT: u16 = 0x110;
+pub(crate) const BTN_RIGHT: u16 = 0x111;
+pub(crate) const BTN_MIDDLE: u16 = 0x112;
+// keys up to this code can be sent by the keyboard
+pub(crate) const KEY_LAST: u16 = 0xff;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum InputKind {
+  Keyboard,
+  // absolute pointer, in pixels of the framebuffer
+  Tablet,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Event {
+  pub(crate) kind: u16,
+  pub(crate) code: u16,
+  pub(crate) value: u32,
+}
+
+impl Event {
+  pub(crate) fn new(kind: u16, code: u16, value: u32) -> Event {
+    Event { kind, code, value }
+  }
+}
+
+// Events arrive in batches, which the device ends with a SYN_REPORT.
+#[derive(Debug)]
+pub(crate) struct VirtioInput {
+  kind: InputKind,
+  select: u8,
+  subsel: u8,
+  receiver: Receiver<Vec<Event>>,
+  pending: VecDeque<Event>,
+}
+
+impl VirtioInput {
+  // returns the sender for the batches
+  pub(crate) fn new(kind: InputKind, notifier: Notifier) -> (VirtioInput, Sender<Vec<Event>>) {
+    let (sender, receiver) = notified_channel(notifier);
+    (VirtioInput {
+      kind,
+      select: 0,
+      subsel: 0,
+      receiver,
+      pending: VecDeque::new(),
+    }, sender)
+  }
+
+  // the data for the selected configuration, empty if there is none
+  fn config(&self) -> Vec<u8> {
+    fn bits(codes: impl Iterator<Item = u16>) -> Vec<u8> {
+      let mut bitmap = Vec::new();
+      for code in codes {
+        let byte = code as usize / 8;
+        if bitmap.len() <= byte { bitmap.resize(byte + 1, 0); }
+        bitmap[byte] |= 1 << (code % 8);
+      }
+      bitmap
+    }
+    match (self.select, self.kind) {
+      (VIRTIO_INPUT_CFG_ID_NAME, InputKind::Keyboard) => b"yuri keyboard".to_vec(),
+      (VIRTIO_INPUT_CFG_ID_NAME, InputKind::Tablet) => b"yuri tablet".to_vec(),
+      (VIRTIO_INPUT_CFG_ID_DEVIDS, _) => [BUS_VIRTUAL, 0, self.kind as u16 + 1, 1]
+        .into_iter().flat_map(u16::to_le_bytes).collect(),
+      (VIRTIO_INPUT_CFG_EV_BITS, InputKind::Keyboard) => match self.subsel as u16 {
+        EV_KEY => bits(1..=KEY_LAST),
+        _ => Vec::new(),
+      },
+      (VIRTIO_INPUT_CFG_EV_BITS, InputKind::Tablet) => match self.subsel as u16 {
+        EV_KEY => bits([BTN_LEFT, BTN_RIGHT, BTN_MIDDLE].into_iter()),
+        EV_ABS => bits([ABS_X, ABS_Y].into_iter()),
+        _ => Vec::new(),
+      },
+      (VIRTIO_INPUT_CFG_ABS_INFO, InputKind::Tablet) => {
+        let max = match self.subsel as u16 {
+          ABS_X => FRAMEBUFFER_WIDTH - 1,
+          ABS_Y => FRAMEBUFFER_HEIGHT - 1,
+          _ => return Vec::new(),
+        };
+        // min, max, fuzz, flat, res
+        [0, max as u32, 0, 0, 0].into_iter().flat_map(u32::to_le_bytes).collect()
+      },
+      _ => Vec::new(),
+    }
+  }
+
+  fn receive(&mut self, queue: &mut Queue, bus: &mut Bus) {
+    while let Some(batch) = self.receiver.try_recv() {
+      self.pending.extend(batch);
+      self.pending.push_back(Event::new(EV_SYN, SYN_REPORT, 0));
+    }
+    while !self.pending.is_empty() {
+      let Some(chain) = queue.pop(bus) else { return };
+      let event = self.pending.pop_front().unwrap();
+      let mut data = [0; EVENT_SIZE];
+      data[0..2].copy_from_slice(&event.kind.to_le_bytes());
+      data[2..4].copy_from_slice(&event.code.to_le_bytes());
+      data[4..8].copy_from_slice(&event.value.to_le_bytes());
+      let written = chain.writable() >= EVENT_SIZE && chain.write_at(bus, 0, &data).is_ok();
+      queue.push(bus, chain, if written { EVENT_SIZE as u32 } else { 0 });
+    }
+  }
+}
+
+impl VirtioDevice for VirtioInput {
+  fn device_id(&self) -> u32 {
+    DEVICE_ID
+  }
+
+  fn features(&self) -> u64 {
+    0
+  }
+
+  fn queue_count(&self) -> usize {
+    2
+  }
+
+  fn read_config(&self, offset: u64) -> u8 {
+    match offset {
+      CONFIG_SELECT => self.select,
+      CONFIG_SUBSEL => self.subsel,
+      CONFIG_SIZE => self.config().len().min(CONFIG_DATA_SIZE) as u8,
+      CONFIG_DATA.. => self.config().get((offset - CONFIG_DATA) as usize).copied().unwrap_or(0),
+      _ => 0,
+    }
+  }
+
+  fn write_config(&mut self, offset: u64, data: u8) {
+    match offset {
+      CONFIG_SELECT => self.select = data,
+      CONFIG_SUBSEL => self.subsel = data,
+      _ => {},
+    }
+  }
+
+  fn notify(&mut self, queue: usize, queues: &mut [Queue], bus: &mut Bus) {
+    match queue {
+      EVENT_QUEUE => self.receive(&mut queues[EVENT_QUEUE], bus),
+      // leds of the keyboard, ignored
+      STATUS_QUEUE => while let Some(chain) = queues[STATUS_QUEUE].pop(bus) {
+        queues[STATUS_QUEUE].push(bus, chain, 0);
+      },
+      _ => {},
+    }
+  }
+
+  fn poll(&mut self, queues: &mut [Queue], bus: &mut Bus) -> bool {
+    self.receive(&mut queues[EVENT_QUEUE], bus);
+    false
+  }
+
+  fn reset(&mut self) {
+    self.select = 0;
+    self.subsel = 0;
+    self.pending.clear();
+  }
+}
@@ -0,0 +1,253 @@
+use std::{collections::VecDeque, fs, io, path::Path};
+
+use crate::{devices::aclint::TIMEBASE_FREQUENCY, utils::channel::Sender};
+
+use super::{Event, InputKind, EV_KEY, EV_ABS, ABS_X, ABS_Y, BTN_LEFT, BTN_RIGHT, BTN_MIDDLE, KEY_LAST};
+
+// An action is one line of the control socket, or a script line after its time:
+//   key <name|code> [down|up]   without a state, the key is pressed and released
+//   type <text>                 presses the keys for the text, shifted where needed
+//   move <x> <y>                moves the pointer to the pixel
+//   button left|right|middle [down|up]
+//   click [left|right|middle]
+
+const KEY_LEFTSHIFT: u16 = 42;
+
+const KEYS: &[(&str, u16)] = &[
+  ("esc", 1), ("1", 2), ("2", 3), ("3", 4), ("4", 5), ("5", 6), ("6", 7), ("7", 8), ("8", 9), ("9", 10), ("0", 11),
+  ("minus", 12), ("equal", 13), ("backspace", 14), ("tab", 15),
+  ("q", 16), ("w", 17), ("e", 18), ("r", 19), ("t", 20), ("y", 21), ("u", 22), ("i", 23), ("o", 24), ("p", 25),
+  ("leftbrace", 26), ("rightbrace", 27), ("enter", 28), ("leftctrl", 29), ("ctrl", 29),
+  ("a", 30), ("s", 31), ("d", 32), ("f", 33), ("g", 34), ("h", 35), ("j", 36), ("k", 37), ("l", 38),
+  ("semicolon", 39), ("apostrophe", 40), ("grave", 41), ("leftshift", 42), ("shift", 42), ("backslash", 43),
+  ("z", 44), ("x", 45), ("c", 46), ("v", 47), ("b", 48), ("n", 49), ("m", 50),
+  ("comma", 51), ("dot", 52), ("slash", 53), ("rightshift", 54), ("leftalt", 56), ("alt", 56), ("space", 57),
+  ("capslock", 58), ("f1", 59), ("f2", 60), ("f3", 61), ("f4", 62), ("f5", 63), ("f6", 64), ("f7", 65),
+  ("f8", 66), ("f9", 67), ("f10", 68), ("f11", 87), ("f12", 88), ("rightctrl", 97), ("rightalt", 100),
+  ("home", 102), ("up", 103), ("pageup", 104), ("left", 105), ("right", 106), ("end", 107), ("down", 108),
+  ("pagedown", 109), ("insert", 110), ("delete", 111), ("leftmeta", 125), ("meta", 125),
+];
+
+// characters which need shift, with the character of the same key without it
+const SHIFTED: &[(char, char)] = &[
+  ('!', '1'), ('@', '2'), ('#', '3'), ('$', '4'), ('%', '5'), ('^', '6'), ('&', '7'), ('*', '8'), ('(', '9'), (')', '0'),
+  ('_', '-'), ('+', '='), ('{', '['), ('}', ']'), (':', ';'), ('"', '\''), ('~', '`'), ('|', '\\'), ('<', ','), ('>', '.'), ('?', '/'),
+];
+
+fn key(name: &str) -> Result<u16, String> {
+  if let Some(&(_, code)) = KEYS.iter().find(|(key, _)| *key == name) { return Ok(code); }
+  match name.parse() {
+    Ok(code) if (1..=KEY_LAST).contains(&code) => Ok(code),
+    _ => Err(format!("unknown key '{}'", name)),
+  }
+}
+
+// the key and whether shift is needed
+fn char_key(c: char) -> Result<(u16, bool), String> {
+  let (c, shift) = match SHIFTED.iter().find(|(shifted, _)| *shifted == c) {
+    Some(&(_, plain)) => (plain, true),
+    None if c.is_ascii_uppercase() => (c.to_ascii_lowercase(), true),
+    None => (c, false),
+  };
+  let name = match c {
+    ' ' => "space",
+    '\n' => "enter",
+    '\t' => "tab",
+    '-' => "minus",
+    '=' => "equal",
+    '[' => "leftbrace",
+    ']' => "rightbrace",
+    ';' => "semicolon",
+    '\'' => "apostrophe",
+    '`' => "grave",
+    '\\' => "backslash",
+    ',' => "comma",
+    '.' => "dot",
+    '/' => "slash",
+    c if c.is_ascii_alphanumeric() => return key(&c.to_string()).map(|code| (code, shift)),
+    _ => return Err(format!("no key types '{}'", c)),
+  };
+  key(name).map(|code| (code, shift))
+}
+
+fn button(name: &str) -> Result<u16, String> {
+  match name {
+    "left" => Ok(BTN_LEFT),
+    "right" => Ok(BTN_RIGHT),
+    "middle" => Ok(BTN_MIDDLE),
+    _ => Err(format!("unknown button '{}'", name)),
+  }
+}
+
+// batches for pressing and releasing the code, or only one of them
+fn press(code: u16, state: Option<&str>) -> Result<Vec<Vec<Event>>, String> {
+  match state {
+    None => Ok(vec![vec![Event::new(EV_KEY, code, 1)], vec![Event::new(EV_KEY, code, 0)]]),
+    Some("down") => Ok(vec![vec![Event::new(EV_KEY, code, 1)]]),
+    Some("up") => Ok(vec![vec![Event::new(EV_KEY, code, 0)]]),
+    Some(state) => Err(format!("unknown state '{}', expected down or up", state)),
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Input {
+  pub(crate) kind: InputKind,
+  pub(crate) batches: Vec<Vec<Event>>,
+}
+
+impl Input {
+  pub(crate) fn parse(line: &str) -> Result<Input, String> {
+    let line = line.trim();
+    let (action, rest) = line.split_once(' ').map_or((line, ""), |(action, rest)| (action, rest.trim()));
+    let words: Vec<&str> = rest.split_whitespace().collect();
+    let (kind, batches) = match (action, words.as_slice()) {
+      ("key", [name]) => (InputKind::Keyboard, press(key(name)?, None)?),
+      ("key", [name, state]) => (InputKind::Keyboard, press(key(name)?, Some(state))?),
+      ("type", _) if !rest.is_empty() => {
+        let mut batches = Vec::new();
+        for c in rest.chars() {
+          let (code, shift) = char_key(c)?;
+          if shift { batches.extend(press(KEY_LEFTSHIFT, Some("down"))?); }
+          batches.extend(press(code, None)?);
+          if shift { batches.extend(press(KEY_LEFTSHIFT, Some("up"))?); }
+        }
+        (InputKind::Keyboard, batches)
+      },
+      ("move", [x, y]) => {
+        let x = x.parse().map_err(|_| format!("invalid x '{}'", x))?;
+        let y = y.parse().map_err(|_| format!("invalid y '{}'", y))?;
+        (InputKind::Tablet, vec![vec![Event::new(EV_ABS, ABS_X, x), Event::new(EV_ABS, ABS_Y, y)]])
+      },
+      ("button", [name]) => (InputKind::Tablet, press(button(name)?, None)?),
+      ("button", [name, state]) => (InputKind::Tablet, press(button(name)?, Some(state))?),
+      ("click", []) => (InputKind::Tablet, press(BTN_LEFT, None)?),
+      ("click", [name]) => (InputKind::Tablet, press(button(name)?, None)?),
+      _ => return Err(format!("invalid input '{}'", line)),
+    };
+    Ok(Input { kind, batches })
+  }
+}
+
+// senders of the keyboard and the tablet
+#[derive(Debug, Clone)]
+pub(crate) struct Inputs {
+  pub(crate) keyboard: Sender<Vec<Event>>,
+  pub(crate) tablet: Sender<Vec<Event>>,
+}
+
+impl Inputs {
+  pub(crate) fn send(&self, input: Input) {
+    let sender = match input.kind {
+      InputKind::Keyboard => &self.keyboard,
+      InputKind::Tablet => &self.tablet,
+    };
+    for batch in input.batches {
+      sender.send(batch);
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum When {
+  // retired instructions
+  Instructions(u64),
+  // ticks of guest time
+  Time(u64),
+}
+
+impl When {
+  fn parse(s: &str) -> Result<When, String> {
+    let invalid = || format!("invalid time '{}', expected @instructions, Ns or Nms", s);
+    if let Some(count) = s.strip_prefix('@') {
+      return count.parse().map(When::Instructions).map_err(|_| invalid());
+    }
+    let (number, unit) = if let Some(ms) = s.strip_suffix("ms") {
+      (ms, TIMEBASE_FREQUENCY / 1000)
+    } else if let Some(seconds) = s.strip_suffix('s') {
+      (seconds, TIMEBASE_FREQUENCY)
+    } else {
+      return Err(invalid());
+    };
+    number.parse::<u64>().ok().and_then(|number| number.checked_mul(unit)).map(When::Time).ok_or_else(invalid)
+  }
+}
+
+// A file of "<time> <action>" lines, time is @instructions, or guest time since boot as Ns or Nms.
+// Lines run in order, each one waits for the lines before it. # starts a comment.
+#[derive(Debug)]
+pub(crate) struct InputScript {
+  lines: VecDeque<(When, Input)>,
+  inputs: Inputs,
+}
+
+impl InputScript {
+  pub(crate) fn load(path: &Path, inputs: Inputs) -> io::Result<InputScript> {
+    let text = fs::read_to_string(path)?;
+    let lines = InputScript::parse(&text).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    Ok(InputScript { lines, inputs })
+  }
+
+  fn parse(text: &str) -> Result<VecDeque<(When, Input)>, String> {
+    let mut lines = VecDeque::new();
+    for (number, line) in text.lines().enumerate() {
+      let line = line.split_once('#').map_or(line, |(line, _)| line).trim();
+      if line.is_empty() { continue; }
+      let parse = || -> Result<(When, Input), String> {
+        let (when, action) = line.split_once(' ').ok_or_else(|| format!("missing action in '{}'", line))?;
+        Ok((When::parse(when)?, Input::parse(action)?))
+      };
+      lines.push_back(parse().map_err(|err| format!("line {}: {}", number + 1, err))?);
+    }
+    Ok(lines)
+  }
+
+  // mtime of the next line, while it waits for guest time
+  pub(crate) fn next_time(&self) -> Option<u64> {
+    match self.lines.front()? {
+      (When::Time(ticks), _) => Some(*ticks),
+      (When::Instructions(_), _) => None,
+    }
+  }
+
+  // called on every bus step, with the instructions retired and mtime
+  pub(crate) fn step(&mut self, instructions: u64, time: u64) {
+    while let Some((when, _)) = self.lines.front() {
+      let due = match *when {
+        When::Instructions(count) => instructions >= count,
+        When::Time(ticks) => time >= ticks,
+      };
+      if !due { return; }
+      let (_, input) = self.lines.pop_front().unwrap();
+      self.inputs.send(input);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::devices::{aclint::TIMEBASE_FREQUENCY, virtio::input::{Event, InputKind, EV_KEY, EV_ABS, ABS_X, ABS_Y}};
+
+  use super::{Input, InputScript, When, KEY_LEFTSHIFT};
+
+  #[test]
+  fn script() {
+    let lines = InputScript::parse("
+      # log in
+      @1000 type Hi
+      2s key enter down   # held
+      1500ms move 10 20
+    ").unwrap();
+    let (when, input) = &lines[0];
+    assert_eq!(*when, When::Instructions(1000));
+    assert_eq!(input.kind, InputKind::Keyboard);
+    // shift down, h, shift up, i
+    let keys: Vec<_> = input.batches.iter().map(|batch| (batch[0].code, batch[0].value)).collect();
+    assert_eq!(keys, [(KEY_LEFTSHIFT, 1), (35, 1), (35, 0), (KEY_LEFTSHIFT, 0), (23, 1), (23, 0)]);
+    assert_eq!(lines[1], (When::Time(2 * TIMEBASE_FREQUENCY), Input { kind: InputKind::Keyboard, batches: vec![vec![Event::new(EV_KEY, 28, 1)]] }));
+    assert_eq!(lines[2].0, When::Time(TIMEBASE_FREQUENCY * 3 / 2));
+    assert_eq!(lines[2].1.batches, [[Event::new(EV_ABS, ABS_X, 10), Event::new(EV_ABS, ABS_Y, 20)]]);
+    assert!(InputScript::parse("1s key nothing").unwrap_err().starts_with("line 1:"));
+    assert!(When::parse("18446744073709551615s").unwrap_err().starts_with("invalid time"));
+    assert!(Input::parse("click up").is_err());
+  }
+}
@@ -0,0 +1,365 @@
+use std::fmt::Debug;
+
+use crate::{device_atomic, hart::Hart, trap::Exception};
+
+use self::queue::{Queue, QUEUE_SIZE_MAX};
+
+use super::{Device, bus::Bus};
+
+pub(crate) mod queue;
+pub(crate) mod blk;
+pub(crate) mod net;
+pub(crate) mod console;
+pub(crate) mod rng;
+pub(crate) mod p9;
+pub(crate) mod input;
+pub(crate) mod pci;
+
+// virtio-mmio version 2
+
+pub(crate) const VIRTIO_START: u64 = 0x10001000;
+pub(crate) const VIRTIO_SIZE: u64 = 0x1000;
+pub(crate) const VIRTIO_COUNT: usize = 8;
+pub(crate) const VIRTIO_END: u64 = VIRTIO_START + VIRTIO_SIZE * VIRTIO_COUNT as u64 - 1;
+// interrupt of the first slot, the others follow
+pub(crate) const VIRTIO_INTERRUPT_ID: u32 = 8;
+
+const MAGIC_VALUE: u64 = 0x000;
+const VERSION: u64 = 0x004;
+const DEVICE_ID: u64 = 0x008;
+const VENDOR_ID: u64 = 0x00c;
+const DEVICE_FEATURES: u64 = 0x010;
+const DEVICE_FEATURES_SEL: u64 = 0x014;
+const DRIVER_FEATURES: u64 = 0x020;
+const DRIVER_FEATURES_SEL: u64 = 0x024;
+const QUEUE_SEL: u64 = 0x030;
+const QUEUE_NUM_MAX: u64 = 0x034;
+const QUEUE_NUM: u64 = 0x038;
+const QUEUE_READY: u64 = 0x044;
+const QUEUE_NOTIFY: u64 = 0x050;
+const INTERRUPT_STATUS: u64 = 0x060;
+const INTERRUPT_ACK: u64 = 0x064;
+const STATUS: u64 = 0x070;
+const QUEUE_DESC_LOW: u64 = 0x080;
+const QUEUE_DESC_HIGH: u64 = 0x084;
+const QUEUE_DRIVER_LOW: u64 = 0x090;
+const QUEUE_DRIVER_HIGH: u64 = 0x094;
+const QUEUE_DEVICE_LOW: u64 = 0x0a0;
+const QUEUE_DEVICE_HIGH: u64 = 0x0a4;
+const SHM_LEN_LOW: u64 = 0x0b0;
+const SHM_BASE_HIGH: u64 = 0x0bc;
+const CONFIG_GENERATION: u64 = 0x0fc;
+const CONFIG: u64 = 0x100;
+
+const MAGIC: u32 = 0x74726976;
+// "yuri"
+const VENDOR: u32 = 0x69727579;
+
+const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+const STATUS_DRIVER_OK: u32 = 0b100;
+
+const INTERRUPT_USED_BUFFER: u32 = 0b01;
+const INTERRUPT_CONFIG_CHANGE: u32 = 0b10;
+
+// device behind the transport
+pub(crate) trait VirtioDevice: Debug + Send {
+  fn device_id(&self) -> u32;
+  // device specific feature bits
+  fn features(&self) -> u64;
+  fn queue_count(&self) -> usize;
+  fn read_config(&self, offset: u64) -> u8;
+  fn write_config(&mut self, _offset: u64, _data: u8) {}
+  // the driver made buffers available in the queue
+  fn notify(&mut self, queue: usize, queues: &mut [Queue], bus: &mut Bus);
+  // called on every step after the driver is ready, for input from the host
+  // true -> the configuration has changed
+  fn poll(&mut self, _queues: &mut [Queue], _bus: &mut Bus) -> bool { false }
+  fn reset(&mut self) {}
+}
+
+// An empty slot has device id 0, which drivers ignore.
+#[derive(Debug)]
+pub(crate) struct VirtioMmio {
+  device: Option<Box<dyn VirtioDevice>>,
+  irq: u32,
+  device_features_sel: u32,
+  driver_features: u64,
+  driver_features_sel: u32,
+  queue_sel: u32,
+  queues: Vec<Queue>,
+  notified: Vec<usize>,
+  interrupt_status: u32,
+  status: u32,
+  config_generation: u32,
+}
+
+impl VirtioMmio {
+  pub(crate) fn new(irq: u32) -> VirtioMmio {
+    VirtioMmio {
+      device: None,
+      irq,
+      device_features_sel: 0,
+      driver_features: 0,
+      driver_features_sel: 0,
+      queue_sel: 0,
+      queues: Vec::new(),
+      notified: Vec::new(),
+      interrupt_status: 0,
+      status: 0,
+      config_generation: 0,
+    }
+  }
+
+  pub(crate) fn is_empty(&self) -> bool {
+    self.device.is_none()
+  }
+
+  pub(crate) fn attach(&mut self, device: Box<dyn VirtioDevice>) {
+    self.queues = (0..device.queue_count()).map(|_| Queue::new()).collect();
+    self.device = Some(device);
+    self.reset();
+  }
+
+  pub(crate) fn reset(&mut self) {
+    self.device_features_sel = 0;
+    self.driver_features = 0;
+    self.driver_features_sel = 0;
+    self.queue_sel = 0;
+    self.queues.iter_mut().for_each(|queue| *queue = Queue::new());
+    self.notified.clear();
+    self.interrupt_status = 0;
+    self.status = 0;
+    if let Some(device) = &mut self.device { device.reset(); }
+  }
+
+  fn device_features(&self) -> u64 {
+    self.device.as_ref().map_or(0, |device| device.features() | VIRTIO_F_VERSION_1)
+  }
+
+  fn queue(&mut self) -> Option<&mut Queue> {
+    self.queues.get_mut(self.queue_sel as usize)
+  }
+
+  // offset from the start of the slot
+  fn read_register(&self, offset: u64) -> u32 {
+    let Some(device) = &self.device else {
+      return match offset {
+        MAGIC_VALUE => MAGIC,
+        VERSION => 2,
+        VENDOR_ID => VENDOR,
+        _ => 0,
+      };
+    };
+    match offset {
+      MAGIC_VALUE => MAGIC,
+      VERSION => 2,
+      DEVICE_ID => device.device_id(),
+      VENDOR_ID => VENDOR,
+      DEVICE_FEATURES => match self.device_features_sel {
+        0 => self.device_features() as u32,
+        1 => (self.device_features() >> 32) as u32,
+        _ => 0,
+      },
+      QUEUE_NUM_MAX => if self.queues.get(self.queue_sel as usize).is_some() { QUEUE_SIZE_MAX as u32 } else { 0 },
+      QUEUE_READY => self.queues.get(self.queue_sel as usize).map_or(0, |queue| queue.ready as u32),
+      INTERRUPT_STATUS => self.interrupt_status,
+      STATUS => self.status,
+      // no shared memory regions
+      SHM_LEN_LOW..=SHM_BASE_HIGH => u32::MAX,
+      CONFIG_GENERATION => self.config_generation,
+      _ => 0,
+    }
+  }
+
+  fn write_register(&mut self, offset: u64, data: u32) {
+    if self.device.is_none() { return; }
+    fn low(origin: u64, data: u32) -> u64 { (origin & !0xffffffff) | data as u64 }
+    fn high(origin: u64, data: u32) -> u64 { (origin & 0xffffffff) | (data as u64) << 32 }
+    match offset {
+      DEVICE_FEATURES_SEL => self.device_features_sel = data,
+      DRIVER_FEATURES => match self.driver_features_sel {
+        0 => self.driver_features = low(self.driver_features, data),
+        1 => self.driver_features = high(self.driver_features, data),
+        _ => {},
+      },
+      DRIVER_FEATURES_SEL => self.driver_features_sel = data,
+      QUEUE_SEL => self.queue_sel = data,
+      QUEUE_NUM => if let Some(queue) = self.queue() {
+        queue.size = (data as u16).min(QUEUE_SIZE_MAX);
+      },
+      QUEUE_READY => if let Some(queue) = self.queue() {
+        queue.ready = data & 1 != 0;
+      },
+      QUEUE_NOTIFY => if (data as usize) < self.queues.len() && !self.notified.contains(&(data as usize)) {
+        self.notified.push(data as usize);
+      },
+      INTERRUPT_ACK => self.interrupt_status &= !data,
+      STATUS => if data == 0 { self.reset() } else { self.status = data },
+      QUEUE_DESC_LOW => if let Some(queue) = self.queue() { queue.desc = low(queue.desc, data) },
+      QUEUE_DESC_HIGH => if let Some(queue) = self.queue() { queue.desc = high(queue.desc, data) },
+      QUEUE_DRIVER_LOW => if let Some(queue) = self.queue() { queue.driver = low(queue.driver, data) },
+      QUEUE_DRIVER_HIGH => if let Some(queue) = self.queue() { queue.driver = high(queue.driver, data) },
+      QUEUE_DEVICE_LOW => if let Some(queue) = self.queue() { queue.device = low(queue.device, data) },
+      QUEUE_DEVICE_HIGH => if let Some(queue) = self.queue() { queue.device = high(queue.device, data) },
+      _ => {},
+    }
+  }
+}
+
+impl Device for VirtioMmio {
+  device_atomic!();
+
+  fn step(&mut self, bus: &mut Bus, _hart: &mut Hart) {
+    let Some(device) = &mut self.device else { return };
+    if self.status & STATUS_DRIVER_OK != 0 {
+      for queue in self.notified.drain(..) {
+        if self.queues[queue].ready {
+          device.notify(queue, &mut self.queues, bus);
+        }
+      }
+      if device.poll(&mut self.queues, bus) {
+        self.config_generation = self.config_generation.wrapping_add(1);
+        self.interrupt_status |= INTERRUPT_CONFIG_CHANGE;
+      }
+    }
+    for queue in self.queues.iter_mut() {
+      if queue.interrupt {
+        queue.interrupt = false;
+        self.interrupt_status |= INTERRUPT_USED_BUFFER;
+      }
+    }
+    bus.plic.lock().unwrap().irq(self.irq, self.interrupt_status != 0);
+  }
+
+  // only the configuration space can be accessed by bytes
+
+  fn read8(&mut self, address: u64) -> Result<u8, Exception> {
+    let offset = address % VIRTIO_SIZE;
+    match &self.device {
+      Some(device) if offset >= CONFIG => Ok(device.read_config(offset - CONFIG)),
+      _ => Err(Exception::LoadAccessFault(address)),
+    }
+  }
+
+  fn read16(&mut self, address: u64) -> Result<u16, Exception> {
+    Ok(u16::from_le_bytes([self.read8(address)?, self.read8(address + 1)?]))
+  }
+
+  fn read32(&mut self, address: u64) -> Result<u32, Exception> {
+    if !address.is_multiple_of(4) { return Err(Exception::LoadAddressMisaligned(address)); }
+    let offset = address % VIRTIO_SIZE;
+    if offset >= CONFIG {
+      return Ok(u32::from_le_bytes([
+        self.read8(address)?,
+        self.read8(address + 1)?,
+        self.read8(address + 2)?,
+        self.read8(address + 3)?,
+      ]));
+    }
+    Ok(self.read_register(offset))
+  }
+
+  fn read64(&mut self, address: u64) -> Result<u64, Exception> {
+    Ok(self.read32(address)? as u64 | (self.read32(address + 4)? as u64) << 32)
+  }
+
+  fn write8(&mut self, address: u64, data: u8) -> Result<(), Exception> {
+    let offset = address % VIRTIO_SIZE;
+    match &mut self.device {
+      Some(device) if offset >= CONFIG => {
+        device.write_config(offset - CONFIG, data);
+        Ok(())
+      },
+      _ => Err(Exception::StoreAMOAccessFault(address)),
+    }
+  }
+
+  fn write16(&mut self, address: u64, data: u16) -> Result<(), Exception> {
+    let data = data.to_le_bytes();
+    self.write8(address, data[0])?;
+    self.write8(address + 1, data[1])
+  }
+
+  fn write32(&mut self, address: u64, data: u32) -> Result<(), Exception> {
+    if !address.is_multiple_of(4) { return Err(Exception::StoreAMOAddressMisaligned(address)); }
+    let offset = address % VIRTIO_SIZE;
+    if offset >= CONFIG {
+      for (index, byte) in data.to_le_bytes().into_iter().enumerate() {
+        self.write8(address + index as u64, byte)?;
+      }
+      return Ok(());
+    }
+    self.write_register(offset, data);
+    Ok(())
+  }
+
+  fn write64(&mut self, address: u64, data: u64) -> Result<(), Exception> {
+    self.write32(address, data as u32)?;
+    self.write32(address + 4, (data >> 32) as u32)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::fs;
+
+  use crate::devices::{memory::MEMORY_START, virtio::blk::{VirtioBlk, ImageMode}};
+
+  use super::*;
+
+  #[test]
+  fn mmio_transport() {
+    let path = std::env::temp_dir().join(format!("yuri-virtio-{}", std::process::id()));
+    fs::write(&path, [0; 512]).unwrap();
+    let (mut bus, _controller) = Bus::new();
+    assert_eq!(bus.attach_virtio(Box::new(VirtioBlk::open(&path, ImageMode::ReadOnly).unwrap())), Some(0));
+    let mut hart = Hart::new();
+    let register = |offset: u64| VIRTIO_START + offset;
+    assert_eq!(bus.read32(register(MAGIC_VALUE)).unwrap(), MAGIC);
+    assert_eq!(bus.read32(register(DEVICE_ID)).unwrap(), 2);
+    assert_eq!(bus.read32(VIRTIO_START + VIRTIO_SIZE + DEVICE_ID).unwrap(), 0);
+    // device features by halves, and those the driver took
+    assert_ne!(bus.read32(register(DEVICE_FEATURES)).unwrap() & 1 << 5, 0);
+    bus.write32(register(DEVICE_FEATURES_SEL), 1).unwrap();
+    assert_eq!(bus.read32(register(DEVICE_FEATURES)).unwrap(), 1);
+    bus.write32(register(DRIVER_FEATURES_SEL), 1).unwrap();
+    bus.write32(register(DRIVER_FEATURES), 1).unwrap();
+    assert_eq!(bus.virtio[0].lock().unwrap().driver_features, VIRTIO_F_VERSION_1);
+    // blk has a single queue
+    bus.write32(register(QUEUE_SEL), 1).unwrap();
+    assert_eq!(bus.read32(register(QUEUE_NUM_MAX)).unwrap(), 0);
+    bus.write32(register(QUEUE_SEL), 0).unwrap();
+    assert_eq!(bus.read32(register(QUEUE_NUM_MAX)).unwrap(), QUEUE_SIZE_MAX as u32);
+    let (desc, driver, device, header) = (MEMORY_START, MEMORY_START + 0x1000, MEMORY_START + 0x2000, MEMORY_START + 0x3000);
+    bus.write32(register(QUEUE_NUM), 8).unwrap();
+    bus.write32(register(QUEUE_DESC_LOW), desc as u32).unwrap();
+    bus.write32(register(QUEUE_DRIVER_LOW), driver as u32).unwrap();
+    bus.write32(register(QUEUE_DEVICE_LOW), device as u32).unwrap();
+    bus.write32(register(QUEUE_READY), 1).unwrap();
+    assert_eq!(bus.read32(register(QUEUE_READY)).unwrap(), 1);
+    bus.write32(register(STATUS), 0b1111).unwrap();
+    // a flush, with the header and the status in one descriptor
+    bus.write32(header, 4).unwrap();
+    bus.write64(desc, header).unwrap();
+    bus.write32(desc + 8, 17).unwrap();
+    bus.write16(desc + 12, 0).unwrap();
+    bus.write16(driver + 2, 1).unwrap();
+    bus.write32(register(QUEUE_NOTIFY), 0).unwrap();
+    bus.virtio[0].lock().unwrap().step(&mut bus.clone(), &mut hart);
+    assert_eq!(bus.read16(device + 2).unwrap(), 1);
+    assert_eq!(bus.read32(register(INTERRUPT_STATUS)).unwrap(), INTERRUPT_USED_BUFFER);
+    bus.write32(register(INTERRUPT_ACK), INTERRUPT_USED_BUFFER).unwrap();
+    assert_eq!(bus.read32(register(INTERRUPT_STATUS)).unwrap(), 0);
+    // descriptors in the registers of the transport, which is locked while it steps, are dropped
+    bus.write32(register(QUEUE_DESC_LOW), VIRTIO_START as u32).unwrap();
+    bus.write16(driver + 2, 2).unwrap();
+    bus.write32(register(QUEUE_NOTIFY), 0).unwrap();
+    bus.virtio[0].lock().unwrap().step(&mut bus.clone(), &mut hart);
+    assert_eq!(bus.read16(device + 2).unwrap(), 1);
+    // reset by writing zero to the status
+    bus.write32(register(STATUS), 0).unwrap();
+    assert_eq!(bus.read32(register(QUEUE_READY)).unwrap(), 0);
+    fs::remove_file(&path).unwrap();
+  }
+}
@@ -0,0 +1,191 @@
+use std::{io, path::PathBuf, str::FromStr};
+
+use crate::{devices::bus::Bus, utils::channel::{Receiver, Sender, Notifier, channel, notified_channel}};
+
+use self::{user::Forward, socket::Endpoint};
+
+use super::{VirtioDevice, queue::Queue};
+
+pub(crate) mod packet;
+pub(crate) mod user;
+pub(crate) mod socket;
+
+const DEVICE_ID: u32 = 1;
+
+const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+const VIRTIO_NET_F_STATUS: u64 = 1 << 16;
+
+const VIRTIO_NET_S_LINK_UP: u16 = 1;
+
+const RX_QUEUE: usize = 0;
+const TX_QUEUE: usize = 1;
+
+// struct virtio_net_hdr, no offloads are offered so only num_buffers is set
+const HEADER_SIZE: usize = 12;
+// the largest frame a driver sends without offloads: the ethernet header, a VLAN tag and 1500 bytes of payload
+const FRAME_MAX: usize = 1518;
+
+// ethernet frames, without the virtio header
+pub(crate) type Frame = Vec<u8>;
+
+// Network card, the backend sits on the other side of the channels.
+#[derive(Debug)]
+pub(crate) struct VirtioNet {
+  mac: [u8; 6],
+  sender: Sender<Frame>,
+  receiver: Receiver<Frame>,
+  // frame waiting for a receive buffer
+  pending: Option<Frame>,
+}
+
+impl VirtioNet {
+  // returns the device, and the channels for the backend: frames sent by the guest and frames for the guest
+  pub(crate) fn new(mac: [u8; 6], notifier: Notifier) -> (VirtioNet, Receiver<Frame>, Sender<Frame>) {
+    let (recv_send, recv) = notified_channel(notifier);
+    let (send, send_recv) = channel();
+    (VirtioNet {
+      mac,
+      sender: send,
+      receiver: recv,
+      pending: None,
+    }, send_recv, recv_send)
+  }
+
+  fn transmit(&mut self, queues: &mut [Queue], bus: &mut Bus) {
+    while let Some(chain) = queues[TX_QUEUE].pop(bus) {
+      // larger frames are dropped
+      let len = chain.readable().saturating_sub(HEADER_SIZE);
+      let mut frame = vec![0; len.min(FRAME_MAX)];
+      if len <= FRAME_MAX && chain.read_at(bus, HEADER_SIZE, &mut frame).is_ok() {
+        self.sender.send(frame);
+      }
+      queues[TX_QUEUE].push(bus, chain, 0);
+    }
+  }
+
+  fn receive(&mut self, queues: &mut [Queue], bus: &mut Bus) {
+    loop {
+      let Some(frame) = self.pending.take().or_else(|| self.receiver.try_recv()) else { return };
+      let Some(chain) = queues[RX_QUEUE].pop(bus) else {
+        self.pending = Some(frame);
+        return;
+      };
+      let mut header = [0; HEADER_SIZE];
+      // num_buffers
+      header[10] = 1;
+      // frames which don't fit the buffer are dropped
+      let len = if chain.write_at(bus, 0, &header).is_ok() && chain.write_at(bus, HEADER_SIZE, &frame).is_ok() {
+        (HEADER_SIZE + frame.len()) as u32
+      } else {
+        0
+      };
+      queues[RX_QUEUE].push(bus, chain, len);
+    }
+  }
+}
+
+impl VirtioDevice for VirtioNet {
+  fn device_id(&self) -> u32 {
+    DEVICE_ID
+  }
+
+  fn features(&self) -> u64 {
+    VIRTIO_NET_F_MAC | VIRTIO_NET_F_STATUS
+  }
+
+  fn queue_count(&self) -> usize {
+    2
+  }
+
+  fn read_config(&self, offset: u64) -> u8 {
+    match offset {
+      0..=5 => self.mac[offset as usize],
+      6..=7 => VIRTIO_NET_S_LINK_UP.to_le_bytes()[offset as usize - 6],
+      _ => 0,
+    }
+  }
+
+  fn notify(&mut self, queue: usize, queues: &mut [Queue], bus: &mut Bus) {
+    match queue {
+      TX_QUEUE => self.transmit(queues, bus),
+      _ => self.receive(queues, bus),
+    }
+  }
+
+  fn poll(&mut self, queues: &mut [Queue], bus: &mut Bus) -> bool {
+    self.receive(queues, bus);
+    false
+  }
+
+  fn reset(&mut self) {
+    self.pending = None;
+  }
+}
+
+// 52:54:00:12:34:56 for the first card, then ...:57 and so on
+pub(crate) fn default_mac(index: usize) -> [u8; 6] {
+  [0x52, 0x54, 0x00, 0x12, 0x34, 0x56u8.wrapping_add(index as u8)]
+}
+
+// "aa:bb:cc:dd:ee:ff"
+pub(crate) fn parse_mac(s: &str) -> Result<[u8; 6], String> {
+  let mut mac = [0; 6];
+  let mut parts = s.split(':');
+  for byte in mac.iter_mut() {
+    *byte = parts.next().and_then(|part| u8::from_str_radix(part, 16).ok())
+      .ok_or_else(|| format!("invalid mac address '{}'", s))?;
+  }
+  if parts.next().is_some() { return Err(format!("invalid mac address '{}'", s)); }
+  Ok(mac)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Backend {
+  User(Vec<Forward>),
+  Socket(Endpoint),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct NetOptions {
+  pub(crate) backend: Backend,
+  pub(crate) mac: Option<[u8; 6]>,
+}
+
+// "user[,hostfwd=tcp:8022:22]...", "socket,listen=path" or "socket,connect=path", all can end with ",mac=..."
+impl FromStr for NetOptions {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let mut parts = s.split(',');
+    let mut backend = match parts.next() {
+      Some("user") => Backend::User(Vec::new()),
+      Some("socket") => Backend::Socket(Endpoint::Listen(PathBuf::new())),
+      _ => return Err(format!("unknown network backend in '{}', expected user or socket", s)),
+    };
+    let mut endpoint = None;
+    let mut mac = None;
+    for part in parts {
+      match (&mut backend, part.split_once('=')) {
+        (_, Some(("mac", value))) => mac = Some(parse_mac(value)?),
+        (Backend::User(forwards), Some(("hostfwd", value))) => forwards.push(value.parse()?),
+        (Backend::Socket(_), Some(("listen", path))) => endpoint = Some(Endpoint::Listen(path.into())),
+        (Backend::Socket(_), Some(("connect", path))) => endpoint = Some(Endpoint::Connect(path.into())),
+        _ => return Err(format!("unknown network option '{}'", part)),
+      }
+    }
+    if let Backend::Socket(_) = backend {
+      backend = Backend::Socket(endpoint.ok_or_else(|| format!("socket needs listen=path or connect=path in '{}'", s))?);
+    }
+    Ok(NetOptions { backend, mac })
+  }
+}
+
+impl NetOptions {
+  // start the backend on the other side of the card's channels
+  pub(crate) fn spawn(&self, frames: Receiver<Frame>, sender: Sender<Frame>) -> io::Result<()> {
+    match &self.backend {
+      Backend::User(forwards) => user::spawn(forwards, frames, sender),
+      Backend::Socket(endpoint) => socket::spawn(endpoint, frames, sender),
+    }
+  }
+}
@@ -0,0 +1,265 @@
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+// Parsing and building of the headers the user mode network needs.
+
+pub(crate) const ETHERTYPE_IPV4: u16 = 0x0800;
+pub(crate) const ETHERTYPE_ARP: u16 = 0x0806;
+
+pub(crate) const PROTOCOL_ICMP: u8 = 1;
+pub(crate) const PROTOCOL_TCP: u8 = 6;
+pub(crate) const PROTOCOL_UDP: u8 = 17;
+
+pub(crate) const TCP_FIN: u8 = 0b000001;
+pub(crate) const TCP_SYN: u8 = 0b000010;
+pub(crate) const TCP_RST: u8 = 0b000100;
+pub(crate) const TCP_PSH: u8 = 0b001000;
+pub(crate) const TCP_ACK: u8 = 0b010000;
+
+const TCP_OPTION_END: u8 = 0;
+const TCP_OPTION_NOP: u8 = 1;
+const TCP_OPTION_MSS: u8 = 2;
+
+fn u16_at(data: &[u8], index: usize) -> u16 {
+  u16::from_be_bytes([data[index], data[index + 1]])
+}
+
+fn u32_at(data: &[u8], index: usize) -> u32 {
+  u32::from_be_bytes(data[index..index + 4].try_into().unwrap())
+}
+
+fn ip_at(data: &[u8], index: usize) -> Ipv4Addr {
+  Ipv4Addr::new(data[index], data[index + 1], data[index + 2], data[index + 3])
+}
+
+// internet checksum of data, starting from a partial sum
+pub(crate) fn checksum(data: &[u8], sum: u32) -> u16 {
+  let mut sum = data.chunks(2)
+    .map(|word| u16::from_be_bytes([word[0], *word.get(1).unwrap_or(&0)]) as u32)
+    .fold(sum, |sum, word| sum + word);
+  while sum >> 16 != 0 {
+    sum = (sum & 0xffff) + (sum >> 16);
+  }
+  !(sum as u16)
+}
+
+fn pseudo_header(src: Ipv4Addr, dst: Ipv4Addr, protocol: u8, len: usize) -> u32 {
+  let (src, dst) = (src.octets(), dst.octets());
+  u16::from_be_bytes([src[0], src[1]]) as u32 + u16::from_be_bytes([src[2], src[3]]) as u32
+    + u16::from_be_bytes([dst[0], dst[1]]) as u32 + u16::from_be_bytes([dst[2], dst[3]]) as u32
+    + protocol as u32 + len as u32
+}
+
+#[derive(Debug)]
+pub(crate) struct Ethernet<'a> {
+  pub(crate) dst: [u8; 6],
+  pub(crate) src: [u8; 6],
+  pub(crate) ethertype: u16,
+  pub(crate) payload: &'a [u8],
+}
+
+impl Ethernet<'_> {
+  pub(crate) fn parse(frame: &[u8]) -> Option<Ethernet<'_>> {
+    if frame.len() < 14 { return None; }
+    Some(Ethernet {
+      dst: frame[0..6].try_into().unwrap(),
+      src: frame[6..12].try_into().unwrap(),
+      ethertype: u16_at(frame, 12),
+      payload: &frame[14..],
+    })
+  }
+
+  pub(crate) fn build(dst: [u8; 6], src: [u8; 6], ethertype: u16, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(14 + payload.len());
+    frame.extend_from_slice(&dst);
+    frame.extend_from_slice(&src);
+    frame.extend_from_slice(&ethertype.to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+  }
+}
+
+// ARP for IPv4 over ethernet
+#[derive(Debug)]
+pub(crate) struct Arp {
+  pub(crate) operation: u16,
+  pub(crate) sender_mac: [u8; 6],
+  pub(crate) sender_ip: Ipv4Addr,
+  pub(crate) target_mac: [u8; 6],
+  pub(crate) target_ip: Ipv4Addr,
+}
+
+pub(crate) const ARP_REQUEST: u16 = 1;
+pub(crate) const ARP_REPLY: u16 = 2;
+
+impl Arp {
+  pub(crate) fn parse(data: &[u8]) -> Option<Arp> {
+    // ethernet, IPv4, 6 byte and 4 byte addresses
+    if data.len() < 28 || data[0..6] != [0, 1, 8, 0, 6, 4] { return None; }
+    Some(Arp {
+      operation: u16_at(data, 6),
+      sender_mac: data[8..14].try_into().unwrap(),
+      sender_ip: ip_at(data, 14),
+      target_mac: data[18..24].try_into().unwrap(),
+      target_ip: ip_at(data, 24),
+    })
+  }
+
+  pub(crate) fn build(&self) -> Vec<u8> {
+    let mut data = vec![0, 1, 8, 0, 6, 4];
+    data.extend_from_slice(&self.operation.to_be_bytes());
+    data.extend_from_slice(&self.sender_mac);
+    data.extend_from_slice(&self.sender_ip.octets());
+    data.extend_from_slice(&self.target_mac);
+    data.extend_from_slice(&self.target_ip.octets());
+    data
+  }
+}
+
+#[derive(Debug)]
+pub(crate) struct Ipv4<'a> {
+  pub(crate) src: Ipv4Addr,
+  pub(crate) dst: Ipv4Addr,
+  pub(crate) protocol: u8,
+  pub(crate) payload: &'a [u8],
+}
+
+impl Ipv4<'_> {
+  // fragments and broken headers are rejected
+  pub(crate) fn parse(data: &[u8]) -> Option<Ipv4<'_>> {
+    if data.len() < 20 || data[0] >> 4 != 4 { return None; }
+    let header = (data[0] & 0xf) as usize * 4;
+    let total = u16_at(data, 2) as usize;
+    let fragment = u16_at(data, 6);
+    // more fragments or a fragment offset
+    if header < 20 || total < header || total > data.len() || fragment & 0x3fff != 0 { return None; }
+    if checksum(&data[..header], 0) != 0 { return None; }
+    Some(Ipv4 {
+      src: ip_at(data, 12),
+      dst: ip_at(data, 16),
+      protocol: data[9],
+      payload: &data[header..total],
+    })
+  }
+
+  pub(crate) fn build(src: Ipv4Addr, dst: Ipv4Addr, protocol: u8, payload: &[u8]) -> Vec<u8> {
+    let mut data = vec![0x45, 0];
+    data.extend_from_slice(&((20 + payload.len()) as u16).to_be_bytes());
+    // identification, don't fragment
+    data.extend_from_slice(&[0, 0, 0x40, 0]);
+    data.extend_from_slice(&[64, protocol, 0, 0]);
+    data.extend_from_slice(&src.octets());
+    data.extend_from_slice(&dst.octets());
+    let sum = checksum(&data, 0);
+    data[10..12].copy_from_slice(&sum.to_be_bytes());
+    data.extend_from_slice(payload);
+    data
+  }
+}
+
+#[derive(Debug)]
+pub(crate) struct Udp<'a> {
+  pub(crate) src_port: u16,
+  pub(crate) dst_port: u16,
+  pub(crate) payload: &'a [u8],
+}
+
+impl Udp<'_> {
+  pub(crate) fn parse(data: &[u8]) -> Option<Udp<'_>> {
+    if data.len() < 8 { return None; }
+    let len = u16_at(data, 4) as usize;
+    if len < 8 || len > data.len() { return None; }
+    Some(Udp {
+      src_port: u16_at(data, 0),
+      dst_port: u16_at(data, 2),
+      payload: &data[8..len],
+    })
+  }
+
+  // the whole IPv4 packet
+  pub(crate) fn build(src: SocketAddrV4, dst: SocketAddrV4, payload: &[u8]) -> Vec<u8> {
+    let len = 8 + payload.len();
+    let mut data = Vec::with_capacity(len);
+    data.extend_from_slice(&src.port().to_be_bytes());
+    data.extend_from_slice(&dst.port().to_be_bytes());
+    data.extend_from_slice(&(len as u16).to_be_bytes());
+    data.extend_from_slice(&[0, 0]);
+    data.extend_from_slice(payload);
+    let sum = match checksum(&data, pseudo_header(*src.ip(), *dst.ip(), PROTOCOL_UDP, len)) {
+      // 0 means no checksum
+      0 => 0xffff,
+      sum => sum,
+    };
+    data[6..8].copy_from_slice(&sum.to_be_bytes());
+    Ipv4::build(*src.ip(), *dst.ip(), PROTOCOL_UDP, &data)
+  }
+}
+
+#[derive(Debug)]
+pub(crate) struct Tcp<'a> {
+  pub(crate) src_port: u16,
+  pub(crate) dst_port: u16,
+  pub(crate) seq: u32,
+  pub(crate) ack: u32,
+  pub(crate) flags: u8,
+  pub(crate) window: u16,
+  pub(crate) mss: Option<u16>,
+  pub(crate) payload: &'a [u8],
+}
+
+impl Tcp<'_> {
+  pub(crate) fn parse(data: &[u8], src: Ipv4Addr, dst: Ipv4Addr) -> Option<Tcp<'_>> {
+    if data.len() < 20 { return None; }
+    let offset = (data[12] >> 4) as usize * 4;
+    if offset < 20 || offset > data.len() { return None; }
+    if checksum(data, pseudo_header(src, dst, PROTOCOL_TCP, data.len())) != 0 { return None; }
+    let mut mss = None;
+    let mut options = &data[20..offset];
+    while let [kind, rest @ ..] = options {
+      match *kind {
+        TCP_OPTION_END => break,
+        TCP_OPTION_NOP => options = rest,
+        _ => {
+          let len = *rest.first()? as usize;
+          if len < 2 || len > options.len() { break; }
+          if *kind == TCP_OPTION_MSS && len == 4 {
+            mss = Some(u16_at(options, 2));
+          }
+          options = &options[len..];
+        },
+      }
+    }
+    Some(Tcp {
+      src_port: u16_at(data, 0),
+      dst_port: u16_at(data, 2),
+      seq: u32_at(data, 4),
+      ack: u32_at(data, 8),
+      flags: data[13] & 0x3f,
+      window: u16_at(data, 14),
+      mss,
+      payload: &data[offset..],
+    })
+  }
+
+  // the whole IPv4 packet
+  #[allow(clippy::too_many_arguments)]
+  pub(crate) fn build(src: SocketAddrV4, dst: SocketAddrV4, seq: u32, ack: u32, flags: u8, window: u16, mss: Option<u16>, payload: &[u8]) -> Vec<u8> {
+    let offset = if mss.is_some() { 24 } else { 20 };
+    let mut data = Vec::with_capacity(offset + payload.len());
+    data.extend_from_slice(&src.port().to_be_bytes());
+    data.extend_from_slice(&dst.port().to_be_bytes());
+    data.extend_from_slice(&seq.to_be_bytes());
+    data.extend_from_slice(&ack.to_be_bytes());
+    data.extend_from_slice(&[(offset as u8 / 4) << 4, flags]);
+    data.extend_from_slice(&window.to_be_bytes());
+    // checksum, urgent pointer
+    data.extend_from_slice(&[0, 0, 0, 0]);
+    if let Some(mss) = mss {
+      data.extend_from_slice(&[TCP_OPTION_MSS, 4]);
+      data.extend_from_slice(&mss.to_be_bytes());
+    }
+    data.extend_from_slice(payload);
+    let sum = checksum(&data, pseudo_header(*src.ip(), *dst.ip(), PROTOCOL_TCP, data.len()));
+    data[16..18].copy_from_slice(&sum.to_be_bytes());
+    Ipv4::build(*src.ip(), *dst.ip(), PROTOCOL_TCP, &data)
+  }
+}
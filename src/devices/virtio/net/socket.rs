@@ -0,0 +1,62 @@
+use std::{io::{self, Read, Write}, os::unix::net::UnixStream, path::PathBuf, sync::{Arc, Mutex}, thread};
+
+use crate::utils::{bind_socket, channel::{Receiver, Sender}};
+
+use super::Frame;
+
+// Raw frames over a unix stream socket, each one after its length as a big endian u32.
+// This is the framing of qemu's stream netdev, so yuri can be cabled to qemu as well.
+
+const FRAME_MAX: usize = 65536;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Endpoint {
+  // wait for the peer, and again whenever it goes away
+  Listen(PathBuf),
+  Connect(PathBuf),
+}
+
+// frames from the guest are dropped while there is no peer
+pub(crate) fn spawn(endpoint: &Endpoint, frames: Receiver<Frame>, sender: Sender<Frame>) -> io::Result<()> {
+  let peer = Arc::new(Mutex::new(None::<UnixStream>));
+  match endpoint {
+    Endpoint::Listen(path) => {
+      let listener = bind_socket(path)?;
+      let peer = peer.clone();
+      thread::spawn(move || for stream in listener.incoming() {
+        let Ok(stream) = stream else { continue };
+        let Ok(reader) = stream.try_clone() else { continue };
+        *peer.lock().unwrap() = Some(stream);
+        receive(reader, &sender);
+      });
+    },
+    Endpoint::Connect(path) => {
+      let stream = UnixStream::connect(path)?;
+      let reader = stream.try_clone()?;
+      *peer.lock().unwrap() = Some(stream);
+      thread::spawn(move || receive(reader, &sender));
+    },
+  }
+  thread::spawn(move || loop {
+    let frame = frames.recv();
+    let mut peer = peer.lock().unwrap();
+    if let Some(stream) = &mut *peer {
+      let sent = stream.write_all(&(frame.len() as u32).to_be_bytes()).and_then(|_| stream.write_all(&frame));
+      if sent.is_err() { *peer = None; }
+    }
+  });
+  Ok(())
+}
+
+// until the peer goes away
+fn receive(mut stream: UnixStream, sender: &Sender<Frame>) {
+  loop {
+    let mut len = [0; 4];
+    if stream.read_exact(&mut len).is_err() { return; }
+    let len = u32::from_be_bytes(len) as usize;
+    if len > FRAME_MAX { return; }
+    let mut frame = vec![0; len];
+    if stream.read_exact(&mut frame).is_err() { return; }
+    sender.send(frame);
+  }
+}
@@ -0,0 +1,688 @@
+use std::{collections::{HashMap, VecDeque, hash_map::Entry}, io::{self, Read, Write, ErrorKind}, net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener, TcpStream, UdpSocket, Shutdown}, str::FromStr, thread, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
+
+use crate::utils::channel::{Receiver, Sender, channel};
+
+use super::{Frame, packet::{Ethernet, Arp, Ipv4, Udp, Tcp, ARP_REQUEST, ARP_REPLY, ETHERTYPE_ARP, ETHERTYPE_IPV4, PROTOCOL_ICMP, PROTOCOL_TCP, PROTOCOL_UDP, TCP_ACK, TCP_FIN, TCP_PSH, TCP_RST, TCP_SYN, checksum}};
+
+// User mode network, the same addresses as qemu's slirp.
+// Packets to the gateway go to the host's localhost, others to their destination.
+// Only the gateway answers pings.
+
+const NETMASK: Ipv4Addr = Ipv4Addr::new(255, 255, 255, 0);
+const GATEWAY: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 2);
+const GUEST: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 15);
+const GATEWAY_MAC: [u8; 6] = [0x52, 0x55, 0x0a, 0x00, 0x02, 0x02];
+const BROADCAST_MAC: [u8; 6] = [0xff; 6];
+
+// ports used by the gateway for forwarded connections
+const EPHEMERAL_PORTS: u16 = 49152;
+
+const MSS: u16 = 1460;
+const WINDOW: usize = 65535;
+const RETRANSMIT: Duration = Duration::from_secs(1);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const UDP_TIMEOUT: Duration = Duration::from_secs(120);
+const POLL: Duration = Duration::from_millis(1);
+
+const DHCP_SERVER_PORT: u16 = 67;
+const DHCP_CLIENT_PORT: u16 = 68;
+const DHCP_MAGIC: [u8; 4] = [99, 130, 83, 99];
+const DHCP_DISCOVER: u8 = 1;
+const DHCP_OFFER: u8 = 2;
+const DHCP_REQUEST: u8 = 3;
+const DHCP_ACK: u8 = 5;
+const DHCP_LEASE: u32 = 86400;
+
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMP_ECHO_REQUEST: u8 = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Protocol {
+  Tcp,
+  Udp,
+}
+
+// a port on the host's localhost forwarded to a port of the guest
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Forward {
+  pub(crate) protocol: Protocol,
+  pub(crate) host: u16,
+  pub(crate) guest: u16,
+}
+
+// "tcp:8022:22"
+impl FromStr for Forward {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let invalid = || format!("invalid forward '{}', expected tcp|udp:host port:guest port", s);
+    let mut parts = s.split(':');
+    let protocol = match parts.next() {
+      Some("tcp") => Protocol::Tcp,
+      Some("udp") => Protocol::Udp,
+      _ => return Err(invalid()),
+    };
+    let host = parts.next().and_then(|port| port.parse().ok()).ok_or_else(invalid)?;
+    let guest = parts.next().and_then(|port| port.parse().ok()).ok_or_else(invalid)?;
+    if parts.next().is_some() { return Err(invalid()); }
+    Ok(Forward { protocol, host, guest })
+  }
+}
+
+// run the network on its own thread
+pub(crate) fn spawn(forwards: &[Forward], frames: Receiver<Frame>, sender: Sender<Frame>) -> io::Result<()> {
+  let mut net = UserNet::new(forwards, sender)?;
+  thread::spawn(move || loop {
+    if let Some(frame) = frames.recv_timeout(POLL) {
+      net.input(&frame);
+    }
+    net.poll();
+  });
+  Ok(())
+}
+
+fn host_address(address: SocketAddrV4) -> SocketAddr {
+  match *address.ip() {
+    GATEWAY => SocketAddr::new(Ipv4Addr::LOCALHOST.into(), address.port()),
+    ip => SocketAddr::new(ip.into(), address.port()),
+  }
+}
+
+// sends IP packets to the guest
+#[derive(Debug)]
+struct Link {
+  sender: Sender<Frame>,
+  // learned from the frames of the guest
+  guest_mac: Option<[u8; 6]>,
+}
+
+impl Link {
+  fn send(&self, packet: &[u8]) {
+    if let Some(mac) = self.guest_mac {
+      self.sender.send(Ethernet::build(mac, GATEWAY_MAC, ETHERTYPE_IPV4, packet));
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TcpState {
+  // the guest sent SYN, waiting for the host to connect
+  Connecting,
+  // forwarded connection, SYN sent to the guest
+  SynSent,
+  // SYN+ACK sent to the guest
+  SynReceived,
+  Established,
+}
+
+#[derive(Debug)]
+struct TcpConnection {
+  guest: SocketAddrV4,
+  remote: SocketAddrV4,
+  state: TcpState,
+  stream: Option<TcpStream>,
+  connecting: Option<Receiver<Option<TcpStream>>>,
+  // next sequence number to send
+  seq: u32,
+  // oldest sequence number the guest hasn't acknowledged
+  acked: u32,
+  // next sequence number expected from the guest
+  ack: u32,
+  window: usize,
+  mss: usize,
+  // sent to the guest but not acknowledged, starts at acked
+  unacked: VecDeque<u8>,
+  // received from the guest but not written to the host
+  to_host: Vec<u8>,
+  host_closed: bool,
+  fin_sent: bool,
+  fin_received: bool,
+  shutdown: bool,
+  // when the guest last made progress
+  timer: Instant,
+}
+
+impl TcpConnection {
+  fn new(guest: SocketAddrV4, remote: SocketAddrV4, state: TcpState, isn: u32) -> TcpConnection {
+    TcpConnection {
+      guest,
+      remote,
+      state,
+      stream: None,
+      connecting: None,
+      seq: isn,
+      acked: isn,
+      ack: 0,
+      window: 0,
+      mss: 536,
+      unacked: VecDeque::new(),
+      to_host: Vec::new(),
+      host_closed: false,
+      fin_sent: false,
+      fin_received: false,
+      shutdown: false,
+      timer: Instant::now(),
+    }
+  }
+
+  fn send(&self, link: &Link, seq: u32, flags: u8, payload: &[u8]) {
+    let window = WINDOW.saturating_sub(self.to_host.len()) as u16;
+    let mss = (flags & TCP_SYN != 0).then_some(MSS);
+    link.send(&Tcp::build(self.remote, self.guest, seq, self.ack, flags, window, mss, payload));
+  }
+
+  fn in_flight(&self) -> usize {
+    self.seq.wrapping_sub(self.acked) as usize
+  }
+
+  fn closed(&self) -> bool {
+    self.fin_sent && self.fin_received && self.in_flight() == 0 && self.to_host.is_empty()
+  }
+
+  // segment from the guest, false -> the connection is gone
+  fn input(&mut self, segment: &Tcp, link: &Link) -> bool {
+    if segment.flags & TCP_RST != 0 { return false; }
+    match self.state {
+      TcpState::Connecting => return true,
+      TcpState::SynSent => {
+        if segment.flags & (TCP_SYN | TCP_ACK) == TCP_SYN | TCP_ACK && segment.ack == self.seq {
+          self.state = TcpState::Established;
+          self.ack = segment.seq.wrapping_add(1);
+          self.acked = self.seq;
+          self.window = segment.window as usize;
+          self.mss = segment.mss.unwrap_or(536) as usize;
+          self.timer = Instant::now();
+          self.send(link, self.seq, TCP_ACK, &[]);
+        }
+        return true;
+      },
+      TcpState::SynReceived => {
+        if segment.flags & TCP_SYN != 0 {
+          self.send(link, self.acked, TCP_SYN | TCP_ACK, &[]);
+          return true;
+        }
+        if segment.flags & TCP_ACK == 0 || segment.ack != self.seq { return true; }
+        self.state = TcpState::Established;
+      },
+      TcpState::Established => if segment.flags & TCP_SYN != 0 {
+        self.send(link, self.seq, TCP_ACK, &[]);
+        return true;
+      },
+    }
+    if segment.flags & TCP_ACK != 0 {
+      let acked = segment.ack.wrapping_sub(self.acked) as usize;
+      if acked <= self.in_flight() {
+        if acked > 0 {
+          self.unacked.drain(..acked.min(self.unacked.len()));
+          self.acked = segment.ack;
+          self.timer = Instant::now();
+        }
+        self.window = segment.window as usize;
+      }
+    }
+    let mut reply = false;
+    if !segment.payload.is_empty() {
+      // skip what was already received
+      let offset = self.ack.wrapping_sub(segment.seq) as usize;
+      if offset < segment.payload.len() && !self.fin_received {
+        // what doesn't fit in the advertised window is dropped and sent again later
+        let len = (segment.payload.len() - offset).min(WINDOW.saturating_sub(self.to_host.len()));
+        self.to_host.extend_from_slice(&segment.payload[offset..offset + len]);
+        self.ack = self.ack.wrapping_add(len as u32);
+      }
+      reply = true;
+    }
+    if segment.flags & TCP_FIN != 0 {
+      if !self.fin_received && segment.seq.wrapping_add(segment.payload.len() as u32) == self.ack {
+        self.fin_received = true;
+        self.ack = self.ack.wrapping_add(1);
+      }
+      reply = true;
+    }
+    if reply {
+      self.send(link, self.seq, TCP_ACK, &[]);
+    }
+    !self.closed()
+  }
+
+  // move data between the host and the guest, false -> the connection is gone
+  fn poll(&mut self, link: &Link) -> bool {
+    if self.state == TcpState::Connecting {
+      match self.connecting.as_ref().and_then(|connecting| connecting.try_recv()) {
+        None => return true,
+        Some(Some(stream)) => {
+          if stream.set_nonblocking(true).is_err() { return false; }
+          self.stream = Some(stream);
+          self.connecting = None;
+          self.state = TcpState::SynReceived;
+          self.send(link, self.seq, TCP_SYN | TCP_ACK, &[]);
+          self.seq = self.seq.wrapping_add(1);
+          self.timer = Instant::now();
+        },
+        Some(None) => {
+          self.send(link, 0, TCP_RST | TCP_ACK, &[]);
+          return false;
+        },
+      }
+    }
+    let Some(mut stream) = self.stream.as_ref() else { return false };
+    if !self.to_host.is_empty() {
+      match stream.write(&self.to_host) {
+        Ok(len) => {
+          let closed = WINDOW.saturating_sub(self.to_host.len()) < self.mss;
+          self.to_host.drain(..len);
+          // tell the guest the window is open again
+          if closed && WINDOW.saturating_sub(self.to_host.len()) >= self.mss {
+            self.send(link, self.seq, TCP_ACK, &[]);
+          }
+        },
+        Err(err) if err.kind() == ErrorKind::WouldBlock => {},
+        Err(_) => {
+          self.send(link, self.seq, TCP_RST | TCP_ACK, &[]);
+          return false;
+        },
+      }
+    }
+    if self.fin_received && self.to_host.is_empty() && !self.shutdown {
+      let _ = stream.shutdown(Shutdown::Write);
+      self.shutdown = true;
+    }
+    if self.state != TcpState::Established {
+      self.retransmit(link);
+      return true;
+    }
+    while !self.host_closed {
+      let room = self.window.saturating_sub(self.in_flight()).min(self.mss);
+      if room == 0 { break; }
+      let mut buf = vec![0; room];
+      match stream.read(&mut buf) {
+        Ok(0) => self.host_closed = true,
+        Ok(len) => {
+          if self.in_flight() == 0 { self.timer = Instant::now(); }
+          self.send(link, self.seq, TCP_PSH | TCP_ACK, &buf[..len]);
+          self.unacked.extend(&buf[..len]);
+          self.seq = self.seq.wrapping_add(len as u32);
+        },
+        Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+        Err(_) => {
+          self.send(link, self.seq, TCP_RST | TCP_ACK, &[]);
+          return false;
+        },
+      }
+    }
+    if self.host_closed && !self.fin_sent {
+      if self.in_flight() == 0 { self.timer = Instant::now(); }
+      self.send(link, self.seq, TCP_FIN | TCP_ACK, &[]);
+      self.seq = self.seq.wrapping_add(1);
+      self.fin_sent = true;
+    }
+    self.retransmit(link);
+    !self.closed()
+  }
+
+  // resend everything the guest hasn't acknowledged in time
+  fn retransmit(&mut self, link: &Link) {
+    if self.in_flight() == 0 || self.timer.elapsed() < RETRANSMIT { return; }
+    self.timer = Instant::now();
+    match self.state {
+      TcpState::SynSent => self.send(link, self.acked, TCP_SYN, &[]),
+      TcpState::SynReceived => self.send(link, self.acked, TCP_SYN | TCP_ACK, &[]),
+      TcpState::Established => {
+        let data = self.unacked.iter().copied().collect::<Vec<_>>();
+        let mut seq = self.acked;
+        for chunk in data.chunks(self.mss) {
+          self.send(link, seq, TCP_PSH | TCP_ACK, chunk);
+          seq = seq.wrapping_add(chunk.len() as u32);
+        }
+        if self.fin_sent {
+          self.send(link, seq, TCP_FIN | TCP_ACK, &[]);
+        }
+      },
+      TcpState::Connecting => {},
+    }
+  }
+}
+
+#[derive(Debug)]
+struct UdpFlow {
+  socket: UdpSocket,
+  last: Instant,
+}
+
+#[derive(Debug)]
+struct UdpForward {
+  socket: UdpSocket,
+  host: u16,
+  guest: u16,
+  // where the last datagram came from, replies go there
+  peer: Option<SocketAddr>,
+}
+
+// (guest, remote as seen by the guest)
+type Flow = (SocketAddrV4, SocketAddrV4);
+
+#[derive(Debug)]
+pub(crate) struct UserNet {
+  link: Link,
+  tcp: HashMap<Flow, TcpConnection>,
+  udp: HashMap<Flow, UdpFlow>,
+  listeners: Vec<(TcpListener, u16)>,
+  udp_forwards: Vec<UdpForward>,
+  next_port: u16,
+  isn: u32,
+}
+
+impl UserNet {
+  // forwarded ports are bound on the host's localhost
+  pub(crate) fn new(forwards: &[Forward], sender: Sender<Frame>) -> io::Result<UserNet> {
+    let mut listeners = Vec::new();
+    let mut udp_forwards = Vec::new();
+    for forward in forwards {
+      match forward.protocol {
+        Protocol::Tcp => {
+          let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, forward.host))?;
+          listener.set_nonblocking(true)?;
+          listeners.push((listener, forward.guest));
+        },
+        Protocol::Udp => {
+          let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, forward.host))?;
+          socket.set_nonblocking(true)?;
+          udp_forwards.push(UdpForward { socket, host: forward.host, guest: forward.guest, peer: None });
+        },
+      }
+    }
+    Ok(UserNet {
+      link: Link { sender, guest_mac: None },
+      tcp: HashMap::new(),
+      udp: HashMap::new(),
+      listeners,
+      udp_forwards,
+      next_port: EPHEMERAL_PORTS,
+      isn: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.subsec_nanos()),
+    })
+  }
+
+  fn next_isn(&mut self) -> u32 {
+    self.isn = self.isn.wrapping_add(64000);
+    self.isn
+  }
+
+  // frame from the guest
+  pub(crate) fn input(&mut self, frame: &[u8]) {
+    let Some(ethernet) = Ethernet::parse(frame) else { return };
+    self.link.guest_mac = Some(ethernet.src);
+    if ethernet.dst != GATEWAY_MAC && ethernet.dst != BROADCAST_MAC { return; }
+    match ethernet.ethertype {
+      ETHERTYPE_ARP => if let Some(arp) = Arp::parse(ethernet.payload) { self.arp(arp) },
+      ETHERTYPE_IPV4 => if let Some(ip) = Ipv4::parse(ethernet.payload) {
+        match ip.protocol {
+          PROTOCOL_ICMP => self.icmp(&ip),
+          PROTOCOL_UDP => if let Some(udp) = Udp::parse(ip.payload) { self.udp(&ip, &udp) },
+          PROTOCOL_TCP => if let Some(tcp) = Tcp::parse(ip.payload, ip.src, ip.dst) { self.tcp(&ip, &tcp) },
+          _ => {},
+        }
+      },
+      _ => {},
+    }
+  }
+
+  fn arp(&mut self, arp: Arp) {
+    if arp.operation != ARP_REQUEST || arp.target_ip != GATEWAY { return; }
+    let reply = Arp {
+      operation: ARP_REPLY,
+      sender_mac: GATEWAY_MAC,
+      sender_ip: GATEWAY,
+      target_mac: arp.sender_mac,
+      target_ip: arp.sender_ip,
+    };
+    self.link.sender.send(Ethernet::build(arp.sender_mac, GATEWAY_MAC, ETHERTYPE_ARP, &reply.build()));
+  }
+
+  fn icmp(&mut self, ip: &Ipv4) {
+    if ip.dst != GATEWAY || ip.payload.len() < 8 || ip.payload[0] != ICMP_ECHO_REQUEST || checksum(ip.payload, 0) != 0 { return; }
+    let mut reply = ip.payload.to_vec();
+    reply[0] = ICMP_ECHO_REPLY;
+    reply[2..4].copy_from_slice(&[0, 0]);
+    let sum = checksum(&reply, 0);
+    reply[2..4].copy_from_slice(&sum.to_be_bytes());
+    self.link.send(&Ipv4::build(GATEWAY, ip.src, PROTOCOL_ICMP, &reply));
+  }
+
+  fn dhcp(&mut self, request: &[u8]) {
+    if request.len() < 240 || request[0] != 1 || request[236..240] != DHCP_MAGIC { return; }
+    let mut kind = None;
+    let mut options = &request[240..];
+    while let [code, rest @ ..] = options {
+      match *code {
+        0 => options = rest,
+        255 => break,
+        _ => {
+          let Some(&len) = rest.first() else { break };
+          if rest.len() < 1 + len as usize { break; }
+          if *code == 53 && len == 1 { kind = Some(rest[1]); }
+          options = &rest[1 + len as usize..];
+        },
+      }
+    }
+    let kind = match kind {
+      Some(DHCP_DISCOVER) => DHCP_OFFER,
+      Some(DHCP_REQUEST) => DHCP_ACK,
+      _ => return,
+    };
+    let mut reply = vec![0; 240];
+    // reply, ethernet, 6 byte addresses
+    reply[0..3].copy_from_slice(&[2, 1, 6]);
+    // transaction id
+    reply[4..8].copy_from_slice(&request[4..8]);
+    // flags
+    reply[10..12].copy_from_slice(&request[10..12]);
+    reply[16..20].copy_from_slice(&GUEST.octets());
+    reply[20..24].copy_from_slice(&GATEWAY.octets());
+    // client hardware address
+    reply[28..44].copy_from_slice(&request[28..44]);
+    reply[236..240].copy_from_slice(&DHCP_MAGIC);
+    reply.extend_from_slice(&[53, 1, kind]);
+    // server identifier
+    reply.extend_from_slice(&[54, 4]);
+    reply.extend_from_slice(&GATEWAY.octets());
+    // lease time
+    reply.extend_from_slice(&[51, 4]);
+    reply.extend_from_slice(&DHCP_LEASE.to_be_bytes());
+    reply.extend_from_slice(&[1, 4]);
+    reply.extend_from_slice(&NETMASK.octets());
+    // router
+    reply.extend_from_slice(&[3, 4]);
+    reply.extend_from_slice(&GATEWAY.octets());
+    reply.push(255);
+    self.link.send(&Udp::build(
+      SocketAddrV4::new(GATEWAY, DHCP_SERVER_PORT),
+      SocketAddrV4::new(Ipv4Addr::BROADCAST, DHCP_CLIENT_PORT),
+      &reply,
+    ));
+  }
+
+  fn udp(&mut self, ip: &Ipv4, udp: &Udp) {
+    if udp.dst_port == DHCP_SERVER_PORT && udp.src_port == DHCP_CLIENT_PORT {
+      return self.dhcp(udp.payload);
+    }
+    if ip.dst == GATEWAY {
+      let forward = self.udp_forwards.iter().find(|forward| forward.host == udp.dst_port && forward.guest == udp.src_port);
+      if let Some(UdpForward { socket, peer: Some(peer), .. }) = forward {
+        let _ = socket.send_to(udp.payload, peer);
+        return;
+      }
+    }
+    if ip.dst.is_broadcast() || ip.dst.is_multicast() { return; }
+    let flow = (SocketAddrV4::new(ip.src, udp.src_port), SocketAddrV4::new(ip.dst, udp.dst_port));
+    let flow = match self.udp.entry(flow) {
+      Entry::Occupied(entry) => entry.into_mut(),
+      Entry::Vacant(entry) => {
+        let Ok(socket) = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)) else { return };
+        if socket.connect(host_address(flow.1)).is_err() || socket.set_nonblocking(true).is_err() { return; }
+        entry.insert(UdpFlow { socket, last: Instant::now() })
+      },
+    };
+    flow.last = Instant::now();
+    let _ = flow.socket.send(udp.payload);
+  }
+
+  fn tcp(&mut self, ip: &Ipv4, tcp: &Tcp) {
+    let flow = (SocketAddrV4::new(ip.src, tcp.src_port), SocketAddrV4::new(ip.dst, tcp.dst_port));
+    if let Some(connection) = self.tcp.get_mut(&flow) {
+      if !connection.input(tcp, &self.link) {
+        self.tcp.remove(&flow);
+      }
+      return;
+    }
+    if tcp.flags & TCP_RST != 0 { return; }
+    if tcp.flags & (TCP_SYN | TCP_ACK) != TCP_SYN {
+      // nothing is listening
+      let mut connection = TcpConnection::new(flow.0, flow.1, TcpState::Established, tcp.ack);
+      let len = tcp.payload.len() as u32 + (tcp.flags & (TCP_SYN | TCP_FIN) != 0) as u32;
+      connection.ack = tcp.seq.wrapping_add(len);
+      let flags = if tcp.flags & TCP_ACK != 0 { TCP_RST } else { TCP_RST | TCP_ACK };
+      connection.send(&self.link, tcp.ack, flags, &[]);
+      return;
+    }
+    let (sender, receiver) = channel();
+    let address = host_address(flow.1);
+    thread::spawn(move || sender.send(TcpStream::connect_timeout(&address, CONNECT_TIMEOUT).ok()));
+    let mut connection = TcpConnection::new(flow.0, flow.1, TcpState::Connecting, self.next_isn());
+    connection.connecting = Some(receiver);
+    connection.ack = tcp.seq.wrapping_add(1);
+    connection.window = tcp.window as usize;
+    connection.mss = tcp.mss.unwrap_or(536) as usize;
+    self.tcp.insert(flow, connection);
+  }
+
+  pub(crate) fn poll(&mut self) {
+    for index in 0..self.listeners.len() {
+      while let Ok((stream, _)) = self.listeners[index].0.accept() {
+        if stream.set_nonblocking(true).is_err() { continue; }
+        let guest = SocketAddrV4::new(GUEST, self.listeners[index].1);
+        let remote = SocketAddrV4::new(GATEWAY, self.next_port);
+        self.next_port = self.next_port.checked_add(1).unwrap_or(EPHEMERAL_PORTS);
+        let mut connection = TcpConnection::new(guest, remote, TcpState::SynSent, self.next_isn());
+        connection.stream = Some(stream);
+        connection.send(&self.link, connection.seq, TCP_SYN, &[]);
+        connection.seq = connection.seq.wrapping_add(1);
+        self.tcp.insert((guest, remote), connection);
+      }
+    }
+    let link = &self.link;
+    self.tcp.retain(|_, connection| connection.poll(link));
+
+    let mut buf = vec![0; 65536];
+    for forward in &mut self.udp_forwards {
+      while let Ok((len, peer)) = forward.socket.recv_from(&mut buf) {
+        forward.peer = Some(peer);
+        link.send(&Udp::build(SocketAddrV4::new(GATEWAY, forward.host), SocketAddrV4::new(GUEST, forward.guest), &buf[..len]));
+      }
+    }
+    self.udp.retain(|(guest, remote), flow| {
+      while let Ok(len) = flow.socket.recv(&mut buf) {
+        flow.last = Instant::now();
+        link.send(&Udp::build(*remote, *guest, &buf[..len]));
+      }
+      flow.last.elapsed() < UDP_TIMEOUT
+    });
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{io::{Read, Write}, net::{Ipv4Addr, SocketAddrV4, TcpListener}, thread, time::Duration};
+
+  use crate::utils::channel::{Receiver, channel};
+  use super::{UserNet, GATEWAY, GATEWAY_MAC, GUEST};
+  use super::super::{Frame, packet::{Ethernet, Ipv4, Tcp, ETHERTYPE_IPV4, TCP_ACK, TCP_FIN, TCP_PSH, TCP_SYN}};
+
+  const GUEST_MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+
+  fn send(net: &mut UserNet, guest: SocketAddrV4, remote: SocketAddrV4, seq: u32, ack: u32, flags: u8, payload: &[u8]) {
+    let packet = Tcp::build(guest, remote, seq, ack, flags, 65535, None, payload);
+    net.input(&Ethernet::build(GATEWAY_MAC, GUEST_MAC, ETHERTYPE_IPV4, &packet));
+  }
+
+  // next segment for the guest as (seq, ack, flags, payload)
+  fn receive(net: &mut UserNet, frames: &Receiver<Frame>) -> (u32, u32, u8, Vec<u8>) {
+    for _ in 0..1000 {
+      net.poll();
+      if let Some(frame) = frames.try_recv() {
+        let ip = Ipv4::parse(&frame[14..]).unwrap();
+        let tcp = Tcp::parse(ip.payload, ip.src, ip.dst).unwrap();
+        return (tcp.seq, tcp.ack, tcp.flags, tcp.payload.to_vec());
+      }
+      thread::sleep(Duration::from_millis(1));
+    }
+    panic!("no segment for the guest");
+  }
+
+  // the guest connects to the gateway, which is the host's localhost
+  #[test]
+  fn tcp_proxy() {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let (sender, frames) = channel();
+    let mut net = UserNet::new(&[], sender).unwrap();
+    let guest = SocketAddrV4::new(GUEST, 40000);
+    let remote = SocketAddrV4::new(GATEWAY, listener.local_addr().unwrap().port());
+
+    send(&mut net, guest, remote, 100, 0, TCP_SYN, &[]);
+    let (mut host, _) = listener.accept().unwrap();
+    let (seq, ack, flags, _) = receive(&mut net, &frames);
+    assert_eq!((ack, flags), (101, TCP_SYN | TCP_ACK));
+    let seq = seq.wrapping_add(1);
+    send(&mut net, guest, remote, 101, seq, TCP_ACK, &[]);
+
+    send(&mut net, guest, remote, 101, seq, TCP_PSH | TCP_ACK, b"ping");
+    assert_eq!(receive(&mut net, &frames), (seq, 105, TCP_ACK, vec![]));
+    net.poll();
+    let mut buf = [0; 4];
+    host.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"ping");
+
+    host.write_all(b"pong").unwrap();
+    drop(host);
+    assert_eq!(receive(&mut net, &frames), (seq, 105, TCP_PSH | TCP_ACK, b"pong".to_vec()));
+    assert_eq!(receive(&mut net, &frames), (seq.wrapping_add(4), 105, TCP_FIN | TCP_ACK, vec![]));
+    send(&mut net, guest, remote, 105, seq.wrapping_add(5), TCP_FIN | TCP_ACK, &[]);
+    assert_eq!(receive(&mut net, &frames), (seq.wrapping_add(5), 106, TCP_ACK, vec![]));
+    assert!(net.tcp.is_empty());
+  }
+
+  // a guest that ignores the window gets only what fits acknowledged
+  #[test]
+  fn tcp_window() {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let (sender, frames) = channel();
+    let mut net = UserNet::new(&[], sender).unwrap();
+    let guest = SocketAddrV4::new(GUEST, 40001);
+    let remote = SocketAddrV4::new(GATEWAY, listener.local_addr().unwrap().port());
+
+    send(&mut net, guest, remote, 100, 0, TCP_SYN, &[]);
+    let (mut host, _) = listener.accept().unwrap();
+    let (seq, _, _, _) = receive(&mut net, &frames);
+    let seq = seq.wrapping_add(1);
+    send(&mut net, guest, remote, 101, seq, TCP_ACK, &[]);
+
+    // without a poll in between nothing reaches the host
+    let data = vec![0x5a; 1460];
+    let mut sent = 0u32;
+    while sent < 80000 {
+      send(&mut net, guest, remote, 101 + sent, seq, TCP_PSH | TCP_ACK, &data);
+      sent += data.len() as u32;
+    }
+    let mut last = None;
+    while let Some(frame) = frames.try_recv() {
+      let ip = Ipv4::parse(&frame[14..]).unwrap();
+      let tcp = Tcp::parse(ip.payload, ip.src, ip.dst).unwrap();
+      last = Some((tcp.ack, tcp.window));
+    }
+    assert_eq!(last, Some((101 + 65535, 0)));
+
+    net.poll();
+    let mut buf = vec![0; 65535];
+    host.read_exact(&mut buf).unwrap();
+    assert!(buf.iter().all(|&byte| byte == 0x5a));
+  }
+}
@@ -0,0 +1,100 @@
+use std::{io, path::{Path, PathBuf}, str::FromStr};
+
+use crate::devices::bus::Bus;
+
+use self::server::{Server, Ids};
+
+use super::{VirtioDevice, queue::Queue};
+
+pub(crate) mod server;
+pub(crate) mod wire;
+
+const DEVICE_ID: u32 = 9;
+
+const VIRTIO_9P_MOUNT_TAG: u64 = 1 << 0;
+
+// Host directory shared over 9P2000.L, mounted by its tag.
+#[derive(Debug)]
+pub(crate) struct VirtioP9 {
+  tag: String,
+  server: Server,
+}
+
+impl VirtioP9 {
+  pub(crate) fn new(options: &ShareOptions) -> io::Result<VirtioP9> {
+    Ok(VirtioP9 {
+      tag: options.tag.clone(),
+      server: Server::new(&options.path, options.read_only, options.ids, options.devices)?,
+    })
+  }
+}
+
+impl VirtioDevice for VirtioP9 {
+  fn device_id(&self) -> u32 {
+    DEVICE_ID
+  }
+
+  fn features(&self) -> u64 {
+    VIRTIO_9P_MOUNT_TAG
+  }
+
+  fn queue_count(&self) -> usize {
+    1
+  }
+
+  fn read_config(&self, offset: u64) -> u8 {
+    // tag length, then the tag without a terminator
+    match offset as usize {
+      0..=1 => (self.tag.len() as u16).to_le_bytes()[offset as usize],
+      offset => self.tag.as_bytes().get(offset - 2).copied().unwrap_or(0),
+    }
+  }
+
+  fn notify(&mut self, queue: usize, queues: &mut [Queue], bus: &mut Bus) {
+    while let Some(chain) = queues[queue].pop(bus) {
+      // longer requests are cut off at msize, and answered with an error
+      let mut request = vec![0; chain.readable().min(self.server.msize() as usize)];
+      let len = if chain.read_at(bus, 0, &mut request).is_ok() {
+        let response = self.server.handle(&request, chain.writable());
+        if chain.write_at(bus, 0, &response).is_ok() { response.len() } else { 0 }
+      } else {
+        0
+      };
+      queues[queue].push(bus, chain, len as u32);
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ShareOptions {
+  pub(crate) path: PathBuf,
+  pub(crate) tag: String,
+  pub(crate) read_only: bool,
+  pub(crate) ids: Ids,
+  // the guest may create device nodes
+  pub(crate) devices: bool,
+}
+
+// "path,tag=name[,ro][,ids=passthrough|root|uid:gid][,devices]"
+impl FromStr for ShareOptions {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let mut parts = s.split(',');
+    let path = Path::new(parts.next().unwrap_or_default()).to_path_buf();
+    let mut options = ShareOptions { path, tag: String::new(), read_only: false, ids: Ids::Passthrough, devices: false };
+    for part in parts {
+      match part.split_once('=') {
+        Some(("tag", tag)) => options.tag = tag.to_string(),
+        Some(("ids", ids)) => options.ids = ids.parse()?,
+        None if part == "ro" => options.read_only = true,
+        None if part == "devices" => options.devices = true,
+        _ => return Err(format!("unknown share option '{}'", part)),
+      }
+    }
+    if options.path.as_os_str().is_empty() || options.tag.is_empty() {
+      return Err(format!("'{}' needs a path and tag=name", s));
+    }
+    Ok(options)
+  }
+}
@@ -0,0 +1,846 @@
+use std::{collections::HashMap, ffi::{CStr, CString, OsStr}, fs::{File, Metadata, OpenOptions}, io, os::{fd::{AsRawFd, FromRawFd, OwnedFd}, unix::{ffi::OsStrExt, fs::{FileExt, MetadataExt, OpenOptionsExt}}}, path::{Path, PathBuf}, str::FromStr};
+
+use super::wire::{Qid, Reader, Writer, QID_SIZE};
+
+// 9P2000.L server exporting a host directory
+
+pub(crate) type Errno = u32;
+
+pub(crate) const EINVAL: Errno = libc::EINVAL as Errno;
+const EIO: Errno = libc::EIO as Errno;
+const EBADF: Errno = libc::EBADF as Errno;
+const ENOTDIR: Errno = libc::ENOTDIR as Errno;
+const EISDIR: Errno = libc::EISDIR as Errno;
+const EROFS: Errno = libc::EROFS as Errno;
+const ENODATA: Errno = libc::ENODATA as Errno;
+const EOPNOTSUPP: Errno = libc::EOPNOTSUPP as Errno;
+const EMSGSIZE: Errno = libc::EMSGSIZE as Errno;
+const EPERM: Errno = libc::EPERM as Errno;
+
+const RLERROR: u8 = 7;
+const TSTATFS: u8 = 8;
+const TLOPEN: u8 = 12;
+const TLCREATE: u8 = 14;
+const TSYMLINK: u8 = 16;
+const TMKNOD: u8 = 18;
+const TRENAME: u8 = 20;
+const TREADLINK: u8 = 22;
+const TGETATTR: u8 = 24;
+const TSETATTR: u8 = 26;
+const TXATTRWALK: u8 = 30;
+const TREADDIR: u8 = 40;
+const TFSYNC: u8 = 50;
+const TLOCK: u8 = 52;
+const TGETLOCK: u8 = 54;
+const TLINK: u8 = 70;
+const TMKDIR: u8 = 72;
+const TRENAMEAT: u8 = 74;
+const TUNLINKAT: u8 = 76;
+const TVERSION: u8 = 100;
+const TATTACH: u8 = 104;
+const TFLUSH: u8 = 108;
+const TWALK: u8 = 110;
+const TREAD: u8 = 116;
+const TWRITE: u8 = 118;
+const TCLUNK: u8 = 120;
+const TREMOVE: u8 = 122;
+
+const VERSION: &[u8] = b"9P2000.L";
+pub(crate) const MSIZE_MAX: u32 = 512 * 1024;
+// size, type, tag
+const HEADER_SIZE: usize = 7;
+
+const QID_DIR: u8 = 0x80;
+const QID_SYMLINK: u8 = 0x02;
+const QID_FILE: u8 = 0x00;
+
+// linux open flags, the same on every architecture yuri runs on
+const O_ACCMODE: u32 = 0o3;
+const O_WRONLY: u32 = 0o1;
+const O_CREAT: u32 = 0o100;
+const O_EXCL: u32 = 0o200;
+const O_TRUNC: u32 = 0o1000;
+const O_APPEND: u32 = 0o2000;
+
+const AT_REMOVEDIR: u32 = 0x200;
+
+const GETATTR_BASIC: u64 = 0x7ff;
+
+const SETATTR_MODE: u32 = 0x1;
+const SETATTR_UID: u32 = 0x2;
+const SETATTR_GID: u32 = 0x4;
+const SETATTR_SIZE: u32 = 0x8;
+const SETATTR_ATIME: u32 = 0x10;
+const SETATTR_MTIME: u32 = 0x20;
+const SETATTR_ATIME_SET: u32 = 0x80;
+const SETATTR_MTIME_SET: u32 = 0x100;
+
+const LOCK_SUCCESS: u8 = 0;
+const LOCK_TYPE_UNLCK: u8 = 2;
+
+fn errno(err: io::Error) -> Errno {
+  err.raw_os_error().map_or(EIO, |errno| errno as Errno)
+}
+
+fn last_errno() -> Errno {
+  errno(io::Error::last_os_error())
+}
+
+fn check(result: libc::c_int) -> Result<(), Errno> {
+  if result == 0 { Ok(()) } else { Err(last_errno()) }
+}
+
+fn openat(dir: &OwnedFd, name: &CStr, flags: libc::c_int, mode: u32) -> Result<OwnedFd, Errno> {
+  let fd = unsafe { libc::openat(dir.as_raw_fd(), name.as_ptr(), flags | libc::O_CLOEXEC, mode as libc::c_uint) };
+  if fd < 0 { return Err(last_errno()); }
+  Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+// metadata of name in dir, a symlink is not followed
+fn metadata_at(dir: &OwnedFd, name: &CStr) -> Result<Metadata, Errno> {
+  File::from(openat(dir, name, libc::O_PATH | libc::O_NOFOLLOW, 0)?).metadata().map_err(errno)
+}
+
+// names in a directory, without . and ..
+fn list(dir: &OwnedFd) -> Result<Vec<CString>, Errno> {
+  let fd = openat(dir, c".", libc::O_RDONLY | libc::O_DIRECTORY, 0)?;
+  let stream = unsafe { libc::fdopendir(fd.as_raw_fd()) };
+  if stream.is_null() { return Err(last_errno()); }
+  // the stream owns the fd now
+  std::mem::forget(fd);
+  let mut names = Vec::new();
+  loop {
+    let entry = unsafe { libc::readdir(stream) };
+    if entry.is_null() { break; }
+    let name = unsafe { CStr::from_ptr((*entry).d_name.as_ptr()) };
+    if name != c"." && name != c".." {
+      names.push(name.to_owned());
+    }
+  }
+  unsafe { libc::closedir(stream); }
+  Ok(names)
+}
+
+fn qid(metadata: &Metadata) -> Qid {
+  let kind = if metadata.is_dir() {
+    QID_DIR
+  } else if metadata.file_type().is_symlink() {
+    QID_SYMLINK
+  } else {
+    QID_FILE
+  };
+  Qid { kind, version: metadata.mtime() as u32 ^ metadata.mtime_nsec() as u32, path: metadata.ino() }
+}
+
+// dirent type of readdir
+fn dirent_type(metadata: &Metadata) -> u8 {
+  (metadata.mode() >> 12) as u8
+}
+
+// owners the guest sees
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Ids {
+  // the host's, chown goes to the host
+  Passthrough,
+  // every file is owned by uid:gid and chown is ignored
+  Fixed(u32, u32),
+}
+
+// "passthrough", "root" or "uid:gid"
+impl FromStr for Ids {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "passthrough" => Ok(Ids::Passthrough),
+      "root" => Ok(Ids::Fixed(0, 0)),
+      _ => s.split_once(':')
+        .and_then(|(uid, gid)| Some(Ids::Fixed(uid.parse().ok()?, gid.parse().ok()?)))
+        .ok_or_else(|| format!("invalid id mapping '{}', expected passthrough, root or uid:gid", s)),
+    }
+  }
+}
+
+#[derive(Debug)]
+struct Fid {
+  path: PathBuf,
+  file: Option<File>,
+  // directory snapshot taken by readdir at offset 0
+  entries: Option<Vec<(Qid, u8, Vec<u8>)>>,
+  // from xattrwalk, reads as empty
+  xattr: bool,
+}
+
+impl Fid {
+  fn new(path: PathBuf) -> Fid {
+    Fid { path, file: None, entries: None, xattr: false }
+  }
+}
+
+// a path under the root, named relative to its open directory so that
+// nothing outside the root can be reached through a symlink
+#[derive(Debug)]
+struct Entry {
+  dir: OwnedFd,
+  name: CString,
+  path: PathBuf,
+}
+
+#[derive(Debug)]
+pub(crate) struct Server {
+  root: PathBuf,
+  root_fd: OwnedFd,
+  read_only: bool,
+  ids: Ids,
+  // mknod may create block and character devices on the host
+  devices: bool,
+  msize: u32,
+  fids: HashMap<u32, Fid>,
+}
+
+impl Server {
+  pub(crate) fn new(root: &Path, read_only: bool, ids: Ids, devices: bool) -> io::Result<Server> {
+    let root = root.canonicalize()?;
+    if !root.is_dir() {
+      return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} is not a directory", root.display())));
+    }
+    let root_fd = OpenOptions::new().read(true).custom_flags(libc::O_PATH | libc::O_DIRECTORY).open(&root)?.into();
+    Ok(Server { root, root_fd, read_only, ids, devices, msize: MSIZE_MAX, fids: HashMap::new() })
+  }
+
+  // the negotiated limit of messages in both directions
+  pub(crate) fn msize(&self) -> u32 {
+    self.msize
+  }
+
+  // handle a T-message, the R-message can't be longer than max
+  pub(crate) fn handle(&mut self, request: &[u8], max: usize) -> Vec<u8> {
+    let mut reader = Reader::new(request);
+    let (Ok(size), Ok(kind), Ok(tag)) = (reader.u32(), reader.u8(), reader.u16()) else { return Vec::new() };
+    let max = max.min(self.msize as usize).saturating_sub(HEADER_SIZE);
+    let mut writer = Writer::new();
+    let result = if size > self.msize { Err(EMSGSIZE) } else { self.dispatch(kind, &mut reader, &mut writer, max) };
+    let kind = match result {
+      Ok(()) => kind + 1,
+      Err(errno) => {
+        writer = Writer::new();
+        writer.u32(errno);
+        RLERROR
+      },
+    };
+    let mut response = Writer::new();
+    response.u32((HEADER_SIZE + writer.len()) as u32).u8(kind).u16(tag).bytes(&writer.data);
+    response.data
+  }
+
+  fn dispatch(&mut self, kind: u8, r: &mut Reader, w: &mut Writer, max: usize) -> Result<(), Errno> {
+    match kind {
+      TVERSION => self.version(r, w),
+      TATTACH => self.attach(r, w),
+      TWALK => self.walk(r, w),
+      TLOPEN => self.lopen(r, w),
+      TLCREATE => self.lcreate(r, w),
+      TREAD => self.read(r, w, max),
+      TWRITE => self.write(r, w),
+      TCLUNK => {
+        self.fids.remove(&r.u32()?).ok_or(EBADF)?;
+        Ok(())
+      },
+      TREMOVE => self.remove(r),
+      TGETATTR => self.getattr(r, w),
+      TSETATTR => self.setattr(r),
+      TREADDIR => self.readdir(r, w, max),
+      TSTATFS => self.statfs(r, w),
+      TMKDIR => self.mkdir(r, w),
+      TSYMLINK => self.symlink(r, w),
+      TMKNOD => self.mknod(r, w),
+      TREADLINK => self.readlink(r, w),
+      TLINK => self.link(r),
+      TRENAME => self.rename(r),
+      TRENAMEAT => self.renameat(r),
+      TUNLINKAT => self.unlinkat(r),
+      TFSYNC => {
+        if let Some(file) = &self.fid(r.u32()?)?.file {
+          file.sync_all().map_err(errno)?;
+        }
+        Ok(())
+      },
+      TXATTRWALK => self.xattrwalk(r, w),
+      TLOCK => {
+        self.fid(r.u32()?)?;
+        w.u8(LOCK_SUCCESS);
+        Ok(())
+      },
+      TGETLOCK => self.getlock(r, w),
+      // requests are handled in order, there is nothing to flush
+      TFLUSH => Ok(()),
+      // no authentication or extended attributes
+      _ => Err(EOPNOTSUPP),
+    }
+  }
+
+  fn fid(&self, fid: u32) -> Result<&Fid, Errno> {
+    self.fids.get(&fid).ok_or(EBADF)
+  }
+
+  fn check_writable(&self) -> Result<(), Errno> {
+    if self.read_only { Err(EROFS) } else { Ok(()) }
+  }
+
+  // opens a directory under the root one component at a time, failing on any
+  // symlink, even one swapped in after the fid was walked
+  fn directory(&self, path: &Path) -> Result<OwnedFd, Errno> {
+    let relative = path.strip_prefix(&self.root).map_err(|_| EINVAL)?;
+    let mut dir = openat(&self.root_fd, c".", libc::O_PATH | libc::O_DIRECTORY, 0)?;
+    for component in relative.components() {
+      let name = CString::new(component.as_os_str().as_bytes()).map_err(|_| EINVAL)?;
+      dir = openat(&dir, &name, libc::O_PATH | libc::O_DIRECTORY | libc::O_NOFOLLOW, 0)?;
+    }
+    Ok(dir)
+  }
+
+  fn entry(&self, path: PathBuf) -> Result<Entry, Errno> {
+    if path == self.root {
+      return Ok(Entry { dir: self.directory(&path)?, name: c".".to_owned(), path });
+    }
+    let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else { return Err(EINVAL) };
+    let name = CString::new(name.as_bytes()).map_err(|_| EINVAL)?;
+    Ok(Entry { dir: self.directory(parent)?, name, path })
+  }
+
+  // name of a new entry in dir
+  fn child(&self, dir: u32, name: &[u8]) -> Result<Entry, Errno> {
+    if name.is_empty() || name == b"." || name == b".." || name.contains(&b'/') || name.contains(&0) {
+      return Err(EINVAL);
+    }
+    self.entry(self.fid(dir)?.path.join(OsStr::from_bytes(name)))
+  }
+
+  fn metadata(&self, path: &Path) -> Result<Metadata, Errno> {
+    let entry = self.entry(path.to_path_buf())?;
+    metadata_at(&entry.dir, &entry.name)
+  }
+
+  // the group of a new file, when the host's ids are used
+  fn set_gid(&self, entry: &Entry, gid: u32) {
+    if self.ids == Ids::Passthrough {
+      unsafe { libc::fchownat(entry.dir.as_raw_fd(), entry.name.as_ptr(), u32::MAX, gid, libc::AT_SYMLINK_NOFOLLOW); }
+    }
+  }
+
+  fn version(&mut self, r: &mut Reader, w: &mut Writer) -> Result<(), Errno> {
+    let msize = r.u32()?.min(MSIZE_MAX);
+    let version = r.string()?;
+    self.fids.clear();
+    self.msize = msize;
+    w.u32(msize).string(if version.starts_with(VERSION) { VERSION } else { b"unknown" });
+    Ok(())
+  }
+
+  fn attach(&mut self, r: &mut Reader, w: &mut Writer) -> Result<(), Errno> {
+    let fid = r.u32()?;
+    let _afid = r.u32()?;
+    let _uname = r.string()?;
+    let _aname = r.string()?;
+    let metadata = self.metadata(&self.root)?;
+    self.fids.insert(fid, Fid::new(self.root.clone()));
+    w.qid(qid(&metadata));
+    Ok(())
+  }
+
+  fn walk(&mut self, r: &mut Reader, w: &mut Writer) -> Result<(), Errno> {
+    let fid = r.u32()?;
+    let newfid = r.u32()?;
+    let count = r.u16()?;
+    let names = (0..count).map(|_| r.string()).collect::<Result<Vec<_>, _>>()?;
+    let mut path = self.fid(fid)?.path.clone();
+    let mut qids = Vec::new();
+    for name in &names {
+      // symlinks are never followed
+      if !self.metadata(&path)?.is_dir() {
+        if qids.is_empty() { return Err(ENOTDIR); }
+        break;
+      }
+      let next = match *name {
+        b".." if path == self.root => path.clone(),
+        b".." => path.parent().map_or_else(|| self.root.clone(), Path::to_path_buf),
+        b"." => path.clone(),
+        _ if name.is_empty() || name.contains(&b'/') || name.contains(&0) => return Err(EINVAL),
+        _ => path.join(OsStr::from_bytes(name)),
+      };
+      match self.metadata(&next) {
+        Ok(metadata) => {
+          qids.push(qid(&metadata));
+          path = next;
+        },
+        Err(errno) if qids.is_empty() => return Err(errno),
+        Err(_) => break,
+      }
+    }
+    // only a complete walk creates newfid
+    if qids.len() == names.len() {
+      self.fids.insert(newfid, Fid::new(path));
+    }
+    w.u16(qids.len() as u16);
+    for qid in qids {
+      w.qid(qid);
+    }
+    Ok(())
+  }
+
+  fn open_flags(&self, flags: u32) -> Result<libc::c_int, Errno> {
+    let access = flags & O_ACCMODE;
+    if access != 0 || flags & (O_CREAT | O_TRUNC) != 0 {
+      self.check_writable()?;
+    }
+    let mut oflags = match access {
+      0 => libc::O_RDONLY,
+      O_WRONLY => libc::O_WRONLY,
+      _ => libc::O_RDWR,
+    } | libc::O_NOFOLLOW;
+    if flags & O_APPEND != 0 { oflags |= libc::O_APPEND; }
+    if flags & O_TRUNC != 0 { oflags |= libc::O_TRUNC; }
+    Ok(oflags)
+  }
+
+  fn lopen(&mut self, r: &mut Reader, w: &mut Writer) -> Result<(), Errno> {
+    let fid = r.u32()?;
+    let flags = r.u32()?;
+    let entry = self.entry(self.fid(fid)?.path.clone())?;
+    let metadata = metadata_at(&entry.dir, &entry.name)?;
+    let file = if metadata.is_dir() {
+      if flags & O_ACCMODE != 0 { return Err(EISDIR); }
+      openat(&entry.dir, &entry.name, libc::O_RDONLY | libc::O_DIRECTORY | libc::O_NOFOLLOW, 0)?
+    } else {
+      openat(&entry.dir, &entry.name, self.open_flags(flags & !(O_CREAT | O_EXCL))?, 0)?
+    };
+    let fid = self.fids.get_mut(&fid).ok_or(EBADF)?;
+    fid.file = Some(file.into());
+    fid.entries = None;
+    w.qid(qid(&metadata)).u32(0);
+    Ok(())
+  }
+
+  fn lcreate(&mut self, r: &mut Reader, w: &mut Writer) -> Result<(), Errno> {
+    let fid = r.u32()?;
+    let entry = self.child(fid, r.string()?)?;
+    let flags = r.u32()?;
+    let mode = r.u32()?;
+    let gid = r.u32()?;
+    let oflags = self.open_flags(flags)? | libc::O_CREAT | if flags & O_EXCL != 0 { libc::O_EXCL } else { 0 };
+    let file = File::from(openat(&entry.dir, &entry.name, oflags, mode & 0o7777)?);
+    self.set_gid(&entry, gid);
+    let metadata = file.metadata().map_err(errno)?;
+    let fid = self.fids.get_mut(&fid).ok_or(EBADF)?;
+    *fid = Fid { path: entry.path, file: Some(file), entries: None, xattr: false };
+    w.qid(qid(&metadata)).u32(0);
+    Ok(())
+  }
+
+  fn read(&mut self, r: &mut Reader, w: &mut Writer, max: usize) -> Result<(), Errno> {
+    let fid = self.fid(r.u32()?)?;
+    let offset = r.u64()?;
+    // count
+    let count = (r.u32()? as usize).min(max.saturating_sub(4));
+    if fid.xattr {
+      w.u32(0);
+      return Ok(());
+    }
+    let file = fid.file.as_ref().ok_or(EBADF)?;
+    let mut buf = vec![0; count];
+    let len = file.read_at(&mut buf, offset).map_err(errno)?;
+    w.u32(len as u32).bytes(&buf[..len]);
+    Ok(())
+  }
+
+  fn write(&mut self, r: &mut Reader, w: &mut Writer) -> Result<(), Errno> {
+    let fid = self.fid(r.u32()?)?;
+    let offset = r.u64()?;
+    let count = r.u32()? as usize;
+    let data = r.bytes(count)?;
+    let file = fid.file.as_ref().ok_or(EBADF)?;
+    let len = file.write_at(data, offset).map_err(errno)?;
+    w.u32(len as u32);
+    Ok(())
+  }
+
+  fn remove(&mut self, r: &mut Reader) -> Result<(), Errno> {
+    // the fid is clunked even if the remove fails
+    let fid = self.fids.remove(&r.u32()?).ok_or(EBADF)?;
+    self.check_writable()?;
+    if fid.path == self.root { return Err(libc::EBUSY as Errno); }
+    let entry = self.entry(fid.path)?;
+    let flags = if metadata_at(&entry.dir, &entry.name)?.is_dir() { libc::AT_REMOVEDIR } else { 0 };
+    check(unsafe { libc::unlinkat(entry.dir.as_raw_fd(), entry.name.as_ptr(), flags) })
+  }
+
+  fn getattr(&mut self, r: &mut Reader, w: &mut Writer) -> Result<(), Errno> {
+    let fid = self.fid(r.u32()?)?;
+    let _mask = r.u64()?;
+    let metadata = self.metadata(&fid.path)?;
+    let (uid, gid) = match self.ids {
+      Ids::Passthrough => (metadata.uid(), metadata.gid()),
+      Ids::Fixed(uid, gid) => (uid, gid),
+    };
+    w.u64(GETATTR_BASIC).qid(qid(&metadata))
+      .u32(metadata.mode()).u32(uid).u32(gid)
+      .u64(metadata.nlink()).u64(metadata.rdev()).u64(metadata.size())
+      .u64(metadata.blksize()).u64(metadata.blocks())
+      .u64(metadata.atime() as u64).u64(metadata.atime_nsec() as u64)
+      .u64(metadata.mtime() as u64).u64(metadata.mtime_nsec() as u64)
+      .u64(metadata.ctime() as u64).u64(metadata.ctime_nsec() as u64)
+      // btime, gen, data_version
+      .u64(0).u64(0).u64(0).u64(0);
+    Ok(())
+  }
+
+  fn setattr(&mut self, r: &mut Reader) -> Result<(), Errno> {
+    let path = self.fid(r.u32()?)?.path.clone();
+    let valid = r.u32()?;
+    let mode = r.u32()?;
+    let uid = r.u32()?;
+    let gid = r.u32()?;
+    let size = r.u64()?;
+    let atime = (r.u64()?, r.u64()?);
+    let mtime = (r.u64()?, r.u64()?);
+    self.check_writable()?;
+    let entry = self.entry(path)?;
+    let (dir, name) = (entry.dir.as_raw_fd(), entry.name.as_ptr());
+    let metadata = metadata_at(&entry.dir, &entry.name)?;
+    if valid & SETATTR_MODE != 0 && !metadata.file_type().is_symlink() {
+      check(unsafe { libc::fchmodat(dir, name, mode & 0o7777, libc::AT_SYMLINK_NOFOLLOW) })?;
+    }
+    if valid & (SETATTR_UID | SETATTR_GID) != 0 && self.ids == Ids::Passthrough {
+      // -1 leaves the id unchanged
+      let uid = if valid & SETATTR_UID != 0 { uid } else { u32::MAX };
+      let gid = if valid & SETATTR_GID != 0 { gid } else { u32::MAX };
+      check(unsafe { libc::fchownat(dir, name, uid, gid, libc::AT_SYMLINK_NOFOLLOW) })?;
+    }
+    if valid & SETATTR_SIZE != 0 {
+      let file = File::from(openat(&entry.dir, &entry.name, libc::O_WRONLY | libc::O_NOFOLLOW, 0)?);
+      file.set_len(size).map_err(errno)?;
+    }
+    if valid & (SETATTR_ATIME | SETATTR_MTIME) != 0 {
+      let time = |set: bool, explicit: bool, (sec, nsec): (u64, u64)| match (set, explicit) {
+        (false, _) => libc::timespec { tv_sec: 0, tv_nsec: libc::UTIME_OMIT },
+        (true, false) => libc::timespec { tv_sec: 0, tv_nsec: libc::UTIME_NOW },
+        (true, true) => libc::timespec { tv_sec: sec as libc::time_t, tv_nsec: nsec as libc::c_long },
+      };
+      let times = [
+        time(valid & SETATTR_ATIME != 0, valid & SETATTR_ATIME_SET != 0, atime),
+        time(valid & SETATTR_MTIME != 0, valid & SETATTR_MTIME_SET != 0, mtime),
+      ];
+      check(unsafe { libc::utimensat(dir, name, times.as_ptr(), libc::AT_SYMLINK_NOFOLLOW) })?;
+    }
+    Ok(())
+  }
+
+  fn readdir(&mut self, r: &mut Reader, w: &mut Writer, max: usize) -> Result<(), Errno> {
+    let fid = r.u32()?;
+    let offset = r.u64()? as usize;
+    let count = (r.u32()? as usize).min(max.saturating_sub(4));
+    let current = self.fids.get(&fid).ok_or(EBADF)?;
+    if offset == 0 || current.entries.is_none() {
+      let path = current.path.clone();
+      let metadata = self.metadata(&path)?;
+      if !metadata.is_dir() { return Err(ENOTDIR); }
+      let parent = if path == self.root { path.clone() } else { path.parent().unwrap_or(&self.root).to_path_buf() };
+      let mut entries = vec![
+        (qid(&metadata), dirent_type(&metadata), b".".to_vec()),
+        (qid(&self.metadata(&parent)?), dirent_type(&metadata), b"..".to_vec()),
+      ];
+      let dir = self.directory(&path)?;
+      for name in list(&dir)? {
+        // entries removed since the listing are skipped
+        let Ok(metadata) = metadata_at(&dir, &name) else { continue };
+        entries.push((qid(&metadata), dirent_type(&metadata), name.into_bytes()));
+      }
+      self.fids.get_mut(&fid).ok_or(EBADF)?.entries = Some(entries);
+    }
+    let mut data = Writer::new();
+    // offsets are indices of the next entry
+    for (index, (qid, kind, name)) in self.fid(fid)?.entries.as_ref().unwrap().iter().enumerate().skip(offset) {
+      if data.len() + QID_SIZE + 8 + 1 + 2 + name.len() > count { break; }
+      data.qid(*qid).u64(index as u64 + 1).u8(*kind).string(name);
+    }
+    w.u32(data.len() as u32).bytes(&data.data);
+    Ok(())
+  }
+
+  fn statfs(&mut self, r: &mut Reader, w: &mut Writer) -> Result<(), Errno> {
+    let entry = self.entry(self.fid(r.u32()?)?.path.clone())?;
+    let file = openat(&entry.dir, &entry.name, libc::O_PATH | libc::O_NOFOLLOW, 0)?;
+    let mut stat = unsafe { std::mem::zeroed::<libc::statvfs>() };
+    check(unsafe { libc::fstatvfs(file.as_raw_fd(), &mut stat) })?;
+    // V9FS_MAGIC
+    w.u32(0x01021997).u32(stat.f_bsize as u32)
+      .u64(stat.f_blocks).u64(stat.f_bfree).u64(stat.f_bavail)
+      .u64(stat.f_files).u64(stat.f_ffree).u64(stat.f_fsid)
+      .u32(stat.f_namemax as u32);
+    Ok(())
+  }
+
+  fn mkdir(&mut self, r: &mut Reader, w: &mut Writer) -> Result<(), Errno> {
+    let entry = self.child(r.u32()?, r.string()?)?;
+    let mode = r.u32()?;
+    let gid = r.u32()?;
+    self.check_writable()?;
+    check(unsafe { libc::mkdirat(entry.dir.as_raw_fd(), entry.name.as_ptr(), mode & 0o7777) })?;
+    self.set_gid(&entry, gid);
+    w.qid(qid(&metadata_at(&entry.dir, &entry.name)?));
+    Ok(())
+  }
+
+  fn symlink(&mut self, r: &mut Reader, w: &mut Writer) -> Result<(), Errno> {
+    let entry = self.child(r.u32()?, r.string()?)?;
+    let target = CString::new(r.string()?).map_err(|_| EINVAL)?;
+    let gid = r.u32()?;
+    self.check_writable()?;
+    check(unsafe { libc::symlinkat(target.as_ptr(), entry.dir.as_raw_fd(), entry.name.as_ptr()) })?;
+    self.set_gid(&entry, gid);
+    w.qid(qid(&metadata_at(&entry.dir, &entry.name)?));
+    Ok(())
+  }
+
+  fn mknod(&mut self, r: &mut Reader, w: &mut Writer) -> Result<(), Errno> {
+    let entry = self.child(r.u32()?, r.string()?)?;
+    let mode = r.u32()?;
+    let major = r.u32()?;
+    let minor = r.u32()?;
+    let gid = r.u32()?;
+    self.check_writable()?;
+    if matches!(mode & libc::S_IFMT, libc::S_IFBLK | libc::S_IFCHR) && !self.devices {
+      return Err(EPERM);
+    }
+    let dev = libc::makedev(major, minor);
+    check(unsafe { libc::mknodat(entry.dir.as_raw_fd(), entry.name.as_ptr(), mode as libc::mode_t, dev) })?;
+    self.set_gid(&entry, gid);
+    w.qid(qid(&metadata_at(&entry.dir, &entry.name)?));
+    Ok(())
+  }
+
+  fn readlink(&mut self, r: &mut Reader, w: &mut Writer) -> Result<(), Errno> {
+    let entry = self.entry(self.fid(r.u32()?)?.path.clone())?;
+    let mut buf = vec![0u8; libc::PATH_MAX as usize];
+    let len = unsafe { libc::readlinkat(entry.dir.as_raw_fd(), entry.name.as_ptr(), buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if len < 0 { return Err(last_errno()); }
+    w.string(&buf[..len as usize]);
+    Ok(())
+  }
+
+  fn link(&mut self, r: &mut Reader) -> Result<(), Errno> {
+    let dir = r.u32()?;
+    let target = self.entry(self.fid(r.u32()?)?.path.clone())?;
+    let entry = self.child(dir, r.string()?)?;
+    self.check_writable()?;
+    // without AT_SYMLINK_FOLLOW a symlink target is linked itself
+    check(unsafe { libc::linkat(target.dir.as_raw_fd(), target.name.as_ptr(), entry.dir.as_raw_fd(), entry.name.as_ptr(), 0) })
+  }
+
+  fn rename_entry(&mut self, from: &Entry, to: &Entry) -> Result<(), Errno> {
+    self.check_writable()?;
+    check(unsafe { libc::renameat(from.dir.as_raw_fd(), from.name.as_ptr(), to.dir.as_raw_fd(), to.name.as_ptr()) })?;
+    // fids follow what they point to
+    for fid in self.fids.values_mut() {
+      if let Ok(rest) = fid.path.strip_prefix(&from.path) {
+        fid.path = if rest.as_os_str().is_empty() { to.path.clone() } else { to.path.join(rest) };
+      }
+    }
+    Ok(())
+  }
+
+  fn rename(&mut self, r: &mut Reader) -> Result<(), Errno> {
+    let from = self.fid(r.u32()?)?.path.clone();
+    let to = self.child(r.u32()?, r.string()?)?;
+    if from == self.root { return Err(EINVAL); }
+    let from = self.entry(from)?;
+    self.rename_entry(&from, &to)
+  }
+
+  fn renameat(&mut self, r: &mut Reader) -> Result<(), Errno> {
+    let from = self.child(r.u32()?, r.string()?)?;
+    let to = self.child(r.u32()?, r.string()?)?;
+    self.rename_entry(&from, &to)
+  }
+
+  fn unlinkat(&mut self, r: &mut Reader) -> Result<(), Errno> {
+    let entry = self.child(r.u32()?, r.string()?)?;
+    let flags = if r.u32()? & AT_REMOVEDIR != 0 { libc::AT_REMOVEDIR } else { 0 };
+    self.check_writable()?;
+    check(unsafe { libc::unlinkat(entry.dir.as_raw_fd(), entry.name.as_ptr(), flags) })
+  }
+
+  // extended attributes aren't supported, the list of them is empty
+  fn xattrwalk(&mut self, r: &mut Reader, w: &mut Writer) -> Result<(), Errno> {
+    let path = self.fid(r.u32()?)?.path.clone();
+    let newfid = r.u32()?;
+    if !r.string()?.is_empty() { return Err(ENODATA); }
+    self.fids.insert(newfid, Fid { xattr: true, ..Fid::new(path) });
+    w.u64(0);
+    Ok(())
+  }
+
+  fn getlock(&mut self, r: &mut Reader, w: &mut Writer) -> Result<(), Errno> {
+    self.fid(r.u32()?)?;
+    let _kind = r.u8()?;
+    let start = r.u64()?;
+    let length = r.u64()?;
+    let proc_id = r.u32()?;
+    let client_id = r.string()?;
+    // locks are never held by anyone else
+    w.u8(LOCK_TYPE_UNLCK).u64(start).u64(length).u32(proc_id).string(client_id);
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{fs, os::unix::ffi::OsStrExt, path::PathBuf};
+
+  use super::{Server, Ids, RLERROR, TVERSION, TATTACH, TWALK, TLCREATE, TWRITE, TREAD, TCLUNK, TREADDIR, TLOPEN, TRENAMEAT, TMKDIR, TUNLINKAT, TGETATTR, TMKNOD, TSYMLINK, EROFS, EPERM, EMSGSIZE};
+  use super::super::wire::{Reader, Writer};
+
+  fn directory(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("yuri-9p-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&path);
+    fs::create_dir(&path).unwrap();
+    path
+  }
+
+  // returns the type and the body of the response
+  fn call(server: &mut Server, kind: u8, body: &Writer) -> (u8, Vec<u8>) {
+    let mut request = Writer::new();
+    request.u32(7 + body.len() as u32).u8(kind).u16(1).bytes(&body.data);
+    let response = server.handle(&request.data, 8192);
+    let mut reader = Reader::new(&response);
+    assert_eq!(reader.u32().unwrap() as usize, response.len());
+    let kind = reader.u8().unwrap();
+    assert_eq!(reader.u16().unwrap(), 1);
+    (kind, response[7..].to_vec())
+  }
+
+  fn mount(server: &mut Server) {
+    let (kind, body) = call(server, TVERSION, Writer::new().u32(8192).string(b"9P2000.L"));
+    assert_eq!(kind, TVERSION + 1);
+    assert_eq!(&body[6..], b"9P2000.L");
+    assert_eq!(call(server, TATTACH, Writer::new().u32(0).u32(!0).string(b"root").string(b"").u32(0)).0, TATTACH + 1);
+  }
+
+  #[test]
+  fn files() {
+    let path = directory("files");
+    let mut server = Server::new(&path, false, Ids::Fixed(0, 0), false).unwrap();
+    mount(&mut server);
+
+    // create and write a file, then read it back through another fid
+    assert_eq!(call(&mut server, TWALK, Writer::new().u32(0).u32(1).u16(0)).0, TWALK + 1);
+    assert_eq!(call(&mut server, TLCREATE, Writer::new().u32(1).string(b"hello").u32(0o102).u32(0o644).u32(0)).0, TLCREATE + 1);
+    let (_, body) = call(&mut server, TWRITE, Writer::new().u32(1).u64(0).u32(5).bytes(b"world"));
+    assert_eq!(body, 5u32.to_le_bytes());
+    assert_eq!(fs::read(path.join("hello")).unwrap(), b"world");
+    call(&mut server, TCLUNK, Writer::new().u32(1));
+
+    let (kind, body) = call(&mut server, TWALK, Writer::new().u32(0).u32(2).u16(1).string(b"hello"));
+    assert_eq!((kind, body[0..2].to_vec()), (TWALK + 1, vec![1, 0]));
+    call(&mut server, TLOPEN, Writer::new().u32(2).u32(0));
+    let (_, body) = call(&mut server, TREAD, Writer::new().u32(2).u64(1).u32(100));
+    assert_eq!(body, [&4u32.to_le_bytes()[..], b"orld"].concat());
+    let (_, body) = call(&mut server, TGETATTR, Writer::new().u32(2).u64(!0));
+    let mut reader = Reader::new(&body);
+    reader.bytes(8 + 13).unwrap();
+    assert_eq!(reader.u32().unwrap() & 0o777, 0o644);
+    assert_eq!((reader.u32().unwrap(), reader.u32().unwrap()), (0, 0));
+
+    // walking above the root stays at the root
+    let (kind, _) = call(&mut server, TWALK, Writer::new().u32(0).u32(3).u16(2).string(b"..").string(b"hello"));
+    assert_eq!(kind, TWALK + 1);
+    let (kind, body) = call(&mut server, TWALK, Writer::new().u32(0).u32(3).u16(1).string(b"missing"));
+    assert_eq!((kind, body), (RLERROR, (libc::ENOENT as u32).to_le_bytes().to_vec()));
+
+    call(&mut server, TMKDIR, Writer::new().u32(0).string(b"dir").u32(0o755).u32(0));
+    call(&mut server, TRENAMEAT, Writer::new().u32(0).string(b"hello").u32(0).string(b"moved"));
+    // fid 2 follows the rename
+    let (_, body) = call(&mut server, TREAD, Writer::new().u32(2).u64(0).u32(100));
+    assert_eq!(&body[4..], b"world");
+
+    call(&mut server, TLOPEN, Writer::new().u32(0).u32(0));
+    let (_, body) = call(&mut server, TREADDIR, Writer::new().u32(0).u64(0).u32(4096));
+    let mut reader = Reader::new(&body);
+    let count = reader.u32().unwrap() as usize;
+    let mut names = Vec::new();
+    while names.len() < 4 && count > 0 {
+      reader.bytes(13 + 8 + 1).unwrap();
+      names.push(reader.string().unwrap().to_vec());
+    }
+    names.sort();
+    assert_eq!(names, [b".".to_vec(), b"..".to_vec(), b"dir".to_vec(), b"moved".to_vec()]);
+
+    assert_eq!(call(&mut server, TUNLINKAT, Writer::new().u32(0).string(b"dir").u32(0x200)).0, TUNLINKAT + 1);
+    assert!(!path.join("dir").exists());
+
+    // fifos but no device nodes, which the share doesn't allow
+    let (kind, _) = call(&mut server, TMKNOD, Writer::new().u32(0).string(b"fifo").u32(libc::S_IFIFO | 0o644).u32(0).u32(0).u32(0));
+    assert_eq!(kind, TMKNOD + 1);
+    let (kind, body) = call(&mut server, TMKNOD, Writer::new().u32(0).string(b"null").u32(libc::S_IFCHR | 0o666).u32(1).u32(3).u32(0));
+    assert_eq!((kind, body), (RLERROR, EPERM.to_le_bytes().to_vec()));
+    assert!(!path.join("null").exists());
+
+    // longer than the negotiated msize
+    let mut request = Writer::new();
+    request.u32(8193).u8(TCLUNK).u16(1).u32(2);
+    let response = server.handle(&request.data, 8192);
+    assert_eq!((response[4], &response[7..]), (RLERROR, &EMSGSIZE.to_le_bytes()[..]));
+    fs::remove_dir_all(path).unwrap();
+  }
+
+  #[test]
+  fn read_only() {
+    let path = directory("read-only");
+    fs::write(path.join("file"), b"data").unwrap();
+    let mut server = Server::new(&path, true, Ids::Passthrough, false).unwrap();
+    mount(&mut server);
+    call(&mut server, TWALK, Writer::new().u32(0).u32(1).u16(1).string(b"file"));
+    let (kind, body) = call(&mut server, TLOPEN, Writer::new().u32(1).u32(2));
+    assert_eq!((kind, body), (RLERROR, EROFS.to_le_bytes().to_vec()));
+    let (kind, _) = call(&mut server, TUNLINKAT, Writer::new().u32(0).string(b"file").u32(0));
+    assert_eq!(kind, RLERROR);
+    assert_eq!(call(&mut server, TLOPEN, Writer::new().u32(1).u32(0)).0, TLOPEN + 1);
+    fs::remove_dir_all(path).unwrap();
+  }
+
+  #[test]
+  fn symlinks() {
+    let path = directory("symlinks");
+    let outside = directory("symlinks-outside");
+    fs::write(outside.join("file"), b"data").unwrap();
+    let mut server = Server::new(&path, false, Ids::Fixed(0, 0), false).unwrap();
+    mount(&mut server);
+
+    // a symlink the guest made to a host directory can't be created or deleted through
+    let (kind, _) = call(&mut server, TSYMLINK, Writer::new().u32(0).string(b"escape").string(outside.as_os_str().as_bytes()).u32(0));
+    assert_eq!(kind, TSYMLINK + 1);
+    let (kind, body) = call(&mut server, TWALK, Writer::new().u32(0).u32(1).u16(1).string(b"escape"));
+    assert_eq!((kind, &body[0..2]), (TWALK + 1, &[1, 0][..]));
+    let (kind, _) = call(&mut server, TLCREATE, Writer::new().u32(1).string(b"created").u32(0o102).u32(0o644).u32(0));
+    assert_eq!(kind, RLERROR);
+    let (kind, _) = call(&mut server, TMKDIR, Writer::new().u32(1).string(b"dir").u32(0o755).u32(0));
+    assert_eq!(kind, RLERROR);
+    let (kind, _) = call(&mut server, TUNLINKAT, Writer::new().u32(1).string(b"file").u32(0));
+    assert_eq!(kind, RLERROR);
+    assert!(!outside.join("created").exists() && !outside.join("dir").exists());
+    assert!(outside.join("file").exists());
+
+    // nor through a directory swapped for a symlink after the walk
+    fs::create_dir(path.join("dir")).unwrap();
+    call(&mut server, TWALK, Writer::new().u32(0).u32(2).u16(1).string(b"dir"));
+    fs::remove_dir(path.join("dir")).unwrap();
+    std::os::unix::fs::symlink(&outside, path.join("dir")).unwrap();
+    let (kind, _) = call(&mut server, TLCREATE, Writer::new().u32(2).string(b"created").u32(0o102).u32(0o644).u32(0));
+    assert_eq!(kind, RLERROR);
+    let (kind, _) = call(&mut server, TWALK, Writer::new().u32(2).u32(3).u16(1).string(b"file"));
+    assert_eq!(kind, RLERROR);
+    assert!(!outside.join("created").exists());
+    fs::remove_dir_all(path).unwrap();
+    fs::remove_dir_all(outside).unwrap();
+  }
+}
@@ -0,0 +1,95 @@
+use super::server::{Errno, EINVAL};
+
+// 9P encoding: little endian integers, strings after their u16 length
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Qid {
+  pub(crate) kind: u8,
+  pub(crate) version: u32,
+  pub(crate) path: u64,
+}
+
+pub(crate) const QID_SIZE: usize = 13;
+
+#[derive(Debug)]
+pub(crate) struct Reader<'a> {
+  data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+  pub(crate) fn new(data: &'a [u8]) -> Reader<'a> {
+    Reader { data }
+  }
+
+  pub(crate) fn bytes(&mut self, len: usize) -> Result<&'a [u8], Errno> {
+    if self.data.len() < len { return Err(EINVAL); }
+    let (bytes, rest) = self.data.split_at(len);
+    self.data = rest;
+    Ok(bytes)
+  }
+
+  pub(crate) fn u8(&mut self) -> Result<u8, Errno> {
+    Ok(self.bytes(1)?[0])
+  }
+
+  pub(crate) fn u16(&mut self) -> Result<u16, Errno> {
+    Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+  }
+
+  pub(crate) fn u32(&mut self) -> Result<u32, Errno> {
+    Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+  }
+
+  pub(crate) fn u64(&mut self) -> Result<u64, Errno> {
+    Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+  }
+
+  pub(crate) fn string(&mut self) -> Result<&'a [u8], Errno> {
+    let len = self.u16()? as usize;
+    self.bytes(len)
+  }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Writer {
+  pub(crate) data: Vec<u8>,
+}
+
+impl Writer {
+  pub(crate) fn new() -> Writer {
+    Writer::default()
+  }
+
+  pub(crate) fn len(&self) -> usize {
+    self.data.len()
+  }
+
+  pub(crate) fn bytes(&mut self, bytes: &[u8]) -> &mut Writer {
+    self.data.extend_from_slice(bytes);
+    self
+  }
+
+  pub(crate) fn u8(&mut self, value: u8) -> &mut Writer {
+    self.bytes(&[value])
+  }
+
+  pub(crate) fn u16(&mut self, value: u16) -> &mut Writer {
+    self.bytes(&value.to_le_bytes())
+  }
+
+  pub(crate) fn u32(&mut self, value: u32) -> &mut Writer {
+    self.bytes(&value.to_le_bytes())
+  }
+
+  pub(crate) fn u64(&mut self, value: u64) -> &mut Writer {
+    self.bytes(&value.to_le_bytes())
+  }
+
+  pub(crate) fn string(&mut self, value: &[u8]) -> &mut Writer {
+    self.u16(value.len() as u16).bytes(value)
+  }
+
+  pub(crate) fn qid(&mut self, qid: Qid) -> &mut Writer {
+    self.u8(qid.kind).u32(qid.version).u64(qid.path)
+  }
+}
@@ -0,0 +1,409 @@
+use crate::devices::{bus::Bus, pci::{Config, PciFunction, REVISION, SUBSYSTEM_VENDOR_ID, SUBSYSTEM_ID, msix::{Msix, NO_VECTOR}}};
+
+use super::{VirtioDevice, VIRTIO_F_VERSION_1, STATUS_DRIVER_OK, queue::{Queue, QUEUE_SIZE_MAX}};
+
+// virtio-pci, modern only. BAR 0 holds the structures the capabilities point at, BAR 1 the MSI-X table.
+
+const VENDOR: u16 = 0x1af4;
+// plus the virtio device id
+const DEVICE_BASE: u16 = 0x1040;
+
+const BAR_SIZE: u64 = 0x4000;
+const COMMON: u64 = 0x0000;
+const ISR: u64 = 0x1000;
+const DEVICE_CONFIG: u64 = 0x2000;
+const NOTIFY: u64 = 0x3000;
+const NOTIFY_MULTIPLIER: u32 = 4;
+
+const MSIX_BAR: usize = 1;
+const MSIX_BAR_SIZE: u64 = 0x1000;
+const MSIX_TABLE: u64 = 0x000;
+const MSIX_PBA: u64 = 0x800;
+
+const CAPABILITY_ID: u8 = 0x09;
+const CAPABILITY_LEN: usize = 16;
+const CFG_TYPE_COMMON: u8 = 1;
+const CFG_TYPE_NOTIFY: u8 = 2;
+const CFG_TYPE_ISR: u8 = 3;
+const CFG_TYPE_DEVICE: u8 = 4;
+
+// common configuration
+const DEVICE_FEATURE_SELECT: u64 = 0x00;
+const DEVICE_FEATURE: u64 = 0x04;
+const DRIVER_FEATURE_SELECT: u64 = 0x08;
+const DRIVER_FEATURE: u64 = 0x0c;
+const CONFIG_MSIX_VECTOR: u64 = 0x10;
+const NUM_QUEUES: u64 = 0x12;
+const DEVICE_STATUS: u64 = 0x14;
+const CONFIG_GENERATION: u64 = 0x15;
+const QUEUE_SELECT: u64 = 0x16;
+const QUEUE_SIZE: u64 = 0x18;
+const QUEUE_MSIX_VECTOR: u64 = 0x1a;
+const QUEUE_ENABLE: u64 = 0x1c;
+const QUEUE_NOTIFY_OFF: u64 = 0x1e;
+const QUEUE_DESC: u64 = 0x20;
+const QUEUE_DRIVER: u64 = 0x28;
+const QUEUE_DEVICE: u64 = 0x30;
+const COMMON_SIZE: u64 = 0x38;
+
+const ISR_QUEUE: u8 = 0b01;
+const ISR_CONFIG: u8 = 0b10;
+
+#[derive(Debug)]
+pub(crate) struct VirtioPci {
+  config: Config,
+  device: Box<dyn VirtioDevice>,
+  msix: Msix,
+  device_features_sel: u32,
+  driver_features: u64,
+  driver_features_sel: u32,
+  queue_sel: u16,
+  queues: Vec<Queue>,
+  queue_vectors: Vec<u16>,
+  config_vector: u16,
+  notified: Vec<usize>,
+  status: u8,
+  config_generation: u8,
+  isr: u8,
+}
+
+impl VirtioPci {
+  pub(crate) fn new(device: Box<dyn VirtioDevice>) -> VirtioPci {
+    let id = device.device_id();
+    // network, storage, communication or other
+    let class = match id {
+      1 => 0x020000,
+      2 => 0x010000,
+      3 => 0x078000,
+      _ => 0xff0000,
+    };
+    let mut config = Config::new(VENDOR, DEVICE_BASE + id as u16, class);
+    config.set(REVISION, &[1]);
+    config.set16(SUBSYSTEM_VENDOR_ID, VENDOR);
+    config.set16(SUBSYSTEM_ID, 0x1100);
+    config.add_bar(0, BAR_SIZE);
+    config.add_bar(MSIX_BAR, MSIX_BAR_SIZE);
+    let queue_count = device.queue_count();
+    let capability = |config: &mut Config, cfg_type: u8, offset: u64, len: u64, extra: usize| {
+      let capability = config.add_capability(CAPABILITY_ID, CAPABILITY_LEN + extra);
+      config.set(capability + 2, &[(CAPABILITY_LEN + extra) as u8, cfg_type, 0]);
+      config.set32(capability + 8, offset as u32);
+      config.set32(capability + 12, len as u32);
+      capability
+    };
+    capability(&mut config, CFG_TYPE_COMMON, COMMON, COMMON_SIZE, 0);
+    let notify = capability(&mut config, CFG_TYPE_NOTIFY, NOTIFY, (queue_count as u64 * NOTIFY_MULTIPLIER as u64).max(4), 4);
+    config.set32(notify + CAPABILITY_LEN, NOTIFY_MULTIPLIER);
+    capability(&mut config, CFG_TYPE_ISR, ISR, 1, 0);
+    capability(&mut config, CFG_TYPE_DEVICE, DEVICE_CONFIG, 0x1000, 0);
+    // one vector for the configuration and one per queue
+    let msix = Msix::new(&mut config, queue_count as u16 + 1, MSIX_BAR as u8, MSIX_TABLE as u32, MSIX_PBA as u32);
+    let mut pci = VirtioPci {
+      config,
+      device,
+      msix,
+      device_features_sel: 0,
+      driver_features: 0,
+      driver_features_sel: 0,
+      queue_sel: 0,
+      queues: (0..queue_count).map(|_| Queue::new()).collect(),
+      queue_vectors: vec![NO_VECTOR; queue_count],
+      config_vector: NO_VECTOR,
+      notified: Vec::new(),
+      status: 0,
+      config_generation: 0,
+      isr: 0,
+    };
+    pci.reset_device();
+    pci
+  }
+
+  // the device and the transport, not the config space or MSI-X
+  fn reset_device(&mut self) {
+    self.device_features_sel = 0;
+    self.driver_features = 0;
+    self.driver_features_sel = 0;
+    self.queue_sel = 0;
+    for queue in self.queues.iter_mut() {
+      *queue = Queue::new();
+      queue.size = QUEUE_SIZE_MAX;
+    }
+    self.queue_vectors.iter_mut().for_each(|vector| *vector = NO_VECTOR);
+    self.config_vector = NO_VECTOR;
+    self.notified.clear();
+    self.status = 0;
+    self.isr = 0;
+    self.device.reset();
+  }
+
+  fn device_features(&self) -> u64 {
+    self.device.features() | VIRTIO_F_VERSION_1
+  }
+
+  // vectors past the table read back as NO_VECTOR, which tells the driver
+  fn vector(&self, vector: u16) -> u16 {
+    if vector < self.msix.vectors() { vector } else { NO_VECTOR }
+  }
+
+  fn common(&self) -> [u8; COMMON_SIZE as usize] {
+    let mut common = [0; COMMON_SIZE as usize];
+    let mut put = |offset: u64, data: &[u8]| common[offset as usize..offset as usize + data.len()].copy_from_slice(data);
+    put(DEVICE_FEATURE_SELECT, &self.device_features_sel.to_le_bytes());
+    let features = match self.device_features_sel {
+      0 => self.device_features() as u32,
+      1 => (self.device_features() >> 32) as u32,
+      _ => 0,
+    };
+    put(DEVICE_FEATURE, &features.to_le_bytes());
+    put(DRIVER_FEATURE_SELECT, &self.driver_features_sel.to_le_bytes());
+    let features = match self.driver_features_sel {
+      0 => self.driver_features as u32,
+      1 => (self.driver_features >> 32) as u32,
+      _ => 0,
+    };
+    put(DRIVER_FEATURE, &features.to_le_bytes());
+    put(CONFIG_MSIX_VECTOR, &self.config_vector.to_le_bytes());
+    put(NUM_QUEUES, &(self.queues.len() as u16).to_le_bytes());
+    put(DEVICE_STATUS, &[self.status]);
+    put(CONFIG_GENERATION, &[self.config_generation]);
+    put(QUEUE_SELECT, &self.queue_sel.to_le_bytes());
+    if let Some(queue) = self.queues.get(self.queue_sel as usize) {
+      put(QUEUE_SIZE, &queue.size.to_le_bytes());
+      put(QUEUE_MSIX_VECTOR, &self.queue_vectors[self.queue_sel as usize].to_le_bytes());
+      put(QUEUE_ENABLE, &(queue.ready as u16).to_le_bytes());
+      put(QUEUE_NOTIFY_OFF, &self.queue_sel.to_le_bytes());
+      put(QUEUE_DESC, &queue.desc.to_le_bytes());
+      put(QUEUE_DRIVER, &queue.driver.to_le_bytes());
+      put(QUEUE_DEVICE, &queue.device.to_le_bytes());
+    }
+    common
+  }
+
+  // fields are written whole, the 64-bit ones also by halves
+  fn write_common(&mut self, offset: u64, len: usize, data: u64) {
+    fn set(origin: u64, offset: u64, len: usize, data: u64) -> u64 {
+      let mask = (u64::MAX >> (64 - 8 * len)) << (8 * offset);
+      (origin & !mask) | (data << (8 * offset) & mask)
+    }
+    let index = self.queue_sel as usize;
+    match offset {
+      DEVICE_FEATURE_SELECT => self.device_features_sel = data as u32,
+      DRIVER_FEATURE_SELECT => self.driver_features_sel = data as u32,
+      DRIVER_FEATURE => match self.driver_features_sel {
+        0 => self.driver_features = set(self.driver_features, 0, 4, data),
+        1 => self.driver_features = set(self.driver_features, 4, 4, data),
+        _ => {},
+      },
+      CONFIG_MSIX_VECTOR => self.config_vector = self.vector(data as u16),
+      DEVICE_STATUS => if data == 0 { self.reset_device() } else { self.status = data as u8 },
+      QUEUE_SELECT => self.queue_sel = data as u16,
+      QUEUE_SIZE => if let Some(queue) = self.queues.get_mut(index) {
+        queue.size = (data as u16).min(QUEUE_SIZE_MAX);
+      },
+      QUEUE_MSIX_VECTOR => if index < self.queues.len() {
+        self.queue_vectors[index] = self.vector(data as u16);
+      },
+      QUEUE_ENABLE => if let Some(queue) = self.queues.get_mut(index) {
+        queue.ready = data & 1 != 0;
+      },
+      QUEUE_DESC..=0x37 => if let Some(queue) = self.queues.get_mut(index) {
+        let (field, start) = match offset {
+          QUEUE_DESC..=0x27 => (&mut queue.desc, QUEUE_DESC),
+          QUEUE_DRIVER..=0x2f => (&mut queue.driver, QUEUE_DRIVER),
+          _ => (&mut queue.device, QUEUE_DEVICE),
+        };
+        *field = set(*field, offset - start, len, data);
+      },
+      _ => {},
+    }
+  }
+
+  // a queue or configuration interrupt, by MSI-X when it is on
+  fn interrupt(&mut self, vector: u16, isr: u8, bus: &mut Bus) {
+    if self.msix.enabled(&self.config) {
+      if vector != NO_VECTOR {
+        self.msix.notify(&self.config, vector, bus);
+      }
+    } else {
+      self.isr |= isr;
+    }
+  }
+}
+
+impl PciFunction for VirtioPci {
+  fn config(&self) -> &Config { &self.config }
+  fn config_mut(&mut self) -> &mut Config { &mut self.config }
+
+  fn read_bar(&mut self, bar: usize, offset: u64, len: usize) -> u64 {
+    if bar == MSIX_BAR {
+      return match offset {
+        MSIX_PBA.. => self.msix.read_pba(offset - MSIX_PBA, len),
+        _ => self.msix.read_table(offset - MSIX_TABLE, len),
+      };
+    }
+    match offset {
+      COMMON..COMMON_SIZE => {
+        let common = self.common();
+        let end = (offset + len as u64).min(COMMON_SIZE) as usize;
+        let mut data = [0; 8];
+        data[..end - offset as usize].copy_from_slice(&common[offset as usize..end]);
+        u64::from_le_bytes(data)
+      },
+      // reading acknowledges
+      ISR => std::mem::take(&mut self.isr) as u64,
+      DEVICE_CONFIG..NOTIFY => (0..len as u64).fold(0, |data, index|
+        data | (self.device.read_config(offset - DEVICE_CONFIG + index) as u64) << (8 * index)),
+      _ => 0,
+    }
+  }
+
+  fn write_bar(&mut self, bar: usize, offset: u64, len: usize, data: u64) {
+    if bar == MSIX_BAR {
+      if offset < MSIX_PBA {
+        self.msix.write_table(offset - MSIX_TABLE, len, data);
+      }
+      return;
+    }
+    match offset {
+      COMMON..COMMON_SIZE => self.write_common(offset, len, data),
+      DEVICE_CONFIG..NOTIFY => for index in 0..len as u64 {
+        self.device.write_config(offset - DEVICE_CONFIG + index, (data >> (8 * index)) as u8);
+      },
+      // the queue index is written to the queue's notify address
+      NOTIFY..BAR_SIZE => {
+        let queue = data as usize;
+        if queue < self.queues.len() && !self.notified.contains(&queue) {
+          self.notified.push(queue);
+        }
+      },
+      _ => {},
+    }
+  }
+
+  fn step(&mut self, bus: &mut Bus) -> bool {
+    if self.status & STATUS_DRIVER_OK as u8 != 0 {
+      for queue in std::mem::take(&mut self.notified) {
+        if self.queues[queue].ready {
+          self.device.notify(queue, &mut self.queues, bus);
+        }
+      }
+      if self.device.poll(&mut self.queues, bus) {
+        self.config_generation = self.config_generation.wrapping_add(1);
+        self.interrupt(self.config_vector, ISR_CONFIG, bus);
+      }
+    }
+    for queue in 0..self.queues.len() {
+      if std::mem::take(&mut self.queues[queue].interrupt) {
+        self.interrupt(self.queue_vectors[queue], ISR_QUEUE, bus);
+      }
+    }
+    self.msix.step(&self.config, bus);
+    self.isr != 0 && !self.msix.enabled(&self.config)
+  }
+
+  fn reset(&mut self) {
+    self.msix.reset();
+    self.reset_device();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::fs;
+
+  use crate::{hart::Hart, devices::{Device, memory::MEMORY_START, virtio::blk::{VirtioBlk, ImageMode},
+    pci::{PCIE_ECAM_START, PCIE_MMIO_START, COMMAND, COMMAND_MEMORY, COMMAND_MASTER, STATUS, STATUS_INTERRUPT, CAPABILITIES}}};
+
+  use super::*;
+
+  #[test]
+  fn pci_transport() {
+    let path = std::env::temp_dir().join(format!("yuri-virtio-pci-{}", std::process::id()));
+    fs::write(&path, [0; 512]).unwrap();
+    let (mut bus, _controller) = Bus::new();
+    assert_eq!(bus.attach_pci(Box::new(VirtioPci::new(Box::new(VirtioBlk::open(&path, ImageMode::ReadOnly).unwrap())))), Some(1));
+    let mut hart = Hart::new();
+    let config = |offset: usize| PCIE_ECAM_START + (1 << 15) + offset as u64;
+    assert_eq!(bus.read32(config(0)).unwrap(), 0x10421af4);
+    bus.write16(config(COMMAND), COMMAND_MEMORY | COMMAND_MASTER).unwrap();
+    let (common, notify, msix) = (PCIE_MMIO_START + COMMON, PCIE_MMIO_START + NOTIFY, PCIE_MMIO_START + BAR_SIZE);
+    let mut step = |bus: &mut Bus| bus.pci.lock().unwrap().step(&mut bus.clone(), &mut hart);
+
+    // common configuration
+    assert_eq!(bus.read16(common + NUM_QUEUES).unwrap(), 1);
+    bus.write32(common + DEVICE_FEATURE_SELECT, 1).unwrap();
+    assert_eq!(bus.read32(common + DEVICE_FEATURE).unwrap(), 1);
+    bus.write32(common + DRIVER_FEATURE_SELECT, 1).unwrap();
+    bus.write32(common + DRIVER_FEATURE, 1).unwrap();
+    assert_eq!(bus.read32(common + DRIVER_FEATURE).unwrap(), 1);
+    assert_eq!(bus.read16(common + QUEUE_SIZE).unwrap(), QUEUE_SIZE_MAX);
+    let (desc, driver, device, header) = (MEMORY_START, MEMORY_START + 0x1000, MEMORY_START + 0x2000, MEMORY_START + 0x3000);
+    bus.write16(common + QUEUE_SIZE, 8).unwrap();
+    bus.write64(common + QUEUE_DESC, desc).unwrap();
+    // the 64-bit fields by halves
+    bus.write32(common + QUEUE_DRIVER, driver as u32).unwrap();
+    bus.write32(common + QUEUE_DRIVER + 4, 0).unwrap();
+    bus.write64(common + QUEUE_DEVICE, device).unwrap();
+    assert_eq!(bus.read64(common + QUEUE_DRIVER).unwrap(), driver);
+    bus.write16(common + QUEUE_ENABLE, 1).unwrap();
+    bus.write8(common + DEVICE_STATUS, 0b1111).unwrap();
+
+    // a flush, with the header and the status in one descriptor, interrupts by INTx without MSI-X
+    bus.write32(header, 4).unwrap();
+    bus.write64(desc, header).unwrap();
+    bus.write32(desc + 8, 17).unwrap();
+    let request = |bus: &mut Bus, index: u16| {
+      bus.write16(driver + 2, index).unwrap();
+      bus.write16(notify, 0).unwrap();
+    };
+    request(&mut bus, 1);
+    step(&mut bus);
+    assert_eq!(bus.read16(device + 2).unwrap(), 1);
+    assert_ne!(bus.read16(config(STATUS)).unwrap() & STATUS_INTERRUPT, 0);
+    assert_eq!(bus.read8(PCIE_MMIO_START + ISR).unwrap(), ISR_QUEUE);
+    assert_eq!(bus.read8(PCIE_MMIO_START + ISR).unwrap(), 0);
+
+    // MSI-X, found through the capability list
+    let mut capability = bus.read8(config(CAPABILITIES)).unwrap() as usize;
+    while bus.read8(config(capability)).unwrap() != 0x11 {
+      capability = bus.read8(config(capability + 1)).unwrap() as usize;
+    }
+    assert_eq!(bus.read16(config(capability + 2)).unwrap(), 1);
+    // vector 1 writes 0x1234 to memory, vectors past the table are refused
+    let message = MEMORY_START + 0x4000;
+    bus.write64(msix + 16, message).unwrap();
+    bus.write32(msix + 24, 0x1234).unwrap();
+    bus.write32(msix + 28, 0).unwrap();
+    bus.write16(common + QUEUE_MSIX_VECTOR, 2).unwrap();
+    assert_eq!(bus.read16(common + QUEUE_MSIX_VECTOR).unwrap(), NO_VECTOR);
+    bus.write16(common + QUEUE_MSIX_VECTOR, 1).unwrap();
+    assert_eq!(bus.read16(common + QUEUE_MSIX_VECTOR).unwrap(), 1);
+    bus.write16(config(capability + 2), 1 << 15).unwrap();
+    request(&mut bus, 2);
+    step(&mut bus);
+    assert_eq!(bus.read16(device + 2).unwrap(), 2);
+    assert_eq!(bus.read32(message).unwrap(), 0x1234);
+    assert_eq!(bus.read8(PCIE_MMIO_START + ISR).unwrap(), 0);
+    // held while the vector is masked
+    bus.write32(message, 0).unwrap();
+    bus.write32(msix + 28, 1).unwrap();
+    request(&mut bus, 3);
+    step(&mut bus);
+    assert_eq!(bus.read32(message).unwrap(), 0);
+    assert_eq!(bus.read64(msix + MSIX_PBA).unwrap(), 0b10);
+    bus.write32(msix + 28, 0).unwrap();
+    step(&mut bus);
+    assert_eq!(bus.read32(message).unwrap(), 0x1234);
+    assert_eq!(bus.read64(msix + MSIX_PBA).unwrap(), 0);
+
+    // descriptors in the BAR of the function, whose host is locked while it steps, are dropped
+    bus.write64(common + QUEUE_DESC, PCIE_MMIO_START).unwrap();
+    request(&mut bus, 4);
+    step(&mut bus);
+    assert_eq!(bus.read16(device + 2).unwrap(), 3);
+    // reset by writing zero to the status
+    bus.write8(common + DEVICE_STATUS, 0).unwrap();
+    assert_eq!(bus.read16(common + QUEUE_ENABLE).unwrap(), 0);
+    fs::remove_file(&path).unwrap();
+  }
+}
@@ -0,0 +1,140 @@
+use crate::{devices::{bus::Bus, memory::Memory}, trap::Exception};
+
+pub(crate) const QUEUE_SIZE_MAX: u16 = 256;
+
+const VIRTQ_DESC_F_NEXT: u16 = 0b001;
+const VIRTQ_DESC_F_WRITE: u16 = 0b010;
+
+#[derive(Debug, Clone, Copy)]
+struct Descriptor {
+  address: u64,
+  len: u32,
+  write: bool,
+}
+
+// Descriptors of a request, readable ones come before writable ones.
+// Rings and buffers are only accessed in memory: a device which locked itself to handle a notification
+// must not reach back into its own registers, or any other device.
+#[derive(Debug)]
+pub(crate) struct Chain {
+  head: u16,
+  descriptors: Vec<Descriptor>,
+}
+
+impl Chain {
+  // bytes the device can read
+  pub(crate) fn readable(&self) -> usize {
+    self.descriptors.iter().filter(|desc| !desc.write).map(|desc| desc.len as usize).sum()
+  }
+
+  // bytes the device can write
+  pub(crate) fn writable(&self) -> usize {
+    self.descriptors.iter().filter(|desc| desc.write).map(|desc| desc.len as usize).sum()
+  }
+
+  pub(crate) fn read_at(&self, bus: &mut Bus, offset: usize, buf: &mut [u8]) -> Result<(), Exception> {
+    Chain::copy(self.descriptors.iter().filter(|desc| !desc.write), offset, buf.len(), |address, start, len| {
+      if bus.memory.read_bytes(address, &mut buf[start..start + len]) { Ok(()) } else { Err(Exception::LoadAccessFault(address)) }
+    })
+  }
+
+  pub(crate) fn write_at(&self, bus: &mut Bus, offset: usize, data: &[u8]) -> Result<(), Exception> {
+    Chain::copy(self.descriptors.iter().filter(|desc| desc.write), offset, data.len(), |address, start, len| {
+      if bus.memory.write_bytes(address, &data[start..start + len]) { Ok(()) } else { Err(Exception::StoreAMOAccessFault(address)) }
+    })
+  }
+
+  // run copy(guest address, offset in the buffer, len) on the parts of descriptors in [offset, offset + len)
+  fn copy<'a, F>(descriptors: impl Iterator<Item = &'a Descriptor>, offset: usize, len: usize, mut copy: F) -> Result<(), Exception>
+  where
+    F: FnMut(u64, usize, usize) -> Result<(), Exception>
+  {
+    let (mut skip, mut done) = (offset, 0);
+    for desc in descriptors {
+      if done == len { break; }
+      let desc_len = desc.len as usize;
+      if skip >= desc_len {
+        skip -= desc_len;
+        continue;
+      }
+      let count = (desc_len - skip).min(len - done);
+      copy(desc.address.wrapping_add(skip as u64), done, count)?;
+      done += count;
+      skip = 0;
+    }
+    if done < len { return Err(Exception::LoadAccessFault(offset as u64)); }
+    Ok(())
+  }
+}
+
+// split virtqueue
+#[derive(Debug, Default)]
+pub(crate) struct Queue {
+  pub(crate) size: u16,
+  pub(crate) ready: bool,
+  pub(crate) desc: u64,
+  pub(crate) driver: u64,
+  pub(crate) device: u64,
+  last_avail: u16,
+  used: u16,
+  // used buffers were added since the last interrupt
+  pub(crate) interrupt: bool,
+}
+
+impl Queue {
+  pub(crate) fn new() -> Queue {
+    Queue::default()
+  }
+
+  // next available request, None if there are none or the queue is broken
+  pub(crate) fn pop(&mut self, bus: &mut Bus) -> Option<Chain> {
+    if !self.ready || self.size == 0 { return None; }
+    let memory = &bus.memory;
+    let avail = u16::from_le_bytes(read(memory, self.driver, 2)?);
+    if avail == self.last_avail { return None; }
+    let head = u16::from_le_bytes(read(memory, self.driver, 4 + 2 * (self.last_avail % self.size) as u64)?);
+    self.last_avail = self.last_avail.wrapping_add(1);
+    let mut descriptors = Vec::new();
+    let mut index = head;
+    // a chain can't be longer than the queue
+    for _ in 0..self.size {
+      if index >= self.size { return None; }
+      let desc: [u8; 16] = read(memory, self.desc, 16 * index as u64)?;
+      let flags = u16::from_le_bytes([desc[12], desc[13]]);
+      descriptors.push(Descriptor {
+        address: u64::from_le_bytes(desc[0..8].try_into().unwrap()),
+        len: u32::from_le_bytes(desc[8..12].try_into().unwrap()),
+        write: flags & VIRTQ_DESC_F_WRITE != 0,
+      });
+      if flags & VIRTQ_DESC_F_NEXT == 0 {
+        return Some(Chain { head, descriptors });
+      }
+      index = u16::from_le_bytes([desc[14], desc[15]]);
+    }
+    None
+  }
+
+  // return the chain to the driver, len is the number of bytes written
+  pub(crate) fn push(&mut self, bus: &mut Bus, chain: Chain, len: u32) {
+    let mut element = [0; 8];
+    element[0..4].copy_from_slice(&(chain.head as u32).to_le_bytes());
+    element[4..8].copy_from_slice(&len.to_le_bytes());
+    write(&mut bus.memory, self.device, 4 + 8 * (self.used % self.size) as u64, &element);
+    self.used = self.used.wrapping_add(1);
+    write(&mut bus.memory, self.device, 2, &self.used.to_le_bytes());
+    self.interrupt = true;
+  }
+}
+
+// N bytes at offset from a ring, None if they are not in memory
+fn read<const N: usize>(memory: &Memory, ring: u64, offset: u64) -> Option<[u8; N]> {
+  let mut data = [0; N];
+  memory.read_bytes(ring.checked_add(offset)?, &mut data).then_some(data)
+}
+
+// dropped if not in memory
+fn write(memory: &mut Memory, ring: u64, offset: u64, data: &[u8]) {
+  if let Some(address) = ring.checked_add(offset) {
+    memory.write_bytes(address, data);
+  }
+}
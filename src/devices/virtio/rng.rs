@@ -0,0 +1,134 @@
+use std::{fs::File, io::{self, Read}, str::FromStr};
+
+use crate::devices::bus::Bus;
+
+use super::{VirtioDevice, queue::Queue};
+
+const DEVICE_ID: u32 = 4;
+
+// largest request served at once
+const CHUNK_SIZE: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum RngSource {
+  Urandom,
+  // the same seed gives the same bytes on every run
+  Seeded(u64),
+}
+
+// "urandom" or "seed=N"
+impl FromStr for RngSource {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s.split_once('=') {
+      None if s == "urandom" => Ok(RngSource::Urandom),
+      Some(("seed", seed)) => seed.parse().map(RngSource::Seeded).map_err(|_| format!("invalid seed '{}'", seed)),
+      _ => Err(format!("unknown entropy source '{}', expected urandom or seed=N", s)),
+    }
+  }
+}
+
+#[derive(Debug)]
+enum Generator {
+  Urandom(File),
+  // splitmix64
+  Seeded(u64),
+}
+
+impl Generator {
+  fn fill(&mut self, buf: &mut [u8]) {
+    match self {
+      Generator::Urandom(file) => if file.read_exact(buf).is_err() {
+        panic!("failed to read /dev/urandom");
+      },
+      Generator::Seeded(state) => for chunk in buf.chunks_mut(8) {
+        *state = state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = *state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^= z >> 31;
+        chunk.copy_from_slice(&z.to_le_bytes()[..chunk.len()]);
+      },
+    }
+  }
+}
+
+#[derive(Debug)]
+pub(crate) struct VirtioRng {
+  source: RngSource,
+  generator: Generator,
+}
+
+impl VirtioRng {
+  pub(crate) fn new(source: RngSource) -> io::Result<VirtioRng> {
+    Ok(VirtioRng { source, generator: VirtioRng::generator(source)? })
+  }
+
+  fn generator(source: RngSource) -> io::Result<Generator> {
+    Ok(match source {
+      RngSource::Urandom => Generator::Urandom(File::open("/dev/urandom")?),
+      RngSource::Seeded(seed) => Generator::Seeded(seed),
+    })
+  }
+}
+
+impl VirtioDevice for VirtioRng {
+  fn device_id(&self) -> u32 {
+    DEVICE_ID
+  }
+
+  fn features(&self) -> u64 {
+    0
+  }
+
+  fn queue_count(&self) -> usize {
+    1
+  }
+
+  fn read_config(&self, _offset: u64) -> u8 {
+    0
+  }
+
+  fn notify(&mut self, queue: usize, queues: &mut [Queue], bus: &mut Bus) {
+    while let Some(chain) = queues[queue].pop(bus) {
+      let mut buf = vec![0; chain.writable().min(CHUNK_SIZE)];
+      self.generator.fill(&mut buf);
+      let len = if chain.write_at(bus, 0, &buf).is_ok() { buf.len() } else { 0 };
+      queues[queue].push(bus, chain, len as u32);
+    }
+  }
+
+  fn reset(&mut self) {
+    // a rebooted guest sees the same bytes again
+    if let RngSource::Seeded(seed) = self.source {
+      self.generator = Generator::Seeded(seed);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn bytes(rng: &mut VirtioRng) -> [u8; 20] {
+    let mut buf = [0; 20];
+    rng.generator.fill(&mut buf);
+    buf
+  }
+
+  #[test]
+  fn seeded() {
+    let mut rng = VirtioRng::new("seed=42".parse().unwrap()).unwrap();
+    let first = bytes(&mut rng);
+    assert_ne!(bytes(&mut rng), first);
+    // on every run and after a reset
+    assert_eq!(bytes(&mut VirtioRng::new(RngSource::Seeded(42)).unwrap()), first);
+    rng.reset();
+    assert_eq!(bytes(&mut rng), first);
+    assert_ne!(bytes(&mut VirtioRng::new(RngSource::Seeded(43)).unwrap()), first);
+    // the first output of splitmix64
+    assert_eq!(bytes(&mut VirtioRng::new(RngSource::Seeded(0)).unwrap())[..8], 0xe220a8397b1dcdafu64.to_le_bytes());
+    assert!("seed=x".parse::<RngSource>().is_err());
+  }
+}
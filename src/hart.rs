@@ -1,6 +1,8 @@
-use crate::{register::{Registers, FRegisters}, csrs::{CsrRegistry, MIEP}, instructions::{parse, extensions::c::decompress, Instructor, InstructionLen, InstructionWithType}, trap::{Exception, Trap, Interrupt}, mmu::MMU};
+use std::rc::Rc;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+use crate::{register::{Registers, FRegisters}, csrs::{CsrRegistry, MIEP}, instructions::{InstructionLen, Decoded, block::Translated}, trap::{Exception, Trap, Interrupt}, mmu::MMU};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub(crate) enum Mode {
   User, Supervisor, Machine,
 }
@@ -31,6 +33,8 @@ pub struct Hart {
   pub(crate) csr: CsrRegistry,
   pub(crate) mode: Mode,
   pub(crate) wfi: bool,
+  // ecalls from supervisor mode are answered by the built-in SBI
+  pub(crate) sbi: bool,
 }
 
 impl Hart {
@@ -42,6 +46,7 @@ impl Hart {
       csr: CsrRegistry::new(),
       mode: Mode::Machine,
       wfi: false,
+      sbi: false,
     }
   }
 
@@ -56,27 +61,61 @@ impl Hart {
     };
   }
 
+  // run a block of instructions, interrupts are only checked before the block
+  pub(crate) fn step_block(&mut self, mmu: &mut MMU) -> u32 {
+    match self.enter_block(mmu) {
+      Some(translated) => self.run_block(&translated.insts, mmu),
+      None => 1,
+    }
+  }
+
+  // None -> the hart is waiting for interrupts, or failed to fetch the block
+  pub(crate) fn enter_block(&mut self, mmu: &mut MMU) -> Option<Rc<Translated>> {
+    let interrupt = self.check_interrupt();
+    if let Some(interrupt) = interrupt {
+      self.handle_trap(Trap::Interrupt(interrupt));
+    }
+    if self.wfi {
+      return None;
+    }
+    match mmu.block(self) {
+      Ok(translated) => Some(translated),
+      Err(exception) => {
+        self.handle_trap(Trap::Exception(exception));
+        None
+      },
+    }
+  }
+
+  pub(crate) fn run_block(&mut self, insts: &[Decoded], mmu: &mut MMU) -> u32 {
+    let mut retired = 0;
+    for &decoded in insts {
+      retired += 1;
+      if !self.run_decoded(decoded, mmu) { break; }
+    }
+    retired
+  }
+
+  // false -> trapped
+  pub(crate) fn run_decoded(&mut self, Decoded { instructor, operands, len }: Decoded, mmu: &mut MMU) -> bool {
+    match (instructor.run)(&operands, len, mmu, self) {
+      Ok(()) => {
+        self.pc = self.pc.wrapping_add(len);
+        true
+      },
+      Err(exception) => {
+        self.handle_trap(Trap::Exception(exception));
+        false
+      },
+    }
+  }
+
   fn instruct(&mut self, mmu: &mut MMU) -> Result<InstructionLen, Exception> {
     if self.wfi {
       return Ok(0);
     }
-    let inst = mmu.fetch(self, self.pc)?;
-    let parsed: Option<(&Instructor, u32, InstructionLen)> = try {
-      let (inst, len) = match inst {
-        InstructionWithType::L32(inst) => {
-          // println!("{:x} {:?}: {:x}", self.pc, self.mode, inst);
-          (inst, 4)
-        },
-        InstructionWithType::L16(inst) => {
-          // println!("{:x} {:?}: {:x}", self.pc, self.mode, inst);
-          (decompress(inst)?, 2)
-        },
-      };
-      (parse(inst)?, inst, len)
-    };
-    let (instructor, inst, len) = parsed
-      .ok_or(Exception::IllegalInstruction)?;
-    (instructor.run)(inst, len, mmu, self)?;
+    let Decoded { instructor, operands, len } = mmu.decode(self, self.pc)?;
+    (instructor.run)(&operands, len, mmu, self)?;
     Ok(len)
   }
 
@@ -104,7 +143,7 @@ impl Hart {
         if check_mode(self, Mode::Machine) => Some(Interrupt::MachineExternal),
       (MIEP { ss: true, .. }, MIEP { ss: true, .. })
         if check_mode(self, Mode::Supervisor) => Some(Interrupt::SupervisorSoftware),
-      (MIEP { mt: true, .. }, MIEP { st: true, .. })
+      (MIEP { st: true, .. }, MIEP { st: true, .. })
         if check_mode(self, Mode::Supervisor) => Some(Interrupt::SupervisorTimer),
       (MIEP { se: true, .. }, MIEP { se: true, .. })
         if check_mode(self, Mode::Supervisor) => Some(Interrupt::SupervisorExternal),
@@ -0,0 +1,207 @@
+use std::{collections::HashMap, rc::Rc, sync::Arc};
+
+use crate::{devices::memory::{CodePages, MEMORY_START, MEMORY_END, PAGE_SIZE}, hart::Mode};
+
+use super::Decoded;
+
+pub(crate) const MAX_BLOCK_LEN: usize = 64;
+
+// branches, jumps, system (csr, trap return, wfi...) and fences
+pub(crate) fn ends_block(opcode: usize) -> bool {
+  matches!(opcode, 0b1100011 | 0b1101111 | 0b1100111 | 0b1110011 | 0b0001111)
+}
+
+pub(crate) fn cacheable(address: u64) -> bool {
+  (MEMORY_START..=MEMORY_END).contains(&address)
+}
+
+#[derive(Debug)]
+pub(crate) struct Translated {
+  pub(crate) insts: Box<[Decoded]>,
+  #[cfg(feature = "jit")]
+  pub(crate) native: crate::jit::Native,
+}
+
+impl Translated {
+  pub(crate) fn new(insts: Vec<Decoded>) -> Translated {
+    Translated {
+      insts: insts.into_boxed_slice(),
+      #[cfg(feature = "jit")]
+      native: crate::jit::Native::new(),
+    }
+  }
+}
+
+// Blocks are only followed while satp stays the same and no SFENCE.VMA happens,
+// so the virtual pc still maps to the block.
+#[derive(Debug, Clone, Copy)]
+struct Link {
+  pc: u64,
+  satp: u64,
+  id: u64,
+  index: usize,
+}
+
+#[derive(Debug, Clone)]
+struct Block {
+  id: u64,
+  physical: u64,
+  mode: Mode,
+  generation: u32,
+  translated: Rc<Translated>,
+  // the most recent two successors
+  links: [Option<Link>; 2],
+}
+
+// Straight-line instructions in one physical page, ending with an instruction which may change pc
+#[derive(Debug, Clone)]
+pub(crate) struct BlockCache {
+  blocks: Vec<Option<Block>>,
+  free: Vec<usize>,
+  map: HashMap<(u64, Mode), usize>,
+  last: Option<usize>,
+  next_id: u64,
+  generation: u64,
+  code: Arc<CodePages>,
+}
+
+impl BlockCache {
+  pub(crate) fn new(code: Arc<CodePages>) -> BlockCache {
+    BlockCache {
+      blocks: Vec::new(),
+      free: Vec::new(),
+      map: HashMap::new(),
+      last: None,
+      next_id: 0,
+      generation: code.generation(),
+      code,
+    }
+  }
+
+  // follow the link of the last block without translating pc
+  #[inline]
+  pub(crate) fn follow(&mut self, pc: u64, satp: u64, mode: Mode) -> Option<Rc<Translated>> {
+    if self.code.generation() != self.generation { self.sync(); }
+    let last = self.blocks[self.last?].as_ref()?;
+    let link = last.links.iter().flatten()
+      .find(|link| link.pc == pc && link.satp == satp)?;
+    let index = link.index;
+    let block = self.blocks[index].as_ref()
+      .filter(|block| block.id == link.id && block.mode == mode)?;
+    let translated = block.translated.clone();
+    self.last = Some(index);
+    Some(translated)
+  }
+
+  // look up the block at the physical address, and link the last block to it
+  pub(crate) fn get(&mut self, pc: u64, satp: u64, physical: u64, mode: Mode) -> Option<Rc<Translated>> {
+    let index = *self.map.get(&(physical, mode))?;
+    self.enter(index, pc, satp);
+    Some(self.blocks[index].as_ref()?.translated.clone())
+  }
+
+  pub(crate) fn insert(&mut self, pc: u64, satp: u64, physical: u64, mode: Mode, translated: Rc<Translated>) {
+    // stores to the page drop the block from now on
+    let index = (physical - MEMORY_START) / PAGE_SIZE;
+    let block = Block {
+      id: self.next_id,
+      physical,
+      mode,
+      generation: self.code.watch(index as usize),
+      translated,
+      links: [None; 2],
+    };
+    self.next_id += 1;
+    let index = match self.free.pop() {
+      Some(index) => {
+        self.blocks[index] = Some(block);
+        index
+      },
+      None => {
+        self.blocks.push(Some(block));
+        self.blocks.len() - 1
+      },
+    };
+    self.map.insert((physical, mode), index);
+    self.enter(index, pc, satp);
+  }
+
+  // the block won't be followed from the last block
+  pub(crate) fn leave(&mut self) {
+    self.last = None;
+  }
+
+  fn enter(&mut self, index: usize, pc: u64, satp: u64) {
+    let id = self.blocks[index].as_ref().unwrap().id;
+    if let Some(last) = self.last.and_then(|last| self.blocks[last].as_mut()) {
+      last.links.swap(0, 1);
+      last.links[0] = Some(Link { pc, satp, id, index });
+    }
+    self.last = Some(index);
+  }
+
+  // SFENCE.VMA
+  pub(crate) fn unlink(&mut self) {
+    for block in self.blocks.iter_mut().flatten() {
+      block.links = [None; 2];
+    }
+    self.last = None;
+  }
+
+  // FENCE.I
+  pub(crate) fn flush(&mut self) {
+    self.blocks.clear();
+    self.free.clear();
+    self.map.clear();
+    self.last = None;
+  }
+
+  // drop blocks in pages which have been written since they were built
+  fn sync(&mut self) {
+    self.generation = self.code.generation();
+    for index in 0..self.blocks.len() {
+      let Some(block) = &self.blocks[index] else { continue };
+      let page = ((block.physical - MEMORY_START) / PAGE_SIZE) as usize;
+      if block.generation == self.code.page_generation(page) { continue; }
+      self.map.remove(&(block.physical, block.mode));
+      self.blocks[index] = None;
+      self.free.push(index);
+      if self.last == Some(index) { self.last = None; }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::{devices::{bus::Bus, Device}, hart::Hart, mmu::MMU};
+
+  use super::*;
+
+  // a0 counts up to a1, in a block which is chained to itself
+  fn count(hart: &mut Hart, mmu: &mut MMU) {
+    hart.pc = MEMORY_START;
+    hart.regs.set(10, 0);
+    hart.regs.set(11, 9);
+    for _ in 0..100 {
+      if hart.pc == MEMORY_START + 8 { return; }
+      hart.step_block(mmu);
+    }
+    panic!("the loop did not end");
+  }
+
+  #[test]
+  fn overwritten_block() {
+    let (mut bus, _controller) = Bus::new();
+    // addi a0, a0, 1; blt a0, a1, -4
+    bus.write32(MEMORY_START, 0x00150513).unwrap();
+    bus.write32(MEMORY_START + 4, 0xfeb54ee3).unwrap();
+    let mut mmu = MMU::new(bus.clone());
+    let mut hart = Hart::new();
+    count(&mut hart, &mut mmu);
+    assert_eq!(hart.regs[10], 9);
+    // addi a0, a0, 2
+    bus.write32(MEMORY_START, 0x00250513).unwrap();
+    count(&mut hart, &mut mmu);
+    assert_eq!(hart.regs[10], 10);
+  }
+}
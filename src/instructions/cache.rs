@@ -0,0 +1,101 @@
+use std::sync::Arc;
+
+use crate::devices::memory::{CodePages, MEMORY_START, MEMORY_END, PAGE_SIZE, PAGE_COUNT};
+
+use super::Decoded;
+
+const SLOTS: usize = PAGE_SIZE as usize / 2;
+
+#[derive(Debug, Clone)]
+struct CachedPage {
+  generation: u32,
+  // indexed by (address % PAGE_SIZE) / 2
+  slots: [Option<Decoded>; SLOTS],
+}
+
+// Decoded instructions of memory, indexed by physical address.
+// Only instructions in memory which don't cross pages are cached.
+#[derive(Debug, Clone)]
+pub(crate) struct DecodeCache {
+  pages: Vec<Option<Box<CachedPage>>>,
+  cached: Vec<usize>,
+  generation: u64,
+  code: Arc<CodePages>,
+}
+
+impl DecodeCache {
+  pub(crate) fn new(code: Arc<CodePages>) -> DecodeCache {
+    DecodeCache {
+      pages: vec![None; PAGE_COUNT],
+      cached: Vec::new(),
+      generation: code.generation(),
+      code,
+    }
+  }
+
+  #[inline]
+  pub(crate) fn get(&mut self, address: u64) -> Option<Decoded> {
+    if !(MEMORY_START..=MEMORY_END).contains(&address) { return None; }
+    if self.code.generation() != self.generation { self.sync(); }
+    let offset = address - MEMORY_START;
+    let page = self.pages[(offset / PAGE_SIZE) as usize].as_ref()?;
+    page.slots[(offset % PAGE_SIZE / 2) as usize]
+  }
+
+  pub(crate) fn insert(&mut self, address: u64, decoded: Decoded) {
+    if !(MEMORY_START..=MEMORY_END).contains(&address) { return; }
+    let offset = address - MEMORY_START;
+    if offset % PAGE_SIZE + decoded.len > PAGE_SIZE { return; }
+    let index = (offset / PAGE_SIZE) as usize;
+    let page = self.pages[index].get_or_insert_with(|| {
+      self.cached.push(index);
+      Box::new(CachedPage {
+        generation: self.code.watch(index),
+        slots: [None; SLOTS],
+      })
+    });
+    page.slots[(offset % PAGE_SIZE / 2) as usize] = Some(decoded);
+  }
+
+  pub(crate) fn flush(&mut self) {
+    for index in self.cached.drain(..) {
+      self.pages[index] = None;
+    }
+  }
+
+  // drop pages which have been written since they were cached
+  fn sync(&mut self) {
+    self.generation = self.code.generation();
+    let DecodeCache { pages, cached, code, .. } = self;
+    cached.retain(|&index| {
+      let stale = pages[index].as_ref()
+        .is_some_and(|page| page.generation != code.page_generation(index));
+      if stale { pages[index] = None; }
+      !stale
+    });
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::{devices::{Device, memory::Memory}, instructions::{InstructionWithType, decode}};
+
+  use super::*;
+
+  #[test]
+  fn overwritten_code() {
+    let mut memory = Memory::new();
+    let mut cache = DecodeCache::new(memory.code.clone());
+    // addi a0, a0, 1
+    cache.insert(MEMORY_START, decode(InstructionWithType::L32(0x00150513)).unwrap());
+    assert_eq!(cache.get(MEMORY_START).map(|decoded| (decoded.instructor.name, decoded.operands.imm)), Some(("ADDI", 1)));
+    // stores to other pages keep it
+    memory.write32(MEMORY_START + PAGE_SIZE, 0x00250513).unwrap();
+    assert!(cache.get(MEMORY_START).is_some());
+    // any store to its page drops it
+    memory.write32(MEMORY_START + 0x100, 0x00250513).unwrap();
+    assert!(cache.get(MEMORY_START).is_none());
+    cache.insert(MEMORY_START, decode(InstructionWithType::L32(0x00250513)).unwrap());
+    assert_eq!(cache.get(MEMORY_START).map(|decoded| (decoded.instructor.name, decoded.operands.imm)), Some(("ADDI", 2)));
+  }
+}
@@ -1,4 +1,4 @@
-use crate::{instructions::{Instructor, InstructionSegment}, hart::Mode, trap::Exception};
+use crate::{instructions::{Instructor, InstructionSegment}, hart::Mode, trap::Exception, sbi};
 
 use super::{U, InstructionParser, funct3, funct37, J, I, B, R, S};
 
@@ -442,7 +442,11 @@ pub(crate) fn i() -> Vec<Instructor> {
       segments: vec![
         InstructionSegment { start: 7, end: 31, comp: 0b0000000000000000000000000 },
       ],
-      run: |_inst, _len, _mmu, hart| {
+      run: |_inst, _len, mmu, hart| {
+        if hart.sbi && hart.mode == Mode::Supervisor {
+          sbi::call(hart, mmu);
+          return Ok(());
+        }
         Err(match hart.mode {
           Mode::User => Exception::EnvironmentCallFromUMode,
           Mode::Supervisor => Exception::EnvironmentCallFromSMode,
@@ -508,9 +512,8 @@ pub(crate) fn i() -> Vec<Instructor> {
         InstructionSegment { start: 26, end: 31, comp: 0b000000 },
       ],
       run: |inst, _len, _mmu, hart| {
-        let shamt = inst >> 20 & 0b111111;
-        let rs1 = (inst >> 15 & 0b11111) as usize;
-        let rd = (inst >> 7 & 0b11111) as usize;
+        let I { imm, rs1, rd } = inst.i();
+        let shamt = imm & 0b111111;
         hart.regs.set(rd, hart.regs[rs1] << shamt);
         Ok(())
       },
@@ -524,9 +527,8 @@ pub(crate) fn i() -> Vec<Instructor> {
         InstructionSegment { start: 26, end: 31, comp: 0b000000 },
       ],
       run: |inst, _len, _mmu, hart| {
-        let shamt = inst >> 20 & 0b111111;
-        let rs1 = (inst >> 15 & 0b11111) as usize;
-        let rd = (inst >> 7 & 0b11111) as usize;
+        let I { imm, rs1, rd } = inst.i();
+        let shamt = imm & 0b111111;
         hart.regs.set(rd, hart.regs[rs1] >> shamt);
         Ok(())
       },
@@ -540,9 +542,8 @@ pub(crate) fn i() -> Vec<Instructor> {
         InstructionSegment { start: 26, end: 31, comp: 0b010000 },
       ],
       run: |inst, _len, _mmu, hart| {
-        let shamt = inst >> 20 & 0b111111;
-        let rs1 = (inst >> 15 & 0b11111) as usize;
-        let rd = (inst >> 7 & 0b11111) as usize;
+        let I { imm, rs1, rd } = inst.i();
+        let shamt = imm & 0b111111;
         hart.regs.set(rd, (hart.regs[rs1] as i64 >> shamt) as u64);
         Ok(())
       },
@@ -567,9 +568,8 @@ pub(crate) fn i() -> Vec<Instructor> {
         InstructionSegment { start: 26, end: 31, comp: 0b000000 },
       ],
       run: |inst, _len, _mmu, hart| {
-        let shamt = inst >> 20 & 0b11111;
-        let rs1 = (inst >> 15 & 0b11111) as usize;
-        let rd = (inst >> 7 & 0b11111) as usize;
+        let I { imm, rs1, rd } = inst.i();
+        let shamt = imm & 0b11111;
         hart.regs.set(rd, ((hart.regs[rs1] as u32 as i32) << shamt) as i64 as u64);
         Ok(())
       },
@@ -583,9 +583,8 @@ pub(crate) fn i() -> Vec<Instructor> {
         InstructionSegment { start: 26, end: 31, comp: 0b000000 },
       ],
       run: |inst, _len, _mmu, hart| {
-        let shamt = inst >> 20 & 0b11111;
-        let rs1 = (inst >> 15 & 0b11111) as usize;
-        let rd = (inst >> 7 & 0b11111) as usize;
+        let I { imm, rs1, rd } = inst.i();
+        let shamt = imm & 0b11111;
         hart.regs.set(rd, (hart.regs[rs1] as u32 >> shamt) as i32 as i64 as u64);
         Ok(())
       },
@@ -599,9 +598,8 @@ pub(crate) fn i() -> Vec<Instructor> {
         InstructionSegment { start: 26, end: 31, comp: 0b010000 },
       ],
       run: |inst, _len, _mmu, hart| {
-        let shamt = inst >> 20 & 0b11111;
-        let rs1 = (inst >> 15 & 0b11111) as usize;
-        let rd = (inst >> 7 & 0b11111) as usize;
+        let I { imm, rs1, rd } = inst.i();
+        let shamt = imm & 0b11111;
         hart.regs.set(rd, (hart.regs[rs1] as u32 as i32 >> shamt) as i64 as u64);
         Ok(())
       },
@@ -73,6 +73,36 @@ pub(crate) struct RFPRS3 {
   pub(crate) rd: usize,
 }
 
+// Fields of an instruction, extracted once when it's decoded. Registers sit at the same bits in
+// every format, the immediate is the one of the opcode's format.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Operands {
+  pub(crate) imm: i64,
+  pub(crate) rd: u8,
+  pub(crate) rs1: u8,
+  pub(crate) rs2: u8,
+  pub(crate) rs3: u8,
+  pub(crate) rm: u8,
+  pub(crate) aq: bool,
+  pub(crate) rl: bool,
+}
+
+impl Operands {
+  pub(crate) fn new(inst: u32) -> Operands {
+    let imm = match inst & 0b1111111 {
+      0b0110111 | 0b0010111 => inst.u().imm,
+      0b1101111 => inst.j().imm,
+      0b1100011 => inst.b().imm,
+      0b0100011 | 0b0100111 => inst.s().imm,
+      // loads, immediate arithmetic, jalr, fences and system
+      _ => inst.i().imm,
+    };
+    let RFPRS3 { rs3, rs2, rs1, rm, rd } = inst.rfp_rs3();
+    let RA { aq, rl, .. } = inst.ra();
+    Operands { imm, rd: rd as u8, rs1: rs1 as u8, rs2: rs2 as u8, rs3: rs3 as u8, rm, aq, rl }
+  }
+}
+
 pub(crate) trait InstructionParser {
   fn r(&self) -> R;
   fn i(&self) -> I;
@@ -162,6 +192,44 @@ impl InstructionParser for u32 {
   }
 }
 
+impl InstructionParser for Operands {
+  fn r(&self) -> R {
+    R { rs2: self.rs2 as usize, rs1: self.rs1 as usize, rd: self.rd as usize }
+  }
+
+  fn i(&self) -> I {
+    I { imm: self.imm, rs1: self.rs1 as usize, rd: self.rd as usize }
+  }
+
+  fn s(&self) -> S {
+    S { imm: self.imm, rs2: self.rs2 as usize, rs1: self.rs1 as usize }
+  }
+
+  fn b(&self) -> B {
+    B { imm: self.imm, rs2: self.rs2 as usize, rs1: self.rs1 as usize }
+  }
+
+  fn u(&self) -> U {
+    U { imm: self.imm, rd: self.rd as usize }
+  }
+
+  fn j(&self) -> J {
+    J { imm: self.imm, rd: self.rd as usize }
+  }
+
+  fn ra(&self) -> RA {
+    RA { aq: self.aq, rl: self.rl, rs2: self.rs2 as usize, rs1: self.rs1 as usize, rd: self.rd as usize }
+  }
+
+  fn rfp(&self) -> RFP {
+    RFP { rs2: self.rs2 as usize, rs1: self.rs1 as usize, rm: self.rm, rd: self.rd as usize }
+  }
+
+  fn rfp_rs3(&self) -> RFPRS3 {
+    RFPRS3 { rs3: self.rs3 as usize, rs2: self.rs2 as usize, rs1: self.rs1 as usize, rm: self.rm, rd: self.rd as usize }
+  }
+}
+
 pub(crate) fn funct3(funct3: u8) -> Vec<InstructionSegment> {
   vec![
     InstructionSegment { start: 12, end: 14, comp: funct3 as u32 },
@@ -1,6 +1,6 @@
 use crate::{instructions::Instructor, csrs::CsrRegistry};
 
-use super::funct3;
+use super::{funct3, I, InstructionParser};
 
 pub(crate) fn zicsr() -> Vec<Instructor> {
   Vec::from([
@@ -9,9 +9,8 @@ pub(crate) fn zicsr() -> Vec<Instructor> {
       opcode: 0b1110011,
       segments: funct3(0b001),
       run: |inst, _len, _mmu, hart| {
-        let csr = (inst >> 20) as u16;
-        let rs1 = (inst >> 15 & 0b11111) as usize;
-        let rd = (inst >> 7 & 0b11111) as usize;
+        let I { imm, rs1, rd } = inst.i();
+        let csr = imm as u16 & 0xfff;
         if rd != 0 {
           let res = CsrRegistry::read(hart, csr)?;
           CsrRegistry::write(hart, csr, hart.regs[rs1])?;
@@ -28,9 +27,8 @@ pub(crate) fn zicsr() -> Vec<Instructor> {
       opcode: 0b1110011,
       segments: funct3(0b010),
       run: |inst, _len, _mmu, hart| {
-        let csr = (inst >> 20) as u16;
-        let rs1 = (inst >> 15 & 0b11111) as usize;
-        let rd = (inst >> 7 & 0b11111) as usize;
+        let I { imm, rs1, rd } = inst.i();
+        let csr = imm as u16 & 0xfff;
         let res = CsrRegistry::read(hart, csr)?;
         if rs1 != 0 {
           CsrRegistry::write(hart, csr, res | hart.regs[rs1])?;
@@ -45,9 +43,8 @@ pub(crate) fn zicsr() -> Vec<Instructor> {
       opcode: 0b1110011,
       segments: funct3(0b011),
       run: |inst, _len, _mmu, hart| {
-        let csr = (inst >> 20) as u16;
-        let rs1 = (inst >> 15 & 0b11111) as usize;
-        let rd = (inst >> 7 & 0b11111) as usize;
+        let I { imm, rs1, rd } = inst.i();
+        let csr = imm as u16 & 0xfff;
         let res = CsrRegistry::read(hart, csr)?;
         if rs1 != 0 {
           CsrRegistry::write(hart, csr, res & !hart.regs[rs1])?;
@@ -62,9 +59,9 @@ pub(crate) fn zicsr() -> Vec<Instructor> {
       opcode: 0b1110011,
       segments: funct3(0b101),
       run: |inst, _len, _mmu, hart| {
-        let csr = (inst >> 20) as u16;
-        let uimm = (inst >> 15 & 0b11111) as u64;
-        let rd = (inst >> 7 & 0b11111) as usize;
+        let I { imm, rs1, rd } = inst.i();
+        let csr = imm as u16 & 0xfff;
+        let uimm = rs1 as u64;
         if rd != 0 {
           let res = CsrRegistry::read(hart, csr)?;
           CsrRegistry::write(hart, csr, uimm)?;
@@ -81,9 +78,9 @@ pub(crate) fn zicsr() -> Vec<Instructor> {
       opcode: 0b1110011,
       segments: funct3(0b110),
       run: |inst, _len, _mmu, hart| {
-        let csr = (inst >> 20) as u16;
-        let uimm = (inst >> 15 & 0b11111) as u64;
-        let rd = (inst >> 7 & 0b11111) as usize;
+        let I { imm, rs1, rd } = inst.i();
+        let csr = imm as u16 & 0xfff;
+        let uimm = rs1 as u64;
         let res = CsrRegistry::read(hart, csr)?;
         if uimm != 0 {
           CsrRegistry::write(hart, csr, res | uimm)?;
@@ -98,9 +95,9 @@ pub(crate) fn zicsr() -> Vec<Instructor> {
       opcode: 0b1110011,
       segments: funct3(0b111),
       run: |inst, _len, _mmu, hart| {
-        let csr = (inst >> 20) as u16;
-        let uimm = (inst >> 15 & 0b11111) as u64;
-        let rd = (inst >> 7 & 0b11111) as usize;
+        let I { imm, rs1, rd } = inst.i();
+        let csr = imm as u16 & 0xfff;
+        let uimm = rs1 as u64;
         let res = CsrRegistry::read(hart, csr)?;
         if uimm != 0 {
           CsrRegistry::write(hart, csr, res & !uimm)?;
@@ -8,8 +8,8 @@ pub(crate) fn zifenci() -> Vec<Instructor> {
       name: "FENCE.I",
       opcode: 0b0001111,
       segments: funct3(0b001),
-      run: |_inst, _len, _mmu, _hart| {
-        // do nothing
+      run: |_inst, _len, mmu, _hart| {
+        mmu.fence_i();
         Ok(())
       },
     },
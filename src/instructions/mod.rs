@@ -4,9 +4,11 @@ use static_init::dynamic;
 
 use crate::{hart::Hart, trap::Exception, mmu::MMU};
 
-use self::extensions::{i::i, zifenci::zifenci, zicsr::zicsr, m::m, a::a, f::f, d::d, sm::sm};
+use self::extensions::{Operands, i::i, zifenci::zifenci, zicsr::zicsr, m::m, a::a, f::f, d::d, sm::sm, c::decompress};
 
 pub(crate) mod extensions;
+pub(crate) mod cache;
+pub(crate) mod block;
 
 // 0, 2, 4
 pub(crate) type InstructionLen = u64;
@@ -16,6 +18,16 @@ pub(crate) enum InstructionWithType {
   L32(u32), L16(u16),
 }
 
+pub(crate) type Run = fn(inst: &Operands, len: InstructionLen, mmu: &mut MMU, hart: &mut Hart) -> Result<(), Exception>;
+
+// instruction resolved to its instructor and operands, compressed instructions are decompressed
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Decoded {
+  pub(crate) instructor: &'static Instructor,
+  pub(crate) operands: Operands,
+  pub(crate) len: InstructionLen,
+}
+
 //                         (mask, comp, instructor), index is opcode
 type InstructionMap = [Vec<(u32, u32, Instructor)>; 128];
 
@@ -51,7 +63,7 @@ pub(crate) struct Instructor {
   pub(crate) name: &'static str,
   pub(crate) opcode: usize,
   pub(crate) segments: Vec<InstructionSegment>,
-  pub(crate) run: fn(inst: u32, len: InstructionLen, mmu: &mut MMU, hart: &mut Hart) -> Result<(), Exception>
+  pub(crate) run: Run,
 }
 
 impl Instructor {
@@ -81,3 +93,11 @@ pub(crate) fn parse(inst: u32) -> Option<&'static Instructor> {
   }
   None
 }
+
+pub(crate) fn decode(inst: InstructionWithType) -> Option<Decoded> {
+  let (inst, len) = match inst {
+    InstructionWithType::L32(inst) => (inst, 4),
+    InstructionWithType::L16(inst) => (decompress(inst)?, 2),
+  };
+  Some(Decoded { instructor: parse(inst)?, operands: Operands::new(inst), len })
+}
@@ -0,0 +1,412 @@
+use std::{cell::Cell, ptr::{null_mut, addr_of_mut}, sync::atomic::AtomicBool};
+
+use crate::{hart::Hart, mmu::MMU, instructions::Decoded, tlb::TlbEntry};
+
+use self::x86_64::{compile, Trampolines};
+
+#[cfg(not(target_arch = "x86_64"))]
+compile_error!("the jit feature only supports x86-64 hosts");
+
+mod x86_64;
+
+// blocks are compiled after being run this many times
+const HOT: u32 = 50;
+const ARENA_SIZE: usize = 16 * 1024 * 1024;
+// of the host, the granularity of mprotect
+const PAGE_SIZE: usize = 4096;
+
+#[derive(Debug, Clone, Copy)]
+struct Code {
+  entry: usize,
+  generation: u64,
+}
+
+// native code of a translated block
+#[derive(Debug, Default)]
+pub(crate) struct Native {
+  hits: Cell<u32>,
+  code: Cell<Option<Code>>,
+  // nothing in the block can run natively
+  cold: Cell<bool>,
+}
+
+impl Native {
+  pub(crate) fn new() -> Native {
+    Native::default()
+  }
+}
+
+// Executable memory, never writable and executable at once: pages are made writable while code is copied in.
+// When it is full, everything is dropped and the generation is bumped, so blocks compiled before are compiled again.
+struct Arena {
+  ptr: *mut u8,
+  used: usize,
+  generation: u64,
+}
+
+impl Arena {
+  fn new() -> Arena {
+    let ptr = unsafe {
+      libc::mmap(null_mut(), ARENA_SIZE, libc::PROT_READ | libc::PROT_EXEC,
+        libc::MAP_PRIVATE | libc::MAP_ANONYMOUS, -1, 0)
+    };
+    assert_ne!(ptr, libc::MAP_FAILED, "failed to map memory for the jit");
+    Arena { ptr: ptr as *mut u8, used: 0, generation: 0 }
+  }
+
+  fn alloc(&mut self, code: &[u8]) -> Code {
+    assert!(code.len() <= ARENA_SIZE);
+    if self.used + code.len() > ARENA_SIZE {
+      self.used = 0;
+      self.generation += 1;
+    }
+    // the pages the code goes to
+    let start = self.used & !(PAGE_SIZE - 1);
+    let len = (self.used + code.len() - start + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+    let entry = unsafe {
+      let pages = self.ptr.add(start) as *mut libc::c_void;
+      assert_eq!(libc::mprotect(pages, len, libc::PROT_READ | libc::PROT_WRITE), 0, "failed to unprotect jit memory");
+      let entry = self.ptr.add(self.used);
+      entry.copy_from_nonoverlapping(code.as_ptr(), code.len());
+      assert_eq!(libc::mprotect(pages, len, libc::PROT_READ | libc::PROT_EXEC), 0, "failed to protect jit memory");
+      entry
+    };
+    // keep entries aligned
+    self.used = (self.used + code.len() + 15) & !15;
+    Code { entry: entry as usize, generation: self.generation }
+  }
+}
+
+impl Drop for Arena {
+  fn drop(&mut self) {
+    unsafe { libc::munmap(self.ptr as *mut _, ARENA_SIZE); }
+  }
+}
+
+// passed to native code, which finds the fields by their offsets
+#[repr(C)]
+struct Context {
+  regs: *mut u64,
+  pc: *mut u64,
+  // TLB entries for loads and stores, in the context the block was entered in
+  read_tlb: *const TlbEntry,
+  write_tlb: *const TlbEntry,
+  // pages with cached instructions
+  watched: *const AtomicBool,
+  hart: *mut Hart,
+  mmu: *mut MMU,
+  insts: *const Decoded,
+  shadow: *mut Hart,
+  mismatch: bool,
+}
+
+type Entry = extern "sysv64" fn(*mut Context) -> u32;
+type Trampoline = extern "sysv64" fn(*mut Context, u32) -> u32;
+
+// run an instruction which can't be compiled, non-zero if it trapped
+extern "sysv64" fn fallback(ctx: *mut Context, index: u32) -> u32 {
+  let ctx = unsafe { &mut *ctx };
+  let (hart, mmu) = unsafe { (&mut *ctx.hart, &mut *ctx.mmu) };
+  let decoded = unsafe { *ctx.insts.add(index as usize) };
+  if hart.run_decoded(decoded, mmu) { 0 } else { 1 }
+}
+
+// run the instruction with the interpreter on the shadow hart
+extern "sysv64" fn check_before(ctx: *mut Context, index: u32) -> u32 {
+  let ctx = unsafe { &mut *ctx };
+  let (hart, shadow, mmu) = unsafe { (&mut *ctx.hart, &mut *ctx.shadow, &mut *ctx.mmu) };
+  let decoded = unsafe { *ctx.insts.add(index as usize) };
+  shadow.regs = hart.regs.clone();
+  shadow.pc = hart.pc;
+  shadow.mode = hart.mode;
+  if !shadow.run_decoded(decoded, mmu) {
+    ctx.mismatch = true;
+    return 1;
+  }
+  0
+}
+
+// compare the native result with the shadow hart
+extern "sysv64" fn check_after(ctx: *mut Context, _index: u32) -> u32 {
+  let ctx = unsafe { &mut *ctx };
+  let (hart, shadow) = unsafe { (&*ctx.hart, &*ctx.shadow) };
+  if hart.regs != shadow.regs || hart.pc != shadow.pc {
+    ctx.mismatch = true;
+    return 1;
+  }
+  0
+}
+
+pub(crate) struct Jit {
+  arena: Arena,
+  trampolines: Trampolines,
+  // lockstep: native instructions are checked against the interpreter
+  shadow: Option<Box<Hart>>,
+}
+
+impl Jit {
+  pub(crate) fn new(lockstep: bool) -> Jit {
+    Jit {
+      arena: Arena::new(),
+      trampolines: Trampolines {
+        fallback: fallback as Trampoline as usize as u64,
+        check: lockstep.then_some((check_before as Trampoline as usize as u64, check_after as Trampoline as usize as u64)),
+      },
+      shadow: lockstep.then(|| Box::new(Hart::new())),
+    }
+  }
+
+  // same as Hart::step_block, but hot blocks run natively
+  pub(crate) fn step_block(&mut self, hart: &mut Hart, mmu: &mut MMU) -> u32 {
+    let Some(translated) = hart.enter_block(mmu) else { return 1 };
+    let native = &translated.native;
+    let code = native.code.get().filter(|code| code.generation == self.arena.generation);
+    let code = match code {
+      Some(code) => code,
+      None if native.cold.get() || native.hits.get() < HOT => {
+        native.hits.set(native.hits.get() + 1);
+        return hart.run_block(&translated.insts, mmu);
+      },
+      None => match compile(&translated.insts, &self.trampolines) {
+        Some(code) => {
+          let code = self.arena.alloc(&code);
+          native.code.set(Some(code));
+          code
+        },
+        None => {
+          native.cold.set(true);
+          return hart.run_block(&translated.insts, mmu);
+        },
+      },
+    };
+    // only instructions which end the block change the translation context
+    let (read_tlb, write_tlb) = mmu.native_tlbs(hart);
+    let watched = mmu.bus().memory.code.watched();
+    if let Some(shadow) = &mut self.shadow {
+      shadow.csr.csr = hart.csr.csr;
+    }
+    let hart: *mut Hart = hart;
+    let mut ctx = Context {
+      regs: unsafe { (*hart).regs.as_mut_ptr() },
+      pc: unsafe { addr_of_mut!((*hart).pc) },
+      read_tlb,
+      write_tlb,
+      watched,
+      hart,
+      mmu,
+      insts: translated.insts.as_ptr(),
+      shadow: self.shadow.as_deref_mut().map_or(null_mut(), |shadow| shadow as *mut _),
+      mismatch: false,
+    };
+    let entry: Entry = unsafe { std::mem::transmute(code.entry) };
+    let retired = entry(&mut ctx);
+    if ctx.mismatch {
+      let index = retired as usize - 1;
+      let decoded = translated.insts[index];
+      let (hart, shadow) = unsafe { (&*ctx.hart, &*ctx.shadow) };
+      panic!("jit mismatch at instruction {} ({} {:?}) of the block, pc: {:#x} (interpreter: {:#x}), regs: {:x?} (interpreter: {:x?})",
+        index, decoded.instructor.name, decoded.operands, hart.pc, shadow.pc, hart.regs, shadow.regs);
+    }
+    retired
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::{cpu::Cpu, devices::{Device, memory::MEMORY_START}};
+  use super::Jit;
+
+  fn r(funct7: u32, rs2: u32, rs1: u32, funct3: u32, rd: u32, opcode: u32) -> u32 {
+    funct7 << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | opcode
+  }
+
+  fn i(imm: i32, rs1: u32, funct3: u32, rd: u32, opcode: u32) -> u32 {
+    (imm as u32) << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | opcode
+  }
+
+  fn s(imm: i32, rs2: u32, rs1: u32, funct3: u32) -> u32 {
+    let imm = imm as u32;
+    (imm >> 5 & 0b1111111) << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12 | (imm & 0b11111) << 7 | 0b0100011
+  }
+
+  fn b(imm: i32, rs2: u32, rs1: u32, funct3: u32) -> u32 {
+    let imm = imm as u32;
+    (imm >> 12 & 1) << 31 | (imm >> 5 & 0b111111) << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12
+      | (imm >> 1 & 0b1111) << 8 | (imm >> 11 & 1) << 7 | 0b1100011
+  }
+
+  fn j(imm: i32, rd: u32) -> u32 {
+    let imm = imm as u32;
+    (imm >> 20 & 1) << 31 | (imm >> 1 & 0x3ff) << 21 | (imm >> 11 & 1) << 20 | (imm >> 12 & 0xff) << 12 | rd << 7 | 0b1101111
+  }
+
+  // every natively compiled instruction, with loads and stores to the next page, accesses left to the interpreter
+  // and jumps and branches skipping an instruction
+  fn program() -> Vec<u32> {
+    let op = 0b0110011;
+    let op_imm = 0b0010011;
+    let op_32 = 0b0111011;
+    let op_imm_32 = 0b0011011;
+    let load = 0b0000011;
+    let mut program = vec![
+      i(300, 0, 0b000, 1, op_imm),                    // addi x1, x0, 300
+      0x12345 << 12 | 2 << 7 | 0b0110111,             // lui x2, 0x12345
+      0xfffff << 12 | 3 << 7 | 0b0110111,             // lui x3, 0xfffff
+      1 << 12 | 3 << 7 | 0b0010111,                   // auipc x3, 1
+      i(-0x100, 3, 0b111, 3, op_imm),                 // andi x3, x3, -0x100
+      s(-0x100, 0, 3, 0b011),                         // sd x0, -0x100(x3)
+      i(-1, 0, 0b000, 10, op_imm),                    // addi x10, x0, -1
+    ];
+    let start = program.len();
+    program.extend([
+      r(0, 1, 2, 0b000, 2, op),                       // add x2, x2, x1
+      r(0b0100000, 2, 3, 0b000, 4, op),               // sub x4, x3, x2
+      r(0, 1, 4, 0b001, 5, op),                       // sll x5, x4, x1
+      r(0, 5, 4, 0b010, 6, op),                       // slt x6, x4, x5
+      r(0, 5, 4, 0b011, 7, op),                       // sltu x7, x4, x5
+      r(0, 2, 5, 0b100, 8, op),                       // xor x8, x5, x2
+      r(0, 1, 8, 0b101, 9, op),                       // srl x9, x8, x1
+      r(0b0100000, 1, 10, 0b101, 11, op),             // sra x11, x10, x1
+      r(0, 9, 11, 0b110, 12, op),                     // or x12, x11, x9
+      r(0, 8, 12, 0b111, 13, op),                     // and x13, x12, x8
+      i(-7, 13, 0b010, 14, op_imm),                   // slti x14, x13, -7
+      i(-7, 13, 0b011, 15, op_imm),                   // sltiu x15, x13, -7
+      i(0x5a5, 2, 0b100, 16, op_imm),                 // xori x16, x2, 0x5a5
+      i(-0x100, 16, 0b110, 17, op_imm),               // ori x17, x16, -0x100
+      i(0x7f0, 17, 0b111, 18, op_imm),                // andi x18, x17, 0x7f0
+      i(63, 2, 0b001, 19, op_imm),                    // slli x19, x2, 63
+      i(33, 19, 0b101, 20, op_imm),                   // srli x20, x19, 33
+      i(0b010000 << 6 | 17, 19, 0b101, 21, op_imm),   // srai x21, x19, 17
+      i(0x7ff, 2, 0b000, 22, op_imm_32),              // addiw x22, x2, 0x7ff
+      i(31, 22, 0b001, 23, op_imm_32),                // slliw x23, x22, 31
+      i(5, 23, 0b101, 24, op_imm_32),                 // srliw x24, x23, 5
+      i(0b010000 << 6 | 5, 23, 0b101, 25, op_imm_32), // sraiw x25, x23, 5
+      r(0, 23, 2, 0b000, 26, op_32),                  // addw x26, x2, x23
+      r(0b0100000, 2, 23, 0b000, 27, op_32),          // subw x27, x23, x2
+      r(0, 1, 27, 0b001, 28, op_32),                  // sllw x28, x27, x1
+      r(0, 1, 28, 0b101, 29, op_32),                  // srlw x29, x28, x1
+      r(0b0100000, 1, 28, 0b101, 30, op_32),          // sraw x30, x28, x1
+      r(0, 30, 0, 0b000, 0, op),                      // add x0, x0, x30
+      // multiplication and division, by zero and MIN / -1 included
+      r(1, 19, 2, 0b000, 5, op),                      // mul x5, x2, x19
+      r(1, 21, 2, 0b001, 6, op),                      // mulh x6, x2, x21
+      r(1, 2, 21, 0b010, 7, op),                      // mulhsu x7, x21, x2
+      r(1, 2, 21, 0b011, 8, op),                      // mulhu x8, x21, x2
+      r(1, 23, 2, 0b000, 9, op_32),                   // mulw x9, x2, x23
+      r(1, 22, 2, 0b100, 11, op),                     // div x11, x2, x22
+      r(1, 1, 21, 0b101, 12, op),                     // divu x12, x21, x1
+      r(1, 1, 21, 0b110, 13, op),                     // rem x13, x21, x1
+      r(1, 1, 2, 0b111, 14, op),                      // remu x14, x2, x1
+      r(1, 1, 23, 0b100, 15, op_32),                  // divw x15, x23, x1
+      r(1, 1, 21, 0b101, 16, op_32),                  // divuw x16, x21, x1
+      r(1, 1, 22, 0b110, 17, op_32),                  // remw x17, x22, x1
+      r(1, 1, 21, 0b111, 18, op_32),                  // remuw x18, x21, x1
+      r(1, 0, 2, 0b100, 20, op),                      // div x20, x2, x0
+      r(1, 0, 2, 0b110, 24, op),                      // rem x24, x2, x0
+      r(1, 0, 2, 0b101, 25, op),                      // divu x25, x2, x0
+      r(1, 0, 2, 0b111, 26, op),                      // remu x26, x2, x0
+      r(1, 0, 2, 0b100, 27, op_32),                   // divw x27, x2, x0
+      r(1, 0, 21, 0b111, 28, op_32),                  // remuw x28, x21, x0
+      i(63, 10, 0b001, 29, op_imm),                   // slli x29, x10, 63
+      r(1, 10, 29, 0b100, 30, op),                    // div x30, x29, x10
+      r(1, 10, 29, 0b110, 31, op),                    // rem x31, x29, x10
+      0x80000 << 12 | 4 << 7 | 0b0110111,             // lui x4, 0x80000
+      r(1, 10, 4, 0b100, 19, op_32),                  // divw x19, x4, x10
+      r(1, 10, 4, 0b110, 23, op_32),                  // remw x23, x4, x10
+      // every width of loads and stores
+      s(0x100, 21, 3, 0b011),                         // sd x21, 0x100(x3)
+      s(0x108, 2, 3, 0b010),                          // sw x2, 0x108(x3)
+      s(0x10c, 22, 3, 0b001),                         // sh x22, 0x10c(x3)
+      s(0x10e, 25, 3, 0b000),                         // sb x25, 0x10e(x3)
+      i(0x10e, 3, 0b000, 4, load),                    // lb x4, 0x10e(x3)
+      i(0x10e, 3, 0b100, 5, load),                    // lbu x5, 0x10e(x3)
+      i(0x10c, 3, 0b001, 6, load),                    // lh x6, 0x10c(x3)
+      i(0x10c, 3, 0b101, 7, load),                    // lhu x7, 0x10c(x3)
+      i(0x108, 3, 0b010, 8, load),                    // lw x8, 0x108(x3)
+      i(0x108, 3, 0b110, 9, load),                    // lwu x9, 0x108(x3)
+      i(0x100, 3, 0b011, 11, load),                   // ld x11, 0x100(x3)
+      // misaligned and to mmio
+      i(0x103, 3, 0b011, 12, load),                   // ld x12, 0x103(x3)
+      0x10000 << 12 | 13 << 7 | 0b0110111,            // lui x13, 0x10000
+      i(5, 13, 0b100, 13, load),                      // lbu x13, 5(x13)
+      s(0x100, 30, 3, 0b011),                         // sd x30, 0x100(x3)
+      i(0x100, 3, 0b011, 31, load),                   // ld x31, 0x100(x3)
+      // the last round replaces an instruction below with addi x6, x6, 100, the page with the program is left
+      // to the interpreter so it drops the stale code
+      i(2, 1, 0b010, 13, op_imm),                     // slti x13, x1, 2
+      r(0b0100000, 13, 0, 0b000, 13, op),             // sub x13, x0, x13
+      0b0010111 | 14 << 7,                            // auipc x14, 0
+    ]);
+    let auipc = program.len() - 1;
+    program.extend([
+      0,                                              // addi x14, x14, <the replaced instruction>
+      i(0x110, 3, 0b000, 15, op_imm),                 // addi x15, x3, 0x110
+      r(0, 15, 14, 0b100, 14, op),                    // xor x14, x14, x15
+      r(0, 13, 14, 0b111, 14, op),                    // and x14, x14, x13
+      r(0, 15, 14, 0b100, 14, op),                    // xor x14, x14, x15
+      0x06430 << 12 | 15 << 7 | 0b0110111,            // lui x15, 0x06430
+      i(0x313, 15, 0b000, 15, op_imm),                // addi x15, x15, 0x313
+      s(0, 15, 14, 0b010),                            // sw x15, 0(x14)
+      // jumps and branches, taken ones skip the addi after them
+      j(8, 4),                                        // jal x4, 8
+      i(1, 6, 0b000, 6, op_imm),                      // addi x6, x6, 1
+      0b0010111 | 4 << 7,                             // auipc x4, 0
+      i(12, 4, 0b000, 4, 0b1100111),                  // jalr x4, 12(x4)
+      i(1, 6, 0b000, 6, op_imm),                      // addi x6, x6, 1
+      b(8, 10, 10, 0b000),                            // beq x10, x10, 8
+      i(1, 6, 0b000, 6, op_imm),                      // addi x6, x6, 1
+      b(8, 10, 10, 0b001),                            // bne x10, x10, 8
+    ]);
+    program[auipc + 1] = i((program.len() - auipc) as i32 * 4, 14, 0b000, 14, op_imm);
+    program.extend([
+      i(1, 6, 0b000, 6, op_imm),                      // addi x6, x6, 1
+      b(8, 0, 10, 0b100),                             // blt x10, x0, 8
+      i(1, 6, 0b000, 6, op_imm),                      // addi x6, x6, 1
+      b(8, 0, 10, 0b101),                             // bge x10, x0, 8
+      i(1, 6, 0b000, 6, op_imm),                      // addi x6, x6, 1
+      b(8, 0, 10, 0b110),                             // bltu x10, x0, 8
+      i(1, 6, 0b000, 6, op_imm),                      // addi x6, x6, 1
+      b(8, 0, 10, 0b111),                             // bgeu x10, x0, 8
+      i(1, 6, 0b000, 6, op_imm),                      // addi x6, x6, 1
+      i(-1, 1, 0b000, 1, op_imm),                     // addi x1, x1, -1
+    ]);
+    let offset = (start as i32 - program.len() as i32) * 4;
+    program.push(b(offset, 0, 1, 0b001));             // bne x1, x0, loop
+    program.push(0b1101111);                          // jal x0, 0
+    program
+  }
+
+  fn run(jit: Option<Jit>) -> (Cpu, u32) {
+    let (mut cpu, _) = Cpu::new();
+    let program = program();
+    for (index, inst) in program.iter().enumerate() {
+      cpu.bus.write32(MEMORY_START + index as u64 * 4, *inst).unwrap();
+    }
+    cpu.hart.pc = MEMORY_START;
+    cpu.jit = jit;
+    let end = MEMORY_START + (program.len() as u64 - 1) * 4;
+    let mut retired = 0;
+    while cpu.hart.pc != end {
+      retired += match &mut cpu.jit {
+        Some(jit) => jit.step_block(&mut cpu.hart, &mut cpu.mmu),
+        None => cpu.hart.step_block(&mut cpu.mmu),
+      };
+    }
+    (cpu, retired)
+  }
+
+  #[test]
+  fn lockstep() {
+    let (mut interpreted, interpreted_retired) = run(None);
+    // the shadow hart stores before native code does, memory is compared without it
+    for lockstep in [true, false] {
+      let (mut native, native_retired) = run(Some(Jit::new(lockstep)));
+      assert_ne!(native.jit.as_ref().unwrap().arena.used, 0);
+      assert_eq!(native.hart.regs, interpreted.hart.regs);
+      assert_eq!(native_retired, interpreted_retired);
+      for address in (MEMORY_START..MEMORY_START + 0x2000).step_by(8) {
+        assert_eq!(native.bus.read64(address).unwrap(), interpreted.bus.read64(address).unwrap());
+      }
+    }
+  }
+}
@@ -0,0 +1,523 @@
+use std::mem::{offset_of, size_of};
+
+use crate::{devices::memory::{MEMORY_START, PAGE_SIZE}, instructions::{Decoded, extensions::{InstructionParser, B, I, J, R, S, U}}, tlb::{TlbEntry, TLB_SIZE}};
+
+use super::Context;
+
+// rax, rcx, rdx, rsi: scratch
+// rbx: pointer to x registers
+// r12: pointer to the context
+// r13: pointer to pc
+const RAX: u8 = 0;
+const RCX: u8 = 1;
+
+const READ_TLB: u8 = offset_of!(Context, read_tlb) as u8;
+const WRITE_TLB: u8 = offset_of!(Context, write_tlb) as u8;
+const WATCHED: u8 = offset_of!(Context, watched) as u8;
+
+// addresses of the functions called from native code,
+// they take (context, index of the instruction) and return non-zero to leave the block
+pub(crate) struct Trampolines {
+  pub(crate) fallback: u64,
+  // None -> no lockstep
+  pub(crate) check: Option<(u64, u64)>,
+}
+
+#[derive(Default)]
+struct Assembler {
+  buf: Vec<u8>,
+}
+
+impl Assembler {
+  fn emit(&mut self, bytes: &[u8]) {
+    self.buf.extend_from_slice(bytes);
+  }
+
+  fn prologue(&mut self) {
+    // push rbx; push r12; push r13
+    self.emit(&[0x53, 0x41, 0x54, 0x41, 0x55]);
+    // mov r12, rdi; mov rbx, [rdi]; mov r13, [rdi + 8]
+    self.emit(&[0x49, 0x89, 0xfc, 0x48, 0x8b, 0x1f, 0x4c, 0x8b, 0x6f, 0x08]);
+  }
+
+  // return retired instructions
+  fn exit(&mut self, retired: u32) {
+    // mov eax, retired
+    self.emit(&[0xb8]);
+    self.emit(&retired.to_le_bytes());
+    // pop r13; pop r12; pop rbx; ret
+    self.emit(&[0x41, 0x5d, 0x41, 0x5c, 0x5b, 0xc3]);
+  }
+
+  // call function(context, index), leave the block if it returns non-zero
+  fn call(&mut self, function: u64, index: u32) {
+    // mov rdi, r12; mov esi, index
+    self.emit(&[0x4c, 0x89, 0xe7, 0xbe]);
+    self.emit(&index.to_le_bytes());
+    // mov rax, function; call rax
+    self.mov_imm(RAX, function);
+    self.emit(&[0xff, 0xd0]);
+    // test eax, eax; je over the exit
+    self.emit(&[0x85, 0xc0, 0x74, 11]);
+    self.exit(index + 1);
+  }
+
+  // jcc rel32 to a label bound later, returns the end of the jump
+  fn jump_if(&mut self, cc: u8) -> usize {
+    self.emit(&[0x0f, 0x80 | cc, 0, 0, 0, 0]);
+    self.buf.len()
+  }
+
+  // jmp rel32 to a label bound later
+  fn jump(&mut self) -> usize {
+    self.emit(&[0xe9, 0, 0, 0, 0]);
+    self.buf.len()
+  }
+
+  // the jumps land here
+  fn bind(&mut self, jumps: &[usize]) {
+    for &end in jumps {
+      let rel = (self.buf.len() - end) as i32;
+      self.buf[end - 4..end].copy_from_slice(&rel.to_le_bytes());
+    }
+  }
+
+  // mov reg, [rbx + index * 8]
+  fn load(&mut self, reg: u8, index: usize) {
+    self.emit(&[0x48, 0x8b, 0x83 | reg << 3]);
+    self.emit(&(index as u32 * 8).to_le_bytes());
+  }
+
+  // mov [rbx + index * 8], reg
+  fn store(&mut self, index: usize, reg: u8) {
+    if index == 0 { return; }
+    self.emit(&[0x48, 0x89, 0x83 | reg << 3]);
+    self.emit(&(index as u32 * 8).to_le_bytes());
+  }
+
+  // mov reg, imm64
+  fn mov_imm(&mut self, reg: u8, imm: u64) {
+    self.emit(&[0x48, 0xb8 | reg]);
+    self.emit(&imm.to_le_bytes());
+  }
+
+  // add reg, imm32, sign extended
+  fn add_imm(&mut self, reg: u8, imm: i64) {
+    self.emit(&[0x48, 0x81, 0xc0 | reg]);
+    self.emit(&(imm as i32).to_le_bytes());
+  }
+
+  // op rax, rcx
+  fn alu64(&mut self, op: u8) {
+    self.emit(&[0x48, op, 0xc8]);
+  }
+
+  // op eax, ecx
+  fn alu32(&mut self, op: u8) {
+    self.emit(&[op, 0xc8]);
+  }
+
+  // shl/shr/sar rax, cl
+  fn shift64(&mut self, ext: u8) {
+    self.emit(&[0x48, 0xd3, 0xc0 | ext << 3]);
+  }
+
+  fn shift32(&mut self, ext: u8) {
+    self.emit(&[0xd3, 0xc0 | ext << 3]);
+  }
+
+  // shl/shr/sar rax, imm8
+  fn shift64_imm(&mut self, ext: u8, shamt: u8) {
+    self.emit(&[0x48, 0xc1, 0xc0 | ext << 3, shamt]);
+  }
+
+  fn shift32_imm(&mut self, ext: u8, shamt: u8) {
+    self.emit(&[0xc1, 0xc0 | ext << 3, shamt]);
+  }
+
+  // cmp rax, rcx
+  fn compare(&mut self) {
+    self.emit(&[0x48, 0x39, 0xc8]);
+  }
+
+  // cmp rax, rcx; setcc al; movzx eax, al
+  fn set(&mut self, cc: u8) {
+    self.compare();
+    self.emit(&[0x0f, cc, 0xc0, 0x0f, 0xb6, 0xc0]);
+  }
+
+  // movsxd rax, eax
+  fn sign_extend32(&mut self) {
+    self.emit(&[0x48, 0x63, 0xc0]);
+  }
+
+  // rax = rax / rcx or rax % rcx, 32-bit when word. Where x86 faults, riscv defines the results:
+  // dividing by zero gives all ones or the dividend, MIN / -1 overflows to MIN with a remainder of 0
+  fn divide(&mut self, remainder: bool, signed: bool, word: bool) {
+    // REX.W for the 64-bit forms
+    let w: &[u8] = if word { &[] } else { &[0x48] };
+    // test rcx, rcx
+    self.emit(w);
+    self.emit(&[0x85, 0xc9]);
+    let nonzero = self.jump_if(JNE);
+    if !remainder {
+      // mov rax, -1
+      self.emit(&[0x48, 0xc7, 0xc0, 0xff, 0xff, 0xff, 0xff]);
+    }
+    let mut done = vec![self.jump()];
+    self.bind(&[nonzero]);
+    if signed {
+      // cmp rcx, -1
+      self.emit(w);
+      self.emit(&[0x83, 0xf9, 0xff]);
+      let other = self.jump_if(JNE);
+      if remainder {
+        // xor eax, eax
+        self.emit(&[0x31, 0xc0]);
+      } else {
+        // neg rax, which leaves MIN as it is
+        self.emit(w);
+        self.emit(&[0xf7, 0xd8]);
+      }
+      done.push(self.jump());
+      self.bind(&[other]);
+      // cqo; idiv rcx
+      self.emit(w);
+      self.emit(&[0x99]);
+      self.emit(w);
+      self.emit(&[0xf7, 0xf9]);
+    } else {
+      // xor edx, edx; div rcx
+      self.emit(&[0x31, 0xd2]);
+      self.emit(w);
+      self.emit(&[0xf7, 0xf1]);
+    }
+    if remainder {
+      // mov rax, rdx
+      self.emit(w);
+      self.emit(&[0x89, 0xd0]);
+    }
+    self.bind(&done);
+    if word { self.sign_extend32(); }
+  }
+
+  // rax = rs1 + imm, rdx = the page of rax in host memory. Returns the jumps taken when the access of len bytes
+  // is misaligned, misses the TLB at offset tlb of the context or is not to memory, and for stores when the page
+  // has cached instructions
+  fn lookup(&mut self, rs1: usize, imm: i64, len: u8, tlb: u8, store: bool) -> Vec<usize> {
+    let mut slow = Vec::new();
+    self.load(RAX, rs1);
+    self.add_imm(RAX, imm);
+    if len > 1 {
+      // test al, len - 1
+      self.emit(&[0xa8, len - 1]);
+      slow.push(self.jump_if(JNE));
+    }
+    // mov rdx, [r12 + tlb]; mov rcx, rax; shr rcx, 12; mov esi, ecx; and esi, TLB_SIZE - 1
+    self.emit(&[0x49, 0x8b, 0x54, 0x24, tlb, 0x48, 0x89, 0xc1, 0x48, 0xc1, 0xe9, 0x0c, 0x89, 0xce, 0x81, 0xe6]);
+    self.emit(&(TLB_SIZE as u32 - 1).to_le_bytes());
+    // imul esi, esi, size of an entry; add rdx, rsi; cmp rcx, [rdx + vpn]
+    self.emit(&[0x6b, 0xf6, size_of::<TlbEntry>() as u8, 0x48, 0x01, 0xf2, 0x48, 0x3b, 0x4a, offset_of!(TlbEntry, vpn) as u8]);
+    slow.push(self.jump_if(JNE));
+    if store {
+      // the page in memory: mov rsi, [rdx + physical]; shr rsi, 12; sub rsi, MEMORY_START >> 12
+      self.emit(&[0x48, 0x8b, 0x72, offset_of!(TlbEntry, physical) as u8, 0x48, 0xc1, 0xee, 0x0c, 0x48, 0x81, 0xee]);
+      self.emit(&((MEMORY_START / PAGE_SIZE) as u32).to_le_bytes());
+    }
+    // mov rdx, [rdx + host]; test rdx, rdx
+    self.emit(&[0x48, 0x8b, 0x52, offset_of!(TlbEntry, host) as u8, 0x48, 0x85, 0xd2]);
+    slow.push(self.jump_if(JE));
+    if store {
+      // mov rcx, [r12 + watched]; cmp byte [rcx + rsi], 0
+      self.emit(&[0x49, 0x8b, 0x4c, 0x24, WATCHED, 0x80, 0x3c, 0x31, 0x00]);
+      slow.push(self.jump_if(JNE));
+    }
+    // and eax, PAGE_SIZE - 1
+    self.emit(&[0x25]);
+    self.emit(&(PAGE_SIZE as u32 - 1).to_le_bytes());
+    slow
+  }
+
+  // add qword [r13], imm32, sign extended
+  fn advance_pc(&mut self, offset: i64) {
+    self.emit(&[0x49, 0x81, 0x45, 0x00]);
+    self.emit(&(offset as i32).to_le_bytes());
+  }
+
+  // mov reg, [r13]
+  fn load_pc(&mut self, reg: u8) {
+    self.emit(&[0x49, 0x8b, 0x45 | reg << 3, 0x00]);
+  }
+
+  // mov [r13], reg
+  fn store_pc(&mut self, reg: u8) {
+    self.emit(&[0x49, 0x89, 0x45 | reg << 3, 0x00]);
+  }
+}
+
+const ADD: u8 = 0x01;
+const SUB: u8 = 0x29;
+const XOR: u8 = 0x31;
+const OR: u8 = 0x09;
+const AND: u8 = 0x21;
+const SHL: u8 = 4;
+const SHR: u8 = 5;
+const SAR: u8 = 7;
+const SETL: u8 = 0x9c;
+const SETB: u8 = 0x92;
+// condition codes of jcc
+const JB: u8 = 0x2;
+const JAE: u8 = 0x3;
+const JE: u8 = 0x4;
+const JNE: u8 = 0x5;
+const JL: u8 = 0xc;
+const JGE: u8 = 0xd;
+
+// name, bytes and the access from [rdx + rax]: movsx/movzx/mov rax
+const LOADS: &[(&str, u8, &[u8])] = &[
+  ("LB", 1, &[0x48, 0x0f, 0xbe, 0x04, 0x02]),
+  ("LBU", 1, &[0x0f, 0xb6, 0x04, 0x02]),
+  ("LH", 2, &[0x48, 0x0f, 0xbf, 0x04, 0x02]),
+  ("LHU", 2, &[0x0f, 0xb7, 0x04, 0x02]),
+  ("LW", 4, &[0x48, 0x63, 0x04, 0x02]),
+  ("LWU", 4, &[0x8b, 0x04, 0x02]),
+  ("LD", 8, &[0x48, 0x8b, 0x04, 0x02]),
+];
+
+// to [rdx + rax]: mov cl/cx/ecx/rcx
+const STORES: &[(&str, u8, &[u8])] = &[
+  ("SB", 1, &[0x88, 0x0c, 0x02]),
+  ("SH", 2, &[0x66, 0x89, 0x0c, 0x02]),
+  ("SW", 4, &[0x89, 0x0c, 0x02]),
+  ("SD", 8, &[0x48, 0x89, 0x0c, 0x02]),
+];
+
+// emit the native code of an instruction, false if it has to be interpreted
+fn translate(asm: &mut Assembler, Decoded { instructor, operands: inst, .. }: &Decoded) -> bool {
+  let shamt = (inst.imm & 0b111111) as u8;
+  let shamtw = (inst.imm & 0b11111) as u8;
+  match instructor.name {
+    "LUI" => {
+      let U { imm, rd } = inst.u();
+      asm.mov_imm(RAX, imm as u64);
+      asm.store(rd, RAX);
+    },
+    "AUIPC" => {
+      let U { imm, rd } = inst.u();
+      asm.load_pc(RAX);
+      asm.mov_imm(RCX, imm as u64);
+      asm.alu64(ADD);
+      asm.store(rd, RAX);
+    },
+    "ADDI" | "XORI" | "ORI" | "ANDI" | "SLTI" | "SLTIU" | "ADDIW" => {
+      let I { imm, rs1, rd } = inst.i();
+      asm.load(RAX, rs1);
+      asm.mov_imm(RCX, imm as u64);
+      match instructor.name {
+        "ADDI" => asm.alu64(ADD),
+        "XORI" => asm.alu64(XOR),
+        "ORI" => asm.alu64(OR),
+        "ANDI" => asm.alu64(AND),
+        "SLTI" => asm.set(SETL),
+        "SLTIU" => asm.set(SETB),
+        "ADDIW" => {
+          asm.alu32(ADD);
+          asm.sign_extend32();
+        },
+        _ => unreachable!(),
+      }
+      asm.store(rd, RAX);
+    },
+    "SLLI" | "SRLI" | "SRAI" | "SLLIW" | "SRLIW" | "SRAIW" => {
+      let I { rs1, rd, .. } = inst.i();
+      asm.load(RAX, rs1);
+      match instructor.name {
+        "SLLI" => asm.shift64_imm(SHL, shamt),
+        "SRLI" => asm.shift64_imm(SHR, shamt),
+        "SRAI" => asm.shift64_imm(SAR, shamt),
+        "SLLIW" => asm.shift32_imm(SHL, shamtw),
+        "SRLIW" => asm.shift32_imm(SHR, shamtw),
+        "SRAIW" => asm.shift32_imm(SAR, shamtw),
+        _ => unreachable!(),
+      }
+      if instructor.name.ends_with('W') { asm.sign_extend32(); }
+      asm.store(rd, RAX);
+    },
+    "ADD" | "SUB" | "XOR" | "OR" | "AND" | "SLT" | "SLTU" | "SLL" | "SRL" | "SRA"
+      | "ADDW" | "SUBW" | "SLLW" | "SRLW" | "SRAW" => {
+      let R { rs2, rs1, rd } = inst.r();
+      asm.load(RAX, rs1);
+      asm.load(RCX, rs2);
+      // x86 masks shift counts the same way as riscv
+      match instructor.name {
+        "ADD" => asm.alu64(ADD),
+        "SUB" => asm.alu64(SUB),
+        "XOR" => asm.alu64(XOR),
+        "OR" => asm.alu64(OR),
+        "AND" => asm.alu64(AND),
+        "SLT" => asm.set(SETL),
+        "SLTU" => asm.set(SETB),
+        "SLL" => asm.shift64(SHL),
+        "SRL" => asm.shift64(SHR),
+        "SRA" => asm.shift64(SAR),
+        "ADDW" => asm.alu32(ADD),
+        "SUBW" => asm.alu32(SUB),
+        "SLLW" => asm.shift32(SHL),
+        "SRLW" => asm.shift32(SHR),
+        "SRAW" => asm.shift32(SAR),
+        _ => unreachable!(),
+      }
+      if instructor.name.ends_with('W') { asm.sign_extend32(); }
+      asm.store(rd, RAX);
+    },
+    "MUL" | "MULH" | "MULHSU" | "MULHU" | "MULW" => {
+      let R { rs2, rs1, rd } = inst.r();
+      asm.load(RAX, rs1);
+      asm.load(RCX, rs2);
+      match instructor.name {
+        // imul rax, rcx
+        "MUL" => asm.emit(&[0x48, 0x0f, 0xaf, 0xc1]),
+        // imul rcx; mov rax, rdx
+        "MULH" => asm.emit(&[0x48, 0xf7, 0xe9, 0x48, 0x89, 0xd0]),
+        // mul rcx; mov rax, rdx
+        "MULHU" => asm.emit(&[0x48, 0xf7, 0xe1, 0x48, 0x89, 0xd0]),
+        // the unsigned high half less rs2 when rs1 is negative:
+        // mov rsi, rax; mul rcx; sar rsi, 63; and rsi, rcx; sub rdx, rsi; mov rax, rdx
+        "MULHSU" => asm.emit(&[0x48, 0x89, 0xc6, 0x48, 0xf7, 0xe1, 0x48, 0xc1, 0xfe, 0x3f, 0x48, 0x21, 0xce,
+          0x48, 0x29, 0xf2, 0x48, 0x89, 0xd0]),
+        // imul eax, ecx
+        "MULW" => {
+          asm.emit(&[0x0f, 0xaf, 0xc1]);
+          asm.sign_extend32();
+        },
+        _ => unreachable!(),
+      }
+      asm.store(rd, RAX);
+    },
+    "DIV" | "DIVU" | "REM" | "REMU" | "DIVW" | "DIVUW" | "REMW" | "REMUW" => {
+      let R { rs2, rs1, rd } = inst.r();
+      asm.load(RAX, rs1);
+      asm.load(RCX, rs2);
+      let name = instructor.name;
+      asm.divide(name.starts_with("REM"), !name.contains('U'), name.ends_with('W'));
+      asm.store(rd, RAX);
+    },
+    _ => return false,
+  }
+  true
+}
+
+// a load or store through the TLB, with the interpreter taking misses, mmio and misaligned accesses.
+// Emits nothing and returns false for other instructions
+fn access(asm: &mut Assembler, Decoded { instructor, operands: inst, len }: &Decoded, index: u32, trampolines: &Trampolines) -> bool {
+  let find = |table: &[(&str, u8, &'static [u8])]| table.iter().find(|(name, ..)| *name == instructor.name).map(|&(_, size, code)| (size, code));
+  let (store, (size, code)) = match (find(LOADS), find(STORES)) {
+    (Some(load), _) => (false, load),
+    (None, Some(store)) => (true, store),
+    (None, None) => return false,
+  };
+  let (rs1, imm, tlb) = if store {
+    let S { imm, rs1, .. } = inst.s();
+    (rs1, imm, WRITE_TLB)
+  } else {
+    let I { imm, rs1, .. } = inst.i();
+    (rs1, imm, READ_TLB)
+  };
+  let mut slow = asm.lookup(rs1, imm, size, tlb, store);
+  if let Some((before, _)) = trampolines.check {
+    // the shadow hart accesses memory first, the entry is still there after it
+    asm.call(before, index);
+    slow.extend(asm.lookup(rs1, imm, size, tlb, store));
+  }
+  if store {
+    asm.load(RCX, inst.s().rs2);
+    asm.emit(code);
+  } else {
+    asm.emit(code);
+    asm.store(inst.i().rd, RAX);
+  }
+  asm.advance_pc(*len as i64);
+  if let Some((_, after)) = trampolines.check { asm.call(after, index); }
+  let done = asm.jump();
+  asm.bind(&slow);
+  asm.call(trampolines.fallback, index);
+  asm.bind(&[done]);
+  true
+}
+
+// branches and jumps end the block, they write pc and leave it.
+// Emits nothing and returns false for other instructions
+fn jump(asm: &mut Assembler, Decoded { instructor, operands: inst, len }: &Decoded, index: u32, trampolines: &Trampolines) -> bool {
+  let len = *len as i64;
+  // jumps over the branch when it is not taken
+  let not_taken = match instructor.name {
+    "BEQ" => JNE,
+    "BNE" => JE,
+    "BLT" => JGE,
+    "BGE" => JL,
+    "BLTU" => JAE,
+    "BGEU" => JB,
+    "JAL" | "JALR" => 0,
+    _ => return false,
+  };
+  if let Some((before, _)) = trampolines.check { asm.call(before, index); }
+  match instructor.name {
+    "JAL" => {
+      let J { imm, rd } = inst.j();
+      asm.load_pc(RAX);
+      asm.add_imm(RAX, len);
+      asm.advance_pc(imm & !1);
+      asm.store(rd, RAX);
+    },
+    "JALR" => {
+      // the target is taken before rd is written, which may be rs1
+      let I { imm, rs1, rd } = inst.i();
+      asm.load(RAX, rs1);
+      asm.add_imm(RAX, imm & !1);
+      asm.load_pc(RCX);
+      asm.add_imm(RCX, len);
+      asm.store_pc(RAX);
+      asm.store(rd, RCX);
+    },
+    _ => {
+      let B { imm, rs2, rs1 } = inst.b();
+      asm.load(RAX, rs1);
+      asm.load(RCX, rs2);
+      asm.compare();
+      let skip = asm.jump_if(not_taken);
+      asm.advance_pc(imm & !1);
+      let done = asm.jump();
+      asm.bind(&[skip]);
+      asm.advance_pc(len);
+      asm.bind(&[done]);
+    },
+  }
+  if let Some((_, after)) = trampolines.check { asm.call(after, index); }
+  asm.exit(index + 1);
+  true
+}
+
+// None if no instruction in the block can run natively
+pub(crate) fn compile(insts: &[Decoded], trampolines: &Trampolines) -> Option<Vec<u8>> {
+  let mut asm = Assembler::default();
+  asm.prologue();
+  let mut native = false;
+  for (index, decoded) in insts.iter().enumerate() {
+    let index = index as u32;
+    let mut inst = Assembler::default();
+    if translate(&mut inst, decoded) {
+      native = true;
+      if let Some((before, _)) = trampolines.check { asm.call(before, index); }
+      asm.emit(&inst.buf);
+      asm.advance_pc(decoded.len as i64);
+      if let Some((_, after)) = trampolines.check { asm.call(after, index); }
+    } else if access(&mut asm, decoded, index, trampolines) || jump(&mut asm, decoded, index, trampolines) {
+      native = true;
+    } else {
+      // the interpreter updates pc and handles traps
+      asm.call(trampolines.fallback, index);
+    }
+  }
+  asm.exit(insts.len() as u32);
+  native.then_some(asm.buf)
+}
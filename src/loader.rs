@@ -0,0 +1,298 @@
+use std::{fs, path::PathBuf, str::FromStr};
+
+use elf::{ElfBytes, endian::LittleEndian};
+
+use crate::{devices::{bus::Bus, memory::{MEMORY_START, MEMORY_END}, framebuffer}, utils::parse_number};
+
+// Images to load into memory: ELF, raw binaries and the Intel HEX and Motorola S-record files of bootloader builds.
+// Raw binaries need a load address, the other formats bring theirs.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Format {
+  Elf,
+  Bin,
+  Ihex,
+  Srec,
+}
+
+impl FromStr for Format {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "elf" => Ok(Format::Elf),
+      "bin" => Ok(Format::Bin),
+      "ihex" => Ok(Format::Ihex),
+      "srec" => Ok(Format::Srec),
+      _ => Err(format!("unknown image format '{}', expected elf, bin, ihex or srec", s)),
+    }
+  }
+}
+
+impl Format {
+  // by the first bytes, anything unknown is a raw binary
+  fn detect(data: &[u8]) -> Format {
+    let text = data.trim_ascii_start();
+    if data.starts_with(b"\x7fELF") {
+      Format::Elf
+    } else if text.starts_with(b":") {
+      Format::Ihex
+    } else if text.len() >= 2 && text[0] == b'S' && text[1].is_ascii_digit() {
+      Format::Srec
+    } else {
+      Format::Bin
+    }
+  }
+}
+
+// path, optionally followed by ,format=..., ,address=... for raw binaries and ,entry=...
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ImageOptions {
+  pub(crate) path: PathBuf,
+  pub(crate) format: Option<Format>,
+  pub(crate) address: Option<u64>,
+  pub(crate) entry: Option<u64>,
+}
+
+impl FromStr for ImageOptions {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let mut parts = s.split(',');
+    let path = PathBuf::from(parts.next().unwrap_or_default());
+    let (mut format, mut address, mut entry) = (None, None, None);
+    for part in parts {
+      let number = |value: &str| parse_number(value).ok_or_else(|| format!("invalid address '{}'", value));
+      match part.split_once('=') {
+        Some(("format", value)) => format = Some(value.parse()?),
+        Some(("address", value)) => address = Some(number(value)?),
+        Some(("entry", value)) => entry = Some(number(value)?),
+        _ => return Err(format!("unknown image option '{}'", part)),
+      }
+    }
+    Ok(ImageOptions { path, format, address, entry })
+  }
+}
+
+// address and bytes
+type Segment = (u64, Vec<u8>);
+
+// what an image puts into memory, and where it is entered
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Image {
+  // in the order of the file
+  segments: Vec<Segment>,
+  pub(crate) entry: u64,
+}
+
+impl Image {
+  pub(crate) fn open(options: &ImageOptions) -> Result<Image, String> {
+    let data = fs::read(&options.path).map_err(|err| err.to_string())?;
+    // only raw binaries take an address, whatever their first bytes look like
+    let format = options.format.unwrap_or_else(|| if options.address.is_some() { Format::Bin } else { Format::detect(&data) });
+    Image::parse(&data, format, options.address, options.entry)
+  }
+
+  pub(crate) fn parse(data: &[u8], format: Format, address: Option<u64>, entry: Option<u64>) -> Result<Image, String> {
+    if address.is_some() && format != Format::Bin {
+      return Err("only raw binaries take a load address".to_string());
+    }
+    let (segments, start) = match format {
+      Format::Elf => elf(data)?,
+      Format::Bin => {
+        let address = address.ok_or("a raw binary needs address=")?;
+        (vec![(address, data.to_vec())], Some(address))
+      },
+      Format::Ihex => ihex(data)?,
+      Format::Srec => srec(data)?,
+    };
+    // the lowest address when the file has no start address
+    let entry = entry.or(start).or_else(|| segments.iter().map(|(address, _)| *address).min())
+      .ok_or("the image is empty")?;
+    Ok(Image { segments, entry })
+  }
+
+  // returns the end of the highest segment
+  pub(crate) fn load(&self, bus: &mut Bus) -> Result<u64, String> {
+    let mut end = 0;
+    for (address, data) in &self.segments {
+      let segment_end = address.saturating_add(data.len() as u64);
+      if *address < MEMORY_START || segment_end > MEMORY_END + 1 {
+        return Err(format!("{:#x}..{:#x} is outside memory", address, segment_end));
+      }
+      if framebuffer::overlaps(*address, segment_end) {
+        return Err(format!("{:#x}..{:#x} overlaps the framebuffer", address, segment_end));
+      }
+      bus.write_bytes(*address, data).map_err(|_| format!("failed to load {:#x}..{:#x}", address, segment_end))?;
+      end = end.max(segment_end);
+    }
+    Ok(end)
+  }
+}
+
+// the loadable segments by physical address, with their bss
+fn elf(data: &[u8]) -> Result<(Vec<Segment>, Option<u64>), String> {
+  let elf = ElfBytes::<LittleEndian>::minimal_parse(data).map_err(|err| err.to_string())?;
+  let mut segments = Vec::new();
+  for segment in elf.segments().ok_or("no program headers")? {
+    if segment.p_type != elf::abi::PT_LOAD { continue; }
+    // before the bss is allocated, which the header can make as large as it likes
+    let size = segment.p_memsz.max(segment.p_filesz);
+    match segment.p_paddr.checked_add(size) {
+      Some(end) if segment.p_paddr >= MEMORY_START && end <= MEMORY_END + 1 => {},
+      _ => return Err(format!("the segment at {:#x} of {:#x} bytes is outside memory", segment.p_paddr, size)),
+    }
+    let mut bytes = elf.segment_data(&segment).map_err(|err| err.to_string())?.to_vec();
+    bytes.resize(size as usize, 0);
+    segments.push((segment.p_paddr, bytes));
+  }
+  Ok((segments, Some(elf.ehdr.e_entry)))
+}
+
+fn hex_bytes(text: &str) -> Option<Vec<u8>> {
+  if text.len() & 1 != 0 { return None; }
+  (0..text.len()).step_by(2).map(|index| u8::from_str_radix(text.get(index..index + 2)?, 16).ok()).collect()
+}
+
+// data at address, which continues the last segment if it ends there
+fn push(segments: &mut Vec<Segment>, address: u64, data: &[u8]) {
+  match segments.last_mut() {
+    Some((start, bytes)) if *start + bytes.len() as u64 == address => bytes.extend_from_slice(data),
+    _ => segments.push((address, data.to_vec())),
+  }
+}
+
+// :LLAAAATT<data>CC records, the bytes of each adding up to zero
+fn ihex(data: &[u8]) -> Result<(Vec<Segment>, Option<u64>), String> {
+  let text = std::str::from_utf8(data).map_err(|_| "not a text file")?;
+  let (mut segments, mut base, mut start) = (Vec::new(), 0, None);
+  for (index, line) in text.lines().enumerate() {
+    let line = line.trim();
+    if line.is_empty() { continue; }
+    let invalid = || format!("line {}: invalid record", index + 1);
+    let bytes = line.strip_prefix(':').and_then(hex_bytes).ok_or_else(invalid)?;
+    if bytes.len() < 5 || bytes.len() != 5 + bytes[0] as usize {
+      return Err(invalid());
+    }
+    if bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) != 0 {
+      return Err(format!("line {}: wrong checksum", index + 1));
+    }
+    let address = u16::from_be_bytes([bytes[1], bytes[2]]) as u64;
+    let data = &bytes[4..bytes.len() - 1];
+    match (bytes[3], data) {
+      (0x00, _) => push(&mut segments, base + address, data),
+      (0x01, _) => break,
+      // extended segment address, then start segment address as CS:IP
+      (0x02, &[high, low]) => base = (u16::from_be_bytes([high, low]) as u64) << 4,
+      (0x03, &[cs_high, cs_low, ip_high, ip_low]) =>
+        start = Some(((u16::from_be_bytes([cs_high, cs_low]) as u64) << 4) + u16::from_be_bytes([ip_high, ip_low]) as u64),
+      // extended linear address, then start linear address
+      (0x04, &[high, low]) => base = (u16::from_be_bytes([high, low]) as u64) << 16,
+      (0x05, &[a, b, c, d]) => start = Some(u32::from_be_bytes([a, b, c, d]) as u64),
+      _ => return Err(invalid()),
+    }
+  }
+  Ok((segments, start))
+}
+
+// STLL<address><data>CC records, the count, address and data adding up to the complement of the checksum
+fn srec(data: &[u8]) -> Result<(Vec<Segment>, Option<u64>), String> {
+  let text = std::str::from_utf8(data).map_err(|_| "not a text file")?;
+  let (mut segments, mut start) = (Vec::new(), None);
+  for (index, line) in text.lines().enumerate() {
+    let line = line.trim();
+    if line.is_empty() { continue; }
+    let invalid = || format!("line {}: invalid record", index + 1);
+    let kind = line.strip_prefix('S').and_then(|rest| rest.chars().next()).ok_or_else(invalid)?;
+    let address_size = match kind {
+      '0' | '1' | '5' | '9' => 2,
+      '2' | '6' | '8' => 3,
+      '3' | '7' => 4,
+      _ => return Err(invalid()),
+    };
+    let bytes = hex_bytes(&line[2..]).ok_or_else(invalid)?;
+    if bytes.len() < 2 + address_size || bytes.len() != 1 + bytes[0] as usize {
+      return Err(invalid());
+    }
+    if bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) != 0xff {
+      return Err(format!("line {}: wrong checksum", index + 1));
+    }
+    let address = bytes[1..1 + address_size].iter().fold(0, |address, byte| address << 8 | *byte as u64);
+    let data = &bytes[1 + address_size..bytes.len() - 1];
+    match kind {
+      '1' | '2' | '3' => push(&mut segments, address, data),
+      '7' | '8' | '9' => start = Some(address),
+      // the header and the record counts
+      _ => {},
+    }
+  }
+  Ok((segments, start))
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::devices::{bus::Bus, Device};
+
+  use super::*;
+
+  #[test]
+  fn formats() {
+    let ihex = b":040000058000000077\n:0200000480007A\n:0400000013000000E9\n:040004007300000085\n:00000001FF\n";
+    assert_eq!(Format::detect(ihex), Format::Ihex);
+    let image = Image::parse(ihex, Format::Ihex, None, None).unwrap();
+    assert_eq!(image, Image { segments: vec![(0x80000000, vec![0x13, 0, 0, 0, 0x73, 0, 0, 0])], entry: 0x80000000 });
+    let srec = b"S00600004844521B\nS309800000001300000063\nS3098000000473000000FF\nS705800000007A\n";
+    assert_eq!(Format::detect(srec), Format::Srec);
+    assert_eq!(Image::parse(srec, Format::Srec, None, None).unwrap(), image);
+    let bin = [0x13, 0, 0, 0, 0x73, 0, 0, 0];
+    assert_eq!(Format::detect(&bin), Format::Bin);
+    assert!(Image::parse(&bin, Format::Bin, None, None).is_err());
+    let image = Image::parse(&bin, Format::Bin, Some(0x80200000), Some(0x80200004)).unwrap();
+    assert_eq!(image.entry, 0x80200004);
+    let (mut bus, _controller) = Bus::new();
+    assert_eq!(image.load(&mut bus).unwrap(), 0x80200008);
+    assert_eq!(bus.read32(0x80200004).unwrap(), 0x73);
+    assert!(Image::parse(&bin, Format::Bin, Some(0x1000), None).unwrap().load(&mut bus).is_err());
+    let framebuffer = framebuffer::FRAMEBUFFER_START + framebuffer::FRAMEBUFFER_SIZE;
+    assert!(Image::parse(&bin, Format::Bin, Some(framebuffer - 4), None).unwrap().load(&mut bus).is_err());
+    assert_eq!(Image::parse(&bin, Format::Bin, Some(framebuffer), None).unwrap().load(&mut bus), Ok(framebuffer + 8));
+    assert!(Image::parse(b":0400000013000000E8\n", Format::Ihex, None, None).is_err());
+    let options: ImageOptions = "app.bin,address=0x80200000,entry=0x80200004".parse().unwrap();
+    assert_eq!((options.format, options.address, options.entry), (None, Some(0x80200000), Some(0x80200004)));
+    // raw binaries which look like hex files are raw with an address
+    let path = std::env::temp_dir().join(format!("yuri-loader-bin-{}", std::process::id()));
+    for bin in [&b":\x01\x02\x03"[..], b"S1\x00\x00"] {
+      fs::write(&path, bin).unwrap();
+      let image = Image::open(&format!("{},address=0x80200000", path.display()).parse().unwrap()).unwrap();
+      assert_eq!(image, Image { segments: vec![(0x80200000, bin.to_vec())], entry: 0x80200000 });
+      assert!(Image::open(&ImageOptions { path: path.clone(), format: None, address: None, entry: None }).is_err());
+    }
+    fs::remove_file(path).unwrap();
+  }
+
+  // a header and one loadable segment of four bytes, with its bss
+  fn elf_file(paddr: u64, memsz: u64) -> Vec<u8> {
+    let mut file = vec![0x7f, b'E', b'L', b'F', 2, 1, 1];
+    file.resize(16, 0);
+    // executable for riscv, with the program headers right after
+    for field in [2u16, 243] { file.extend(field.to_le_bytes()); }
+    file.extend(1u32.to_le_bytes());
+    for field in [paddr, 64, 0] { file.extend(field.to_le_bytes()); }
+    file.extend(0u32.to_le_bytes());
+    for field in [64u16, 56, 1, 64, 0, 0] { file.extend(field.to_le_bytes()); }
+    for field in [elf::abi::PT_LOAD, 7] { file.extend(field.to_le_bytes()); }
+    for field in [120, paddr, paddr, 4, memsz, 4] { file.extend(field.to_le_bytes()); }
+    file.extend(0x00000073u32.to_le_bytes());
+    file
+  }
+
+  #[test]
+  fn elf_segments() {
+    let image = Image::parse(&elf_file(0x80000000, 16), Format::Elf, None, None).unwrap();
+    assert_eq!(image, Image { segments: vec![(0x80000000, vec![0x73, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0])], entry: 0x80000000 });
+    // rejected before the bss is allocated
+    assert!(Image::parse(&elf_file(0x80000000, u64::MAX / 2), Format::Elf, None, None).is_err());
+    assert!(Image::parse(&elf_file(u64::MAX - 8, 16), Format::Elf, None, None).is_err());
+    assert!(Image::parse(&elf_file(0x1000, 16), Format::Elf, None, None).is_err());
+  }
+}
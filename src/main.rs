@@ -1,50 +1,286 @@
-#![feature(try_blocks)]
-use std::{path::PathBuf, thread::spawn, io::Read};
+use std::{fs, path::PathBuf, thread::spawn, io::Read};
 
 use clap::Parser;
+use boot::Kernel;
+use loader::{Image, ImageOptions};
 use cpu::Cpu;
-use utils::channel::channel;
+use devices::{endpoint::{self, Endpoint}, uart::{UartOptions, UART_START, UART_STRIDE, UART_INTERRUPT_ID}, rtc::{Clock, GoldfishRtc}, framebuffer::{Screenshot, Screenshots}, virtio::{blk::{VirtioBlk, ImageMode}, net::{VirtioNet, NetOptions, default_mac}, console::{VirtioConsole, PortOptions}, rng::{VirtioRng, RngSource}, p9::{VirtioP9, ShareOptions}, input::{VirtioInput, InputKind, script::{Inputs, InputScript}, control}, pci::VirtioPci, VirtioDevice}, pci, bus::Bus, flash::{CfiFlash, FlashOptions}};
+use monitor::Monitor;
+use terminal::Session;
+use utils::{parse_number, channel::{channel, notified_channel}};
 
 mod cpu;
 mod hart;
 mod register;
 mod mmu;
+mod tlb;
 mod instructions;
 mod csrs;
 mod utils;
 mod trap;
 mod devices;
+mod monitor;
+mod terminal;
+mod boot;
+mod loader;
+mod sbi;
+#[cfg(feature = "jit")]
+mod jit;
 
 #[derive(Debug, Parser)]
 struct Args {
   #[arg(long, default_value = "false")]
   htif: bool,
-  file: PathBuf,
+  /// Make guest time depend only on executed instructions
+  #[arg(long, default_value = "false")]
+  deterministic: bool,
+  /// Run the integer ALU instructions of hot blocks as native code
+  #[cfg(feature = "jit")]
+  #[arg(long, default_value = "false")]
+  jit: bool,
+  /// Check every native instruction against the interpreter
+  #[cfg(feature = "jit")]
+  #[arg(long, default_value = "false")]
+  jit_lockstep: bool,
+  /// Start the real-time clock at a fixed time, as seconds since the epoch or YYYY-MM-DD[THH:MM:SS] in UTC,
+  /// which then follows guest time, or follow the host clock with now. --deterministic starts at the epoch by default
+  #[arg(long)]
+  rtc: Option<Clock>,
+  /// Connect the UART to stdio, file=path, socket=path, tcp=port, telnet=port or pty.
+  /// Sockets take one client at a time, on localhost for tcp and telnet, and later clients wait until it disconnects
+  #[arg(long, default_value = "stdio")]
+  serial: Endpoint,
+  /// Add a UART, as an endpoint like --serial optionally followed by ,address=... and ,irq=N.
+  /// The nth one defaults to 0x10000000 + n * 0x100 and irq 1 + n
+  #[arg(long)]
+  uart: Vec<UartOptions>,
+  /// Where C-a l copies what the guest writes to stdio
+  #[arg(long, default_value = "yuri.log")]
+  stdio_log: PathBuf,
+  /// Attach a CFI flash backed by a file, as path[,intel|amd]. The file is created if it is missing
+  /// and grown to 32 MiB. The first one is at 0x20000000, the second at 0x22000000
+  #[arg(long)]
+  flash: Vec<FlashOptions>,
+  /// Put the virtio devices on the PCIe bus instead of the MMIO slots
+  #[arg(long, default_value = "false")]
+  virtio_pci: bool,
+  /// Attach an emulated PCIe endpoint, which for now can only be edu
+  #[arg(long)]
+  pci_device: Vec<String>,
+  /// Attach a virtio-blk disk backed by a raw image, as path[,rw|ro|cow]
+  #[arg(long)]
+  blk: Vec<String>,
+  /// Attach a virtio-net card, as user[,hostfwd=tcp|udp:host port:guest port]... or
+  /// socket,listen=path or socket,connect=path, optionally followed by ,mac=52:54:00:12:34:56
+  #[arg(long)]
+  net: Vec<NetOptions>,
+  /// Add a port to the virtio console, as stdio, file=path, socket=path, tcp=port, telnet=port or pty, optionally followed by ,name=...
+  /// The first port is the console (hvc0). Stdin goes to the stdio port instead of the UART.
+  #[arg(long)]
+  console: Vec<PortOptions>,
+  /// Attach a virtio-rng, fed by urandom or by seed=N, which gives the same bytes on every run
+  #[arg(long)]
+  rng: Option<RngSource>,
+  /// Share a host directory over virtio-9p, as path,tag=name[,ro][,ids=passthrough|root|uid:gid][,devices]
+  #[arg(long)]
+  share: Vec<ShareOptions>,
+  /// Save the framebuffer to path.png or path.ppm, after the given number of instructions
+  /// as path@N, or else whenever yuri gets SIGUSR1
+  #[arg(long)]
+  screenshot: Vec<Screenshot>,
+  /// Attach a virtio keyboard and tablet, fed from a file of "<@instructions|Ns|Nms> <action>" lines
+  #[arg(long)]
+  input_script: Option<PathBuf>,
+  /// Attach a virtio keyboard and tablet, fed from actions written to a unix socket at this path
+  #[arg(long)]
+  input_socket: Option<PathBuf>,
+  /// Put the boot ROM, where the hart starts, at this address instead of 0x1000. It passes the hart id in a0 and
+  /// the device tree in a1 to the firmware
+  #[arg(long, value_parser = |s: &str| parse_number(s).ok_or(format!("invalid address '{}'", s)))]
+  reset_address: Option<u64>,
+  /// Answer SBI calls in yuri instead of firmware, and start the kernel, or else the image, in supervisor mode
+  #[arg(long, default_value = "false")]
+  sbi: bool,
+  /// Load a Linux Image after the firmware, which finds it through fw_dynamic info in a2
+  #[arg(long)]
+  kernel: Option<PathBuf>,
+  /// Load an initrd at the top of memory, for the kernel
+  #[arg(long, requires = "kernel")]
+  initrd: Option<PathBuf>,
+  /// The kernel command line, bootargs in /chosen of the device tree
+  #[arg(long)]
+  append: Option<String>,
+  /// Write the device tree of the machine to this path and exit
+  #[arg(long)]
+  dump_dtb: Option<PathBuf>,
+  /// Load another image beside the firmware, like the firmware as path[,format=...][,address=...][,entry=...]
+  #[arg(long)]
+  image: Vec<ImageOptions>,
+  /// The firmware, which --sbi and --kernel together do without. An ELF, Intel HEX or S-record file, or a raw binary
+  /// with ,address=..., optionally followed by ,format=elf|bin|ihex|srec and ,entry=... for another entry point
+  #[arg(required_unless_present_all = ["sbi", "kernel"])]
+  file: Option<ImageOptions>,
 }
 
 fn main() {
-  let Args { htif, file } = Args::parse();
+  let args = Args::parse();
+  let Args { htif, deterministic, .. } = args;
   let (mut cpu, controller) = Cpu::new();
-  let uart_sender = controller.uart_sender.clone();
+  cpu.deterministic = deterministic;
+  #[cfg(feature = "jit")]
+  if args.jit || args.jit_lockstep {
+    cpu.jit = Some(jit::Jit::new(args.jit_lockstep));
+  }
+  let clock = args.rtc.unwrap_or(if deterministic { Clock::Fixed(0) } else { Clock::Host });
+  if deterministic && clock == Clock::Host {
+    panic!("--deterministic needs --rtc with a fixed time");
+  }
+  *cpu.bus.rtc.lock().unwrap() = GoldfishRtc::new(clock);
+  if let Some(address) = args.reset_address {
+    cpu.bus.move_rom(address).unwrap_or_else(|err| panic!("failed to put the boot rom at {:#x}: {}", address, err));
+  }
+  let virtio_pci = args.virtio_pci;
+  let attach = |bus: &mut Bus, device: Box<dyn VirtioDevice>| if virtio_pci {
+    bus.attach_pci(Box::new(VirtioPci::new(device))).expect("no free PCI slot");
+  } else {
+    bus.attach_virtio(device).expect("no free virtio slot");
+  };
+  for options in &args.flash {
+    let flash = CfiFlash::open(&options.path, options.command_set)
+      .unwrap_or_else(|err| panic!("failed to open {}: {}", options.path.display(), err));
+    cpu.bus.attach_flash(flash).expect("no free flash bank");
+  }
+  for name in &args.pci_device {
+    let function = pci::endpoint(name).unwrap_or_else(|| panic!("unknown PCI device {}", name));
+    cpu.bus.attach_pci(function).expect("no free PCI slot");
+  }
+  for blk in &args.blk {
+    let (path, mode) = match blk.split_once(',') {
+      Some((path, mode)) => (path, mode.parse().unwrap_or_else(|err| panic!("{}", err))),
+      None => (blk.as_str(), ImageMode::ReadWrite),
+    };
+    let device = VirtioBlk::open(path.as_ref(), mode)
+      .unwrap_or_else(|err| panic!("failed to open {}: {}", path, err));
+    attach(&mut cpu.bus, Box::new(device));
+  }
+  for (index, options) in args.net.iter().enumerate() {
+    let mac = options.mac.unwrap_or_else(|| default_mac(index));
+    let (device, frames, sender) = VirtioNet::new(mac, cpu.bus.notifier.clone());
+    options.spawn(frames, sender).unwrap_or_else(|err| panic!("failed to start the network backend: {}", err));
+    attach(&mut cpu.bus, Box::new(device));
+  }
+  if let Some(source) = args.rng {
+    if deterministic && source == RngSource::Urandom {
+      panic!("--deterministic needs --rng seed=N");
+    }
+    let device = VirtioRng::new(source).unwrap_or_else(|err| panic!("failed to open the entropy source: {}", err));
+    attach(&mut cpu.bus, Box::new(device));
+  }
+  for options in &args.share {
+    let device = VirtioP9::new(options)
+      .unwrap_or_else(|err| panic!("failed to share {}: {}", options.path.display(), err));
+    attach(&mut cpu.bus, Box::new(device));
+  }
+  if !args.screenshot.is_empty() {
+    cpu.screenshots = Some(Screenshots::new(args.screenshot));
+  }
+  if args.input_script.is_some() || args.input_socket.is_some() {
+    let (keyboard, keyboard_sender) = VirtioInput::new(InputKind::Keyboard, cpu.bus.notifier.clone());
+    let (tablet, tablet_sender) = VirtioInput::new(InputKind::Tablet, cpu.bus.notifier.clone());
+    attach(&mut cpu.bus, Box::new(keyboard));
+    attach(&mut cpu.bus, Box::new(tablet));
+    let inputs = Inputs { keyboard: keyboard_sender, tablet: tablet_sender };
+    if let Some(path) = &args.input_script {
+      cpu.input_script = Some(InputScript::load(path, inputs.clone())
+        .unwrap_or_else(|err| panic!("failed to load {}: {}", path.display(), err)));
+    }
+    if let Some(path) = &args.input_socket {
+      control::spawn(path, inputs).unwrap_or_else(|err| panic!("failed to listen on {}: {}", path.display(), err));
+    }
+  }
+  let mut console_input = None;
+  if !args.console.is_empty() {
+    let mut console = VirtioConsole::new(cpu.bus.notifier.clone());
+    for options in &args.console {
+      let (output, input) = console.add_port(options.name.clone());
+      if options.endpoint == Endpoint::Stdio {
+        console_input = Some(input.clone());
+      }
+      options.spawn(output, input).unwrap_or_else(|err| panic!("failed to open the console endpoint: {}", err));
+    }
+    attach(&mut cpu.bus, Box::new(console));
+  }
+  // stdin goes to the first UART on stdio only if no console port takes it
+  let mut uart_input = (args.serial == Endpoint::Stdio).then(|| controller.uart_sender.clone());
+  args.serial.spawn("serial port", controller.uart_receiver, controller.uart_sender)
+    .unwrap_or_else(|err| panic!("failed to open the serial endpoint: {}", err));
+  for (index, options) in args.uart.iter().enumerate() {
+    let index = index + 1;
+    let address = options.address.unwrap_or(UART_START + UART_STRIDE * index as u64);
+    let irq = options.irq.unwrap_or(UART_INTERRUPT_ID + index as u32);
+    let (sender, receiver) = cpu.bus.attach_uart(address, irq)
+      .unwrap_or_else(|err| panic!("failed to attach the uart at {:#x}: {}", address, err));
+    if options.endpoint == Endpoint::Stdio && uart_input.is_none() {
+      uart_input = Some(sender.clone());
+    }
+    options.endpoint.spawn(&format!("serial port {}", index), receiver, sender)
+      .unwrap_or_else(|err| panic!("failed to open the serial endpoint: {}", err));
+  }
+  if console_input.is_some() {
+    uart_input = None;
+  }
+  cpu.chosen.bootargs = args.append.clone();
+  cpu.sbi = args.sbi;
+  for options in args.file.iter().chain(&args.image) {
+    cpu.images.push(Image::open(options)
+      .unwrap_or_else(|err| panic!("failed to load {}: {}", options.path.display(), err)));
+  }
+  if let Some(path) = &args.kernel {
+    cpu.kernel = Some(Kernel::open(path, args.initrd.as_deref())
+      .unwrap_or_else(|err| panic!("failed to load {}: {}", path.display(), err)));
+  }
+  // the tree as the guest gets it, with where the initrd went
+  if let Some(path) = &args.dump_dtb {
+    fs::write(path, cpu.boot())
+      .unwrap_or_else(|err| panic!("failed to write {}: {}", path.display(), err));
+    return;
+  }
   let (htif_stdin_sender, htif_stdin_receiver) = channel::<i32>();
   let (htif_stdout_sender, htif_stdout_receiver) = channel::<i32>();
-  spawn(move || loop {
-    print!("{}", char::from_u32(controller.uart_receiver.recv() as u32).unwrap());
-  });
   spawn(move || loop {
     print!("{}", char::from_u32(htif_stdout_receiver.recv() as u32).unwrap());
   });
+  // raw mode while keys go to the guest, with C-a for yuri
+  let mut session = None;
+  if !htif && (console_input.is_some() || uart_input.is_some()) && terminal::raw() {
+    let (sender, receiver) = notified_channel(cpu.bus.notifier.clone());
+    cpu.monitor = Some(Monitor::new(receiver));
+    session = Some(Session::new(sender, args.stdio_log));
+    eprintln!("C-a h for help");
+  }
   spawn(move || {
     for input in std::io::stdin().bytes() {
       let input = input.unwrap();
-      uart_sender.send(input);
+      let input = match &mut session {
+        Some(session) => match session.input(input) {
+          Some(input) => input,
+          None => continue,
+        },
+        None => input,
+      };
+      if let Some(console) = &console_input { console.send(vec![input]); }
+      if let Some(uart) = &uart_input { uart.send(vec![input]); }
       if htif { htif_stdin_sender.send(input as i32); }
     }
     if htif { htif_stdin_sender.send(-1); }
   });
   if !htif {
-    cpu.run_elf(file);
+    let code = cpu.run();
+    // what the guest wrote just before it finished
+    endpoint::flush();
+    terminal::restore();
+    std::process::exit(code);
   } else {
-    cpu.run_htif(file, htif_stdin_receiver, htif_stdout_sender);
+    cpu.run_htif(args.file.expect("--htif needs an image").path, htif_stdin_receiver, htif_stdout_sender, false);
   }
 }
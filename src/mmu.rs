@@ -1,6 +1,6 @@
-use std::sync::{Arc, Mutex, atomic::Ordering};
+use std::{rc::Rc, sync::{Arc, Mutex, atomic::Ordering}};
 
-use crate::{devices::{bus::Bus, Device}, hart::{Hart, Mode}, trap::Exception, instructions::InstructionWithType};
+use crate::{devices::{bus::Bus, Device, memory::MEMORY_START}, hart::{Hart, Mode}, trap::Exception, tlb::{Tlb, TlbContext, TlbEntry}, instructions::{InstructionWithType, Decoded, decode, cache::DecodeCache, block::{BlockCache, Translated, ends_block, cacheable, MAX_BLOCK_LEN}}};
 
 const PAGESIZE: u64 = 4096;
 const LEVELS: usize = 3;
@@ -93,11 +93,18 @@ enum AccessType {
 pub(crate) struct MMU {
   bus: Bus,
   reservation: Arc<Mutex<Vec<u64>>>,
+  cache: DecodeCache,
+  blocks: BlockCache,
+  // execute, read, write
+  tlbs: [Tlb; 3],
 }
 
 impl MMU {
   pub(crate) fn new(bus: Bus) -> MMU {
     MMU {
+      cache: DecodeCache::new(bus.memory.code.clone()),
+      blocks: BlockCache::new(bus.memory.code.clone()),
+      tlbs: [Tlb::new(), Tlb::new(), Tlb::new()],
       bus,
       reservation: Arc::new(Mutex::new(Vec::new())),
     }
@@ -117,6 +124,53 @@ impl MMU {
   }
 
   fn translate(&mut self, address: u64, hart: &Hart, access: AccessType) -> Result<u64, Exception> {
+    Ok(self.access(address, hart, access)?.0)
+  }
+
+  // physical address and the pointer to it in host memory, null for mmio
+  #[inline]
+  fn access(&mut self, address: u64, hart: &Hart, access: AccessType) -> Result<(u64, *mut u8), Exception> {
+    let tlb = match access {
+      AccessType::Execute => 0,
+      AccessType::Read => 1,
+      // writable pages are readable
+      AccessType::Write | AccessType::ReadWrite => 2,
+    };
+    let context = MMU::context(hart, &access);
+    let offset = address % PAGESIZE;
+    let (page, host) = match self.tlbs[tlb].get(context, address) {
+      Some(TlbEntry { physical, host, .. }) => (physical, host),
+      None => {
+        let page = self.walk(address, hart, access)? - offset;
+        let host = self.bus.memory.host(page);
+        self.tlbs[tlb].insert(address, page, host);
+        (page, host)
+      },
+    };
+    if host.is_null() { return Ok((page + offset, host)); }
+    Ok((page + offset, host.wrapping_add(offset as usize)))
+  }
+
+  // the read and write TLBs for the current context of the hart, native code runs loads and stores through them
+  #[cfg(feature = "jit")]
+  pub(crate) fn native_tlbs(&mut self, hart: &Hart) -> (*const TlbEntry, *const TlbEntry) {
+    let (read, write) = (MMU::context(hart, &AccessType::Read), MMU::context(hart, &AccessType::Write));
+    (self.tlbs[1].entries(read), self.tlbs[2].entries(write))
+  }
+
+  #[inline]
+  fn context(hart: &Hart, access: &AccessType) -> TlbContext {
+    // not translated
+    const BARE: TlbContext = TlbContext { satp: 0, mode: Mode::Machine, sum: false, mxr: false };
+    let satp = hart.csr.read_satp();
+    if satp >> 60 != 8 { return BARE; }
+    let (mprv, mpp, sum, mxr) = hart.csr.read_mstatus_mprv_mpp_sum_mxr();
+    let mode = if mprv && *access != AccessType::Execute { mpp } else { hart.mode };
+    if mode == Mode::Machine { return BARE; }
+    TlbContext { satp, mode, sum, mxr }
+  }
+
+  fn walk(&mut self, address: u64, hart: &Hart, access: AccessType) -> Result<u64, Exception> {
     let satp = SATP::from_u64(hart.csr.read_satp());
     if satp.mode != 8 { return Ok(address); }
     let (mprv, mpp, sum, mxr) = hart.csr.read_mstatus_mprv_mpp_sum_mxr();
@@ -228,9 +282,9 @@ impl MMU {
     Ok(())
   }
 
-  pub(crate) fn fetch(&mut self, hart: &Hart, address: u64) -> Result<InstructionWithType, Exception> {
+  // address_low is where address translates to
+  pub(crate) fn fetch(&mut self, hart: &Hart, address: u64, address_low: u64) -> Result<InstructionWithType, Exception> {
     debug_assert!(address % 2 == 0);
-    let address_low = self.translate(address, hart, AccessType::Execute)?;
     let instruction_low = self.bus.read16(address_low)
       .map_err(|_| Exception::InstructionAccessFault(address))?;
     if instruction_low & 0b11 != 0b11 {
@@ -248,43 +302,123 @@ impl MMU {
     }
   }
 
+  pub(crate) fn decode(&mut self, hart: &Hart, address: u64) -> Result<Decoded, Exception> {
+    let physical = self.translate(address, hart, AccessType::Execute)?;
+    if let Some(decoded) = self.cache.get(physical) {
+      return Ok(decoded);
+    }
+    let decoded = decode(self.fetch(hart, address, physical)?)
+      .ok_or(Exception::IllegalInstruction)?;
+    self.cache.insert(physical, decoded);
+    Ok(decoded)
+  }
+
+  // the block starting at pc
+  pub(crate) fn block(&mut self, hart: &Hart) -> Result<Rc<Translated>, Exception> {
+    let satp = hart.csr.read_satp();
+    if let Some(translated) = self.blocks.follow(hart.pc, satp, hart.mode) {
+      return Ok(translated);
+    }
+    let physical = self.translate(hart.pc, hart, AccessType::Execute)?;
+    if let Some(translated) = self.blocks.get(hart.pc, satp, physical, hart.mode) {
+      return Ok(translated);
+    }
+    if !cacheable(physical) {
+      self.blocks.leave();
+      return Ok(Rc::new(Translated::new(vec![self.decode(hart, hart.pc)?])));
+    }
+    let page_end = (physical / PAGESIZE + 1) * PAGESIZE;
+    let mut insts = Vec::new();
+    let (mut pc, mut end) = (hart.pc, physical);
+    loop {
+      let decoded = match self.decode(hart, pc) {
+        Ok(decoded) => decoded,
+        // the exception will be raised when it becomes the first instruction of a block
+        Err(_) if !insts.is_empty() => break,
+        Err(exception) => return Err(exception),
+      };
+      if end + decoded.len > page_end {
+        if !insts.is_empty() { break; }
+        // crosses pages, which can't be cached
+        self.blocks.leave();
+        return Ok(Rc::new(Translated::new(vec![decoded])));
+      }
+      insts.push(decoded);
+      pc = pc.wrapping_add(decoded.len);
+      end += decoded.len;
+      if ends_block(decoded.instructor.opcode) || insts.len() == MAX_BLOCK_LEN || end == page_end { break; }
+    }
+    let translated = Rc::new(Translated::new(insts));
+    self.blocks.insert(hart.pc, satp, physical, hart.mode, translated.clone());
+    Ok(translated)
+  }
+
+  pub(crate) fn bus(&mut self) -> &mut Bus {
+    &mut self.bus
+  }
+
+  pub(crate) fn fence_i(&mut self) {
+    self.cache.flush();
+    self.blocks.flush();
+  }
+
+  pub(crate) fn fence_vma(&mut self) {
+    self.blocks.unlink();
+    for tlb in self.tlbs.iter_mut() {
+      tlb.flush();
+    }
+  }
+
+  // memory is accessed without going through the bus
+  fn device(&mut self, physical: u64) -> &mut dyn Device {
+    if cacheable(physical) { &mut self.bus.memory } else { &mut self.bus }
+  }
+
   pub(crate) fn read8(&mut self, hart: &Hart, address: u64) -> Result<u8, Exception> {
-    let address = self.translate(address, hart, AccessType::Read)?;
-    self.bus.read8(address)
+    let (address, host) = self.access(address, hart, AccessType::Read)?;
+    if host.is_null() { return self.bus.read8(address); }
+    Ok(unsafe { *host })
   }
   pub(crate) fn read16(&mut self, hart: &Hart, address: u64) -> Result<u16, Exception> {
     if address % 2 == 0 {
-      let address = self.translate(address, hart, AccessType::Read)?;
-      Ok(self.bus.read16(address)?)
+      let (address, host) = self.access(address, hart, AccessType::Read)?;
+      if host.is_null() { return self.bus.read16(address); }
+      Ok(u16::from_le(unsafe { *(host as *const u16) }))
     } else {
       Ok(u16::from_le_bytes(self.misaligned_read(hart, address)?))
     }
   }
   pub(crate) fn read32(&mut self, hart: &Hart, address: u64) -> Result<u32, Exception> {
     if address % 4 == 0 {
-      let address = self.translate(address, hart, AccessType::Read)?;
-      Ok(self.bus.read32(address)?)
+      let (address, host) = self.access(address, hart, AccessType::Read)?;
+      if host.is_null() { return self.bus.read32(address); }
+      Ok(u32::from_le(unsafe { *(host as *const u32) }))
     } else {
       Ok(u32::from_le_bytes(self.misaligned_read(hart, address)?))
     }
   }
   pub(crate) fn read64(&mut self, hart: &Hart, address: u64) -> Result<u64, Exception> {
     if address % 8 == 0 {
-      let address = self.translate(address, hart, AccessType::Read)?;
-      Ok(self.bus.read64(address)?)
+      let (address, host) = self.access(address, hart, AccessType::Read)?;
+      if host.is_null() { return self.bus.read64(address); }
+      Ok(u64::from_le(unsafe { *(host as *const u64) }))
     } else {
       Ok(u64::from_le_bytes(self.misaligned_read(hart, address)?))
     }
   }
   pub(crate) fn write8(&mut self, hart: &Hart, address: u64, data: u8) -> Result<(), Exception> {
-    let address = self.translate(address, hart, AccessType::Write)?;
-    self.bus.write8(address, data)?;
+    let (address, host) = self.access(address, hart, AccessType::Write)?;
+    if host.is_null() { return self.bus.write8(address, data); }
+    self.bus.memory.code.touch(address - MEMORY_START, 1);
+    unsafe { *host = data; }
     Ok(())
   }
   pub(crate) fn write16(&mut self, hart: &Hart, address: u64, data: u16) -> Result<(), Exception> {
     if address % 2 == 0 {
-      let address = self.translate(address, hart, AccessType::Write)?;
-      self.bus.write16(address, data)?;
+      let (address, host) = self.access(address, hart, AccessType::Write)?;
+      if host.is_null() { return self.bus.write16(address, data); }
+      self.bus.memory.code.touch(address - MEMORY_START, 2);
+      unsafe { *(host as *mut u16) = data.to_le(); }
     } else {
       self.misaligned_write(hart, address, data.to_le_bytes())?;
     }
@@ -292,8 +426,10 @@ impl MMU {
   }
   pub(crate) fn write32(&mut self, hart: &Hart, address: u64, data: u32) -> Result<(), Exception> {
     if address % 4 == 0 {
-      let address = self.translate(address, hart, AccessType::Write)?;
-      self.bus.write32(address, data)?;
+      let (address, host) = self.access(address, hart, AccessType::Write)?;
+      if host.is_null() { return self.bus.write32(address, data); }
+      self.bus.memory.code.touch(address - MEMORY_START, 4);
+      unsafe { *(host as *mut u32) = data.to_le(); }
     } else {
       self.misaligned_write(hart, address, data.to_le_bytes())?;
     }
@@ -301,8 +437,10 @@ impl MMU {
   }
   pub(crate) fn write64(&mut self, hart: &Hart, address: u64, data: u64) -> Result<(), Exception> {
     if address % 8 == 0 {
-      let address = self.translate(address, hart, AccessType::Write)?;
-      self.bus.write64(address, data)?;
+      let (address, host) = self.access(address, hart, AccessType::Write)?;
+      if host.is_null() { return self.bus.write64(address, data); }
+      self.bus.memory.code.touch(address - MEMORY_START, 8);
+      unsafe { *(host as *mut u64) = data.to_le(); }
     } else {
       self.misaligned_write(hart, address, data.to_le_bytes())?;
     }
@@ -311,91 +449,162 @@ impl MMU {
   pub(crate) fn atomic_swap32(&mut self, hart: &Hart, address: u64, val: u32, ordering: Ordering) -> Result<u32, Exception> {
     if address % 4 != 0 { return Err(Exception::StoreAMOAddressMisaligned(address)); }
     let address = self.translate(address, hart, AccessType::ReadWrite)?;
-    self.bus.atomic_swap32(address, val, ordering)
+    self.device(address).atomic_swap32(address, val, ordering)
   }
   pub(crate) fn atomic_swap64(&mut self, hart: &Hart, address: u64, val: u64, ordering: Ordering) -> Result<u64, Exception> {
     if address % 8 != 0 { return Err(Exception::StoreAMOAddressMisaligned(address)); }
     let address = self.translate(address, hart, AccessType::ReadWrite)?;
-    self.bus.atomic_swap64(address, val, ordering)
+    self.device(address).atomic_swap64(address, val, ordering)
   }
   pub(crate) fn atomic_add32(&mut self, hart: &Hart, address: u64, val: u32, ordering: Ordering) -> Result<u32, Exception> {
     if address % 4 != 0 { return Err(Exception::StoreAMOAddressMisaligned(address)); }
     let address = self.translate(address, hart, AccessType::ReadWrite)?;
-    self.bus.atomic_add32(address, val, ordering)
+    self.device(address).atomic_add32(address, val, ordering)
   }
   pub(crate) fn atomic_add64(&mut self, hart: &Hart, address: u64, val: u64, ordering: Ordering) -> Result<u64, Exception> {
     if address % 8 != 0 { return Err(Exception::StoreAMOAddressMisaligned(address)); }
     let address = self.translate(address, hart, AccessType::ReadWrite)?;
-    self.bus.atomic_add64(address, val, ordering)
+    self.device(address).atomic_add64(address, val, ordering)
   }
   pub(crate) fn atomic_xor32(&mut self, hart: &Hart, address: u64, val: u32, ordering: Ordering) -> Result<u32, Exception> {
     if address % 4 != 0 { return Err(Exception::StoreAMOAddressMisaligned(address)); }
     let address = self.translate(address, hart, AccessType::ReadWrite)?;
-    self.bus.atomic_xor32(address, val, ordering)
+    self.device(address).atomic_xor32(address, val, ordering)
   }
   pub(crate) fn atomic_xor64(&mut self, hart: &Hart, address: u64, val: u64, ordering: Ordering) -> Result<u64, Exception> {
     if address % 8 != 0 { return Err(Exception::StoreAMOAddressMisaligned(address)); }
     let address = self.translate(address, hart, AccessType::ReadWrite)?;
-    self.bus.atomic_xor64(address, val, ordering)
+    self.device(address).atomic_xor64(address, val, ordering)
   }
   pub(crate) fn atomic_and32(&mut self, hart: &Hart, address: u64, val: u32, ordering: Ordering) -> Result<u32, Exception> {
     if address % 4 != 0 { return Err(Exception::StoreAMOAddressMisaligned(address)); }
     let address = self.translate(address, hart, AccessType::ReadWrite)?;
-    self.bus.atomic_and32(address, val, ordering)
+    self.device(address).atomic_and32(address, val, ordering)
   }
   pub(crate) fn atomic_and64(&mut self, hart: &Hart, address: u64, val: u64, ordering: Ordering) -> Result<u64, Exception> {
     if address % 8 != 0 { return Err(Exception::StoreAMOAddressMisaligned(address)); }
     let address = self.translate(address, hart, AccessType::ReadWrite)?;
-    self.bus.atomic_and64(address, val, ordering)
+    self.device(address).atomic_and64(address, val, ordering)
   }
   pub(crate) fn atomic_or32(&mut self, hart: &Hart, address: u64, val: u32, ordering: Ordering) -> Result<u32, Exception> {
     if address % 4 != 0 { return Err(Exception::StoreAMOAddressMisaligned(address)); }
     let address = self.translate(address, hart, AccessType::ReadWrite)?;
-    self.bus.atomic_or32(address, val, ordering)
+    self.device(address).atomic_or32(address, val, ordering)
   }
   pub(crate) fn atomic_or64(&mut self, hart: &Hart, address: u64, val: u64, ordering: Ordering) -> Result<u64, Exception> {
     if address % 8 != 0 { return Err(Exception::StoreAMOAddressMisaligned(address)); }
     let address = self.translate(address, hart, AccessType::ReadWrite)?;
-    self.bus.atomic_or64(address, val, ordering)
+    self.device(address).atomic_or64(address, val, ordering)
   }
   pub(crate) fn atomic_min_i32(&mut self, hart: &Hart, address: u64, val: i32, ordering: Ordering) -> Result<i32, Exception> {
     if address % 4 != 0 { return Err(Exception::StoreAMOAddressMisaligned(address)); }
     let address = self.translate(address, hart, AccessType::ReadWrite)?;
-    self.bus.atomic_min_i32(address, val, ordering)
+    self.device(address).atomic_min_i32(address, val, ordering)
   }
   pub(crate) fn atomic_min_i64(&mut self, hart: &Hart, address: u64, val: i64, ordering: Ordering) -> Result<i64, Exception> {
     if address % 8 != 0 { return Err(Exception::StoreAMOAddressMisaligned(address)); }
     let address = self.translate(address, hart, AccessType::ReadWrite)?;
-    self.bus.atomic_min_i64(address, val, ordering)
+    self.device(address).atomic_min_i64(address, val, ordering)
   }
   pub(crate) fn atomic_max_i32(&mut self, hart: &Hart, address: u64, val: i32, ordering: Ordering) -> Result<i32, Exception> {
     if address % 4 != 0 { return Err(Exception::StoreAMOAddressMisaligned(address)); }
     let address = self.translate(address, hart, AccessType::ReadWrite)?;
-    self.bus.atomic_max_i32(address, val, ordering)
+    self.device(address).atomic_max_i32(address, val, ordering)
   }
   pub(crate) fn atomic_max_i64(&mut self, hart: &Hart, address: u64, val: i64, ordering: Ordering) -> Result<i64, Exception> {
     if address % 8 != 0 { return Err(Exception::StoreAMOAddressMisaligned(address)); }
     let address = self.translate(address, hart, AccessType::ReadWrite)?;
-    self.bus.atomic_max_i64(address, val, ordering)
+    self.device(address).atomic_max_i64(address, val, ordering)
   }
   pub(crate) fn atomic_min_u32(&mut self, hart: &Hart, address: u64, val: u32, ordering: Ordering) -> Result<u32, Exception> {
     if address % 4 != 0 { return Err(Exception::StoreAMOAddressMisaligned(address)); }
     let address = self.translate(address, hart, AccessType::ReadWrite)?;
-    self.bus.atomic_min_u32(address, val, ordering)
+    self.device(address).atomic_min_u32(address, val, ordering)
   }
   pub(crate) fn atomic_min_u64(&mut self, hart: &Hart, address: u64, val: u64, ordering: Ordering) -> Result<u64, Exception> {
     if address % 8 != 0 { return Err(Exception::StoreAMOAddressMisaligned(address)); }
     let address = self.translate(address, hart, AccessType::ReadWrite)?;
-    self.bus.atomic_min_u64(address, val, ordering)
+    self.device(address).atomic_min_u64(address, val, ordering)
   }
   pub(crate) fn atomic_max_u32(&mut self, hart: &Hart, address: u64, val: u32, ordering: Ordering) -> Result<u32, Exception> {
     if address % 4 != 0 { return Err(Exception::StoreAMOAddressMisaligned(address)); }
     let address = self.translate(address, hart, AccessType::ReadWrite)?;
-    self.bus.atomic_max_u32(address, val, ordering)
+    self.device(address).atomic_max_u32(address, val, ordering)
   }
   pub(crate) fn atomic_max_u64(&mut self, hart: &Hart, address: u64, val: u64, ordering: Ordering) -> Result<u64, Exception> {
     if address % 8 != 0 { return Err(Exception::StoreAMOAddressMisaligned(address)); }
     let address = self.translate(address, hart, AccessType::ReadWrite)?;
-    self.bus.atomic_max_u64(address, val, ordering)
+    self.device(address).atomic_max_u64(address, val, ordering)
+  }
+}
+
+// cargo test --release -- --ignored --nocapture bench
+#[cfg(test)]
+mod benches {
+  use std::time::Instant;
+  use crate::{cpu::Cpu, devices::{Device, memory::MEMORY_START}, hart::Mode};
+
+  const ITERATIONS: u64 = 10_000_000;
+
+  fn cpu(translated: bool) -> Cpu {
+    let (mut cpu, _) = Cpu::new();
+    if translated {
+      // identity mapped gigapage at MEMORY_START
+      cpu.bus.write64(0x80100000 + 2 * 8, (MEMORY_START >> 12) << 10 | 0b11001111).unwrap();
+      cpu.hart.csr.csr[0x180] = 8 << 60 | 0x80100;
+      cpu.hart.mode = Mode::Supervisor;
+    }
+    cpu
+  }
+
+  fn report(name: &str, start: Instant, count: u64) {
+    let elapsed = start.elapsed();
+    println!("{}: {:.2} ns per access", name, elapsed.as_nanos() as f64 / count as f64);
+  }
+
+  #[test]
+  #[ignore]
+  fn bench_accesses() {
+    for translated in [false, true] {
+      let mut cpu = cpu(translated);
+      let start = Instant::now();
+      for i in 0..ITERATIONS {
+        let address = MEMORY_START + 0x200000 + (i * 8) % 0x10000;
+        cpu.mmu.write64(&cpu.hart, address, i).unwrap();
+        assert_eq!(cpu.mmu.read64(&cpu.hart, address).unwrap(), i);
+      }
+      report(if translated { "mmu sv39" } else { "mmu bare" }, start, ITERATIONS * 2);
+    }
+  }
+
+  #[test]
+  #[ignore]
+  fn bench_guest() {
+    let program = [
+      0x00010097, // auipc x1, 0x10
+      0x40000113, // addi x2, x0, 1024
+      0x0020b023, // sd x2, 0(x1)
+      0x0000b183, // ld x3, 0(x1)
+      0x00320233, // add x4, x4, x3
+      0x00808093, // addi x1, x1, 8
+      0xfff10113, // addi x2, x2, -1
+      0xfe0116e3, // bne x2, x0, -20
+      0xfe1ff06f, // jal x0, -32
+    ];
+    for translated in [false, true] {
+      let mut cpu = cpu(translated);
+      for (index, inst) in program.iter().enumerate() {
+        cpu.bus.write32(MEMORY_START + index as u64 * 4, *inst).unwrap();
+      }
+      cpu.hart.pc = MEMORY_START;
+      let start = Instant::now();
+      let mut retired = 0;
+      while retired < ITERATIONS * 4 {
+        retired += cpu.hart.step_block(&mut cpu.mmu) as u64;
+      }
+      let elapsed = start.elapsed();
+      println!("guest {}: {:.2} MIPS", if translated { "sv39" } else { "bare" },
+        retired as f64 / elapsed.as_secs_f64() / 1e6);
+    }
   }
 }
@@ -0,0 +1,116 @@
+use std::io::Write;
+
+use crate::{hart::Hart, devices::{bus::Bus, Device, finisher::Finish, pci::{self, VENDOR_ID, DEVICE_ID, CLASS}}, utils::{parse_number, channel::Receiver}};
+
+// A line-based monitor reached through the escape key, on the cpu thread between bus steps.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Command {
+  // typed in the monitor
+  Line(String),
+  Break,
+  Quit,
+}
+
+pub(crate) const PROMPT: &str = "(yuri) ";
+
+const HELP: &str = "\
+help                 this help
+info registers       pc, privilege mode and integer registers
+info pci             functions on the PCIe bus
+x address [count]    physical memory as 64-bit words
+stop                 pause the guest
+cont                 resume the guest
+device_add name      hot-add a PCIe endpoint, edu for now
+system_reset         reset the machine
+quit                 exit yuri";
+
+#[derive(Debug)]
+pub(crate) struct Monitor {
+  receiver: Receiver<Command>,
+  stopped: bool,
+}
+
+impl Monitor {
+  pub(crate) fn new(receiver: Receiver<Command>) -> Monitor {
+    Monitor { receiver, stopped: false }
+  }
+
+  // run what was sent since the last bus step, blocking while the guest is stopped
+  pub(crate) fn poll(&mut self, hart: &Hart, bus: &mut Bus) -> Option<Finish> {
+    loop {
+      let command = if self.stopped { self.receiver.recv() } else { self.receiver.try_recv()? };
+      let finish = match command {
+        Command::Line(line) => {
+          let finish = self.run(line.trim(), hart, bus);
+          if finish.is_none() {
+            print!("{}", PROMPT);
+            let _ = std::io::stdout().flush();
+          }
+          finish
+        },
+        Command::Break => {
+          bus.uarts[0].lock().unwrap().receive_break();
+          None
+        },
+        Command::Quit => Some(Finish::Exit(0)),
+      };
+      if finish.is_some() { return finish; }
+    }
+  }
+
+  fn run(&mut self, line: &str, hart: &Hart, bus: &mut Bus) -> Option<Finish> {
+    let words: Vec<_> = line.split_whitespace().collect();
+    match words[..] {
+      [] => {},
+      ["help"] => println!("{}", HELP),
+      ["info", "registers"] => {
+        println!("pc  {:016x}  mode {:?}", hart.pc, hart.mode);
+        for index in 0..32 {
+          print!("x{:<2} {:016x}{}", index, hart.regs[index], if index % 4 == 3 { "\n" } else { "  " });
+        }
+      },
+      ["info", "pci"] => {
+        for (slot, function) in bus.pci.lock().unwrap().functions() {
+          let config = function.config();
+          println!("00:{:02x}.0  {:04x}:{:04x}  class {:06x}", slot, config.get16(VENDOR_ID), config.get16(DEVICE_ID),
+            config.get32(CLASS - 1) >> 8);
+        }
+      },
+      ["x", address] | ["x", address, _] => {
+        let count = words.get(2).map_or(Some(1), |count| count.parse().ok());
+        let (Some(address), Some(count)) = (parse_number(address), count) else {
+          println!("usage: x address [count]");
+          return None;
+        };
+        for index in 0..count {
+          let address = address.wrapping_add(index * 8);
+          match bus.read64(address) {
+            Ok(data) => println!("{:016x}: {:016x}", address, data),
+            Err(_) => {
+              println!("{:016x}: cannot be read", address);
+              break;
+            },
+          }
+        }
+      },
+      // the guest sees it after a rescan
+      ["device_add", name] => match pci::endpoint(name) {
+        Some(function) => match bus.attach_pci(function) {
+          Some(slot) => println!("{} in slot {}", name, slot),
+          None => println!("no free PCI slot"),
+        },
+        None => println!("unknown device '{}'", name),
+      },
+      ["stop"] => self.stopped = true,
+      ["cont"] => self.stopped = false,
+      ["system_reset"] => {
+        self.stopped = false;
+        return Some(Finish::Reset);
+      },
+      ["quit"] => return Some(Finish::Exit(0)),
+      _ => println!("unknown command '{}', try help", line),
+    }
+    None
+  }
+}
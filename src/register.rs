@@ -1,5 +1,6 @@
 use std::ops::Index;
 
+#[derive(Debug, Clone, PartialEq)]
 pub(crate) struct Registers {
   regs: [u64; 32],
 }
@@ -13,6 +14,12 @@ impl Registers {
     if index == 0 { return; }
     self.regs[index] = value;
   }
+
+  // x0 must stay zero when written through the pointer
+  #[cfg(feature = "jit")]
+  pub(crate) fn as_mut_ptr(&mut self) -> *mut u64 {
+    self.regs.as_mut_ptr()
+  }
 }
 
 impl Index<usize> for Registers {
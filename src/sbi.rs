@@ -0,0 +1,259 @@
+use crate::{hart::Hart, mmu::MMU, devices::{Device, bus::Bus, memory::{MEMORY_START, MEMORY_END}, aclint::MTIMECMP0_START, finisher::{FINISHER_START, FINISHER_PASS, FINISHER_FAIL, FINISHER_RESET}}};
+
+// The SBI answered by yuri itself, so that a kernel or another supervisor mode program runs without firmware.
+// Ecalls from supervisor mode never reach machine mode, the timer goes through mtimecmp to STIP, and the console
+// is the first UART.
+
+const SPEC_VERSION: u64 = 2 << 24;
+// not a registered implementation, "yuri"
+const IMPL_ID: u64 = 0x79757269;
+
+const EXT_BASE: u64 = 0x10;
+const EXT_TIME: u64 = 0x54494d45;
+const EXT_IPI: u64 = 0x735049;
+const EXT_RFENCE: u64 = 0x52464e43;
+const EXT_HSM: u64 = 0x48534d;
+const EXT_SRST: u64 = 0x53525354;
+const EXT_DBCN: u64 = 0x4442434e;
+
+const LEGACY_SET_TIMER: u64 = 0x00;
+const LEGACY_CONSOLE_PUTCHAR: u64 = 0x01;
+const LEGACY_CONSOLE_GETCHAR: u64 = 0x02;
+const LEGACY_CLEAR_IPI: u64 = 0x03;
+const LEGACY_SEND_IPI: u64 = 0x04;
+const LEGACY_REMOTE_FENCE_I: u64 = 0x05;
+const LEGACY_REMOTE_SFENCE_VMA: u64 = 0x06;
+const LEGACY_REMOTE_SFENCE_VMA_ASID: u64 = 0x07;
+const LEGACY_SHUTDOWN: u64 = 0x08;
+
+const EXTENSIONS: [u64; 7] = [EXT_BASE, EXT_TIME, EXT_IPI, EXT_RFENCE, EXT_HSM, EXT_SRST, EXT_DBCN];
+
+const ERR_FAILED: i64 = -1;
+const ERR_NOT_SUPPORTED: i64 = -2;
+const ERR_INVALID_PARAM: i64 = -3;
+const ERR_ALREADY_AVAILABLE: i64 = -6;
+
+const HSM_STARTED: u64 = 0;
+const HSM_SUSPEND_RETENTIVE: u64 = 0;
+const HSM_SUSPEND_NON_RETENTIVE: u64 = 0x80000000;
+
+const SRST_SHUTDOWN: u64 = 0;
+const SRST_COLD_REBOOT: u64 = 1;
+const SRST_WARM_REBOOT: u64 = 2;
+const SRST_NO_REASON: u64 = 0;
+
+// supervisor software, timer and external interrupts
+const MIDELEG: u64 = (1 << 1) | (1 << 5) | (1 << 9);
+// every exception but ecalls from supervisor and machine mode
+const MEDELEG: u64 = 0xb1ff;
+
+// the delegation and timer as firmware leaves them for the next stage, no timer interrupt until the first
+// set_timer. The boot ROM then enters supervisor mode
+pub(crate) fn enter(hart: &mut Hart, bus: &mut Bus) {
+  bus.write64(MTIMECMP0_START, u64::MAX).unwrap();
+  hart.sbi = true;
+  hart.csr.write_mideleg(MIDELEG);
+  hart.csr.write_medeleg(MEDELEG);
+}
+
+// the extension in a7 and the function in a6, the error goes to a0 and the value to a1
+pub(crate) fn call(hart: &mut Hart, mmu: &mut MMU) {
+  let (extension, function) = (hart.regs[17], hart.regs[16]);
+  let args = [hart.regs[10], hart.regs[11], hart.regs[12]];
+  if extension <= LEGACY_SHUTDOWN {
+    // only a0 comes back
+    let value = legacy(hart, mmu, extension, args[0]);
+    hart.regs.set(10, value as u64);
+    return;
+  }
+  let result = match extension {
+    EXT_BASE => base(function, args[0]),
+    EXT_TIME if function == 0 => {
+      set_timer(hart, mmu, args[0]);
+      Ok(0)
+    },
+    EXT_IPI if function == 0 => selects(hart, args[0], args[1]).map(|selected| {
+      if selected { hart.csr.write_mip_ssip(1); }
+      0
+    }),
+    EXT_RFENCE => rfence(hart, mmu, function, args[0], args[1]),
+    EXT_HSM => hsm(hart, function, args[0]),
+    EXT_SRST if function == 0 => system_reset(hart, mmu, args[0], args[1]),
+    EXT_DBCN => console(mmu, function, args),
+    _ => Err(ERR_NOT_SUPPORTED),
+  };
+  let (error, value) = match result {
+    Ok(value) => (0, value),
+    Err(error) => (error as u64, 0),
+  };
+  hart.regs.set(10, error);
+  hart.regs.set(11, value);
+}
+
+fn legacy(hart: &mut Hart, mmu: &mut MMU, extension: u64, arg: u64) -> i64 {
+  match extension {
+    LEGACY_SET_TIMER => set_timer(hart, mmu, arg),
+    LEGACY_CONSOLE_PUTCHAR => mmu.bus().uarts[0].lock().unwrap().console_write(&[arg as u8]),
+    LEGACY_CONSOLE_GETCHAR => return mmu.bus().uarts[0].lock().unwrap().console_read().map_or(-1, i64::from),
+    LEGACY_CLEAR_IPI => hart.csr.write_mip_ssip(0),
+    // the mask is behind a pointer, and there is only this hart to send to
+    LEGACY_SEND_IPI => hart.csr.write_mip_ssip(1),
+    LEGACY_REMOTE_FENCE_I => mmu.fence_i(),
+    LEGACY_REMOTE_SFENCE_VMA | LEGACY_REMOTE_SFENCE_VMA_ASID => mmu.fence_vma(),
+    LEGACY_SHUTDOWN => {
+      system_reset(hart, mmu, SRST_SHUTDOWN, SRST_NO_REASON).unwrap();
+    },
+    _ => return ERR_NOT_SUPPORTED,
+  }
+  0
+}
+
+fn base(function: u64, arg: u64) -> Result<u64, i64> {
+  match function {
+    0 => Ok(SPEC_VERSION),
+    1 => Ok(IMPL_ID),
+    2 => Ok(env!("CARGO_PKG_VERSION_MAJOR").parse::<u64>().unwrap() << 16 | env!("CARGO_PKG_VERSION_MINOR").parse::<u64>().unwrap()),
+    3 => Ok((EXTENSIONS.contains(&arg) || arg <= LEGACY_SHUTDOWN) as u64),
+    // mvendorid, marchid and mimpid are zero
+    4..=6 => Ok(0),
+    _ => Err(ERR_NOT_SUPPORTED),
+  }
+}
+
+// the next timer interrupt, which also takes back the pending one
+fn set_timer(hart: &mut Hart, mmu: &mut MMU, time: u64) {
+  let bus = mmu.bus();
+  bus.write64(MTIMECMP0_START, time).unwrap();
+  let mtime = bus.aclint.lock().unwrap().mtime();
+  hart.csr.write_mip_stip(if mtime >= time { 1 } else { 0 });
+}
+
+// whether this hart is in the mask from the base, which selects every hart when it is all ones.
+// Any other hart does not exist
+fn selects(hart: &Hart, mask: u64, base: u64) -> Result<bool, i64> {
+  if base == u64::MAX { return Ok(true); }
+  let bit = hart.csr.read_mhartid().checked_sub(base).filter(|&bit| bit < 64).map_or(0, |bit| 1 << bit);
+  if mask & !bit != 0 { return Err(ERR_INVALID_PARAM); }
+  Ok(mask & bit != 0)
+}
+
+fn rfence(hart: &mut Hart, mmu: &mut MMU, function: u64, mask: u64, base: u64) -> Result<u64, i64> {
+  // the hypervisor fences have no hypervisor extension to fence
+  if function > 2 { return Err(ERR_NOT_SUPPORTED); }
+  if selects(hart, mask, base)? {
+    if function == 0 { mmu.fence_i() } else { mmu.fence_vma() }
+  }
+  Ok(0)
+}
+
+fn hsm(hart: &mut Hart, function: u64, arg: u64) -> Result<u64, i64> {
+  let hartid = hart.csr.read_mhartid();
+  match function {
+    0 if arg == hartid => Err(ERR_ALREADY_AVAILABLE),
+    0 => Err(ERR_INVALID_PARAM),
+    // the last hart keeps running
+    1 => Err(ERR_FAILED),
+    2 if arg == hartid => Ok(HSM_STARTED),
+    2 => Err(ERR_INVALID_PARAM),
+    // like wfi, the call returns once an interrupt is pending
+    3 if arg == HSM_SUSPEND_RETENTIVE => {
+      hart.wfi = true;
+      Ok(0)
+    },
+    3 if arg == HSM_SUSPEND_NON_RETENTIVE => Err(ERR_NOT_SUPPORTED),
+    3 => Err(ERR_INVALID_PARAM),
+    _ => Err(ERR_NOT_SUPPORTED),
+  }
+}
+
+// through the finisher, as OpenSBI does. The hart waits for the cpu to take the finish after the call
+fn system_reset(hart: &mut Hart, mmu: &mut MMU, kind: u64, reason: u64) -> Result<u64, i64> {
+  let data = match kind {
+    SRST_SHUTDOWN if reason == SRST_NO_REASON => FINISHER_PASS,
+    SRST_SHUTDOWN => (1 << 16) | FINISHER_FAIL,
+    SRST_COLD_REBOOT | SRST_WARM_REBOOT => FINISHER_RESET,
+    _ => return Err(ERR_INVALID_PARAM),
+  };
+  mmu.bus().write32(FINISHER_START, data).unwrap();
+  hart.wfi = true;
+  Ok(0)
+}
+
+// write and read of bytes in physical memory, and write of a single byte
+fn console(mmu: &mut MMU, function: u64, [count, base_lo, base_hi]: [u64; 3]) -> Result<u64, i64> {
+  let bus = mmu.bus();
+  match function {
+    // the upper half of the address is for rv32, and the bytes have to be in memory
+    0 | 1 if base_hi != 0 || !base_lo.checked_add(count).is_some_and(|end| base_lo >= MEMORY_START && end <= MEMORY_END + 1) =>
+      Err(ERR_INVALID_PARAM),
+    0 => {
+      let mut data = vec![0; count as usize];
+      bus.read_bytes(base_lo, &mut data).map_err(|_| ERR_INVALID_PARAM)?;
+      bus.uarts[0].lock().unwrap().console_write(&data);
+      Ok(count)
+    },
+    1 => {
+      let mut data = Vec::new();
+      let uart = bus.uarts[0].clone();
+      while (data.len() as u64) < count {
+        let Some(byte) = uart.lock().unwrap().console_read() else { break };
+        data.push(byte);
+      }
+      bus.write_bytes(base_lo, &data).map_err(|_| ERR_INVALID_PARAM)?;
+      Ok(data.len() as u64)
+    },
+    2 => {
+      bus.uarts[0].lock().unwrap().console_write(&[count as u8]);
+      Ok(0)
+    },
+    _ => Err(ERR_NOT_SUPPORTED),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::{devices::{bus::Bus, finisher::Finish}, hart::Hart, mmu::MMU};
+
+  use super::*;
+
+  fn ecall(hart: &mut Hart, mmu: &mut MMU, extension: u64, function: u64, args: &[u64]) -> (i64, u64) {
+    hart.regs.set(17, extension);
+    hart.regs.set(16, function);
+    for (index, arg) in args.iter().enumerate() {
+      hart.regs.set(10 + index, *arg);
+    }
+    call(hart, mmu);
+    (hart.regs[10] as i64, hart.regs[11])
+  }
+
+  #[test]
+  fn calls() {
+    let (mut bus, controller) = Bus::new();
+    let mut mmu = MMU::new(bus.clone());
+    let mut hart = Hart::new();
+    enter(&mut hart, &mut bus);
+    assert_eq!(ecall(&mut hart, &mut mmu, EXT_BASE, 0, &[]), (0, SPEC_VERSION));
+    assert_eq!(ecall(&mut hart, &mut mmu, EXT_BASE, 3, &[EXT_DBCN]), (0, 1));
+    assert_eq!(ecall(&mut hart, &mut mmu, EXT_BASE, 3, &[0x504d55]), (0, 0));
+    // a timer in the past is pending at once, one in the future takes it back
+    ecall(&mut hart, &mut mmu, EXT_TIME, 0, &[0]);
+    assert!(hart.csr.read_mip().st);
+    ecall(&mut hart, &mut mmu, EXT_TIME, 0, &[1000]);
+    assert!(!hart.csr.read_mip().st);
+    assert_eq!(ecall(&mut hart, &mut mmu, EXT_IPI, 0, &[0b10, 0]), (ERR_INVALID_PARAM, 0));
+    ecall(&mut hart, &mut mmu, EXT_IPI, 0, &[0b1, 0]);
+    assert!(hart.csr.read_mip().ss);
+    assert_eq!(ecall(&mut hart, &mut mmu, EXT_HSM, 2, &[0]), (0, HSM_STARTED));
+    bus.write_bytes(MEMORY_START, b"hello").unwrap();
+    assert_eq!(ecall(&mut hart, &mut mmu, EXT_DBCN, 0, &[5, MEMORY_START, 0]), (0, 5));
+    ecall(&mut hart, &mut mmu, LEGACY_CONSOLE_PUTCHAR, 0, &[b'!' as u64]);
+    assert_eq!(controller.uart_receiver.try_recv(), Some(b"hello".to_vec()));
+    assert_eq!(controller.uart_receiver.try_recv(), Some(b"!".to_vec()));
+    controller.uart_sender.send(b"yo".to_vec());
+    assert_eq!(ecall(&mut hart, &mut mmu, LEGACY_CONSOLE_GETCHAR, 0, &[]).0, b'y' as i64);
+    assert_eq!(ecall(&mut hart, &mut mmu, EXT_DBCN, 1, &[4, MEMORY_START, 0]), (0, 1));
+    assert_eq!(bus.read8(MEMORY_START).unwrap(), b'o');
+    ecall(&mut hart, &mut mmu, EXT_SRST, 0, &[SRST_COLD_REBOOT, SRST_NO_REASON]);
+    assert_eq!(bus.finisher.lock().unwrap().finish, Some(Finish::Reset));
+  }
+}
@@ -0,0 +1,152 @@
+use std::{io::Write, path::PathBuf, sync::Mutex};
+
+use crate::{devices::endpoint, monitor::{Command, PROMPT}, utils::channel::Sender};
+
+// The host terminal in raw mode, so that every key including Ctrl-C reaches the guest,
+// and the escape key for talking to yuri instead.
+
+pub(crate) const ESCAPE: u8 = 0x01;
+
+static SAVED: Mutex<Option<libc::termios>> = Mutex::new(None);
+
+// false if stdin is not a terminal, which is left as it is
+pub(crate) fn raw() -> bool {
+  unsafe {
+    if libc::isatty(libc::STDIN_FILENO) == 0 { return false; }
+    let mut termios = std::mem::zeroed::<libc::termios>();
+    if libc::tcgetattr(libc::STDIN_FILENO, &mut termios) != 0 { return false; }
+    *SAVED.lock().unwrap() = Some(termios);
+    termios.c_iflag &= !(libc::IGNBRK | libc::BRKINT | libc::PARMRK | libc::ISTRIP | libc::INLCR | libc::IGNCR | libc::ICRNL | libc::IXON);
+    // output keeps turning \n into \r\n
+    termios.c_oflag |= libc::OPOST;
+    termios.c_lflag &= !(libc::ECHO | libc::ECHONL | libc::ICANON | libc::ISIG | libc::IEXTEN);
+    termios.c_cflag &= !(libc::CSIZE | libc::PARENB);
+    termios.c_cflag |= libc::CS8;
+    termios.c_cc[libc::VMIN] = 1;
+    termios.c_cc[libc::VTIME] = 0;
+    libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios);
+  }
+  let hook = std::panic::take_hook();
+  std::panic::set_hook(Box::new(move |info| {
+    restore();
+    hook(info);
+  }));
+  true
+}
+
+// back to how the terminal was before raw, if it was changed
+pub(crate) fn restore() {
+  if let Some(termios) = SAVED.lock().unwrap_or_else(|err| err.into_inner()).take() {
+    unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios); }
+  }
+}
+
+// keys typed in raw mode, going to the guest or to the monitor
+#[derive(Debug)]
+pub(crate) struct Session {
+  monitor: Sender<Command>,
+  // where stdio output is copied while logging
+  log: PathBuf,
+  escaped: bool,
+  // the line being typed, Some while in the monitor
+  line: Option<String>,
+}
+
+impl Session {
+  pub(crate) fn new(monitor: Sender<Command>, log: PathBuf) -> Session {
+    Session { monitor, log, escaped: false, line: None }
+  }
+
+  // the byte for the guest, if it is one
+  pub(crate) fn input(&mut self, byte: u8) -> Option<u8> {
+    if self.escaped {
+      self.escaped = false;
+      return self.command(byte);
+    }
+    if byte == ESCAPE {
+      self.escaped = true;
+      return None;
+    }
+    let Some(line) = &mut self.line else { return Some(byte) };
+    match byte {
+      b'\r' | b'\n' => {
+        println!();
+        self.monitor.send(Command::Line(std::mem::take(line)));
+      },
+      // backspace or delete
+      0x08 | 0x7f => if line.pop().is_some() { print!("\x08 \x08") },
+      0x20..=0x7e => {
+        line.push(byte as char);
+        print!("{}", byte as char);
+      },
+      _ => {},
+    }
+    let _ = std::io::stdout().flush();
+    None
+  }
+
+  // the key after the escape key
+  fn command(&mut self, byte: u8) -> Option<u8> {
+    match byte {
+      ESCAPE => return Some(ESCAPE),
+      b'x' => self.monitor.send(Command::Quit),
+      b'b' => self.monitor.send(Command::Break),
+      b'l' => match endpoint::toggle_log(&self.log) {
+        Ok(true) => eprintln!("\nlogging to {}", self.log.display()),
+        Ok(false) => eprintln!("\nlogging stopped"),
+        Err(err) => eprintln!("\nfailed to open {}: {}", self.log.display(), err),
+      },
+      b'c' => {
+        if self.line.take().is_none() {
+          self.line = Some(String::new());
+          print!("\n{}", PROMPT);
+        } else {
+          println!();
+        }
+        let _ = std::io::stdout().flush();
+      },
+      b'h' => eprintln!("\n\
+        C-a x    quit\n\
+        C-a b    send a break to the serial port\n\
+        C-a l    start or stop copying stdio output to {}\n\
+        C-a c    switch between the guest and the monitor\n\
+        C-a C-a  send C-a\n\
+        C-a h    this help", self.log.display()),
+      _ => {},
+    }
+    None
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::{monitor::Command, utils::channel::channel};
+
+  use super::{Session, ESCAPE};
+
+  #[test]
+  fn escape() {
+    let (sender, receiver) = channel();
+    let mut session = Session::new(sender, "yuri.log".into());
+    assert_eq!(session.input(b'a'), Some(b'a'));
+    // ctrl-c goes to the guest, the escape key twice sends it once
+    assert_eq!(session.input(0x03), Some(0x03));
+    assert_eq!(session.input(ESCAPE), None);
+    assert_eq!(session.input(ESCAPE), Some(ESCAPE));
+    assert_eq!(session.input(ESCAPE), None);
+    assert_eq!(session.input(b'b'), None);
+    assert_eq!(receiver.try_recv(), Some(Command::Break));
+    // in the monitor, keys make up lines
+    for byte in [ESCAPE, b'c', b's', b't', b'x', 0x7f, b'o', b'p', b'\r'] {
+      assert_eq!(session.input(byte), None);
+    }
+    assert_eq!(receiver.try_recv(), Some(Command::Line("stop".to_string())));
+    for byte in [ESCAPE, b'c'] {
+      assert_eq!(session.input(byte), None);
+    }
+    assert_eq!(session.input(b'q'), Some(b'q'));
+    assert_eq!(session.input(ESCAPE), None);
+    assert_eq!(session.input(b'x'), None);
+    assert_eq!(receiver.try_recv(), Some(Command::Quit));
+  }
+}
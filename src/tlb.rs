@@ -0,0 +1,77 @@
+use crate::hart::Mode;
+
+pub(crate) const TLB_SIZE: usize = 256;
+const EMPTY: u64 = u64::MAX;
+
+// everything a translation depends on besides the page tables
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct TlbContext {
+  pub(crate) satp: u64,
+  pub(crate) mode: Mode,
+  pub(crate) sum: bool,
+  pub(crate) mxr: bool,
+}
+
+// laid out for the native code of the jit, which looks entries up itself
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub(crate) struct TlbEntry {
+  // virtual page number
+  pub(crate) vpn: u64,
+  // physical address of the page
+  pub(crate) physical: u64,
+  // address of the page in host memory, null for mmio
+  pub(crate) host: *mut u8,
+}
+
+// Direct mapped translations of one access type.
+// Entries are dropped when the context changes or SFENCE.VMA is executed.
+#[derive(Debug, Clone)]
+pub(crate) struct Tlb {
+  context: Option<TlbContext>,
+  entries: Box<[TlbEntry]>,
+}
+
+impl Tlb {
+  pub(crate) fn new() -> Tlb {
+    Tlb {
+      context: None,
+      entries: vec![TlbEntry { vpn: EMPTY, physical: 0, host: std::ptr::null_mut() }; TLB_SIZE].into_boxed_slice(),
+    }
+  }
+
+  #[inline]
+  pub(crate) fn get(&mut self, context: TlbContext, address: u64) -> Option<TlbEntry> {
+    if self.switch(context) { return None; }
+    let vpn = address >> 12;
+    let entry = self.entries[vpn as usize % TLB_SIZE];
+    (entry.vpn == vpn).then_some(entry)
+  }
+
+  pub(crate) fn insert(&mut self, address: u64, physical: u64, host: *mut u8) {
+    let vpn = address >> 12;
+    self.entries[vpn as usize % TLB_SIZE] = TlbEntry { vpn, physical, host };
+  }
+
+  // the entries after switching to context, which stay valid until the context changes again
+  #[cfg(feature = "jit")]
+  pub(crate) fn entries(&mut self, context: TlbContext) -> *const TlbEntry {
+    self.switch(context);
+    self.entries.as_ptr()
+  }
+
+  // true -> the entries were for another context and are dropped
+  #[inline]
+  fn switch(&mut self, context: TlbContext) -> bool {
+    if self.context == Some(context) { return false; }
+    self.flush();
+    self.context = Some(context);
+    true
+  }
+
+  pub(crate) fn flush(&mut self) {
+    for entry in self.entries.iter_mut() {
+      entry.vpn = EMPTY;
+    }
+  }
+}
@@ -1,10 +1,11 @@
-use std::{sync::{Arc, Mutex, Condvar}, collections::LinkedList};
+use std::{sync::{Arc, Mutex, Condvar}, collections::LinkedList, time::Duration};
 
 
 #[derive(Debug)]
 pub(crate) struct Channel<T> {
   buffer: Arc<Mutex<LinkedList<T>>>,
   condvar: Arc<Condvar>,
+  notifier: Option<Notifier>,
 }
 
 impl<T> Clone for Channel<T> {
@@ -12,6 +13,7 @@ impl<T> Clone for Channel<T> {
     Self {
       buffer: self.buffer.clone(),
       condvar: self.condvar.clone(),
+      notifier: self.notifier.clone(),
     }
   }
 }
@@ -31,20 +33,13 @@ impl<T> Sender<T> {
     let mut buffer = self.channel.buffer.lock().unwrap();
     buffer.push_back(t);
     self.channel.condvar.notify_one();
+    if let Some(notifier) = &self.channel.notifier {
+      notifier.notify();
+    }
   }
 }
 
 impl<T> Receiver<T> {
-  pub(crate) fn avaliable(&self) -> bool {
-    let buffer = self.channel.buffer.lock().unwrap();
-    buffer.len() != 0
-  }
-
-  pub(crate) fn clear(&self) {
-    let mut buffer = self.channel.buffer.lock().unwrap();
-    buffer.clear();
-  }
-
   pub(crate) fn recv(&self) -> T {
     let mut buffer = self.channel.buffer.lock().unwrap();
     if buffer.len() == 0 {
@@ -52,12 +47,68 @@ impl<T> Receiver<T> {
     }
     buffer.pop_front().unwrap()
   }
+
+  pub(crate) fn try_recv(&self) -> Option<T> {
+    self.channel.buffer.lock().unwrap().pop_front()
+  }
+
+  // None -> nothing was sent before the timeout
+  pub(crate) fn recv_timeout(&self, timeout: Duration) -> Option<T> {
+    let buffer = self.channel.buffer.lock().unwrap();
+    let mut buffer = self.channel.condvar.wait_timeout_while(buffer, timeout, |buffer| buffer.is_empty()).unwrap().0;
+    buffer.pop_front()
+  }
 }
 
 pub(crate) fn channel<T>() -> (Sender<T>, Receiver<T>) {
   let channel: Channel<T> = Channel {
     buffer: Arc::new(Mutex::new(LinkedList::new())),
     condvar: Arc::new(Condvar::new()),
+    notifier: None,
+  };
+  (Sender { channel: channel.clone() }, Receiver { channel })
+}
+
+// same as channel, but every send also wakes up the notifier
+pub(crate) fn notified_channel<T>(notifier: Notifier) -> (Sender<T>, Receiver<T>) {
+  let channel: Channel<T> = Channel {
+    buffer: Arc::new(Mutex::new(LinkedList::new())),
+    condvar: Arc::new(Condvar::new()),
+    notifier: Some(notifier),
   };
   (Sender { channel: channel.clone() }, Receiver { channel })
 }
+
+// wakes up an idle hart (WFI) when something happens outside of it
+#[derive(Debug, Clone)]
+pub(crate) struct Notifier {
+  notified: Arc<Mutex<bool>>,
+  condvar: Arc<Condvar>,
+}
+
+impl Notifier {
+  pub(crate) fn new() -> Notifier {
+    Notifier {
+      notified: Arc::new(Mutex::new(false)),
+      condvar: Arc::new(Condvar::new()),
+    }
+  }
+
+  pub(crate) fn notify(&self) {
+    let mut notified = self.notified.lock().unwrap();
+    *notified = true;
+    self.condvar.notify_all();
+  }
+
+  // None -> wait until notified
+  pub(crate) fn wait(&self, timeout: Option<Duration>) {
+    let mut notified = self.notified.lock().unwrap();
+    if !*notified {
+      notified = match timeout {
+        Some(timeout) => self.condvar.wait_timeout_while(notified, timeout, |notified| !*notified).unwrap().0,
+        None => self.condvar.wait_while(notified, |notified| !*notified).unwrap(),
+      };
+    }
+    *notified = false;
+  }
+}
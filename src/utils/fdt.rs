@@ -0,0 +1,122 @@
+use std::collections::HashMap;
+
+// Writer of flattened device trees, version 17.
+
+const MAGIC: u32 = 0xd00dfeed;
+const VERSION: u32 = 17;
+const LAST_COMPATIBLE_VERSION: u32 = 16;
+const HEADER_SIZE: usize = 40;
+
+const BEGIN_NODE: u32 = 1;
+const END_NODE: u32 = 2;
+const PROP: u32 = 3;
+const END: u32 = 9;
+
+#[derive(Debug, Default)]
+pub(crate) struct Fdt {
+  structure: Vec<u8>,
+  strings: Vec<u8>,
+  // property name -> offset in strings
+  names: HashMap<String, u32>,
+  depth: usize,
+}
+
+impl Fdt {
+  pub(crate) fn new() -> Fdt {
+    Fdt::default()
+  }
+
+  fn token(&mut self, token: u32) {
+    self.structure.extend_from_slice(&token.to_be_bytes());
+  }
+
+  fn pad(&mut self) {
+    self.structure.resize(self.structure.len().next_multiple_of(4), 0);
+  }
+
+  pub(crate) fn begin_node(&mut self, name: &str) -> &mut Fdt {
+    self.token(BEGIN_NODE);
+    self.structure.extend_from_slice(name.as_bytes());
+    self.structure.push(0);
+    self.pad();
+    self.depth += 1;
+    self
+  }
+
+  pub(crate) fn end_node(&mut self) -> &mut Fdt {
+    self.token(END_NODE);
+    self.depth -= 1;
+    self
+  }
+
+  pub(crate) fn property(&mut self, name: &str, value: &[u8]) -> &mut Fdt {
+    let offset = match self.names.get(name) {
+      Some(&offset) => offset,
+      None => {
+        let offset = self.strings.len() as u32;
+        self.strings.extend_from_slice(name.as_bytes());
+        self.strings.push(0);
+        self.names.insert(name.to_string(), offset);
+        offset
+      },
+    };
+    self.token(PROP);
+    self.structure.extend_from_slice(&(value.len() as u32).to_be_bytes());
+    self.structure.extend_from_slice(&offset.to_be_bytes());
+    self.structure.extend_from_slice(value);
+    self.pad();
+    self
+  }
+
+  pub(crate) fn empty(&mut self, name: &str) -> &mut Fdt {
+    self.property(name, &[])
+  }
+
+  pub(crate) fn u32(&mut self, name: &str, value: u32) -> &mut Fdt {
+    self.property(name, &value.to_be_bytes())
+  }
+
+  pub(crate) fn u64(&mut self, name: &str, value: u64) -> &mut Fdt {
+    self.property(name, &value.to_be_bytes())
+  }
+
+  pub(crate) fn cells(&mut self, name: &str, cells: &[u32]) -> &mut Fdt {
+    let value: Vec<u8> = cells.iter().flat_map(|cell| cell.to_be_bytes()).collect();
+    self.property(name, &value)
+  }
+
+  // pairs of 64-bit addresses and sizes, for two address and two size cells
+  pub(crate) fn reg(&mut self, name: &str, pairs: &[(u64, u64)]) -> &mut Fdt {
+    let value: Vec<u8> = pairs.iter().flat_map(|&(address, size)| [address, size]).flat_map(u64::to_be_bytes).collect();
+    self.property(name, &value)
+  }
+
+  pub(crate) fn string(&mut self, name: &str, value: &str) -> &mut Fdt {
+    self.strings_list(name, &[value])
+  }
+
+  pub(crate) fn strings_list(&mut self, name: &str, values: &[&str]) -> &mut Fdt {
+    let value: Vec<u8> = values.iter().flat_map(|value| value.bytes().chain([0])).collect();
+    self.property(name, &value)
+  }
+
+  // the blob, with an empty memory reservation block
+  pub(crate) fn finish(mut self) -> Vec<u8> {
+    assert_eq!(self.depth, 0, "unclosed device tree node");
+    self.token(END);
+    let reservations = HEADER_SIZE;
+    // one terminating entry of two zero u64s
+    let structure = reservations + 16;
+    let strings = structure + self.structure.len();
+    let total = strings + self.strings.len();
+    let mut blob = Vec::with_capacity(total);
+    for field in [MAGIC, total as u32, structure as u32, strings as u32, reservations as u32, VERSION,
+      LAST_COMPATIBLE_VERSION, 0, self.strings.len() as u32, self.structure.len() as u32] {
+      blob.extend_from_slice(&field.to_be_bytes());
+    }
+    blob.extend_from_slice(&[0; 16]);
+    blob.extend_from_slice(&self.structure);
+    blob.extend_from_slice(&self.strings);
+    blob
+  }
+}
@@ -0,0 +1,88 @@
+use std::io::{self, Write};
+
+// Encoders for screenshots, rgb is 3 bytes per pixel, row after row.
+
+pub(crate) fn write_ppm<W: Write>(out: &mut W, width: usize, height: usize, rgb: &[u8]) -> io::Result<()> {
+  write!(out, "P6\n{} {}\n255\n", width, height)?;
+  out.write_all(rgb)
+}
+
+// the image data is stored without compression, so no deflate is needed
+pub(crate) fn write_png<W: Write>(out: &mut W, width: usize, height: usize, rgb: &[u8]) -> io::Result<()> {
+  out.write_all(b"\x89PNG\r\n\x1a\n")?;
+  let mut header = Vec::with_capacity(13);
+  header.extend_from_slice(&(width as u32).to_be_bytes());
+  header.extend_from_slice(&(height as u32).to_be_bytes());
+  // 8 bit depth, truecolor, deflate, adaptive filtering, no interlace
+  header.extend_from_slice(&[8, 2, 0, 0, 0]);
+  write_chunk(out, b"IHDR", &header)?;
+
+  // every row starts with filter type none
+  let mut raw = Vec::with_capacity((width * 3 + 1) * height);
+  for row in rgb.chunks(width * 3).take(height) {
+    raw.push(0);
+    raw.extend_from_slice(row);
+  }
+  // zlib stream with the lowest compression level
+  let mut data = vec![0x78, 0x01];
+  let mut blocks = raw.chunks(0xffff).peekable();
+  if blocks.peek().is_none() { data.extend_from_slice(&[1, 0, 0, 0xff, 0xff]); }
+  while let Some(block) = blocks.next() {
+    data.push(blocks.peek().is_none() as u8);
+    data.extend_from_slice(&(block.len() as u16).to_le_bytes());
+    data.extend_from_slice(&(!(block.len() as u16)).to_le_bytes());
+    data.extend_from_slice(block);
+  }
+  data.extend_from_slice(&adler32(&raw).to_be_bytes());
+  write_chunk(out, b"IDAT", &data)?;
+  write_chunk(out, b"IEND", &[])
+}
+
+fn write_chunk<W: Write>(out: &mut W, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+  out.write_all(&(data.len() as u32).to_be_bytes())?;
+  out.write_all(kind)?;
+  out.write_all(data)?;
+  let crc = crc32(crc32(!0, kind), data);
+  out.write_all(&(!crc).to_be_bytes())
+}
+
+// not inverted, the caller starts with !0 and inverts at the end
+fn crc32(mut crc: u32, data: &[u8]) -> u32 {
+  for byte in data {
+    crc ^= *byte as u32;
+    for _ in 0..8 {
+      crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+    }
+  }
+  crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+  let (mut a, mut b) = (1u32, 0u32);
+  // 5552 bytes can be summed before b overflows
+  for chunk in data.chunks(5552) {
+    for byte in chunk {
+      a += *byte as u32;
+      b += a;
+    }
+    a %= 65521;
+    b %= 65521;
+  }
+  b << 16 | a
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{adler32, crc32, write_png};
+
+  #[test]
+  fn png() {
+    assert_eq!(!crc32(!0, b"IEND"), 0xae426082);
+    assert_eq!(adler32(b"Wikipedia"), 0x11e60398);
+    let mut out = Vec::new();
+    write_png(&mut out, 1, 1, &[255, 0, 0]).unwrap();
+    // signature, IHDR, IDAT with 4 bytes of pixel row, IEND
+    assert_eq!(out.len(), 8 + 25 + 12 + 2 + 5 + 4 + 4 + 12);
+    assert_eq!(&out[out.len() - 8..], &[b'I', b'E', b'N', b'D', 0xae, 0x42, 0x60, 0x82]);
+  }
+}
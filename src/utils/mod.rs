@@ -1,8 +1,12 @@
+use std::{fs, io, os::unix::{fs::FileTypeExt, net::UnixListener}, path::Path};
+
 use softfloat_wrapper::{RoundingMode, Float, F32, ExceptionFlags};
 
 use crate::{hart::Hart, csrs::CsrRegistry, trap::Exception};
 
 pub(crate) mod channel;
+pub(crate) mod image;
+pub(crate) mod fdt;
 
 pub(crate) fn extend_sign(origin: u64, length: usize) -> i64 {
   let pos = origin & (1 << (length - 1)) == 0;
@@ -14,6 +18,25 @@ pub(crate) fn extend_sign(origin: u64, length: usize) -> i64 {
   }
 }
 
+// decimal or 0x-prefixed hex
+pub(crate) fn parse_number(s: &str) -> Option<u64> {
+  match s.strip_prefix("0x") {
+    Some(hex) => u64::from_str_radix(hex, 16).ok(),
+    None => s.parse().ok(),
+  }
+}
+
+// a unix socket listening at path. A socket left there by an earlier run is replaced, anything else is an error
+pub(crate) fn bind_socket(path: &Path) -> io::Result<UnixListener> {
+  match fs::symlink_metadata(path) {
+    Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(path)?,
+    Ok(_) => return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} exists and is not a socket", path.display()))),
+    Err(err) if err.kind() == io::ErrorKind::NotFound => {},
+    Err(err) => return Err(err),
+  }
+  UnixListener::bind(path)
+}
+
 pub(crate) fn round_mode(rm: u8, hart: &Hart) -> Result<RoundingMode, Exception> {
   match rm {
     0b000 => Ok(RoundingMode::TiesToEven),